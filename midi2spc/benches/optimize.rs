@@ -0,0 +1,63 @@
+extern crate midi2spc;
+
+use midi2spc::manifest::DEFAULT_TEMPO_ADJUST;
+use midi2spc::midi::MidiHandler;
+use midi2spc::nspc::{OptimizeBudget, Song};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+fn sample_dir() -> PathBuf {
+    let mut path_buf = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    if fs::read_dir(path_buf.clone()).is_err() {
+        path_buf = PathBuf::from("midi2spc");
+    }
+    path_buf.push("sample");
+    path_buf
+}
+
+fn main() {
+    let mut paths = fs::read_dir(sample_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext.eq("mid")))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    println!(
+        "{:<32} {:>10} {:>10} {:>10} {:>10}",
+        "song", "raw", "optimized", "saved", "time (ms)"
+    );
+    let mut total_time = 0;
+    for path in paths {
+        let mut midi = MidiHandler::new();
+        let result = midi.read(&path, false).and_then(|_| {
+            let raw = Song::from_midi(&midi, DEFAULT_TEMPO_ADJUST, None, false)?;
+            let start = Instant::now();
+            let optimized = Song::from_midi(
+                &midi,
+                DEFAULT_TEMPO_ADJUST,
+                Some(OptimizeBudget::default()),
+                false,
+            )?;
+            let elapsed = start.elapsed();
+            Ok((raw.data_size()?, optimized.data_size()?, elapsed))
+        });
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        match result {
+            Ok((raw_size, optimized_size, elapsed)) => {
+                let millis = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+                total_time += millis;
+                println!(
+                    "{:<32} {:>10} {:>10} {:>10} {:>10}",
+                    name,
+                    raw_size,
+                    optimized_size,
+                    raw_size - optimized_size,
+                    millis
+                );
+            }
+            Err(err) => println!("{:<32} {}", name, err),
+        }
+    }
+    println!("total optimization time {} ms", total_time);
+}
@@ -0,0 +1,310 @@
+use crate::error::ConvertError;
+use crate::manifest::Manifest;
+use crate::midi::Transform;
+use crate::nspc::{Song, SONG_FORMAT_VERSION};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_error::SimpleError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counting_converter(count: Arc<AtomicUsize>) -> Arc<Converter> {
+        Arc::new(move |path: &Path, _, _: &Transform| {
+            count.fetch_add(1, Ordering::SeqCst);
+            if path.ends_with("bad.mid") {
+                Err(Box::from(SimpleError::new("bad song")))
+            } else if path.ends_with("panic.mid") {
+                panic!("no tracks")
+            } else {
+                Song::empty()
+            }
+        })
+    }
+
+    #[test]
+    fn test_converts_each_input_once() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let conversions = Conversions::new(counting_converter(count.clone()), None, "");
+        let manifest = Manifest::single_song(Path::new("sample/short.mid"));
+        conversions.prefetch(&manifest, 4);
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        let transform = Transform::default();
+        let song = &manifest.banks[0].songs[0];
+        for _ in 0..3 {
+            conversions
+                .convert(Path::new("sample/short.mid"), song.tempo_factor, &transform)
+                .unwrap();
+        }
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        conversions
+            .convert(Path::new("sample/short.mid"), 0.5, &transform)
+            .unwrap();
+        assert_eq!(2, count.load(Ordering::SeqCst));
+        for _ in 0..2 {
+            let err = conversions
+                .convert(Path::new("bad.mid"), 0.5, &transform)
+                .unwrap_err();
+            assert_eq!("bad.mid: bad song", err.to_string());
+        }
+        assert_eq!(3, count.load(Ordering::SeqCst));
+        conversions.forget(Path::new("sample/short.mid"));
+        conversions
+            .convert(Path::new("sample/short.mid"), 0.5, &transform)
+            .unwrap();
+        conversions
+            .convert(Path::new("bad.mid"), 0.5, &transform)
+            .unwrap_err();
+        assert_eq!(4, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_prefetch_panic() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let conversions = Conversions::new(counting_converter(count.clone()), None, "");
+        let mut manifest = Manifest::single_song(Path::new("panic.mid"));
+        manifest.banks[0].songs[1] = crate::manifest::Song::default(Path::new("a.mid"));
+        conversions.prefetch(&manifest, 1);
+        assert_eq!(2, count.load(Ordering::SeqCst));
+        let transform = Transform::default();
+        let song = &manifest.banks[0].songs[0];
+        let err = conversions
+            .convert(Path::new("panic.mid"), song.tempo_factor, &transform)
+            .unwrap_err();
+        assert_eq!("panic.mid: conversion panicked: no tracks", err.to_string());
+        conversions
+            .convert(Path::new("a.mid"), song.tempo_factor, &transform)
+            .unwrap();
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let cache_dir = std::env::temp_dir().join(format!("midi2spc-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&cache_dir);
+        let count = Arc::new(AtomicUsize::new(0));
+        let transform = Transform::default();
+        let path = Path::new("sample/short.mid");
+        for _ in 0..2 {
+            let conversions = Conversions::new(
+                counting_converter(count.clone()),
+                Some(cache_dir.clone()),
+                "options",
+            );
+            conversions.convert(path, 0.2, &transform).unwrap();
+        }
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        let conversions =
+            Conversions::new(counting_converter(count.clone()), Some(cache_dir.clone()), "other");
+        conversions.convert(path, 0.2, &transform).unwrap();
+        assert_eq!(2, count.load(Ordering::SeqCst));
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
+
+pub type Converter = Fn(&Path, f32, &Transform) -> Result<Song, Box<Error>> + Send + Sync;
+
+/// Converts song inputs, each distinct input and set of options only once.
+/// Conversions for a whole manifest can be run in parallel up front, and
+/// with a cache directory, songs are kept on disk between runs.
+pub struct Conversions {
+    converter: Arc<Converter>,
+    cache_dir: Option<PathBuf>,
+    // describes the conversion options, so cached songs are only reused with
+    // the same ones
+    options: String,
+    songs: Mutex<HashMap<(PathBuf, String), Result<Song, ConvertError>>>,
+}
+
+impl Conversions {
+    pub fn new(converter: Arc<Converter>, cache_dir: Option<PathBuf>, options: &str) -> Conversions {
+        Conversions {
+            converter,
+            cache_dir,
+            options: options.to_string(),
+            songs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Converts every input in the manifest, running up to `jobs` conversions
+    /// at once.  Errors are kept until the song is asked for.
+    pub fn prefetch(&self, manifest: &Manifest, jobs: usize) {
+        let mut inputs = Vec::new();
+        {
+            let songs = self.songs.lock().unwrap();
+            for song in manifest.songs() {
+                if let Some(path) = &song.input {
+                    let input = (path.clone(), song.tempo_factor, song.transform.clone());
+                    let key = key(&input.0, input.1, &input.2);
+                    if !songs.contains_key(&key)
+                        && !inputs.iter().any(|(other, _)| *other == key)
+                    {
+                        inputs.push((key, input));
+                    }
+                }
+            }
+        }
+        let queue = Arc::new(Mutex::new(inputs));
+        // what each worker is converting, so a panic can be put down to its song
+        let in_progress = Arc::new(Mutex::new(HashMap::new()));
+        let (sender, receiver) = mpsc::channel();
+        let workers = (0..jobs.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let sender = sender.clone();
+                let converter = self.converter.clone();
+                let cache_dir = self.cache_dir.clone();
+                let options = self.options.clone();
+                let in_progress = in_progress.clone();
+                thread::spawn(move || loop {
+                    let next = queue.lock().unwrap().pop();
+                    match next {
+                        Some((key, (path, tempo_factor, transform))) => {
+                            in_progress
+                                .lock()
+                                .unwrap()
+                                .insert(thread::current().id(), key.clone());
+                            let song = load_or_convert(
+                                converter.as_ref(),
+                                cache_dir.as_ref().map(PathBuf::as_path),
+                                &options,
+                                &path,
+                                tempo_factor,
+                                &transform,
+                            );
+                            in_progress.lock().unwrap().remove(&thread::current().id());
+                            sender.send((key, song)).unwrap();
+                        }
+                        None => break,
+                    }
+                })
+            })
+            .collect::<Vec<thread::JoinHandle<()>>>();
+        drop(sender);
+        for (key, song) in receiver {
+            self.songs.lock().unwrap().insert(key, song);
+        }
+        for worker in workers {
+            let id = worker.thread().id();
+            if let Err(panic) = worker.join() {
+                let message = match panic.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => panic
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .unwrap_or_else(|| "unknown error".to_string()),
+                };
+                if let Some(key) = in_progress.lock().unwrap().remove(&id) {
+                    let err = ConvertError::Invalid {
+                        path: Some(key.0.clone()),
+                        message: format!("conversion panicked: {}", message),
+                    };
+                    self.songs.lock().unwrap().insert(key, Err(err));
+                }
+            }
+        }
+    }
+
+    pub fn convert(
+        &self,
+        path: &Path,
+        tempo_factor: f32,
+        transform: &Transform,
+    ) -> Result<Song, Box<Error>> {
+        let key = key(path, tempo_factor, transform);
+        let existing = self.songs.lock().unwrap().get(&key).cloned();
+        let song = match existing {
+            Some(song) => song,
+            None => {
+                let song = load_or_convert(
+                    self.converter.as_ref(),
+                    self.cache_dir.as_ref().map(PathBuf::as_path),
+                    &self.options,
+                    path,
+                    tempo_factor,
+                    transform,
+                );
+                self.songs.lock().unwrap().insert(key, song.clone());
+                song
+            }
+        };
+        song.map_err(Box::from)
+    }
+
+    /// Drops every song converted from the input, so it's converted again.
+    pub fn forget(&self, path: &Path) {
+        self.songs
+            .lock()
+            .unwrap()
+            .retain(|(input, _), _| input != path);
+    }
+}
+
+fn key(path: &Path, tempo_factor: f32, transform: &Transform) -> (PathBuf, String) {
+    (path.to_path_buf(), format!("{}|{:?}", tempo_factor, transform))
+}
+
+// Reads the song from the cache if it's there, or converts it and adds it.
+fn load_or_convert(
+    converter: &Converter,
+    cache_dir: Option<&Path>,
+    options: &str,
+    path: &Path,
+    tempo_factor: f32,
+    transform: &Transform,
+) -> Result<Song, ConvertError> {
+    let cache_path = match cache_dir {
+        Some(cache_dir) => Some(cache_file(cache_dir, options, path, tempo_factor, transform)?),
+        None => None,
+    };
+    if let Some(cache_path) = &cache_path {
+        if let Ok(song) = Song::from_json(cache_path) {
+            return Ok(song);
+        }
+    }
+    let song = converter(path, tempo_factor, transform)
+        .map_err(|err| ConvertError::from(err).in_file(path))?;
+    if let Some(cache_path) = &cache_path {
+        // a song that can't be cached is converted again next time
+        if fs::create_dir_all(cache_path.parent().unwrap()).is_ok() {
+            let _ = song.write_to_json(cache_path);
+        }
+    }
+    Ok(song)
+}
+
+// The cache file for an input, named for a hash of its contents and
+// everything else that affects its conversion.  The hash isn't guaranteed to
+// be the same between Rust releases, which at worst converts songs again.
+fn cache_file(
+    cache_dir: &Path,
+    options: &str,
+    path: &Path,
+    tempo_factor: f32,
+    transform: &Transform,
+) -> Result<PathBuf, ConvertError> {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&fs::read(path).map_err(ConvertError::io(path))?);
+    hasher.write(
+        format!(
+            "{}|{}|{}|{}|{}|{:?}",
+            env!("CARGO_PKG_VERSION"),
+            SONG_FORMAT_VERSION,
+            options,
+            path.extension().and_then(|ext| ext.to_str()).unwrap_or(""),
+            tempo_factor,
+            transform
+        )
+        .as_bytes(),
+    );
+    Ok(cache_dir.join(format!("{:016x}.json", hasher.finish())))
+}
@@ -0,0 +1,191 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_error::SimpleError;
+
+    #[test]
+    fn test_from_boxed() {
+        let err: Box<Error> = Box::from(ConvertError::VoiceAllocation {
+            path: PathBuf::from("a.mid"),
+            message: "couldn't fit notes into available channels".to_string(),
+        });
+        match ConvertError::from(err) {
+            ConvertError::VoiceAllocation { path, .. } => assert_eq!(Path::new("a.mid"), path),
+            other => panic!("unexpected error {:?}", other),
+        }
+        let err: Box<Error> = Box::from(SimpleError::new("line 1: bad\nline 2: worse"));
+        let err = ConvertError::from(err).in_file(Path::new("a.nspc"));
+        assert_eq!(
+            "a.nspc: line 1: bad\na.nspc: line 2: worse",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_clone_io() {
+        let err = ConvertError::io(Path::new("a.mid"))(io::Error::new(
+            io::ErrorKind::NotFound,
+            "missing",
+        ));
+        assert_eq!("a.mid: missing", err.clone().to_string());
+        assert!(err.clone().source().is_some());
+    }
+}
+
+/// Why converting songs or building a ROM failed, with the file, bank or song
+/// the problem was found in.
+#[derive(Debug)]
+pub enum ConvertError {
+    /// A MIDI file couldn't be parsed.
+    MidiParse { path: PathBuf, message: String },
+    /// A song plays more notes at once than the driver has voices for.
+    VoiceAllocation { path: PathBuf, message: String },
+    /// Songs don't fit in the ARAM or ROM space available to them.
+    LayoutOverflow {
+        bank: Option<String>,
+        song: Option<String>,
+        message: String,
+    },
+    /// A file couldn't be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// Any other problem with a song, manifest or ROM.
+    Invalid {
+        path: Option<PathBuf>,
+        message: String,
+    },
+}
+
+impl ConvertError {
+    pub fn io(path: &Path) -> impl Fn(io::Error) -> ConvertError {
+        let path = path.to_path_buf();
+        move |source| ConvertError::Io {
+            path: path.clone(),
+            source,
+        }
+    }
+
+    pub fn layout(bank: &str, song: Option<&str>, message: String) -> ConvertError {
+        ConvertError::LayoutOverflow {
+            bank: Some(bank.to_string()),
+            song: song.map(str::to_string),
+            message,
+        }
+    }
+
+    /// Adds the file to an error that doesn't say where it was found.
+    pub fn in_file(self, file: &Path) -> ConvertError {
+        match self {
+            ConvertError::Invalid {
+                path: None,
+                message,
+            } => ConvertError::Invalid {
+                path: Some(file.to_path_buf()),
+                message,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::MidiParse { path, message } => {
+                write!(f, "{}: MIDI read error: {}", path.display(), message)
+            }
+            ConvertError::VoiceAllocation { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            ConvertError::LayoutOverflow {
+                bank,
+                song,
+                message,
+            } => {
+                if let Some(bank) = bank {
+                    write!(f, "{} bank: ", bank)?;
+                }
+                if let Some(song) = song {
+                    write!(f, "{}: ", song)?;
+                }
+                write!(f, "{}", message)
+            }
+            ConvertError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ConvertError::Invalid {
+                path: Some(path),
+                message,
+            } => {
+                // every line is a separate problem with the file
+                let lines = message
+                    .lines()
+                    .map(|line| format!("{}: {}", path.display(), line))
+                    .collect::<Vec<String>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+            ConvertError::Invalid {
+                path: None,
+                message,
+            } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ConvertError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match self {
+            ConvertError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// errors are shared between everything converted from the same input
+impl Clone for ConvertError {
+    fn clone(&self) -> ConvertError {
+        match self {
+            ConvertError::MidiParse { path, message } => ConvertError::MidiParse {
+                path: path.clone(),
+                message: message.clone(),
+            },
+            ConvertError::VoiceAllocation { path, message } => ConvertError::VoiceAllocation {
+                path: path.clone(),
+                message: message.clone(),
+            },
+            ConvertError::LayoutOverflow {
+                bank,
+                song,
+                message,
+            } => ConvertError::LayoutOverflow {
+                bank: bank.clone(),
+                song: song.clone(),
+                message: message.clone(),
+            },
+            ConvertError::Io { path, source } => ConvertError::Io {
+                path: path.clone(),
+                source: io::Error::new(source.kind(), source.to_string()),
+            },
+            ConvertError::Invalid { path, message } => ConvertError::Invalid {
+                path: path.clone(),
+                message: message.clone(),
+            },
+        }
+    }
+}
+
+/// Keeps errors that are already a `ConvertError`, and describes any other
+/// error by its message.
+impl From<Box<Error>> for ConvertError {
+    fn from(err: Box<Error>) -> ConvertError {
+        match err.downcast::<ConvertError>() {
+            Ok(err) => *err,
+            Err(err) => ConvertError::Invalid {
+                path: None,
+                message: err.to_string(),
+            },
+        }
+    }
+}
@@ -1,38 +1,210 @@
 use clap::ArgMatches;
+use pbr::ProgressBar;
+use simple_error::SimpleError;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::io;
 use std::num::ParseIntError;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+pub mod convert;
+pub mod error;
+pub mod log;
 pub mod manifest;
 pub mod midi;
+pub mod musicxml;
 pub mod nspc;
+pub mod patch;
 pub mod rom;
 
+use crate::error::ConvertError;
+use crate::log::{Event, Log};
+use crate::rom::report::Report;
+
+// songs converted at once for a manifest build
+const DEFAULT_JOBS: usize = 4;
+// how often watch checks for changed files
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+// a sixteenth note
+const PREVIEW_TICKS_PER_COLUMN: u32 = 6;
+const DEFAULT_EXPORT_LABEL: &str = "song";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_poll_changes() {
+        let dir = env::temp_dir().join(format!("midi2spc-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest_path = dir.join("manifest.json");
+        fs::write(
+            &manifest_path,
+            r#"{"banks": [{"name": "overworld"}], "overworld": {
+                "0": {"input": "a.mid"}, "1": {"input": "b.mid"}
+            }}"#,
+        )
+        .unwrap();
+        let (a, b) = (dir.join("a.mid"), dir.join("b.mid"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let conversions = convert::Conversions::new(
+            Arc::new(move |_: &Path, _, _: &midi::Transform| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                nspc::Song::empty()
+            }),
+            None,
+            "",
+        );
+        let convert_all = || {
+            for path in &[&a, &b] {
+                conversions.convert(path, 1.0, &midi::Transform::default()).unwrap();
+            }
+            count.load(Ordering::SeqCst)
+        };
+        let mut stamps = HashMap::new();
+        assert_eq!(Some(vec![]), poll_changes(&manifest_path, &mut stamps, &conversions));
+        assert_eq!(2, convert_all());
+        assert_eq!(None, poll_changes(&manifest_path, &mut stamps, &conversions));
+        assert_eq!(2, convert_all());
+
+        fs::write(&a, "changed").unwrap();
+        assert_eq!(
+            Some(vec![a.clone()]),
+            poll_changes(&manifest_path, &mut stamps, &conversions)
+        );
+        assert_eq!(3, convert_all());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_file() {
+        let rom_path = env::temp_dir().join(format!("midi2spc-same-{}.smc", std::process::id()));
+        fs::write(&rom_path, "rom").unwrap();
+        let dir = rom_path.parent().unwrap();
+        let dotted = dir.join(".").join(rom_path.file_name().unwrap());
+        assert!(same_file(&dotted, &rom_path));
+        assert!(!same_file(&dir.join("other.smc"), &rom_path));
+        fs::remove_file(&rom_path).unwrap();
+    }
+}
+
 pub fn run(matches: clap::ArgMatches) -> Result<(), Box<Error>> {
-    let optimize = !matches.is_present("skip_optimization");
-    let verbose = matches.is_present("verbose");
-    let asm_file = matches.value_of("out_ASM");
-    let asm_module = matches.value_of("asm_module");
-    let asm_label = matches.value_of("asm_label");
+    let quiet = matches.is_present("quiet");
+    // debugging output is left out of a quiet build too
+    let verbose = matches.is_present("verbose") && !quiet;
+    let mut options = Options::new()
+        .optimize(if matches.is_present("skip_optimization") {
+            None
+        } else {
+            Some(read_optimize_budget(&matches)?)
+        })
+        .verbose(verbose);
+    if matches.value_of("log_format") == Some("json") {
+        options = options.log(log::json_lines(io::stderr()));
+    } else if !quiet {
+        options = options.log(terminal_log(verbose));
+    }
+    if let Some(relocation) = read_relocation(&matches)? {
+        options = options.relocation(relocation);
+    }
+    if let Some(value) = matches.value_of("jobs") {
+        options = options.jobs(value.parse::<usize>()?);
+    }
+    if let Some(cache_dir) = matches.value_of("cache") {
+        options = options.cache_dir(Path::new(cache_dir));
+    }
+    let dry_run = matches.is_present("dry_run");
+    let output = matches.value_of("output");
+    let patch_path = if dry_run { None } else { matches.value_of("patch") };
+    let asm_file = if dry_run { None } else { matches.value_of("out_ASM") };
+    if let Some(patch_path) = patch_path {
+        options = options.patch(Path::new(patch_path));
+    }
+    if let Some(asm_file) = asm_file {
+        options = options.asm_file(
+            asm_file,
+            matches.value_of("asm_module"),
+            matches.value_of("asm_label"),
+        );
+    }
+    // the ROM subcommands write to the output file, or the input ROM if
+    // nothing else is being written
+    let rom_options = |options: Options, matches: &ArgMatches| -> Result<Options, Box<Error>> {
+        let rom_path = matches.value_of("ROM").unwrap();
+        let options = options.bank_addrs(read_bank_addrs(matches)?);
+        Ok(
+            match rom_output_path(rom_path, output, dry_run, asm_file, patch_path) {
+                Some(output_path) => options.output(Path::new(output_path)),
+                None => options,
+            },
+        )
+    };
     if let Some(matches) = matches.subcommand_matches("build_rom") {
         let manifest_path = matches.value_of("MANIFEST").unwrap();
         let rom_path = matches.value_of("ROM").unwrap();
-        let bank_addrs = read_bank_addrs(&matches)?;
-        build_rom(manifest_path, rom_path, bank_addrs, optimize, verbose, asm_file, asm_module, asm_label)?;
+        let report = rom_options(options, matches)?
+            .build_rom(Path::new(manifest_path), Path::new(rom_path))?;
+        match read_report_format(matches) {
+            Some(format) => report.write(&mut io::stdout(), format)?,
+            None if dry_run => report.write(&mut io::stdout(), rom::report::ReportFormat::Text)?,
+            None => (),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("watch") {
+        let manifest_path = matches.value_of("MANIFEST").unwrap();
+        let rom_path = matches.value_of("ROM").unwrap();
+        rom_options(options, matches)?.watch(
+            Path::new(manifest_path),
+            Path::new(rom_path),
+            &|changed, result| {
+                if !changed.is_empty() && !quiet {
+                    let names = changed
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<String>>();
+                    println!("Rebuilt after changes to {}", names.join(", "));
+                }
+                match result {
+                    Ok(ref report) if !quiet => {
+                        let _ = report.write(&mut io::stdout(), rom::report::ReportFormat::Text);
+                    }
+                    Ok(_) => (),
+                    Err(err) => println!("Error: {}", err),
+                }
+                if !quiet {
+                    println!("Watching for changes...");
+                }
+            },
+        )?;
     } else if let Some(matches) = matches.subcommand_matches("all_overworld") {
         let input_path = matches.value_of("INPUT").unwrap();
         let rom_path = matches.value_of("ROM").unwrap();
-        let bank_addrs = read_bank_addrs(matches)?;
-        write_all_overworld(input_path, rom_path, bank_addrs, optimize, verbose, asm_file, asm_module, asm_label)?;
+        let report = rom_options(options, matches)?
+            .write_all_overworld(Path::new(input_path), Path::new(rom_path))?;
+        if dry_run {
+            report.write(&mut io::stdout(), rom::report::ReportFormat::Text)?;
+        }
     } else if let Some(matches) = matches.subcommand_matches("file_select") {
         let input_path = matches.value_of("INPUT").unwrap();
         let rom_path = matches.value_of("ROM").unwrap();
-        let bank_addrs = read_bank_addrs(matches)?;
-        write_file_select(input_path, rom_path, bank_addrs, optimize, verbose, asm_file, asm_module, asm_label)?;
+        let report = rom_options(options, matches)?
+            .write_file_select(Path::new(input_path), Path::new(rom_path))?;
+        if dry_run {
+            report.write(&mut io::stdout(), rom::report::ReportFormat::Text)?;
+        }
     } else if let Some(matches) = matches.subcommand_matches("dump_midi") {
         let input_path = matches.value_of("INPUT");
         let mut midi = midi::MidiHandler::new();
-        midi.read(Path::new(input_path.unwrap()), verbose)
+        midi.read(Path::new(input_path.unwrap()), options.verbose)
             .unwrap_or_else(|err| {
                 println!("Error reading MIDI: {:?}", err);
             });
@@ -41,9 +213,47 @@ pub fn run(matches: clap::ArgMatches) -> Result<(), Box<Error>> {
         let input_path = matches.value_of("INPUT");
         let output_path = matches.value_of("OUTPUT");
         let mut midi = midi::MidiHandler::new();
-        midi.read(Path::new(input_path.unwrap()), verbose)?;
-        let song = nspc::Song::from_midi(&midi, manifest::DEFAULT_TEMPO_ADJUST, optimize, verbose)?;
-        song.write_to_json(Path::new(output_path.unwrap()));
+        midi.read(Path::new(input_path.unwrap()), options.verbose)?;
+        let song = nspc::Song::from_midi(
+            &mut midi,
+            manifest::DEFAULT_TEMPO_ADJUST,
+            options.optimize,
+            options.verbose,
+        )?;
+        write_song(&song, Path::new(output_path.unwrap()))?;
+    } else if let Some(matches) = matches.subcommand_matches("convert_song") {
+        let input_path = matches.value_of("INPUT").unwrap();
+        let output_path = matches.value_of("OUTPUT").unwrap();
+        let song = options.convert_song(Path::new(input_path))?;
+        write_song(&song, Path::new(output_path))?;
+    } else if let Some(matches) = matches.subcommand_matches("export_song") {
+        let input_path = Path::new(matches.value_of("INPUT").unwrap());
+        let aram_addr = match matches.value_of("aram_addr") {
+            Some(value) => usize::from_str_radix(value, 16)?,
+            None => 0,
+        };
+        let song = options.convert_song(input_path)?;
+        let errors = song.validate();
+        if !errors.is_empty() {
+            return Err(nspc::errors_in(input_path, &errors));
+        }
+        write_export(
+            &nspc::export::RelocatableSong::new(&song, !matches.is_present("no_loop"))?,
+            Path::new(matches.value_of("OUTPUT").unwrap()),
+            aram_addr,
+            matches.value_of("label").unwrap_or(DEFAULT_EXPORT_LABEL),
+        )?;
+    } else if let Some(matches) = matches.subcommand_matches("preview") {
+        let input_path = matches.value_of("INPUT").unwrap();
+        let ticks = match matches.value_of("ticks") {
+            Some(value) => value.parse::<u32>()?,
+            None => PREVIEW_TICKS_PER_COLUMN,
+        };
+        let song = options.convert_song(Path::new(input_path))?;
+        write_preview(&song, matches.value_of("OUTPUT").map(Path::new), ticks)?;
+    } else if let Some(matches) = matches.subcommand_matches("validate") {
+        let input_paths = matches.values_of("INPUT").unwrap().collect::<Vec<&str>>();
+        validate(&options, &input_paths, quiet)?;
     } else if let Some(matches) = matches.subcommand_matches("gen_fake_rom") {
         let input_path = matches.value_of("INPUT");
         let output_path = matches.value_of("OUTPUT");
@@ -56,85 +266,443 @@ pub fn run(matches: clap::ArgMatches) -> Result<(), Box<Error>> {
     Ok(())
 }
 
-pub fn build_rom(
-    manifest_path: &str,
-    rom_path: &str,
+/// Options for converting songs and building ROMs, set builder-style:
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # fn main() -> Result<(), midi2spc::error::ConvertError> {
+/// let report = midi2spc::Options::new()
+///     .jobs(8)
+///     .output(Path::new("patched.sfc"))
+///     .build_rom(Path::new("manifest.json"), Path::new("game.sfc"))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Options {
+    optimize: Option<nspc::OptimizeBudget>,
+    relocation: Option<rom::Relocation>,
     bank_addrs: [u32; 3],
-    optimize: bool,
+    jobs: usize,
+    cache_dir: Option<PathBuf>,
+    output: Option<PathBuf>,
+    patch: Option<PathBuf>,
+    asm_file: Option<String>,
+    asm_module: Option<String>,
+    asm_label: Option<String>,
     verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
-) -> Result<(), Box<Error>> {
-    let manifest = manifest::Manifest::new(Path::new(manifest_path))?;
-    rom::write(
-        &manifest,
-        Path::new(rom_path),
-        bank_addrs,
-        converter(optimize, verbose).as_ref(),
-        verbose,
-        asm_file,
-        asm_module,
-        asm_label,
-    )?;
+    log: Arc<Log>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+impl Options {
+    /// Songs are optimized without a budget, banks are found at their usual
+    /// addresses and nothing is written.
+    pub fn new() -> Options {
+        Options {
+            optimize: Some(nspc::OptimizeBudget::default()),
+            relocation: None,
+            bank_addrs: rom::DEFAULT_BANK_BASE_ADDRS,
+            jobs: DEFAULT_JOBS,
+            cache_dir: None,
+            output: None,
+            patch: None,
+            asm_file: None,
+            asm_module: None,
+            asm_label: None,
+            verbose: false,
+            log: Arc::new(|_: &Event| ()),
+        }
+    }
+
+    /// Limits how long CallLoops are extracted for, or skips it with `None`.
+    pub fn optimize(mut self, optimize: Option<nspc::OptimizeBudget>) -> Options {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Moves the music banks to free space in the ROM.
+    pub fn relocation(mut self, relocation: rom::Relocation) -> Options {
+        self.relocation = Some(relocation);
+        self
+    }
+
+    pub fn bank_addrs(mut self, bank_addrs: [u32; 3]) -> Options {
+        self.bank_addrs = bank_addrs;
+        self
+    }
+
+    /// How many songs are converted at once.
+    pub fn jobs(mut self, jobs: usize) -> Options {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Keeps converted songs in the directory, so unchanged songs aren't
+    /// converted again.
+    pub fn cache_dir(mut self, cache_dir: &Path) -> Options {
+        self.cache_dir = Some(cache_dir.to_path_buf());
+        self
+    }
+
+    /// Writes the patched ROM to the file, which can be the input ROM.
+    pub fn output(mut self, output: &Path) -> Options {
+        self.output = Some(output.to_path_buf());
+        self
+    }
+
+    /// Writes the changes to an IPS or BPS patch, by extension.
+    pub fn patch(mut self, patch: &Path) -> Options {
+        self.patch = Some(patch.to_path_buf());
+        self
+    }
+
+    /// Writes the music banks to an ASM file, with optional prefixes for the
+    /// module and label of each bank.
+    pub fn asm_file(
+        mut self,
+        asm_file: &str,
+        module: Option<&str>,
+        label: Option<&str>,
+    ) -> Options {
+        self.asm_file = Some(asm_file.to_string());
+        self.asm_module = module.map(str::to_string);
+        self.asm_label = label.map(str::to_string);
+        self
+    }
+
+    /// Prints debugging output.
+    pub fn verbose(mut self, verbose: bool) -> Options {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Called with each song converted, each bank and song written to the
+    /// ROM and any warnings along the way.
+    pub fn log<F: Fn(&Event) + Send + Sync + 'static>(mut self, log: F) -> Options {
+        self.log = Arc::new(log);
+        self
+    }
+
+    /// Builds the ROM according to a manifest file.
+    pub fn build_rom(
+        &self,
+        manifest_path: &Path,
+        rom_path: &Path,
+    ) -> Result<Report, ConvertError> {
+        let manifest = manifest::Manifest::new(manifest_path)?;
+        self.write_manifest(&manifest, &self.conversions(), rom_path)
+    }
+
+    /// Replaces all music with one song.
+    pub fn write_all_overworld(
+        &self,
+        input_path: &Path,
+        rom_path: &Path,
+    ) -> Result<Report, ConvertError> {
+        let manifest = manifest::Manifest::single_song(input_path);
+        self.write_manifest(&manifest, &self.conversions(), rom_path)
+    }
+
+    /// Replaces the file select music with one song, keeping the others.
+    pub fn write_file_select(
+        &self,
+        input_path: &Path,
+        rom_path: &Path,
+    ) -> Result<Report, ConvertError> {
+        let manifest = manifest::Manifest::file_select(input_path);
+        self.write_manifest(&manifest, &self.conversions(), rom_path)
+    }
+
+    /// Builds the ROM like `build_rom`, then watches the manifest and every
+    /// input it uses, rebuilding whenever one changes.  Only changed inputs
+    /// are converted again.  `on_build` is given the files that changed and
+    /// the result of each build.  Runs until interrupted.
+    pub fn watch(
+        &self,
+        manifest_path: &Path,
+        rom_path: &Path,
+        on_build: &Fn(&[PathBuf], Result<Report, ConvertError>),
+    ) -> Result<(), ConvertError> {
+        // original songs are read from the ROM, so it can't be overwritten
+        if self.output.as_ref().map_or(false, |output| same_file(output, rom_path)) {
+            return Err(ConvertError::Invalid {
+                path: None,
+                message:
+                    "watch needs --output, --patch or --asm_file so the input ROM isn't modified"
+                        .to_string(),
+            });
+        }
+        let conversions = self.conversions();
+        let mut stamps = HashMap::new();
+        loop {
+            if let Some(paths) = poll_changes(manifest_path, &mut stamps, &conversions) {
+                let result = manifest::Manifest::new(manifest_path)
+                    .map_err(ConvertError::from)
+                    .and_then(|manifest| self.write_manifest(&manifest, &conversions, rom_path));
+                on_build(&paths, result);
+            }
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+
+    /// Converts any song input accepted by a manifest to N-SPC commands.
+    pub fn convert_song(&self, input_path: &Path) -> Result<nspc::Song, ConvertError> {
+        (*converter(self.optimize, self.verbose, self.log.clone()))(
+            input_path,
+            manifest::DEFAULT_TEMPO_ADJUST,
+            &midi::Transform::default(),
+        )
+        .map_err(|err| ConvertError::from(err).in_file(input_path))
+    }
+
+    /// Converts a song input and returns anything that would keep the driver
+    /// from playing it.
+    pub fn validate(&self, input_path: &Path) -> Result<Vec<String>, ConvertError> {
+        Ok(self.convert_song(input_path)?.validate())
+    }
+
+    fn write_manifest(
+        &self,
+        manifest: &manifest::Manifest,
+        conversions: &convert::Conversions,
+        rom_path: &Path,
+    ) -> Result<Report, ConvertError> {
+        conversions.prefetch(manifest, self.jobs);
+        let report = rom::write(
+            manifest,
+            rom_path,
+            &rom::BuildOptions {
+                output_path: self.output.as_ref().map(PathBuf::as_path),
+                patch_path: self.patch.as_ref().map(PathBuf::as_path),
+                bank_base_addrs: self.bank_addrs,
+                converter: &|path, tempo_factor, transform| {
+                    conversions.convert(path, tempo_factor, transform)
+                },
+                optimize: self.optimize,
+                relocation: self.relocation.as_ref(),
+                log: &*self.log,
+                verbose: self.verbose,
+                asm_file: self.asm_file.as_ref().map(String::as_str),
+                asm_module: self.asm_module.as_ref().map(String::as_str),
+                asm_label: self.asm_label.as_ref().map(String::as_str),
+            },
+        )?;
+        Ok(report)
+    }
+
+    fn conversions(&self) -> convert::Conversions {
+        convert::Conversions::new(
+            converter(self.optimize, self.verbose, self.log.clone()),
+            self.cache_dir.clone(),
+            &format!("{:?}", self.optimize),
+        )
+    }
+}
+
+// the manifest and the inputs it uses, if it can be read
+fn watched_files(manifest_path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![manifest_path.to_path_buf()];
+    if let Ok(manifest) = manifest::Manifest::new(manifest_path) {
+        for song in manifest.songs() {
+            if let Some(input) = &song.input {
+                if !files.contains(input) {
+                    files.push(input.clone());
+                }
+            }
+        }
+    }
+    files
+}
+
+// one pass of watch, which forgets the conversions of the files that changed
+// since the last pass and returns them, or None if none did.  Every file is
+// new to the first pass, which returns no paths.
+fn poll_changes(
+    manifest_path: &Path,
+    stamps: &mut HashMap<PathBuf, Option<(SystemTime, u64)>>,
+    conversions: &convert::Conversions,
+) -> Option<Vec<PathBuf>> {
+    let changed = watched_files(manifest_path)
+        .into_iter()
+        .map(|path| {
+            let stamp = file_stamp(&path);
+            (path, stamp)
+        })
+        .filter(|(path, stamp)| stamps.get(path) != Some(stamp))
+        .collect::<Vec<(PathBuf, Option<(SystemTime, u64)>)>>();
+    if changed.is_empty() {
+        return None;
+    }
+    let first_pass = stamps.is_empty();
+    let mut paths = Vec::new();
+    for (path, stamp) in changed {
+        conversions.forget(&path);
+        stamps.insert(path.clone(), stamp);
+        paths.push(path);
+    }
+    if first_pass {
+        paths.clear();
+    }
+    Some(paths)
+}
+
+// whether two paths name the same file, however they're written
+fn same_file(path: &Path, other: &Path) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(other)) {
+        (Ok(path), Ok(other)) => path == other,
+        _ => path == other,
+    }
+}
+
+// when a file was last modified and its size, or None if it doesn't exist
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// a progress bar for each bank as it's written, and any warnings
+fn terminal_log(verbose: bool) -> impl Fn(&Event) + Send + Sync {
+    let bar = Mutex::new(None);
+    move |event: &Event| match *event {
+        Event::Warning { input, message } => match input {
+            Some(input) => println!("Warning: {}: {}", input.display(), message),
+            None => println!("Warning: {}", message),
+        },
+        Event::BankStarted { bank, songs } => {
+            let mut pb = ProgressBar::new(songs as u64);
+            pb.message(&format!("Writing {} songs ", bank));
+            *bar.lock().unwrap() = Some(pb);
+        }
+        Event::SongWritten { .. } => {
+            if let Some(pb) = bar.lock().unwrap().as_mut() {
+                pb.inc();
+            }
+        }
+        Event::BankFinished { report } => {
+            if let Some(mut pb) = bar.lock().unwrap().take() {
+                pb.finish_print(&format!(
+                    "{} bank complete.  0x{:X} bytes saved by sharing.",
+                    report.name, report.bytes_shared
+                ));
+            }
+        }
+        Event::Detail { message } => println!("{}", message),
+        Event::SongConverted { input } if verbose => println!("Converted {}", input.display()),
+        _ => (),
+    }
+}
+
+// converts and checks each song input, printing any problems found
+fn validate(options: &Options, input_paths: &[&str], quiet: bool) -> Result<(), Box<Error>> {
+    let mut invalid = 0;
+    for input_path in input_paths {
+        let path = Path::new(input_path);
+        match options.validate(path) {
+            Ok(ref errors) if errors.is_empty() => {
+                if !quiet {
+                    println!("{}: ok", path.display());
+                }
+            }
+            Ok(errors) => {
+                println!("{}", nspc::errors_in(path, &errors));
+                invalid += 1;
+            }
+            Err(err) => {
+                println!("{}", err);
+                invalid += 1;
+            }
+        }
+    }
+    if invalid > 0 {
+        return Err(Box::from(SimpleError::new(format!(
+            "{} of {} songs can't be played",
+            invalid,
+            input_paths.len()
+        ))));
+    }
     Ok(())
 }
 
-pub fn write_all_overworld(
-    input_path: &str,
-    rom_path: &str,
-    bank_addrs: [u32; 3],
-    optimize: bool,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
-) -> Result<(), Box<Error>> {
-    rom::write_all_overworld(
-        Path::new(input_path),
-        Path::new(rom_path),
-        bank_addrs,
-        converter(optimize, verbose).as_ref(),
-        verbose,
-        asm_file,
-        asm_module,
-        asm_label,
-    )?;
+fn write_song(song: &nspc::Song, path: &Path) -> Result<(), Box<Error>> {
+    if path.extension().map_or(false, |ext| ext.eq("nspc")) {
+        fs::write(path, song.to_text()).map_err(ConvertError::io(path))?;
+    } else {
+        song.write_to_json(path)?;
+    }
     Ok(())
 }
 
-pub fn write_file_select(
-    input_path: &str,
-    rom_path: &str,
-    bank_addrs: [u32; 3],
-    optimize: bool,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
+// writes an ASM module, or a binary with its relocations beside it
+fn write_export(
+    song: &nspc::export::RelocatableSong,
+    path: &Path,
+    aram_addr: usize,
+    label: &str,
 ) -> Result<(), Box<Error>> {
-    rom::write_file_select(
-        Path::new(input_path),
-        Path::new(rom_path),
-        bank_addrs,
-        converter(optimize, verbose).as_ref(),
-        verbose,
-        asm_file,
-        asm_module,
-        asm_label,
-    )?;
+    if path.extension().map_or(false, |ext| ext.eq("asm")) {
+        fs::write(path, song.asm(label)).map_err(ConvertError::io(path))?;
+    } else {
+        fs::write(path, song.at(aram_addr)?).map_err(ConvertError::io(path))?;
+        let relocs_path = path.with_extension("relocs.json");
+        let relocs = serde_json::to_string_pretty(song)?;
+        fs::write(&relocs_path, relocs + "\n").map_err(ConvertError::io(&relocs_path))?;
+    }
     Ok(())
 }
 
-fn converter(optimize: bool, verbose: bool) -> Box<Fn(&Path, f32) -> Result<nspc::Song, Box<Error>>> {
-    let converter = move |path: &Path, tempo_factor| {
-        if path.extension().map_or(false, |ext| ext.eq("mid")) {
-            song_from_midi(path, tempo_factor, optimize, verbose)
-        } else {
-            Ok(nspc::Song::from_json(path))
+fn write_preview(song: &nspc::Song, path: Option<&Path>, ticks: u32) -> Result<(), Box<Error>> {
+    match path {
+        Some(path) if path.extension().map_or(false, |ext| ext.eq("svg")) => {
+            fs::write(path, song.preview_svg()?).map_err(ConvertError::io(path))?;
         }
+        Some(path) => fs::write(path, song.preview(ticks)?).map_err(ConvertError::io(path))?,
+        None => print!("{}", song.preview(ticks)?),
+    }
+    Ok(())
+}
+
+fn converter(
+    optimize: Option<nspc::OptimizeBudget>,
+    verbose: bool,
+    log: Arc<Log>,
+) -> Arc<convert::Converter> {
+    let converter = move |path: &Path, tempo_factor, transform: &midi::Transform| {
+        let song = song_from_input(path, tempo_factor, transform, optimize, verbose, &*log)?;
+        log(&Event::SongConverted { input: path });
+        Ok(song)
     };
-    Box::new(converter)
+    Arc::new(converter)
+}
+
+fn song_from_input(
+    path: &Path,
+    tempo_factor: f32,
+    transform: &midi::Transform,
+    optimize: Option<nspc::OptimizeBudget>,
+    verbose: bool,
+    log: &Log,
+) -> Result<nspc::Song, Box<Error>> {
+    if path.extension().map_or(false, |ext| ext.eq("mid")) {
+        song_from_midi(path, tempo_factor, transform, optimize, verbose, log)
+    } else if *transform != midi::Transform::default() {
+        Err(Box::from(ConvertError::Invalid {
+            path: Some(path.to_path_buf()),
+            message: "transforms can only be applied to MIDI input".to_string(),
+        }))
+    } else if path.extension().map_or(false, |ext| ext.eq("mml")) {
+        song_from_mml(path, tempo_factor, optimize, verbose)
+    } else if path.extension().map_or(false, |ext| ext.eq("musicxml") || ext.eq("xml")) {
+        song_from_musicxml(path, tempo_factor, optimize, verbose, log)
+    } else if path.extension().map_or(false, |ext| ext.eq("nspc")) {
+        song_from_text(path)
+    } else {
+        nspc::Song::from_json(path)
+    }
 }
 
 fn read_bank_addrs(matches: &ArgMatches) -> Result<[u32; 3], Box<Error>> {
@@ -151,13 +719,139 @@ fn read_bank_addrs(matches: &ArgMatches) -> Result<[u32; 3], Box<Error>> {
     }
 }
 
+// the file to write the patched ROM to, or None if it shouldn't be written
+fn rom_output_path<'a>(
+    rom_path: &'a str,
+    output: Option<&'a str>,
+    dry_run: bool,
+    asm_file: Option<&str>,
+    patch_path: Option<&str>,
+) -> Option<&'a str> {
+    if dry_run {
+        None
+    } else if output.is_some() {
+        output
+    } else if asm_file.is_some() || patch_path.is_some() {
+        None
+    } else {
+        Some(rom_path)
+    }
+}
+
+fn read_report_format(matches: &ArgMatches) -> Option<rom::report::ReportFormat> {
+    match matches.value_of("report") {
+        Some("json") => Some(rom::report::ReportFormat::Json),
+        Some(_) => Some(rom::report::ReportFormat::Text),
+        None => None,
+    }
+}
+
+fn read_relocation(matches: &ArgMatches) -> Result<Option<rom::Relocation>, Box<Error>> {
+    let rom_addr = match matches.value_of("relocate") {
+        Some(value) => usize::from_str_radix(value, 16)?,
+        None => {
+            if matches.is_present("aram_chunks") {
+                return Err(Box::from(SimpleError::new(
+                    "--aram_chunks can only be used with --relocate",
+                )));
+            }
+            return Ok(None);
+        }
+    };
+    let mut aram_chunks = Vec::new();
+    if let Some(values) = matches.values_of("aram_chunks") {
+        for value in values {
+            let parts = value.split(':').collect::<Vec<&str>>();
+            if parts.len() != 2 {
+                return Err(Box::from(SimpleError::new(format!(
+                    "ARAM chunk {} should be in the form ADDR:LENGTH",
+                    value
+                ))));
+            }
+            aram_chunks.push((
+                usize::from_str_radix(parts[0], 16)?,
+                usize::from_str_radix(parts[1], 16)?,
+            ));
+        }
+    }
+    Ok(Some(rom::Relocation {
+        rom_addr,
+        aram_chunks,
+    }))
+}
+
+fn read_optimize_budget(matches: &ArgMatches) -> Result<nspc::OptimizeBudget, Box<Error>> {
+    Ok(nspc::OptimizeBudget {
+        max_iterations: match matches.value_of("optimize_iterations") {
+            Some(value) => Some(value.parse::<usize>()?),
+            None => None,
+        },
+        max_time: match matches.value_of("optimize_time") {
+            Some(value) => Some(Duration::from_millis(value.parse::<u64>()?)),
+            None => None,
+        },
+    })
+}
+
 fn song_from_midi(
     path: &Path,
     tempo_factor: f32,
-    optimize: bool,
+    transform: &midi::Transform,
+    optimize: Option<nspc::OptimizeBudget>,
     verbose: bool,
+    log: &Log,
 ) -> Result<nspc::Song, Box<Error>> {
-    let mut midi = midi::MidiHandler::new();
+    let mut midi = midi::MidiHandler::with_transform(transform.clone());
     midi.read(path, verbose)?;
-    nspc::Song::from_midi(&midi, tempo_factor, optimize, verbose)
+    let song = nspc::Song::from_midi(&mut midi, tempo_factor, optimize, verbose)?;
+    log_warnings(path, &midi, log);
+    Ok(song)
+}
+
+fn log_warnings(path: &Path, midi: &midi::MidiHandler, log: &Log) {
+    for warning in &midi.warnings {
+        log(&Event::Warning {
+            input: Some(path),
+            message: warning,
+        });
+    }
+}
+
+fn song_from_mml(
+    path: &Path,
+    tempo_factor: f32,
+    optimize: Option<nspc::OptimizeBudget>,
+    verbose: bool,
+) -> Result<nspc::Song, Box<Error>> {
+    let text = fs::read_to_string(path).map_err(ConvertError::io(path))?;
+    nspc::Song::from_mml(&text, tempo_factor, optimize, verbose)
+        .map_err(|err| Box::from(ConvertError::from(err).in_file(path)))
+}
+
+fn song_from_musicxml(
+    path: &Path,
+    tempo_factor: f32,
+    optimize: Option<nspc::OptimizeBudget>,
+    verbose: bool,
+    log: &Log,
+) -> Result<nspc::Song, Box<Error>> {
+    let mut score = musicxml::Score::read(path, verbose)?;
+    let song = nspc::Song::from_sections(
+        &mut score.sections,
+        &score.ties,
+        &score.sequence,
+        tempo_factor,
+        optimize,
+        verbose,
+    )?;
+    for section in &score.sections {
+        log_warnings(path, section, log);
+    }
+    Ok(song)
+}
+
+fn song_from_text(path: &Path) -> Result<nspc::Song, Box<Error>> {
+    let text = fs::read_to_string(path).map_err(ConvertError::io(path))?;
+    nspc::Song::from_text(&text)
+        .map_err(|err| Box::from(ConvertError::from(err).in_file(path)))
 }
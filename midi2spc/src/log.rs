@@ -0,0 +1,107 @@
+use crate::rom::report::BankReport;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let log = json_lines(Shared(out.clone()));
+        log(&Event::SongStarted {
+            bank: "overworld",
+            number: 2,
+            name: "a.mid",
+        });
+        log(&Event::Warning {
+            input: Some(Path::new("a.mid")),
+            message: "channel 1: 2 notes are outside the range the driver can play",
+        });
+        assert_eq!(
+            "{\"event\":\"song_started\",\"bank\":\"overworld\",\"number\":2,\"name\":\"a.mid\"}\n\
+             {\"event\":\"warning\",\"input\":\"a.mid\",\
+             \"message\":\"channel 1: 2 notes are outside the range the driver can play\"}\n",
+            String::from_utf8(out.lock().unwrap().clone()).unwrap()
+        );
+    }
+}
+
+/// Something that happened while converting songs or writing a ROM.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// Verbose output about what's being done, only sent when it's asked for.
+    Detail { message: &'a str },
+    /// A song input has been converted.
+    SongConverted { input: &'a Path },
+    /// Something in a song input couldn't be converted as written.
+    Warning {
+        input: Option<&'a Path>,
+        message: &'a str,
+    },
+    /// A bank's songs are about to be written.
+    BankStarted { bank: &'a str, songs: usize },
+    /// A song is about to be written to the bank.
+    SongStarted {
+        bank: &'a str,
+        number: usize,
+        name: &'a str,
+    },
+    /// A song didn't fit in what was left of an ARAM chunk, so it continues
+    /// in the next one.
+    ChunkSwitched {
+        bank: &'a str,
+        song: &'a str,
+        aram_addr: usize,
+        space_left: usize,
+    },
+    /// A song has been written to the bank, or pointed at an identical one.
+    SongWritten {
+        bank: &'a str,
+        number: usize,
+        name: &'a str,
+        bytes: usize,
+    },
+    /// Every song in the bank has been written.
+    BankFinished { report: &'a BankReport },
+    /// A sound effect has been written to a slot of a sound effect table,
+    /// taking no bytes if it shares an identical one's data.
+    SoundWritten {
+        table: &'a str,
+        slot: usize,
+        name: &'a str,
+        bytes: usize,
+    },
+}
+
+/// Receives events as they happen, from any thread.
+pub type Log = Fn(&Event) + Send + Sync;
+
+/// Writes each event as a line of JSON.
+pub fn json_lines<W: Write + Send + 'static>(out: W) -> impl Fn(&Event) + Send + Sync {
+    let out = Mutex::new(out);
+    move |event: &Event| {
+        let mut out = out.lock().unwrap();
+        // logging carries on even if the output has gone away
+        if serde_json::to_writer(&mut *out, event).is_ok() {
+            let _ = writeln!(out);
+        }
+    }
+}
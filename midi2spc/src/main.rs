@@ -5,15 +5,33 @@ fn main() -> Result<(), Box<Error>> {
     let matches = clap_app!(midi2spc =>
         (@setting SubcommandRequiredElseHelp)
         (@arg skip_optimization: -s --skip_optimization  "skips optimization of CallLoops")
+        (@arg optimize_iterations: --optimize_iterations +takes_value "maximum number of CallLoops to extract per song")
+        (@arg optimize_time: --optimize_time +takes_value "maximum time in milliseconds to spend optimizing each song")
         (@arg verbose: -v --verbose "prints debugging output")
+        (@arg quiet: -q --quiet conflicts_with[log_format] "prints only errors and requested reports")
+        (@arg log_format: --("log-format") +takes_value possible_value[text json] "prints progress and warnings as text (the default) or as lines of JSON on stderr")
+        (@arg jobs: -j --jobs +takes_value "number of songs to convert at once (default 4)")
+        (@arg cache: --cache +takes_value "directory to keep converted songs in, so unchanged songs aren't converted again")
         (@arg out_ASM: -a --asm_file +takes_value "name of ASM file to output containing music banks")
         (@arg asm_module: --asm_module +takes_value "module name prefix to use for banks in the ASM file")
         (@arg asm_label: --asm_label +takes_value "label prefix to use for banks in the ASM file")
+        (@arg dry_run: --("dry-run") "converts and lays out all songs and prints a size report without writing anything")
+        (@arg output: -o --output +takes_value "file to write the patched ROM to instead of modifying the input ROM")
+        (@arg patch: -p --patch +takes_value "IPS or BPS patch file to write the changes to, by extension")
+        (@arg relocate: --relocate +takes_value "ROM address of free space to move music banks to")
+        (@arg aram_chunks: --aram_chunks +takes_value +multiple +use_delimiter "additional ARAM chunks (ADDR:LENGTH) to use for relocated banks")
         (@subcommand build_rom =>
             (about: "build a ROM according to a manifest file")
             (@arg MANIFEST: +required "the manifest file to use")
             (@arg ROM: +required "the ROM file to use")
             (@arg bank_addrs: --bank_addrs #{3,3} +use_delimiter "song bank addresses in the ROM")
+            (@arg report: --report +takes_value possible_value[text json] "prints a report of ARAM usage per bank and song")
+        )
+        (@subcommand watch =>
+            (about: "build a ROM according to a manifest file, then rebuild it whenever the manifest or a song changes")
+            (@arg MANIFEST: +required "the manifest file to use")
+            (@arg ROM: +required "the ROM file to use")
+            (@arg bank_addrs: --bank_addrs #{3,3} +use_delimiter "song bank addresses in the ROM")
         )
         (@subcommand all_overworld =>
             (about: "convert a MIDI or JSON file and replace all music with it")
@@ -22,7 +40,7 @@ fn main() -> Result<(), Box<Error>> {
             (@arg bank_addrs: --bank_addrs #{3,3} +use_delimiter "song bank addresses in the ROM")
         )
         (@subcommand file_select =>
-            (about: "convert a MIDI or JSON file and replace file select music with it")
+            (about: "convert a MIDI or JSON file and replace file select music with it, keeping the other songs")
             (@arg INPUT: +required "the input file to use")
             (@arg ROM: +required "the ROM file to use")
             (@arg bank_addrs: --bank_addrs #{3,3} +use_delimiter "song bank addresses in the ROM")
@@ -32,10 +50,33 @@ fn main() -> Result<(), Box<Error>> {
             (@arg INPUT: +required "the input file to use")
         )
         (@subcommand midi2json =>
-            (about: "convert a MIDI file to NSPC commands in JSON")
+            (about: "convert a MIDI file to NSPC commands in JSON, or text if OUTPUT ends in .nspc")
+            (@arg INPUT: +required "the input file to use")
+            (@arg OUTPUT: +required "the output file to use")
+        )
+        (@subcommand convert_song =>
+            (about: "convert any song input to NSPC commands in JSON, or text if OUTPUT ends in .nspc")
             (@arg INPUT: +required "the input file to use")
             (@arg OUTPUT: +required "the output file to use")
         )
+        (@subcommand export_song =>
+            (about: "convert any song input to a standalone module that can be placed at any ARAM address: an ASM module with labels if OUTPUT ends in .asm, or a binary with its pointers listed in a .relocs.json file beside it")
+            (@arg INPUT: +required "the input file to use")
+            (@arg OUTPUT: +required "the output file to use")
+            (@arg no_loop: --no_loop "ends the song instead of looping back to the start")
+            (@arg aram_addr: --aram_addr +takes_value "ARAM address in hex to set the pointers of a binary for (default 0)")
+            (@arg label: --label +takes_value "label prefix to use in the ASM module (default song)")
+        )
+        (@subcommand preview =>
+            (about: "convert any song input and show what each voice plays as a piano roll, or write it as an image if OUTPUT ends in .svg")
+            (@arg INPUT: +required "the input file to use")
+            (@arg OUTPUT: "the output file to use instead of printing")
+            (@arg ticks: --ticks +takes_value "ticks per column of the printed piano roll (default 6, a sixteenth note)")
+        )
+        (@subcommand validate =>
+            (about: "check that songs can be played, converting them first if they aren't JSON")
+            (@arg INPUT: +required +multiple "the song files to check")
+        )
         (@subcommand gen_fake_rom =>
             (about: "generate a dummy ROM file from a real one")
             (@arg INPUT: +required "the real ROM file to use")
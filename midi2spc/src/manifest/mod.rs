@@ -1,3 +1,5 @@
+use crate::error::ConvertError;
+use crate::midi::Transform;
 use serde_json;
 use serde_json::Value;
 use std::error::Error;
@@ -42,29 +44,348 @@ const INDOOR_SONGS: [&str; 16] = [
     "Last Boss",
 ];
 const ENDING_SONGS: [&str; 3] = ["Triforce", "Ending", "Staff"];
+const FILE_SELECT_SLOT: usize = 10;
+const SONG_FIELDS: [&str; 13] = [
+    "input",
+    "tempoAdjust",
+    "loop",
+    "transpose",
+    "channelTranspose",
+    "masterVolume",
+    "mute",
+    "solo",
+    "start",
+    "end",
+    "aftertouch",
+    "fadeIn",
+    "fadeOut",
+];
+// sound effects don't loop and have no master volume of their own
+const SOUND_FIELDS: [&str; 8] = [
+    "input",
+    "tempoAdjust",
+    "transpose",
+    "channelTranspose",
+    "mute",
+    "solo",
+    "start",
+    "end",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(json: &str) -> (Manifest, Vec<String>) {
+        let mut errors = Vec::new();
+        let manifest = Manifest::from_json(
+            &serde_json::from_str(json).unwrap(),
+            Path::new("sample"),
+            &mut errors,
+        );
+        (manifest, errors)
+    }
+
+    #[test]
+    fn test_songs_by_name_or_index() {
+        let (manifest, errors) = read(
+            r#"{
+                "banks": [{"name": "ending"}],
+                "ending": {"Triforce": {"input": "a.mid", "loop": false}, "2": "empty"}
+            }"#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(1, manifest.banks.len());
+        let songs = &manifest.banks[0].songs;
+        assert_eq!(Some(Path::new("sample").join("a.mid")), songs[0].input);
+        assert!(!songs[0].loops);
+        // slots not given keep their original song
+        assert!(songs[1].original);
+        assert!(songs[2].input.is_none() && !songs[2].original);
+    }
+
+    #[test]
+    fn test_custom_bank() {
+        let (manifest, errors) = read(
+            r#"{
+                "banks": [{"name": "extra", "songs": 2, "baseAddr": "93C", "firstSongAddr": "D050"}],
+                "extra": {"0": "original", "1": "original"}
+            }"#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(Some(0x93C), manifest.banks[0].base_addr);
+        assert_eq!(Some(0xD050), manifest.banks[0].first_song_addr);
+        assert_eq!(2, manifest.banks[0].songs.len());
+    }
+
+    #[test]
+    fn test_transform() {
+        let (manifest, errors) = read(
+            r#"{
+                "banks": [{"name": "ending"}],
+                "ending": {"Triforce": {
+                    "input": "a.mid",
+                    "transpose": -2,
+                    "channelTranspose": {"10": 12},
+                    "masterVolume": 160,
+                    "mute": [3, 4],
+                    "start": 8,
+                    "end": 72.5,
+                    "aftertouch": true
+                }}
+            }"#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let transform = &manifest.banks[0].songs[0].transform;
+        assert_eq!(-2, transform.transpose);
+        assert_eq!(12, transform.channel_transpose[9]);
+        assert_eq!(Some(160), transform.master_volume);
+        assert_eq!(vec![2, 3], transform.mute);
+        assert!(transform.solo.is_empty());
+        assert_eq!((Some(8.0), Some(72.5)), (transform.start, transform.end));
+        assert!(transform.aftertouch);
+        assert_eq!(Transform::default(), manifest.banks[0].songs[1].transform);
+    }
+
+    #[test]
+    fn test_fades() {
+        let (manifest, errors) = read(
+            r#"{
+                "banks": [{"name": "ending"}],
+                "ending": {
+                    "Triforce": {
+                        "input": "a.mid",
+                        "fadeIn": 2,
+                        "fadeOut": {"start": 64, "length": 8}
+                    },
+                    "Ending": {"input": "b.mid", "fadeIn": 0.5}
+                }
+            }"#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let songs = &manifest.banks[0].songs;
+        assert_eq!(Some(2.0), songs[0].transform.fade_in);
+        assert_eq!(Some((64.0, 8.0)), songs[0].transform.fade_out);
+        assert!(!songs[0].loops);
+        assert_eq!((Some(0.5), None), (songs[1].transform.fade_in, songs[1].transform.fade_out));
+        assert!(songs[1].loops);
+    }
+
+    #[test]
+    fn test_fade_errors() {
+        let (_, errors) = read(
+            r#"{
+                "banks": [{"name": "ending"}],
+                "ending": {
+                    "Triforce": {
+                        "input": "a.mid",
+                        "loop": true,
+                        "fadeOut": {"start": 4, "length": 2}
+                    },
+                    "Ending": {"input": "b.mid", "fadeIn": 0, "fadeOut": {"length": -1, "at": 2}},
+                    "Staff": {"input": "c.mid", "end": 16, "fadeOut": {"start": 16, "length": 2}}
+                }
+            }"#,
+        );
+        assert_eq!(
+            vec![
+                "ending.Ending.fadeIn: expected a positive number of beats",
+                "ending.Ending.fadeOut.at: unknown field",
+                "ending.Ending.fadeOut.start: expected a number of beats",
+                "ending.Ending.fadeOut.length: expected a positive number of beats",
+                "ending.Staff.fadeOut.start: must be before end",
+                "ending.Triforce.loop: a song that fades out can't loop",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_transform_errors() {
+        let (_, errors) = read(
+            r#"{
+                "banks": [{"name": "ending"}],
+                "ending": {"Triforce": {
+                    "input": "a.mid",
+                    "transpose": 200,
+                    "channelTranspose": {"17": 1},
+                    "solo": [0],
+                    "start": 8,
+                    "end": 4
+                }}
+            }"#,
+        );
+        assert_eq!(
+            vec![
+                "ending.Triforce.transpose: expected a whole number from -128 to 127",
+                "ending.Triforce.channelTranspose.17: expected a channel from 1 to 16",
+                "ending.Triforce.solo: expected channels from 1 to 16",
+                "ending.Triforce.end: must be after start",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_sfx() {
+        let (manifest, errors) = read(
+            r#"{
+                "sfx": {
+                    "baseAddr": "8901",
+                    "dataAddr": "2A00",
+                    "dataEnd": "2C00",
+                    "tables": [{"name": "sfx2", "addr": "191C", "slots": 64, "sounds": {
+                        "5": {"input": "chime.mid", "transpose": 12}
+                    }}]
+                }
+            }"#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let sfx = manifest.sfx.unwrap();
+        assert_eq!((0x8901, 0x2A00, 0x2C00), (sfx.base_addr, sfx.data_addr, sfx.data_end));
+        let table = &sfx.tables[0];
+        assert_eq!(("sfx2", 0x191C, 64), (&table.name[..], table.addr, table.slots));
+        let (slot, sound) = &table.sounds[0];
+        assert_eq!((5, 12), (*slot, sound.transform.transpose));
+        assert!(!sound.loops);
+    }
+
+    #[test]
+    fn test_sfx_errors() {
+        let (_, errors) = read(
+            r#"{
+                "sfx": {
+                    "dataAddr": "2C00",
+                    "dataEnd": "2A00",
+                    "tables": [
+                        {"name": "sfx2", "addr": "191C", "slots": 4, "sounds": {
+                            "4": {"input": "a.mid"},
+                            "1": {"input": "b.mid", "loop": true},
+                            "02": {"input": "c.mid"},
+                            "2": {"input": "d.mid"}
+                        }},
+                        {"name": "sfx2", "addr": "1A00", "slots": 4}
+                    ]
+                }
+            }"#,
+        );
+        assert_eq!(
+            vec![
+                "sfx.baseAddr: missing",
+                "sfx.dataEnd: must be after dataAddr and within ARAM",
+                "sfx.tables[0].sounds.1.loop: can't be used for a sound effect",
+                "sfx.tables[0].sounds.2: sound 2 is already given",
+                "sfx.tables[0].sounds.4: expected a slot from 0 to 3",
+                "sfx.tables[1].name: table sfx2 is already given",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_schema_errors() {
+        let (_, errors) = read(
+            r#"{
+                "banks": [{"name": "ending", "songs": "three"}, {"name": "extra"}],
+                "ending": {"Triforce": {"tempoAdjust": "fast"}, "Ending": 3, "Staff": "original", "5": "empty"},
+                "outdoor": {}
+            }"#,
+        );
+        assert_eq!(
+            vec![
+                "banks[0].songs: expected a positive number",
+                "banks[1].songs: missing",
+                "outdoor: unknown bank",
+                "ending.5: unknown song",
+                "ending.Ending: expected an object, \"original\" or \"empty\"",
+                "ending.Triforce.input: missing",
+                "ending.Triforce.tempoAdjust: expected a number",
+            ],
+            errors
+        );
+    }
+}
 
 #[derive(Debug)]
 pub struct Song {
     pub input: Option<PathBuf>,
     pub tempo_factor: f32,
     pub loops: bool,
+    pub original: bool,
+    pub transform: Transform,
 }
 
 impl Song {
-    pub fn new(input: &Value, base_path: &Path) -> Song {
-        let path = Path::new(input["input"].as_str().unwrap());
-        let path_buf;
-        if path.is_absolute() {
-            path_buf = path.to_path_buf();
-        } else {
-            path_buf = base_path.join(path).to_path_buf();
+    fn new(input: &Value, base_path: &Path, location: &str, errors: &mut Vec<String>) -> Song {
+        match input {
+            Value::String(mode) if mode == "original" => return Song::original(),
+            Value::String(mode) if mode == "empty" => return Song::empty(),
+            Value::Object(fields) => {
+                for key in fields.keys() {
+                    if !SONG_FIELDS.contains(&key.as_str()) {
+                        errors.push(format!("{}.{}: unknown field", location, key));
+                    }
+                }
+            }
+            _ => {
+                errors.push(format!(
+                    "{}: expected an object, \"original\" or \"empty\"",
+                    location
+                ));
+                return Song::empty();
+            }
         }
+        let input_path = match &input["input"] {
+            Value::String(path) => {
+                let path = Path::new(path);
+                if path.is_absolute() {
+                    Some(path.to_path_buf())
+                } else {
+                    Some(base_path.join(path).to_path_buf())
+                }
+            }
+            Value::Null => {
+                errors.push(format!("{}.input: missing", location));
+                None
+            }
+            _ => {
+                errors.push(format!("{}.input: expected a path", location));
+                None
+            }
+        };
+        let tempo_factor = match &input["tempoAdjust"] {
+            Value::Null => DEFAULT_TEMPO_ADJUST,
+            value => value.as_f64().map_or_else(
+                || {
+                    errors.push(format!("{}.tempoAdjust: expected a number", location));
+                    DEFAULT_TEMPO_ADJUST
+                },
+                |value| value as f32,
+            ),
+        };
+        let transform = read_transform(input, location, errors);
+        // a song that fades out ends once it's silent
+        let fades_out = transform.fade_out.is_some();
+        let loops = match &input["loop"] {
+            Value::Null => !fades_out,
+            value => match value.as_bool() {
+                Some(true) if fades_out => {
+                    errors.push(format!("{}.loop: a song that fades out can't loop", location));
+                    false
+                }
+                Some(loops) => loops,
+                None => {
+                    errors.push(format!("{}.loop: expected true or false", location));
+                    !fades_out
+                }
+            },
+        };
         Song {
-            input: Some(path_buf),
-            tempo_factor: input["tempoAdjust"]
-                .as_f64()
-                .unwrap_or(DEFAULT_TEMPO_ADJUST as f64) as f32,
-            loops: input["loop"].as_bool().unwrap_or(true),
+            input: input_path,
+            tempo_factor,
+            loops,
+            original: false,
+            transform,
         }
     }
 
@@ -73,6 +394,8 @@ impl Song {
             input: Some(path.to_path_buf()),
             tempo_factor: DEFAULT_TEMPO_ADJUST,
             loops: true,
+            original: false,
+            transform: Transform::default(),
         }
     }
 
@@ -81,52 +404,707 @@ impl Song {
             input: None,
             tempo_factor: DEFAULT_TEMPO_ADJUST,
             loops: false,
+            original: false,
+            transform: Transform::default(),
+        }
+    }
+
+    // keeps the song already in the ROM
+    pub fn original() -> Song {
+        Song {
+            input: None,
+            tempo_factor: DEFAULT_TEMPO_ADJUST,
+            loops: false,
+            original: true,
+            transform: Transform::default(),
+        }
+    }
+}
+
+/// A table of the driver's sound effect pointers, and the sound effects to
+/// replace in it by slot.
+#[derive(Debug)]
+pub struct SfxTable {
+    pub name: String,
+    pub addr: usize,
+    pub slots: usize,
+    pub sounds: Vec<(usize, Song)>,
+}
+
+/// Sound effects to write over ones in the driver's tables, with their data
+/// going to the ARAM from `data_addr` to `data_end`.  `base_addr` is where
+/// the driver's own transfer blocks are loaded from, as for a bank.
+#[derive(Debug)]
+pub struct Sfx {
+    pub base_addr: u32,
+    pub data_addr: usize,
+    pub data_end: usize,
+    pub tables: Vec<SfxTable>,
+}
+
+impl Sfx {
+    fn new(input: &Value, base_path: &Path, errors: &mut Vec<String>) -> Option<Sfx> {
+        let fields = match input.as_object() {
+            Some(fields) => fields,
+            None => {
+                errors.push("sfx: expected an object".to_string());
+                return None;
+            }
+        };
+        for key in fields.keys() {
+            if !["baseAddr", "dataAddr", "dataEnd", "tables"].contains(&key.as_str()) {
+                errors.push(format!("sfx.{}: unknown field", key));
+            }
+        }
+        let base_addr = read_required_hex(input, "sfx", "baseAddr", errors);
+        let data_addr = read_required_hex(input, "sfx", "dataAddr", errors);
+        let data_end = read_required_hex(input, "sfx", "dataEnd", errors);
+        if let (Some(data_addr), Some(data_end)) = (data_addr, data_end) {
+            if data_end <= data_addr || data_end > 0x10000 {
+                errors.push("sfx.dataEnd: must be after dataAddr and within ARAM".to_string());
+            }
+        }
+        let mut tables = Vec::<SfxTable>::new();
+        match &input["tables"] {
+            Value::Array(entries) => {
+                for (i, entry) in entries.iter().enumerate() {
+                    let location = format!("sfx.tables[{}]", i);
+                    if let Some(table) = SfxTable::new(entry, base_path, &location, errors) {
+                        if tables.iter().any(|other| other.name == table.name) {
+                            errors.push(format!(
+                                "{}.name: table {} is already given",
+                                location, table.name
+                            ));
+                        }
+                        tables.push(table);
+                    }
+                }
+            }
+            Value::Null => errors.push("sfx.tables: missing".to_string()),
+            _ => errors.push("sfx.tables: expected a list".to_string()),
+        }
+        Some(Sfx {
+            base_addr: base_addr? as u32,
+            data_addr: data_addr?,
+            data_end: data_end?,
+            tables,
+        })
+    }
+}
+
+impl SfxTable {
+    fn new(
+        input: &Value,
+        base_path: &Path,
+        location: &str,
+        errors: &mut Vec<String>,
+    ) -> Option<SfxTable> {
+        let fields = match input.as_object() {
+            Some(fields) => fields,
+            None => {
+                errors.push(format!("{}: expected an object", location));
+                return None;
+            }
+        };
+        for key in fields.keys() {
+            if !["name", "addr", "slots", "sounds"].contains(&key.as_str()) {
+                errors.push(format!("{}.{}: unknown field", location, key));
+            }
         }
+        let name = match input["name"].as_str() {
+            Some(name) => name.to_string(),
+            None => {
+                errors.push(format!("{}.name: expected a string", location));
+                return None;
+            }
+        };
+        let addr = read_required_hex(input, location, "addr", errors);
+        let slots = match input["slots"].as_u64() {
+            Some(slots) if slots > 0 => slots as usize,
+            _ => {
+                errors.push(format!("{}.slots: expected a positive number", location));
+                return None;
+            }
+        };
+        let mut sounds = Vec::new();
+        match &input["sounds"] {
+            Value::Object(entries) => {
+                for (key, entry) in entries {
+                    let location = format!("{}.sounds.{}", location, key);
+                    match key.parse::<usize>() {
+                        Ok(slot) if sounds.iter().any(|&(other, _)| other == slot) => errors
+                            .push(format!("{}: sound {} is already given", location, slot)),
+                        Ok(slot) if slot < slots => {
+                            let sound = SfxTable::sound(entry, base_path, &location, errors);
+                            if let Some(sound) = sound {
+                                sounds.push((slot, sound));
+                            }
+                        }
+                        _ => errors.push(format!(
+                            "{}: expected a slot from 0 to {}",
+                            location,
+                            slots - 1
+                        )),
+                    }
+                }
+            }
+            Value::Null => (),
+            _ => errors.push(format!("{}.sounds: expected an object", location)),
+        }
+        Some(SfxTable {
+            name,
+            addr: addr?,
+            slots,
+            sounds,
+        })
+    }
+
+    // a sound effect is read like a song, without what only songs can use
+    fn sound(
+        input: &Value,
+        base_path: &Path,
+        location: &str,
+        errors: &mut Vec<String>,
+    ) -> Option<Song> {
+        let fields = match input.as_object() {
+            Some(fields) => fields,
+            None => {
+                errors.push(format!("{}: expected an object", location));
+                return None;
+            }
+        };
+        for key in fields.keys() {
+            if SONG_FIELDS.contains(&key.as_str()) && !SOUND_FIELDS.contains(&key.as_str()) {
+                errors.push(format!(
+                    "{}.{}: can't be used for a sound effect",
+                    location, key
+                ));
+            }
+        }
+        Some(Song {
+            loops: false,
+            ..Song::new(input, base_path, location, errors)
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct Bank {
-    pub name: &'static str,
+    pub name: String,
+    pub base_addr: Option<u32>,
+    pub first_song: Option<usize>,
+    pub first_song_addr: Option<usize>,
     pub songs: Vec<Song>,
 }
 
-impl Bank {
-    pub fn new(input: &Value, name: &'static str, song_names: &[&str], base_path: &Path) -> Bank {
-        Bank {
-            name,
-            songs: song_names
-                .iter()
-                .map(|&song_name| Song::new(&input[song_name], base_path))
-                .collect(),
+// the layout of a bank before its songs are read
+struct BankLayout {
+    name: String,
+    base_addr: Option<u32>,
+    first_song: Option<usize>,
+    first_song_addr: Option<usize>,
+    song_names: Vec<String>,
+    num_songs: usize,
+}
+
+impl BankLayout {
+    fn default_banks() -> Vec<BankLayout> {
+        vec![
+            BankLayout::named("overworld", &OVERWORLD_SONGS),
+            BankLayout::named("indoor", &INDOOR_SONGS),
+            BankLayout::named("ending", &ENDING_SONGS),
+        ]
+    }
+
+    fn named(name: &str, song_names: &[&str]) -> BankLayout {
+        BankLayout {
+            name: name.to_string(),
+            base_addr: None,
+            first_song: None,
+            first_song_addr: None,
+            song_names: song_names.iter().map(|name| name.to_string()).collect(),
+            num_songs: song_names.len(),
+        }
+    }
+
+    fn new(input: &Value, location: &str, errors: &mut Vec<String>) -> Option<BankLayout> {
+        let fields = match input.as_object() {
+            Some(fields) => fields,
+            None => {
+                errors.push(format!("{}: expected an object", location));
+                return None;
+            }
+        };
+        for key in fields.keys() {
+            if ![
+                "name",
+                "baseAddr",
+                "firstSong",
+                "firstSongAddr",
+                "songs",
+                "songNames",
+            ]
+            .contains(&key.as_str())
+            {
+                errors.push(format!("{}.{}: unknown field", location, key));
+            }
+        }
+        let name = match input["name"].as_str() {
+            Some(name) => name,
+            None => {
+                errors.push(format!("{}.name: expected a string", location));
+                return None;
+            }
+        };
+        let mut layout = BankLayout::default_banks()
+            .into_iter()
+            .find(|layout| layout.name == name)
+            .unwrap_or_else(|| BankLayout::named(name, &[]));
+        if !input["songNames"].is_null() {
+            match input["songNames"].as_array().and_then(|names| {
+                names
+                    .iter()
+                    .map(|name| name.as_str().map(|name| name.to_string()))
+                    .collect::<Option<Vec<String>>>()
+            }) {
+                Some(names) => {
+                    layout.num_songs = names.len();
+                    layout.song_names = names;
+                }
+                None => errors.push(format!(
+                    "{}.songNames: expected a list of strings",
+                    location
+                )),
+            }
+        }
+        match &input["songs"] {
+            Value::Null => {
+                if layout.num_songs == 0 {
+                    errors.push(format!("{}.songs: missing", location));
+                }
+            }
+            value => match value.as_u64() {
+                Some(num_songs) if num_songs > 0 => {
+                    layout.num_songs = num_songs as usize;
+                    layout.song_names.truncate(layout.num_songs);
+                }
+                _ => errors.push(format!("{}.songs: expected a positive number", location)),
+            },
+        }
+        layout.base_addr = read_hex(
+            &input["baseAddr"],
+            &format!("{}.baseAddr", location),
+            errors,
+        )
+        .map(|addr| addr as u32);
+        layout.first_song = match &input["firstSong"] {
+            Value::Null => None,
+            value => value
+                .as_u64()
+                .map(|first_song| first_song as usize)
+                .or_else(|| {
+                    errors.push(format!("{}.firstSong: expected a number", location));
+                    None
+                }),
+        };
+        layout.first_song_addr = read_hex(
+            &input["firstSongAddr"],
+            &format!("{}.firstSongAddr", location),
+            errors,
+        );
+        Some(layout)
+    }
+
+    // finds the slot a manifest key refers to, either by name or by index
+    fn slot(&self, key: &str) -> Option<usize> {
+        match key.parse::<usize>() {
+            Ok(idx) if idx < self.num_songs => Some(idx),
+            Ok(_) => None,
+            Err(_) => self.song_names.iter().position(|name| name == key),
+        }
+    }
+
+    fn song_name(&self, slot: usize) -> String {
+        match self.song_names.get(slot) {
+            Some(name) => format!("{} ({})", slot, name),
+            None => slot.to_string(),
+        }
+    }
+}
+
+fn read_transform(input: &Value, location: &str, errors: &mut Vec<String>) -> Transform {
+    let mut transform = Transform::default();
+    if let Some(transpose) = read_int(
+        &input["transpose"],
+        -128,
+        127,
+        location,
+        "transpose",
+        errors,
+    ) {
+        transform.transpose = transpose as i8;
+    }
+    match &input["channelTranspose"] {
+        Value::Null => (),
+        Value::Object(channels) => {
+            for (ch, transpose) in channels {
+                let field = format!("channelTranspose.{}", ch);
+                match read_channel(ch) {
+                    Some(ch) => {
+                        if let Some(transpose) =
+                            read_int(transpose, -127, 127, location, &field, errors)
+                        {
+                            transform.channel_transpose[ch] = transpose as i8;
+                        }
+                    }
+                    None => errors.push(format!(
+                        "{}.{}: expected a channel from 1 to 16",
+                        location, field
+                    )),
+                }
+            }
+        }
+        _ => errors.push(format!("{}.channelTranspose: expected an object", location)),
+    }
+    transform.master_volume = read_int(
+        &input["masterVolume"],
+        0,
+        255,
+        location,
+        "masterVolume",
+        errors,
+    )
+    .map(|volume| volume as u8);
+    transform.mute = read_channels(&input["mute"], location, "mute", errors);
+    transform.solo = read_channels(&input["solo"], location, "solo", errors);
+    for (field, time) in &mut [("start", &mut transform.start), ("end", &mut transform.end)] {
+        match &input[*field] {
+            Value::Null => (),
+            value => match value.as_f64() {
+                Some(beats) if beats >= 0.0 => **time = Some(beats as f32),
+                _ => errors.push(format!(
+                    "{}.{}: expected a number of beats",
+                    location, field
+                )),
+            },
+        }
+    }
+    if let (Some(start), Some(end)) = (transform.start, transform.end) {
+        if end <= start {
+            errors.push(format!("{}.end: must be after start", location));
+        }
+    }
+    transform.aftertouch = match &input["aftertouch"] {
+        Value::Null => false,
+        value => value.as_bool().unwrap_or_else(|| {
+            errors.push(format!("{}.aftertouch: expected true or false", location));
+            false
+        }),
+    };
+    transform.fade_in = match &input["fadeIn"] {
+        Value::Null => None,
+        value => read_beats(value, location, "fadeIn", errors),
+    };
+    transform.fade_out = match &input["fadeOut"] {
+        Value::Null => None,
+        Value::Object(fields) => {
+            for key in fields.keys() {
+                if !["start", "length"].contains(&key.as_str()) {
+                    errors.push(format!("{}.fadeOut.{}: unknown field", location, key));
+                }
+            }
+            let start = match fields.get("start").and_then(Value::as_f64) {
+                Some(start) if start >= 0.0 => Some(start as f32),
+                _ => {
+                    errors.push(format!(
+                        "{}.fadeOut.start: expected a number of beats",
+                        location
+                    ));
+                    None
+                }
+            };
+            let length = read_beats(
+                fields.get("length").unwrap_or(&Value::Null),
+                location,
+                "fadeOut.length",
+                errors,
+            );
+            match (start, length) {
+                (Some(start), Some(length)) => {
+                    if let Some(end) = transform.end {
+                        if start >= end {
+                            errors.push(format!("{}.fadeOut.start: must be before end", location));
+                        }
+                    }
+                    Some((start, length))
+                }
+                _ => None,
+            }
+        }
+        _ => {
+            errors.push(format!("{}.fadeOut: expected an object", location));
+            None
+        }
+    };
+    transform
+}
+
+fn read_beats(input: &Value, location: &str, field: &str, errors: &mut Vec<String>) -> Option<f32> {
+    match input.as_f64() {
+        Some(beats) if beats > 0.0 => Some(beats as f32),
+        _ => {
+            errors.push(format!(
+                "{}.{}: expected a positive number of beats",
+                location, field
+            ));
+            None
+        }
+    }
+}
+
+fn read_int(
+    input: &Value,
+    min: i64,
+    max: i64,
+    location: &str,
+    field: &str,
+    errors: &mut Vec<String>,
+) -> Option<i64> {
+    match input {
+        Value::Null => None,
+        value => match value.as_i64() {
+            Some(value) if value >= min && value <= max => Some(value),
+            _ => {
+                errors.push(format!(
+                    "{}.{}: expected a whole number from {} to {}",
+                    location, field, min, max
+                ));
+                None
+            }
+        },
+    }
+}
+
+// MIDI channels are numbered from 1 in the manifest
+fn read_channel(input: &str) -> Option<usize> {
+    match input.parse::<usize>() {
+        Ok(ch) if ch >= 1 && ch <= 16 => Some(ch - 1),
+        _ => None,
+    }
+}
+
+fn read_channels(
+    input: &Value,
+    location: &str,
+    field: &str,
+    errors: &mut Vec<String>,
+) -> Vec<usize> {
+    match input {
+        Value::Null => vec![],
+        Value::Array(channels) => channels
+            .iter()
+            .filter_map(|ch| {
+                let channel = ch.as_u64().and_then(|ch| read_channel(&ch.to_string()));
+                if channel.is_none() {
+                    errors.push(format!(
+                        "{}.{}: expected channels from 1 to 16",
+                        location, field
+                    ));
+                }
+                channel
+            })
+            .collect(),
+        _ => {
+            errors.push(format!(
+                "{}.{}: expected a list of channels",
+                location, field
+            ));
+            vec![]
         }
     }
 }
 
+fn read_required_hex(
+    input: &Value,
+    location: &str,
+    field: &str,
+    errors: &mut Vec<String>,
+) -> Option<usize> {
+    let location = format!("{}.{}", location, field);
+    match &input[field] {
+        Value::Null => {
+            errors.push(format!("{}: missing", location));
+            None
+        }
+        value => read_hex(value, &location, errors),
+    }
+}
+
+fn read_hex(input: &Value, location: &str, errors: &mut Vec<String>) -> Option<usize> {
+    match input {
+        Value::Null => None,
+        Value::String(value) => match usize::from_str_radix(value, 16) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                errors.push(format!("{}: expected a hex address", location));
+                None
+            }
+        },
+        _ => {
+            errors.push(format!("{}: expected a hex address string", location));
+            None
+        }
+    }
+}
+
+/// Songs to write to each bank, read from a JSON file like
+///
+/// ```json
+/// {
+///   "banks": [
+///     {"name": "overworld"},
+///     {"name": "extra", "songs": 2, "baseAddr": "93C", "firstSong": 34, "firstSongAddr": "D050"}
+///   ],
+///   "overworld": {"Title": {"input": "title.mid"}, "3": "original", "Warp": "empty"},
+///   "extra": {"0": {"input": "extra.mid", "tempoAdjust": 0.25, "loop": false}}
+/// }
+/// ```
+///
+/// Songs are given by name or index within their bank, and slots not given
+/// keep the song already in the ROM.  `banks` is optional and defaults to the
+/// overworld, indoor and ending banks, whose addresses and song names needn't
+/// be repeated.
+///
+/// An optional `sfx` section replaces sound effects in the driver's tables:
+///
+/// ```json
+/// "sfx": {
+///   "baseAddr": "8901",
+///   "dataAddr": "2A00",
+///   "dataEnd": "2C00",
+///   "tables": [
+///     {"name": "sfx2", "addr": "191C", "slots": 64, "sounds": {"5": {"input": "chime.mid"}}}
+///   ]
+/// }
+/// ```
 #[derive(Debug)]
 pub struct Manifest {
-    pub banks: [Bank; 3],
+    pub banks: Vec<Bank>,
+    pub sfx: Option<Sfx>,
 }
 
 impl Manifest {
     pub fn new(path: &Path) -> Result<Manifest, Box<Error>> {
-        let reader = File::open(path)?;
-        let json: Value = serde_json::from_reader(reader)?;
+        let reader = File::open(path).map_err(ConvertError::io(path))?;
+        let json: Value = serde_json::from_reader(reader).map_err(|err| ConvertError::Invalid {
+            path: Some(path.to_path_buf()),
+            message: err.to_string(),
+        })?;
         let parent = path.parent().unwrap();
-        Ok(Manifest {
-            banks: [
-                Bank::new(&json["overworld"], "overworld", &OVERWORLD_SONGS, parent),
-                Bank::new(&json["indoor"], "indoor", &INDOOR_SONGS, parent),
-                Bank::new(&json["ending"], "ending", &ENDING_SONGS, parent),
-            ],
-        })
+        let mut errors = Vec::new();
+        let manifest = Manifest::from_json(&json, parent, &mut errors);
+        if errors.is_empty() {
+            Ok(manifest)
+        } else {
+            Err(Box::from(ConvertError::Invalid {
+                path: Some(path.to_path_buf()),
+                message: errors.join("\n"),
+            }))
+        }
+    }
+
+    fn from_json(json: &Value, base_path: &Path, errors: &mut Vec<String>) -> Manifest {
+        let fields = match json.as_object() {
+            Some(fields) => fields,
+            None => {
+                errors.push("expected an object".to_string());
+                return Manifest {
+                    banks: vec![],
+                    sfx: None,
+                };
+            }
+        };
+        let layouts = match &json["banks"] {
+            Value::Null => BankLayout::default_banks(),
+            Value::Array(banks) => banks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, bank)| BankLayout::new(bank, &format!("banks[{}]", i), errors))
+                .collect(),
+            _ => {
+                errors.push("banks: expected a list".to_string());
+                vec![]
+            }
+        };
+        for key in fields.keys() {
+            if key != "banks" && key != "sfx" && !layouts.iter().any(|layout| &layout.name == key) {
+                errors.push(format!("{}: unknown bank", key));
+            }
+        }
+        let banks = layouts
+            .iter()
+            .map(|layout| {
+                let mut songs = (0..layout.num_songs)
+                    .map(|_| None)
+                    .collect::<Vec<Option<Song>>>();
+                match &json[&layout.name] {
+                    Value::Object(entries) => {
+                        for (key, entry) in entries {
+                            let location = format!("{}.{}", layout.name, key);
+                            match layout.slot(key) {
+                                None => errors.push(format!("{}: unknown song", location)),
+                                Some(slot) if songs[slot].is_some() => errors.push(format!(
+                                    "{}: song {} is already given",
+                                    location,
+                                    layout.song_name(slot)
+                                )),
+                                Some(slot) => {
+                                    songs[slot] =
+                                        Some(Song::new(entry, base_path, &location, errors))
+                                }
+                            }
+                        }
+                    }
+                    Value::Null => (),
+                    _ => errors.push(format!("{}: expected an object", layout.name)),
+                }
+                Bank {
+                    name: layout.name.clone(),
+                    base_addr: layout.base_addr,
+                    first_song: layout.first_song,
+                    first_song_addr: layout.first_song_addr,
+                    songs: songs
+                        .into_iter()
+                        .map(|song| song.unwrap_or_else(Song::original))
+                        .collect(),
+                }
+            })
+            .collect();
+        let sfx = match &json["sfx"] {
+            Value::Null => None,
+            sfx => Sfx::new(sfx, base_path, errors),
+        };
+        Manifest { banks, sfx }
+    }
+
+    /// Every song and sound effect the manifest converts.
+    pub fn songs(&self) -> impl Iterator<Item = &Song> {
+        let sounds = self
+            .sfx
+            .iter()
+            .flat_map(|sfx| sfx.tables.iter())
+            .flat_map(|table| table.sounds.iter().map(|(_, sound)| sound));
+        self.banks.iter().flat_map(|bank| bank.songs.iter()).chain(sounds)
     }
 
     pub fn single_song(song_path: &Path) -> Manifest {
         Manifest {
-            banks: [
+            banks: vec![
                 Bank {
-                    name: "Overworld",
+                    name: "Overworld".to_string(),
+                    base_addr: None,
+                    first_song: None,
+                    first_song_addr: None,
                     songs: vec![
                         Song::default(song_path),
                         Song::default(song_path),
@@ -146,7 +1124,10 @@ impl Manifest {
                     ],
                 },
                 Bank {
-                    name: "Indoor",
+                    name: "Indoor".to_string(),
+                    base_addr: None,
+                    first_song: None,
+                    first_song_addr: None,
                     songs: vec![
                         Song::default(song_path),
                         Song::default(song_path),
@@ -167,7 +1148,10 @@ impl Manifest {
                     ],
                 },
                 Bank {
-                    name: "Ending",
+                    name: "Ending".to_string(),
+                    base_addr: None,
+                    first_song: None,
+                    first_song_addr: None,
                     songs: vec![
                         Song::default(song_path),
                         Song::default(song_path),
@@ -175,58 +1159,25 @@ impl Manifest {
                     ],
                 },
             ],
+            sfx: None,
         }
     }
 
+    // replaces the file select song, keeping the rest of the overworld bank
     pub fn file_select(song_path: &Path) -> Manifest {
+        let mut songs = (0..OVERWORLD_SONGS.len())
+            .map(|_| Song::original())
+            .collect::<Vec<Song>>();
+        songs[FILE_SELECT_SLOT] = Song::default(song_path);
         Manifest {
-            banks: [
-                Bank {
-                    name: "Overworld",
-                    songs: vec![
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::default(song_path),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                    ],
-                },
-                Bank {
-                    name: "Indoor",
-                    songs: vec![
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                        Song::empty(),
-                    ],
-                },
-                Bank {
-                    name: "Ending",
-                    songs: vec![Song::empty(), Song::empty(), Song::empty()],
-                },
-            ],
+            banks: vec![Bank {
+                name: "Overworld".to_string(),
+                base_addr: None,
+                first_song: None,
+                first_song_addr: None,
+                songs,
+            }],
+            sfx: None,
         }
     }
 }
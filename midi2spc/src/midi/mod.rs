@@ -1,13 +1,265 @@
 use super::nspc::instruments::*;
+use crate::error::ConvertError;
 use ghakuf::messages::*;
 use ghakuf::reader::*;
 use itertools::*;
 use simple_error::SimpleError;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::path::Path;
 
 const SNARE_NOTE: u8 = 68;
+// the notes the driver can play, c1 to b6
+const LOWEST_NOTE: u8 = 24;
+const HIGHEST_NOTE: u8 = 95;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nspc::Song;
+    use std::fs;
+
+    fn event(delta_time: u32, event: MidiEvent) -> Message {
+        Message::MidiEvent { delta_time, event }
+    }
+
+    fn note(ch: u8, note: u8, on: bool) -> MidiEvent {
+        if on {
+            MidiEvent::NoteOn { ch, note, velocity: 100 }
+        } else {
+            MidiEvent::NoteOff { ch, note, velocity: 0 }
+        }
+    }
+
+    fn transformed(tracks: Vec<Vec<Message>>, transform: Transform) -> MidiHandler {
+        let mut midi = MidiHandler::with_transform(transform);
+        midi.load_tracks(tracks, 96, Path::new("a.mid"), false).unwrap();
+        midi
+    }
+
+    // the notes a voice plays, as (note, start, end)
+    fn notes(midi: &MidiHandler, voice: usize) -> Vec<(u8, u32, u32)> {
+        let mut notes = Vec::new();
+        let mut started = HashMap::new();
+        for &(ref message, time) in midi.events_for_voice(voice) {
+            match *message {
+                Message::MidiEvent {
+                    event: MidiEvent::NoteOn { note, velocity, .. },
+                    ..
+                } if velocity > 0 => {
+                    started.insert(note, time);
+                }
+                Message::MidiEvent {
+                    event: MidiEvent::NoteOff { note, .. },
+                    ..
+                }
+                | Message::MidiEvent {
+                    event: MidiEvent::NoteOn { note, .. },
+                    ..
+                } => {
+                    if let Some(start) = started.remove(&note) {
+                        notes.push((note, start, time));
+                    }
+                }
+                _ => {}
+            }
+        }
+        notes
+    }
+
+    #[test]
+    fn test_window() {
+        let tracks = vec![vec![
+            event(0, MidiEvent::ProgramChange { ch: 0, program: 5 }),
+            event(0, note(0, 60, true)),
+            event(192, note(0, 60, false)),
+            event(0, note(0, 62, true)),
+            event(192, note(0, 62, false)),
+            event(0, note(0, 64, true)),
+            event(96, note(0, 64, false)),
+        ]];
+        let transform = Transform {
+            start: Some(1.0),
+            end: Some(4.5),
+            ..Transform::default()
+        };
+        assert_eq!((96, Some(432)), transform.window(96));
+        let fade_out = Transform {
+            end: Some(8.0),
+            fade_out: Some((2.0, 1.0)),
+            ..transform.clone()
+        };
+        assert_eq!((96, Some(288)), fade_out.window(96));
+
+        // the note started before the window is dropped, the one playing at
+        // its end is cut there and the program change still sets the channel
+        let midi = transformed(tracks.clone(), transform);
+        assert_eq!(336, midi.max_time);
+        assert_eq!(vec![(62, 96, 288), (64, 288, 336)], notes(&midi, 0));
+        assert!(midi.events_for_voice(0).iter().any(|&(ref message, time)| {
+            time == 0
+                && match *message {
+                    Message::MidiEvent {
+                        event: MidiEvent::ProgramChange { program: 5, .. },
+                        ..
+                    } => true,
+                    _ => false,
+                }
+        }));
+
+        let midi = transformed(tracks, fade_out);
+        assert_eq!(192, midi.max_time);
+        assert_eq!(vec![(62, 96, 192)], notes(&midi, 0));
+    }
+
+    #[test]
+    fn test_mute_solo() {
+        let muted = Transform {
+            mute: vec![1],
+            ..Transform::default()
+        };
+        assert_eq!((true, false), (muted.plays(0), muted.plays(1)));
+        let solo = Transform {
+            solo: vec![1, 2],
+            ..Transform::default()
+        };
+        assert_eq!((false, true, true), (solo.plays(0), solo.plays(1), solo.plays(2)));
+        let both = Transform {
+            mute: vec![1],
+            ..solo.clone()
+        };
+        assert_eq!((false, false, true), (both.plays(0), both.plays(1), both.plays(2)));
+
+        let tracks = vec![vec![
+            event(0, note(0, 60, true)),
+            event(0, note(1, 64, true)),
+            event(0, note(2, 67, true)),
+            event(96, note(0, 60, false)),
+            event(0, note(1, 64, false)),
+            event(0, note(2, 67, false)),
+        ]];
+        let midi = transformed(tracks, both);
+        let played = (0..8)
+            .flat_map(|voice| notes(&midi, voice))
+            .map(|(note, _, _)| note)
+            .collect::<Vec<u8>>();
+        assert_eq!(vec![67], played);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut transform = Transform {
+            transpose: -2,
+            master_volume: Some(160),
+            ..Transform::default()
+        };
+        transform.channel_transpose[1] = 12;
+        let tracks = vec![vec![
+            event(0, note(0, 60, true)),
+            event(96, note(0, 60, false)),
+            event(0, note(1, 60, true)),
+            event(96, note(1, 60, false)),
+        ]];
+        let mut midi = transformed(tracks, transform.clone());
+        let played = (0..8)
+            .flat_map(|voice| notes(&midi, voice))
+            .map(|(note, _, _)| note)
+            .collect::<Vec<u8>>();
+        assert_eq!(vec![60, 72], played);
+
+        // the song-wide transpose and master volume are set by the song
+        let song = Song::from_midi(&mut midi, 1.0, None, false).unwrap().to_text();
+        assert!(song.starts_with("master_volume 160\n"), "{}", song);
+        assert!(song.contains("global_transpose 254\n"), "{}", song);
+
+        let out_of_range = vec![vec![event(0, note(1, 120, true))]];
+        let mut midi = MidiHandler::with_transform(transform);
+        assert_eq!(
+            "Transposing note 120 on channel 2 by 12 is out of range",
+            midi.load_tracks(out_of_range, 96, Path::new("a.mid"), false)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_warnings() {
+        let tracks = vec![vec![
+            event(0, note(0, 60, true)),
+            event(0, MidiEvent::PitchBendChange { ch: 0, data: 0x100 }),
+            event(0, MidiEvent::ChannelPressure { ch: 0, pressure: 10 }),
+            event(0, MidiEvent::PitchBendChange { ch: 0, data: 0 }),
+            event(96, note(0, 60, false)),
+            event(0, note(1, 100, true)),
+            event(96, note(1, 100, false)),
+            event(0, note(1, 12, true)),
+            event(96, note(1, 12, false)),
+        ]];
+        let midi = MidiHandler::from_tracks(tracks, 96, Path::new("a.mid"), false).unwrap();
+        assert_eq!(
+            vec![
+                "channel 1: aftertouch was ignored, since the song doesn't turn it on",
+                "channel 1: pitch bends aren't supported and were ignored",
+                "channel 2: 2 notes are outside the range the driver can play (c1 to b6)",
+            ],
+            midi.warnings
+        );
+    }
+
+    #[test]
+    fn test_midi_formats() {
+        let path = std::env::temp_dir().join("midi2spc-format.mid");
+        let file = |format: u8| {
+            let mut data = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, format, 0, 1, 0, 96];
+            data.extend_from_slice(&[b'M', b'T', b'r', b'k', 0, 0, 0, 20]);
+            data.extend_from_slice(&[0x00, 0x90, 60, 100, 0x00, 0x91, 64, 100]);
+            data.extend_from_slice(&[0x60, 0x80, 60, 0, 0x00, 0x81, 64, 0]);
+            data.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+            fs::write(&path, data).unwrap();
+        };
+
+        // both channels of a format 0 file play from its one track
+        file(0);
+        let mut midi = MidiHandler::new();
+        midi.read(&path, false).unwrap();
+        assert_eq!(vec![(60, 0, 96)], notes(&midi, 0));
+        assert_eq!(vec![(64, 0, 96)], notes(&midi, 1));
+
+        file(2);
+        let err = MidiHandler::new().read(&path, false).unwrap_err();
+        match *err.downcast::<ConvertError>().unwrap() {
+            ConvertError::MidiParse { message, .. } => {
+                assert_eq!("MIDI format 2 isn't supported", message)
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_sys_ex() {
+        let sys_ex = |data: &[u8]| Message::SysExEvent {
+            delta_time: 0,
+            event: SysExEvent::F0,
+            data: data.to_vec(),
+        };
+        let master_volume = sys_ex(&[0x7f, 0x7f, 0x04, 0x01, 0x7f, 0x7f, 0xf7]);
+        let tracks = vec![vec![
+            sys_ex(&[0x7e, 0x7f, 0x09, 0x01, 0xf7]),
+            sys_ex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7]),
+            sys_ex(&[0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7]),
+            master_volume.clone(),
+            sys_ex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x01, 0x30, 0x00, 0x0f, 0xf7]),
+            sys_ex(&[0x7d, 0xf7]),
+            event(0, MidiEvent::NoteOn { ch: 0, note: 60, velocity: 100 }),
+            event(96, MidiEvent::NoteOff { ch: 0, note: 60, velocity: 0 }),
+        ]];
+        let midi = MidiHandler::from_tracks(tracks, 96, Path::new("a.mid"), false).unwrap();
+        assert_eq!(vec!["2 SysEx messages weren't recognised and were ignored"], midi.warnings);
+        let voice = midi.events_for_voice(0);
+        assert_eq!(1, voice.iter().filter(|&&(ref message, _)| *message == master_volume).count());
+        assert_eq!(Some(0xff), super::master_volume(&master_volume));
+    }
+}
 
 fn channel(event: &MidiEvent) -> usize {
     match *event {
@@ -22,6 +274,56 @@ fn channel(event: &MidiEvent) -> usize {
     }
 }
 
+// what a SysEx message asks of the synth, as far as the driver can follow it
+#[derive(Debug, PartialEq)]
+enum SysEx {
+    Reset,
+    MasterVolume(u8),
+    Unknown,
+}
+
+fn sys_ex(event: &SysExEvent, data: &[u8]) -> SysEx {
+    if *event != SysExEvent::F0 {
+        return SysEx::Unknown;
+    }
+    let data = match data.first() {
+        Some(&0xf0) => &data[1..],
+        _ => data,
+    };
+    let data = match data.last() {
+        Some(&0xf7) => &data[..data.len() - 1],
+        _ => data,
+    };
+    match data {
+        // GM system on and off
+        [0x7e, _, 0x09, 0x01..=0x03] => SysEx::Reset,
+        // GS reset
+        [0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41] => SysEx::Reset,
+        // XG system on
+        [0x43, device, 0x4c, 0x00, 0x00, 0x7e, 0x00] if *device & 0xf0 == 0x10 => SysEx::Reset,
+        // GM master volume, scaled from 14 bits to the driver's 8
+        [0x7f, _, 0x04, 0x01, lsb, msb] => {
+            SysEx::MasterVolume(((((*msb as u16) << 7) | *lsb as u16) >> 6) as u8)
+        }
+        _ => SysEx::Unknown,
+    }
+}
+
+/// The master volume a GM Master Volume SysEx message sets.
+pub fn master_volume(message: &Message) -> Option<u8> {
+    match *message {
+        Message::SysExEvent {
+            ref event,
+            ref data,
+            ..
+        } => match sys_ex(event, data) {
+            SysEx::MasterVolume(volume) => Some(volume),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn priority(message: &Message) -> u8 {
     match *message {
         Message::MidiEvent { ref event, .. } => match *event {
@@ -39,6 +341,43 @@ fn priority(message: &Message) -> u8 {
     }
 }
 
+/// Changes made to a MIDI file as it's converted.  Channels are numbered from
+/// 0 and times are in beats from the start of the file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transform {
+    pub transpose: i8,
+    pub channel_transpose: [i8; 16],
+    pub master_volume: Option<u8>,
+    pub mute: Vec<usize>,
+    pub solo: Vec<usize>,
+    pub start: Option<f32>,
+    pub end: Option<f32>,
+    /// Whether aftertouch swells the volume of held notes.
+    pub aftertouch: bool,
+    /// Beats to fade in from silence over.
+    pub fade_in: Option<f32>,
+    /// The beat to start fading out at and the beats to fade over, after
+    /// which the song ends.
+    pub fade_out: Option<(f32, f32)>,
+}
+
+impl Transform {
+    fn plays(&self, ch: usize) -> bool {
+        !self.mute.contains(&ch) && (self.solo.is_empty() || self.solo.contains(&ch))
+    }
+
+    // the window to keep, in ticks
+    fn window(&self, ticks_per_beat: u16) -> (u32, Option<u32>) {
+        let to_ticks = |beats: f32| (beats * ticks_per_beat as f32).round() as u32;
+        let end = match (self.end, self.fade_out) {
+            (Some(end), Some((start, length))) => Some(end.min(start + length)),
+            (None, Some((start, length))) => Some(start + length),
+            (end, None) => end,
+        };
+        (self.start.map_or(0, to_ticks), end.map(to_ticks))
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 struct VoiceInterval {
     start: u32,
@@ -95,12 +434,20 @@ pub struct MidiHandler {
     tracks: Vec<MidiTrack>,
     channels: [MidiChannel; 16],
     voices: [MidiVoice; 8],
+    format: u16,
     pub ticks_per_beat: u16,
     pub max_time: u32,
+    pub transform: Transform,
+    /// Things in the MIDI file that couldn't be converted as written.
+    pub warnings: Vec<String>,
 }
 
 impl MidiHandler {
     pub fn new() -> MidiHandler {
+        MidiHandler::with_transform(Transform::default())
+    }
+
+    pub fn with_transform(transform: Transform) -> MidiHandler {
         MidiHandler {
             tracks: Vec::new(),
             channels: [
@@ -131,8 +478,11 @@ impl MidiHandler {
                 MidiVoice::new(),
                 MidiVoice::new(),
             ],
+            format: 1,
             ticks_per_beat: 0,
             max_time: 0,
+            transform,
+            warnings: Vec::new(),
         }
     }
 
@@ -141,27 +491,84 @@ impl MidiHandler {
             println!("reading {:?}", path);
         }
         {
-            let mut midi_reader = Reader::new(self, path).map_err(|err| {
-                Box::new(SimpleError::new(format!(
-                    "MIDI read error: {:?}",
-                    err
-                )))
+            let mut midi_reader = Reader::new(self, path).map_err(|err| ConvertError::MidiParse {
+                path: path.to_path_buf(),
+                message: format!("{:?}", err),
             })?;
-            midi_reader.read().map_err(|err| {
-                Box::new(SimpleError::new(format!(
-                    "MIDI read error: {:?}",
-                    err
-                )))
+            midi_reader.read().map_err(|err| ConvertError::MidiParse {
+                path: path.to_path_buf(),
+                message: format!("{:?}", err),
             })?;
         }
-        self.tracks_to_channels(verbose);
+        // format 0 puts every channel in one track, which reads the same as
+        // format 1, but format 2's tracks are separate songs
+        if self.format > 1 {
+            return Err(Box::from(ConvertError::MidiParse {
+                path: path.to_path_buf(),
+                message: format!("MIDI format {} isn't supported", self.format),
+            }));
+        }
+        self.allocate_voices(path, verbose)
+    }
+
+    /// Allocates voices for MIDI-style tracks produced from some other input,
+    /// as if they'd been read from a MIDI file.
+    pub fn from_tracks(
+        tracks: Vec<Vec<Message>>,
+        ticks_per_beat: u16,
+        path: &Path,
+        verbose: bool,
+    ) -> Result<MidiHandler, Box<Error>> {
+        let mut midi = MidiHandler::new();
+        midi.load_tracks(tracks, ticks_per_beat, path, verbose)?;
+        Ok(midi)
+    }
+
+    fn load_tracks(
+        &mut self,
+        tracks: Vec<Vec<Message>>,
+        ticks_per_beat: u16,
+        path: &Path,
+        verbose: bool,
+    ) -> Result<(), Box<Error>> {
+        self.tracks = tracks
+            .into_iter()
+            .map(|messages| MidiTrack { messages })
+            .collect();
+        self.ticks_per_beat = ticks_per_beat;
+        self.allocate_voices(path, verbose)
+    }
+
+    fn allocate_voices(&mut self, path: &Path, verbose: bool) -> Result<(), Box<Error>> {
+        self.tracks_to_channels(verbose)?;
         for (i, channel) in &mut self.channels.iter_mut().enumerate() {
             let intervals = &mut channel.intervals;
             let mut last_interval_end = 0u32;
             let mut active_voices = 0usize;
+            let mut unplayable_notes = 0;
+            let mut ignored = Vec::new();
             for message in &channel.messages {
                 match *message {
                     (Message::MidiEvent { ref event, .. }, abs_time) => {
+                        match *event {
+                            MidiEvent::NoteOn { note, velocity, .. }
+                                if velocity > 0 && (note < LOWEST_NOTE || note > HIGHEST_NOTE) =>
+                            {
+                                unplayable_notes += 1;
+                            }
+                            MidiEvent::PitchBendChange { .. } => {
+                                ignored.push("pitch bends aren't supported and were ignored")
+                            }
+                            MidiEvent::PolyphonicKeyPressure { .. }
+                            | MidiEvent::ChannelPressure { .. }
+                                if !self.transform.aftertouch =>
+                            {
+                                ignored.push(
+                                    "aftertouch was ignored, since the song doesn't turn it on",
+                                )
+                            }
+                            _ => {}
+                        }
                         match *event {
                             MidiEvent::NoteOff { .. } => {
                                 if abs_time > last_interval_end {
@@ -200,6 +607,19 @@ impl MidiHandler {
                     _ => {}
                 }
             }
+            if unplayable_notes > 0 {
+                self.warnings.push(format!(
+                    "channel {}: {} {} outside the range the driver can play (c1 to b6)",
+                    i + 1,
+                    unplayable_notes,
+                    if unplayable_notes == 1 { "note is" } else { "notes are" }
+                ));
+            }
+            ignored.sort();
+            ignored.dedup();
+            for message in ignored {
+                self.warnings.push(format!("channel {}: {}", i + 1, message));
+            }
         }
         let active_base_intervals = vec![
             vec![],
@@ -216,40 +636,114 @@ impl MidiHandler {
         Ok(())
     }
 
-    fn tracks_to_channels(&mut self, verbose: bool) {
+    fn tracks_to_channels(&mut self, verbose: bool) -> Result<(), Box<Error>> {
+        let (start, end) = self.transform.window(self.ticks_per_beat);
+        let mut unknown_sys_ex = 0;
+        let mut dropped_notes = HashSet::new();
         for (i, track) in self.tracks.iter().enumerate() {
             if verbose {
                 println!("extracting events from midi track {}", i);
             }
             let mut abs_time = 0;
             for message in &track.messages {
-                match *message {
-                    Message::MetaEvent { delta_time, .. } => {
-                        abs_time += delta_time;
-                        self.channels[0].messages.push((message.clone(), abs_time));
-                    }
+                let (message_ch, delta_time) = match *message {
+                    Message::MetaEvent { delta_time, .. } => (0, delta_time),
                     Message::MidiEvent {
                         delta_time,
                         ref event,
+                    } => (channel(event), delta_time),
+                    Message::SysExEvent { delta_time, .. } => (0, delta_time),
+                    _ => continue,
+                };
+                abs_time += delta_time;
+                let message = match *message {
+                    Message::MidiEvent { ref event, .. } => {
+                        if !self.transform.plays(message_ch) {
+                            continue;
+                        }
+                        let is_note = match *event {
+                            MidiEvent::NoteOn { .. } | MidiEvent::NoteOff { .. } => true,
+                            _ => false,
+                        };
+                        let is_note_end = match *event {
+                            MidiEvent::NoteOff { .. } | MidiEvent::NoteOn { velocity: 0, .. } => true,
+                            _ => false,
+                        };
+                        let note = match *event {
+                            MidiEvent::NoteOn { note, .. } | MidiEvent::NoteOff { note, .. } => {
+                                (message_ch, note)
+                            }
+                            _ => (message_ch, 0),
+                        };
+                        // notes are cut at the end of the window, and other
+                        // events before the start still set up the channel
+                        if (is_note && abs_time < start)
+                            || end.map_or(false, |end| abs_time >= end && !is_note_end)
+                        {
+                            if is_note_end {
+                                dropped_notes.remove(&note);
+                            } else if is_note {
+                                dropped_notes.insert(note);
+                            }
+                            continue;
+                        }
+                        // as are the ends of notes started outside it
+                        if is_note_end && dropped_notes.remove(&note) {
+                            continue;
+                        }
+                        transpose(message, self.transform.channel_transpose[message_ch])?
+                    }
+                    Message::SysExEvent {
+                        ref event,
+                        ref data,
+                        ..
                     } => {
-                        abs_time += delta_time;
-                        self.channels[channel(event)]
-                            .messages
-                            .push((message.clone(), abs_time));
+                        if end.map_or(false, |end| abs_time >= end) {
+                            continue;
+                        }
+                        match sys_ex(event, data) {
+                            SysEx::MasterVolume(_) => message.clone(),
+                            // the driver starts every song from its defaults anyway
+                            SysEx::Reset => continue,
+                            SysEx::Unknown => {
+                                unknown_sys_ex += 1;
+                                continue;
+                            }
+                        }
                     }
-                    Message::SysExEvent { delta_time, .. } => {
-                        abs_time += delta_time;
-                        self.channels[0].messages.push((message.clone(), abs_time));
+                    _ => {
+                        if end.map_or(false, |end| abs_time >= end) {
+                            continue;
+                        }
+                        message.clone()
                     }
-                    _ => {}
+                };
+                let window_time = match end {
+                    Some(end) => abs_time.min(end),
+                    None => abs_time,
                 }
+                .max(start)
+                    - start;
+                self.channels[message_ch].messages.push((message, window_time));
             }
         }
+        if let Some(end) = end {
+            self.max_time = end.max(start) - start;
+        }
+        if unknown_sys_ex > 0 {
+            self.warnings.push(format!(
+                "{} SysEx {} recognised and {} ignored",
+                unknown_sys_ex,
+                if unknown_sys_ex == 1 { "message wasn't" } else { "messages weren't" },
+                if unknown_sys_ex == 1 { "was" } else { "were" }
+            ));
+        }
         for channel in &mut self.channels {
             channel
                 .messages
                 .sort_by_key(|&(ref event, abs_time)| (abs_time, priority(event)));
         }
+        Ok(())
     }
 
     fn find_base_voices(
@@ -306,10 +800,7 @@ impl MidiHandler {
                 Err(_) => (),
             }
         }
-        Err(Box::from(SimpleError::new(format!(
-            "{}: couldn't fit notes into available channels",
-            path.to_str().unwrap().to_owned()
-        ))))
+        Err(Box::from(no_voice_left(path)))
     }
 
     fn overlapping_interval(
@@ -476,10 +967,7 @@ impl MidiHandler {
                                 next_voice = Some(base_voice);
                             }
                             let next_voice =
-                                next_voice.ok_or(Box::new(SimpleError::new(format!(
-                                    "{}: couldn't fit notes into available channels",
-                                    path.to_str().unwrap().to_owned()
-                                ))))?;
+                                next_voice.ok_or_else(|| no_voice_left(path))?;
 
                             active_notes[ch].insert(note, next_voice);
                             let messages = &mut self.voices[next_voice].messages;
@@ -635,11 +1123,60 @@ impl MidiHandler {
     }
 }
 
+fn no_voice_left(path: &Path) -> ConvertError {
+    ConvertError::VoiceAllocation {
+        path: path.to_path_buf(),
+        message: "couldn't fit notes into available channels".to_string(),
+    }
+}
+
+fn transpose(message: &Message, semitones: i8) -> Result<Message, Box<Error>> {
+    if semitones == 0 {
+        return Ok(message.clone());
+    }
+    let shift = |ch: u8, note: u8| -> Result<u8, Box<Error>> {
+        let shifted = note as i16 + semitones as i16;
+        if shifted < 0 || shifted > 0x7f {
+            Err(Box::from(SimpleError::new(format!(
+                "Transposing note {} on channel {} by {} is out of range",
+                note,
+                ch + 1,
+                semitones
+            ))))
+        } else {
+            Ok(shifted as u8)
+        }
+    };
+    Ok(match *message {
+        Message::MidiEvent {
+            delta_time,
+            event: MidiEvent::NoteOn { ch, note, velocity },
+        } => Message::MidiEvent {
+            delta_time,
+            event: MidiEvent::NoteOn {
+                ch,
+                note: shift(ch, note)?,
+                velocity,
+            },
+        },
+        Message::MidiEvent {
+            delta_time,
+            event: MidiEvent::NoteOff { ch, note, velocity },
+        } => Message::MidiEvent {
+            delta_time,
+            event: MidiEvent::NoteOff {
+                ch,
+                note: shift(ch, note)?,
+                velocity,
+            },
+        },
+        _ => message.clone(),
+    })
+}
+
 impl Handler for MidiHandler {
     fn header(&mut self, format: u16, _track: u16, time_base: u16) {
-        if format != 1 {
-            unimplemented!("MIDI format {}", format)
-        }
+        self.format = format;
         self.ticks_per_beat = time_base;
     }
 
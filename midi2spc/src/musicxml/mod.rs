@@ -0,0 +1,862 @@
+use self::xml::Element;
+use crate::error::ConvertError;
+use crate::midi::MidiHandler;
+use ghakuf::messages::*;
+use simple_error::SimpleError;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+mod xml;
+
+// ticks per quarter note in the MIDI tracks built for each section
+const TICKS_PER_BEAT: u16 = 480;
+const DEFAULT_TEMPO: f32 = 120.0;
+const DEFAULT_VELOCITY: u8 = 80;
+const DEFAULT_REPEAT_TIMES: usize = 2;
+const PERCUSSION_CHANNEL: u8 = 9;
+const MAX_CHANNELS: u8 = 16;
+// <sound dynamics> is a percentage of the velocity of forte
+const FORTE_VELOCITY: f32 = 90.0;
+const DYNAMICS: [(&str, u8); 10] = [
+    ("pppp", 10),
+    ("ppp", 16),
+    ("pp", 33),
+    ("p", 49),
+    ("mp", 64),
+    ("mf", 80),
+    ("f", 96),
+    ("ff", 112),
+    ("fff", 126),
+    ("ffff", 127),
+];
+// fraction of a note's written length that's played
+const ARTICULATIONS: [(&str, f32); 5] = [
+    ("staccatissimo", 0.25),
+    ("spiccato", 0.25),
+    ("staccato", 0.5),
+    ("detached-legato", 0.75),
+    ("tenuto", 1.0),
+];
+const PITCHES: [(&str, i32); 7] = [
+    ("C", 0),
+    ("D", 2),
+    ("E", 4),
+    ("F", 5),
+    ("G", 7),
+    ("A", 9),
+    ("B", 11),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(forward_repeat: bool, backward_repeat: Option<usize>, endings: &[usize]) -> Measure {
+        Measure {
+            start: 0,
+            forward_repeat,
+            backward_repeat,
+            endings: endings.to_vec(),
+        }
+    }
+
+    fn note(channel: u8, note: u8, velocity: u8, start: u64, end: u64) -> ScoreNote {
+        ScoreNote {
+            channel,
+            note,
+            velocity,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_play_order() {
+        let measures = vec![
+            measure(false, None, &[]),
+            measure(true, None, &[]),
+            measure(false, None, &[]),
+            measure(false, Some(2), &[1]),
+            measure(false, None, &[2]),
+            measure(false, None, &[]),
+            measure(false, Some(2), &[]),
+        ];
+        let order = play_order(&measures);
+        assert_eq!(vec![0, 1, 2, 3, 1, 2, 4, 5, 6, 5, 6], order);
+        let (sections, sequence) = split(&order);
+        assert_eq!(vec![(0, 0), (1, 2), (3, 3), (4, 4), (5, 6)], sections);
+        assert_eq!(vec![0, 1, 2, 1, 3, 4, 4], sequence);
+    }
+
+    #[test]
+    fn test_no_repeats() {
+        let measures = vec![measure(false, None, &[]); 3];
+        let order = play_order(&measures);
+        assert_eq!(vec![0, 1, 2], order);
+        assert_eq!((vec![(0, 2)], vec![0]), split(&order));
+    }
+
+    #[test]
+    fn test_read_notes() {
+        let root = xml::parse(
+            r#"<score-partwise>
+              <part-list>
+                <score-part id="P1">
+                  <midi-instrument id="P1-I1"><midi-channel>3</midi-channel><midi-program>41</midi-program></midi-instrument>
+                </score-part>
+                <score-part id="P2"/>
+              </part-list>
+              <part id="P1">
+                <measure number="1">
+                  <attributes><divisions>2</divisions><transpose><chromatic>-2</chromatic></transpose></attributes>
+                  <direction><direction-type><dynamics><p/></dynamics></direction-type><sound tempo="90"/></direction>
+                  <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><tie type="start"/></note>
+                  <note><chord/><pitch><step>E</step><alter>-1</alter><octave>4</octave></pitch><duration>2</duration></note>
+                  <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><tie type="stop"/></note>
+                  <backup><duration>4</duration></backup>
+                  <note><rest/><duration>2</duration></note>
+                  <note dynamics="50"><pitch><step>G</step><octave>3</octave></pitch><duration>2</duration>
+                    <notations><articulations><staccato/></articulations></notations></note>
+                </measure>
+                <measure number="2">
+                  <note><grace/><pitch><step>D</step><octave>4</octave></pitch></note>
+                  <note><pitch><step>D</step><octave>4</octave></pitch><duration>8</duration></note>
+                </measure>
+              </part>
+              <part id="P2">
+                <measure number="1">
+                  <attributes><divisions>1</divisions></attributes>
+                  <note><pitch><step>A</step><octave>2</octave></pitch><duration>1</duration>
+                    <notations><articulations><staccato/><tenuto/></articulations></notations></note>
+                </measure>
+              </part>
+            </score-partwise>"#,
+        )
+        .unwrap();
+        let timeline = Timeline::read(&root).unwrap();
+        assert_eq!(vec![(2, 40), (0, 0)], timeline.programs);
+        assert_eq!(vec![(0, 90.0)], timeline.tempos);
+        assert_eq!(
+            vec![0, 960],
+            timeline
+                .measures
+                .iter()
+                .map(|m| m.start)
+                .collect::<Vec<u64>>()
+        );
+        assert_eq!(960 + 1920, timeline.length);
+        assert_eq!(
+            vec![
+                note(2, 58, 49, 0, 960),
+                note(2, 61, 49, 0, 480),
+                note(0, 45, 80, 0, 360),
+                note(2, 53, 45, 480, 720),
+                note(2, 60, 49, 960, 2880),
+            ],
+            timeline.notes
+        );
+        assert_eq!(vec![58], timeline.held_notes(480));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| {
+            Timeline::read(&xml::parse(text).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            "Only partwise MusicXML scores are supported",
+            error("<score-timewise/>")
+        );
+        assert_eq!(
+            "Part P1 isn't in the part list",
+            error("<score-partwise><part-list/><part id=\"P1\"/></score-partwise>")
+        );
+        assert_eq!(
+            "Note in measure 1 of part P1 has no duration",
+            error(
+                "<score-partwise><part-list><score-part id=\"P1\"/></part-list>
+                <part id=\"P1\"><measure><note><rest/></note></measure></part></score-partwise>"
+            )
+        );
+    }
+}
+
+/// A MusicXML score split into sections at its repeats and endings, with
+/// the order to play them in.
+pub struct Score {
+    pub sections: Vec<MidiHandler>,
+    /// The pitches of the notes held into each section from the measure
+    /// written before it.
+    pub ties: Vec<Vec<u8>>,
+    pub sequence: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ScoreNote {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Measure {
+    start: u64,
+    forward_repeat: bool,
+    backward_repeat: Option<usize>,
+    endings: Vec<usize>,
+}
+
+// a note read from a part, before ties are joined
+struct PartNote {
+    note: ScoreNote,
+    measure: usize,
+    written_end: u64,
+    tie_start: bool,
+    tie_stop: bool,
+}
+
+// state carried through the measures of a part
+struct PartState {
+    divisions: f64,
+    velocity: u8,
+    transpose: i32,
+    ending: Option<Vec<usize>>,
+}
+
+/// Everything played in a score, with times in ticks from its start in
+/// written order.
+#[derive(Debug, Default)]
+struct Timeline {
+    notes: Vec<ScoreNote>,
+    tempos: Vec<(u64, f32)>,
+    programs: Vec<(u8, u8)>,
+    measures: Vec<Measure>,
+    length: u64,
+}
+
+fn invalid(path: &Path, message: &str) -> Box<Error> {
+    Box::from(ConvertError::Invalid {
+        path: Some(path.to_path_buf()),
+        message: message.to_string(),
+    })
+}
+
+impl Score {
+    pub fn read(path: &Path, verbose: bool) -> Result<Score, Box<Error>> {
+        let text = fs::read_to_string(path).map_err(ConvertError::io(path))?;
+        let timeline = xml::parse(&text)
+            .and_then(|root| Timeline::read(&root))
+            .map_err(|err| invalid(path, &err.to_string()))?;
+        let order = play_order(&timeline.measures);
+        if order.is_empty() {
+            return Err(invalid(path, "score has no measures"));
+        }
+        let (sections, sequence) = split(&order);
+        if verbose {
+            println!("sections {:?} played in order {:?}", sections, sequence);
+        }
+        let ties = sections
+            .iter()
+            .map(|&(first, _)| timeline.held_notes(timeline.measures[first].start))
+            .collect();
+        let sections = sections
+            .iter()
+            .map(|&(first, last)| {
+                let start = timeline.measures[first].start;
+                let end = timeline.measure_end(last);
+                let mut midi = MidiHandler::from_tracks(
+                    timeline.section_tracks(start, end),
+                    TICKS_PER_BEAT,
+                    path,
+                    verbose,
+                )?;
+                midi.max_time = (end - start) as u32;
+                Ok(midi)
+            })
+            .collect::<Result<Vec<MidiHandler>, Box<Error>>>()?;
+        Ok(Score {
+            sections,
+            ties,
+            sequence,
+        })
+    }
+}
+
+impl Timeline {
+    fn read(root: &Element) -> Result<Timeline, Box<Error>> {
+        if root.name != "score-partwise" {
+            return Err(Box::from(SimpleError::new(
+                "Only partwise MusicXML scores are supported",
+            )));
+        }
+        let mut timeline = Timeline::default();
+        let mut instruments = HashMap::new();
+        let mut next_channel = 0;
+        if let Some(part_list) = root.child("part-list") {
+            for score_part in part_list.children("score-part") {
+                let id = score_part.attribute("id").unwrap_or("");
+                let midi_instrument = score_part.child("midi-instrument");
+                let read = |name: &str, max: u8| -> Option<u8> {
+                    midi_instrument
+                        .and_then(|instrument| instrument.child_text(name))
+                        .and_then(|value| value.parse::<u8>().ok())
+                        .filter(|&value| value >= 1 && value <= max)
+                        .map(|value| value - 1)
+                };
+                let channel = match read("midi-channel", MAX_CHANNELS) {
+                    Some(channel) => channel,
+                    None => {
+                        if next_channel == PERCUSSION_CHANNEL {
+                            next_channel += 1;
+                        }
+                        if next_channel >= MAX_CHANNELS {
+                            return Err(Box::from(SimpleError::new(
+                                "Too many parts to give each a MIDI channel",
+                            )));
+                        }
+                        next_channel += 1;
+                        next_channel - 1
+                    }
+                };
+                let program = read("midi-program", 128).unwrap_or(0);
+                let unpitched = score_part
+                    .children("midi-instrument")
+                    .filter_map(|instrument| {
+                        let note = instrument
+                            .child_text("midi-unpitched")?
+                            .parse::<u8>()
+                            .ok()?;
+                        Some((instrument.attribute("id")?.to_string(), note.max(1) - 1))
+                    })
+                    .collect::<HashMap<String, u8>>();
+                timeline.programs.push((channel, program));
+                instruments.insert(id.to_string(), (channel, unpitched));
+            }
+        }
+        let mut part_notes = Vec::new();
+        let mut extents = Vec::new();
+        let mut tempos = Vec::new();
+        for part in root.children("part") {
+            let id = part.attribute("id").unwrap_or("");
+            let (channel, unpitched) = match instruments.get(id) {
+                Some(instrument) => instrument,
+                None => {
+                    return Err(Box::from(SimpleError::new(format!(
+                        "Part {} isn't in the part list",
+                        id
+                    ))))
+                }
+            };
+            timeline.read_part(
+                part,
+                *channel,
+                unpitched,
+                &mut part_notes,
+                &mut extents,
+                &mut tempos,
+            )?;
+        }
+        for (i, extent) in extents.iter().enumerate() {
+            timeline.measures[i].start = timeline.length;
+            timeline.length += extent;
+        }
+        for &(measure, offset, bpm) in &tempos {
+            let time = timeline.measures[measure].start + offset;
+            if !timeline.tempos.iter().any(|&(other, _)| other == time) {
+                timeline.tempos.push((time, bpm));
+            }
+        }
+        timeline.tempos.sort_by_key(|&(time, _)| time);
+        timeline.join_ties(part_notes);
+        Ok(timeline)
+    }
+
+    fn read_part(
+        &mut self,
+        part: &Element,
+        channel: u8,
+        unpitched: &HashMap<String, u8>,
+        part_notes: &mut Vec<PartNote>,
+        extents: &mut Vec<u64>,
+        tempos: &mut Vec<(usize, u64, f32)>,
+    ) -> Result<(), Box<Error>> {
+        let id = part.attribute("id").unwrap_or("");
+        let mut state = PartState {
+            divisions: 1.0,
+            velocity: DEFAULT_VELOCITY,
+            transpose: 0,
+            ending: None,
+        };
+        for (m, measure) in part.children("measure").enumerate() {
+            if self.measures.len() <= m {
+                self.measures.push(Measure::default());
+                extents.push(0);
+            }
+            if let Some(ending) = &state.ending {
+                self.measures[m].endings = ending.clone();
+            }
+            let mut cursor = 0u64;
+            let mut chord_start = 0u64;
+            for element in measure.elements() {
+                match element.name.as_str() {
+                    "attributes" => {
+                        if let Some(divisions) = element.child_text("divisions") {
+                            state.divisions = divisions
+                                .parse::<f64>()
+                                .ok()
+                                .filter(|&d| d > 0.0)
+                                .ok_or_else(|| {
+                                    SimpleError::new(format!(
+                                        "Invalid divisions in measure {} of part {}",
+                                        m + 1,
+                                        id
+                                    ))
+                                })?;
+                        }
+                        if let Some(transpose) = element.child("transpose") {
+                            let read = |name| {
+                                transpose
+                                    .child_text(name)
+                                    .and_then(|value| value.parse::<i32>().ok())
+                                    .unwrap_or(0)
+                            };
+                            state.transpose = read("chromatic") + 12 * read("octave-change");
+                        }
+                    }
+                    "note" => {
+                        if element.child("grace").is_some() || element.child("cue").is_some() {
+                            continue;
+                        }
+                        let length = match element.child_text("duration") {
+                            Some(duration) => ticks(&duration, state.divisions),
+                            None => None,
+                        }
+                        .ok_or_else(|| {
+                            SimpleError::new(format!(
+                                "Note in measure {} of part {} has no duration",
+                                m + 1,
+                                id
+                            ))
+                        })?;
+                        if element.child("chord").is_none() {
+                            chord_start = cursor;
+                            cursor += length;
+                        }
+                        if element.child("rest").is_some() {
+                            continue;
+                        }
+                        let note = read_pitch(element, unpitched, state.transpose)
+                            .filter(|&note| note >= 0 && note <= 0x7f)
+                            .ok_or_else(|| {
+                                SimpleError::new(format!(
+                                    "Note in measure {} of part {} has an invalid pitch",
+                                    m + 1,
+                                    id
+                                ))
+                            })?;
+                        let velocity = element
+                            .attribute("dynamics")
+                            .and_then(|dynamics| dynamics.parse::<f32>().ok())
+                            .map_or(state.velocity, dynamics_velocity);
+                        let ties = element
+                            .children("tie")
+                            .filter_map(|tie| tie.attribute("type"))
+                            .collect::<Vec<&str>>();
+                        let sounding =
+                            ((length as f32 * articulation(element)).round() as u64).max(1);
+                        part_notes.push(PartNote {
+                            note: ScoreNote {
+                                channel,
+                                note: note as u8,
+                                velocity,
+                                start: chord_start,
+                                end: chord_start + sounding,
+                            },
+                            measure: m,
+                            written_end: chord_start + length,
+                            tie_start: ties.contains(&"start"),
+                            tie_stop: ties.contains(&"stop"),
+                        });
+                    }
+                    "backup" | "forward" => {
+                        let length = element
+                            .child_text("duration")
+                            .and_then(|duration| ticks(&duration, state.divisions))
+                            .unwrap_or(0);
+                        if element.name == "backup" {
+                            cursor = cursor.saturating_sub(length);
+                        } else {
+                            cursor += length;
+                        }
+                    }
+                    "direction" => {
+                        for direction_type in element.children("direction-type") {
+                            for dynamics in direction_type.children("dynamics") {
+                                for mark in dynamics.elements() {
+                                    if let Some(&(_, velocity)) =
+                                        DYNAMICS.iter().find(|(name, _)| *name == mark.name)
+                                    {
+                                        state.velocity = velocity;
+                                    }
+                                }
+                            }
+                        }
+                        if let Some(sound) = element.child("sound") {
+                            read_sound(sound, &mut state, m, cursor, tempos);
+                        }
+                    }
+                    "sound" => read_sound(element, &mut state, m, cursor, tempos),
+                    "barline" => {
+                        if let Some(repeat) = element.child("repeat") {
+                            match repeat.attribute("direction") {
+                                Some("forward") => self.measures[m].forward_repeat = true,
+                                Some("backward") => {
+                                    self.measures[m].backward_repeat = Some(
+                                        repeat
+                                            .attribute("times")
+                                            .and_then(|times| times.parse::<usize>().ok())
+                                            .unwrap_or(DEFAULT_REPEAT_TIMES),
+                                    )
+                                }
+                                _ => (),
+                            }
+                        }
+                        if let Some(ending) = element.child("ending") {
+                            if ending.attribute("type") == Some("start") {
+                                let numbers = ending
+                                    .attribute("number")
+                                    .unwrap_or("")
+                                    .split(|c: char| c == ',' || c.is_whitespace())
+                                    .filter_map(|number| {
+                                        number.trim_end_matches('.').parse::<usize>().ok()
+                                    })
+                                    .collect::<Vec<usize>>();
+                                self.measures[m].endings = numbers.clone();
+                                state.ending = Some(numbers);
+                            } else {
+                                state.ending = None;
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+                extents[m] = extents[m].max(cursor);
+            }
+        }
+        Ok(())
+    }
+
+    // joins tied notes, now that measures have their start times
+    fn join_ties(&mut self, mut part_notes: Vec<PartNote>) {
+        for part_note in &mut part_notes {
+            let start = self.measures[part_note.measure].start;
+            part_note.note.start += start;
+            part_note.note.end += start;
+            part_note.written_end += start;
+        }
+        part_notes.sort_by_key(|part_note| part_note.note.start);
+        let mut written_ends = Vec::new();
+        let mut tied: HashMap<(u8, u8), usize> = HashMap::new();
+        for part_note in part_notes {
+            let key = (part_note.note.channel, part_note.note.note);
+            if part_note.tie_stop {
+                if let Some(&idx) = tied.get(&key) {
+                    if written_ends[idx] == part_note.note.start {
+                        self.notes[idx].end = part_note.note.end;
+                        written_ends[idx] = part_note.written_end;
+                        if !part_note.tie_start {
+                            tied.remove(&key);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if part_note.tie_start {
+                tied.insert(key, self.notes.len());
+            }
+            written_ends.push(part_note.written_end);
+            self.notes.push(part_note.note);
+        }
+    }
+
+    fn measure_end(&self, measure: usize) -> u64 {
+        self.measures
+            .get(measure + 1)
+            .map_or(self.length, |next| next.start)
+    }
+
+    // the pitches of the notes started before a time and still held at it
+    fn held_notes(&self, time: u64) -> Vec<u8> {
+        self.notes
+            .iter()
+            .filter(|note| note.start < time && note.end > time)
+            .map(|note| note.note)
+            .collect()
+    }
+
+    // MIDI tracks for the notes and tempo changes between start and end,
+    // starting with the tempo and instruments in effect at the start
+    fn section_tracks(&self, start: u64, end: u64) -> Vec<Vec<Message>> {
+        let tempo = self
+            .tempos
+            .iter()
+            .take_while(|&&(time, _)| time <= start)
+            .last()
+            .map_or(DEFAULT_TEMPO, |&(_, bpm)| bpm);
+        let mut tempo_events = vec![(0, tempo)];
+        tempo_events.extend(
+            self.tempos
+                .iter()
+                .filter(|&&(time, _)| time > start && time < end)
+                .map(|&(time, bpm)| (time - start, bpm)),
+        );
+        let tempo_messages = with_delta_times(tempo_events.into_iter().map(|(time, bpm)| {
+            let usec_per_beat = (6e7 / bpm) as u32;
+            (
+                time,
+                Message::MetaEvent {
+                    delta_time: 0,
+                    event: MetaEvent::SetTempo,
+                    data: vec![
+                        (usec_per_beat >> 16) as u8,
+                        (usec_per_beat >> 8) as u8,
+                        usec_per_beat as u8,
+                    ],
+                },
+            )
+        }));
+        let notes = self
+            .notes
+            .iter()
+            .filter(|note| note.start < end && note.end > start)
+            .collect::<Vec<&ScoreNote>>();
+        let mut events = Vec::new();
+        for &(ch, program) in &self.programs {
+            if ch != PERCUSSION_CHANNEL && notes.iter().any(|note| note.channel == ch) {
+                events.push((0, MidiEvent::ProgramChange { ch, program }));
+            }
+        }
+        // notes held into the section start again at its start, and are tied
+        // on where they can be once the section's converted
+        for note in notes {
+            events.push((
+                note.start.max(start) - start,
+                MidiEvent::NoteOn {
+                    ch: note.channel,
+                    note: note.note,
+                    velocity: note.velocity,
+                },
+            ));
+            events.push((
+                note.end.min(end) - start,
+                MidiEvent::NoteOff {
+                    ch: note.channel,
+                    note: note.note,
+                    velocity: 0,
+                },
+            ));
+        }
+        events.sort_by_key(|&(time, _)| time);
+        let note_messages = with_delta_times(events.into_iter().map(|(time, event)| {
+            (
+                time,
+                Message::MidiEvent {
+                    delta_time: 0,
+                    event,
+                },
+            )
+        }));
+        vec![tempo_messages, note_messages]
+    }
+}
+
+fn with_delta_times<I: Iterator<Item = (u64, Message)>>(events: I) -> Vec<Message> {
+    let mut last_time = 0;
+    events
+        .map(|(time, message)| {
+            let delta = (time - last_time) as u32;
+            last_time = time;
+            match message {
+                Message::MetaEvent { event, data, .. } => Message::MetaEvent {
+                    delta_time: delta,
+                    event,
+                    data,
+                },
+                Message::MidiEvent { event, .. } => Message::MidiEvent {
+                    delta_time: delta,
+                    event,
+                },
+                message => message,
+            }
+        })
+        .collect()
+}
+
+fn ticks(duration: &str, divisions: f64) -> Option<u64> {
+    duration
+        .parse::<f64>()
+        .ok()
+        .filter(|&duration| duration >= 0.0)
+        .map(|duration| (duration * TICKS_PER_BEAT as f64 / divisions).round() as u64)
+}
+
+fn read_pitch(note: &Element, unpitched: &HashMap<String, u8>, transpose: i32) -> Option<i32> {
+    let pitch = |element: &Element, step: &str, octave: &str| -> Option<i32> {
+        let step = element.child_text(step)?;
+        let &(_, semitone) = PITCHES.iter().find(|(name, _)| *name == step)?;
+        let octave = element.child_text(octave)?.parse::<i32>().ok()?;
+        Some(12 * (octave + 1) + semitone)
+    };
+    if let Some(element) = note.child("pitch") {
+        let alter = element
+            .child_text("alter")
+            .and_then(|alter| alter.parse::<f32>().ok())
+            .map_or(0, |alter| alter.round() as i32);
+        return pitch(element, "step", "octave").map(|note| note + alter + transpose);
+    }
+    let instrument = note
+        .child("instrument")
+        .and_then(|instrument| instrument.attribute("id"))
+        .and_then(|id| unpitched.get(id));
+    match instrument {
+        Some(&note) => Some(note as i32),
+        None => note
+            .child("unpitched")
+            .and_then(|element| pitch(element, "display-step", "display-octave")),
+    }
+}
+
+fn articulation(note: &Element) -> f32 {
+    let marks = note
+        .children("notations")
+        .flat_map(|notations| notations.children("articulations"))
+        .flat_map(|articulations| articulations.elements())
+        .map(|mark| mark.name.as_str())
+        .collect::<Vec<&str>>();
+    if marks.contains(&"staccato") && marks.contains(&"tenuto") {
+        return 0.75;
+    }
+    ARTICULATIONS
+        .iter()
+        .filter(|(name, _)| marks.contains(name))
+        .map(|&(_, fraction)| fraction)
+        .fold(1.0, f32::min)
+}
+
+fn dynamics_velocity(dynamics: f32) -> u8 {
+    (dynamics * FORTE_VELOCITY / 100.0)
+        .round()
+        .max(1.0)
+        .min(127.0) as u8
+}
+
+fn read_sound(
+    sound: &Element,
+    state: &mut PartState,
+    measure: usize,
+    offset: u64,
+    tempos: &mut Vec<(usize, u64, f32)>,
+) {
+    if let Some(bpm) = sound
+        .attribute("tempo")
+        .and_then(|tempo| tempo.parse::<f32>().ok())
+        .filter(|&bpm| bpm > 0.0)
+    {
+        tempos.push((measure, offset, bpm));
+    }
+    if let Some(dynamics) = sound
+        .attribute("dynamics")
+        .and_then(|dynamics| dynamics.parse::<f32>().ok())
+    {
+        state.velocity = dynamics_velocity(dynamics);
+    }
+}
+
+// The measures in the order they're played, following repeats and endings.
+fn play_order(measures: &[Measure]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut repeat_start = 0;
+    let mut pass = 1;
+    let mut jumped = false;
+    let mut after_ending = false;
+    let mut m = 0;
+    while m < measures.len() {
+        let measure = &measures[m];
+        if measure.forward_repeat && !jumped {
+            repeat_start = m;
+            pass = 1;
+        }
+        jumped = false;
+        if measure.endings.is_empty() {
+            // the last ending is over, and a later repeat starts from here
+            if after_ending {
+                repeat_start = m;
+                pass = 1;
+                after_ending = false;
+            }
+        } else if measure.endings.contains(&pass) {
+            after_ending = true;
+        } else {
+            m += 1;
+            continue;
+        }
+        order.push(m);
+        if let Some(times) = measure.backward_repeat {
+            if pass < times {
+                pass += 1;
+                m = repeat_start;
+                jumped = true;
+                after_ending = false;
+                continue;
+            }
+            repeat_start = m + 1;
+            pass = 1;
+            after_ending = false;
+        }
+        m += 1;
+    }
+    order
+}
+
+// Splits the played measures into sections that are always played straight
+// through, returning the first and last measure of each and the order to
+// play them in.
+fn split(order: &[usize]) -> (Vec<(usize, usize)>, Vec<usize>) {
+    let mut starts = BTreeSet::new();
+    starts.extend(order.first());
+    for pair in order.windows(2) {
+        if pair[1] != pair[0] + 1 {
+            starts.insert(pair[0] + 1);
+            starts.insert(pair[1]);
+        }
+    }
+    let mut sections: Vec<(usize, usize)> = Vec::new();
+    let mut sequence = Vec::new();
+    for (i, &m) in order.iter().enumerate() {
+        if !starts.contains(&m) {
+            continue;
+        }
+        let section = match sections.iter().position(|&(first, _)| first == m) {
+            Some(section) => section,
+            None => {
+                let mut last = m;
+                for &next in &order[i + 1..] {
+                    if next != last + 1 || starts.contains(&next) {
+                        break;
+                    }
+                    last = next;
+                }
+                sections.push((m, last));
+                sections.len() - 1
+            }
+        };
+        sequence.push(section);
+    }
+    (sections, sequence)
+}
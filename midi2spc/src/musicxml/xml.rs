@@ -0,0 +1,303 @@
+use simple_error::SimpleError;
+use std::error::Error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let root = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "x.dtd">
+            <!-- comment -->
+            <score a='1' b="x &amp; y">
+              <empty/>
+              <text>A &lt;b&gt; &#67;&#x44;<![CDATA[<e>]]></text>
+            </score>"#,
+        )
+        .unwrap();
+        assert_eq!("score", root.name);
+        assert_eq!(Some("1"), root.attribute("a"));
+        assert_eq!(Some("x & y"), root.attribute("b"));
+        assert_eq!(2, root.elements().count());
+        assert!(root.child("empty").unwrap().children.is_empty());
+        assert_eq!(Some("A <b> CD<e>".to_string()), root.child_text("text"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+        assert_eq!("XML error on line 2: expected </b>", error("<a>\n<b></a>"));
+        assert_eq!("XML error on line 1: unexpected end of file", error("<a>"));
+        assert_eq!(
+            "XML error on line 1: unknown entity &nbsp;",
+            error("<a>&nbsp;</a>")
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|node| match node {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    pub fn child_text(&self, name: &str) -> Option<String> {
+        self.child(name).map(|element| element.text())
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+/// Parses an XML document into its root element.  Whitespace between
+/// elements is dropped, and DTDs are skipped rather than read.
+pub fn parse(text: &str) -> Result<Element, Box<Error>> {
+    let mut parser = Parser { text, pos: 0 };
+    parser.skip_misc()?;
+    let root = parser.element()?;
+    parser.skip_misc()?;
+    if parser.pos < text.len() {
+        return Err(parser.error("unexpected content after the root element"));
+    }
+    Ok(root)
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> Box<Error> {
+        let line = self.text[..self.pos].matches('\n').count() + 1;
+        Box::from(SimpleError::new(format!(
+            "XML error on line {}: {}",
+            line, message
+        )))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<(), Box<Error>> {
+        match self.rest().find(end) {
+            Some(idx) => {
+                self.pos += idx + end.len();
+                Ok(())
+            }
+            None => {
+                self.pos = self.text.len();
+                Err(self.error("unexpected end of file"))
+            }
+        }
+    }
+
+    // skips the declaration, processing instructions, comments and doctype
+    fn skip_misc(&mut self) -> Result<(), Box<Error>> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<!DOCTYPE") {
+                let mut depth = 0;
+                for (idx, c) in rest.char_indices() {
+                    match c {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        '>' if depth == 0 => {
+                            self.pos += idx + 1;
+                            break;
+                        }
+                        _ => (),
+                    }
+                }
+                if self.rest().starts_with("<!DOCTYPE") {
+                    return Err(self.error("unterminated doctype"));
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, Box<Error>> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>' || c == '=')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += len;
+        Ok(rest[..len].to_string())
+    }
+
+    fn element(&mut self) -> Result<Element, Box<Error>> {
+        if !self.rest().starts_with('<') {
+            return Err(self.error("expected an element"));
+        }
+        self.pos += 1;
+        let name = self.name()?;
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                });
+            } else if rest.starts_with('>') {
+                self.pos += 1;
+                break;
+            } else if rest.is_empty() {
+                return Err(self.error("unexpected end of file"));
+            }
+            let key = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(self.error(&format!("expected a value for attribute {}", key)));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => return Err(self.error("expected a quoted attribute value")),
+            };
+            self.pos += 1;
+            let len = match self.rest().find(quote) {
+                Some(len) => len,
+                None => return Err(self.error("unterminated attribute value")),
+            };
+            let value = self.decode(&self.rest()[..len])?;
+            self.pos += len + 1;
+            attributes.push((key, value));
+        }
+        let mut children = Vec::new();
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.pos += 2;
+                let end_name = self.name()?;
+                self.skip_whitespace();
+                if end_name != name || !self.rest().starts_with('>') {
+                    return Err(self.error(&format!("expected </{}>", name)));
+                }
+                self.pos += 1;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children,
+                });
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with("<![CDATA[") {
+                self.pos += 9;
+                let start = self.pos;
+                self.skip_past("]]>")?;
+                let text = &self.text[start..self.pos - 3];
+                children.push(Node::Text(text.to_string()));
+            } else if rest.starts_with('<') {
+                children.push(Node::Element(self.element()?));
+            } else if rest.is_empty() {
+                return Err(self.error("unexpected end of file"));
+            } else {
+                let len = rest.find('<').unwrap_or(rest.len());
+                let text = &rest[..len];
+                if !text.trim().is_empty() {
+                    children.push(Node::Text(self.decode(text)?));
+                }
+                self.pos += len;
+            }
+        }
+    }
+
+    fn decode(&self, text: &str) -> Result<String, Box<Error>> {
+        let mut decoded = String::new();
+        let mut rest = text;
+        while let Some(idx) = rest.find('&') {
+            decoded.push_str(&rest[..idx]);
+            let end = match rest[idx..].find(';') {
+                Some(end) => idx + end,
+                None => return Err(self.error("unterminated entity")),
+            };
+            let entity = &rest[idx + 1..end];
+            let c = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32),
+                _ if entity.starts_with('#') => entity[1..]
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(std::char::from_u32),
+                _ => None,
+            };
+            match c {
+                Some(c) => decoded.push(c),
+                None => return Err(self.error(&format!("unknown entity &{};", entity))),
+            }
+            rest = &rest[end + 1..];
+        }
+        decoded.push_str(rest);
+        Ok(decoded)
+    }
+}
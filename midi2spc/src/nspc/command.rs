@@ -3,6 +3,18 @@ use std::error::Error;
 use std::io::Cursor;
 use serde_derive::{Serialize, Deserialize};
 use super::CallLoopRef;
+use simple_error::SimpleError;
+
+const MAX_DURATION: u8 = 0x7f;
+const MAX_VELOCITY: u8 = 0x0f;
+const MAX_SUSTAIN: u8 = 0x07;
+// bytes from 0xc8 play as ties, rests and percussion, but anything outside this
+// range would be read as a duration or a command
+const MIN_NOTE: u8 = 0x80;
+const MAX_NOTE: u8 = 0xdf;
+// the low six bits of a pan index the driver's pan table; the rest invert channels
+const MAX_PAN: u8 = 0x14;
+const MAX_ECHO_DELAY: u8 = 0x0f;
 
 #[cfg(test)]
 mod tests {
@@ -88,6 +100,43 @@ mod tests {
         assert_eq!(7, first.sustain.unwrap());
         assert_eq!(25, first.duration.unwrap());
     }
+
+    #[test]
+    fn test_write_after_call_loop() {
+        let note = ParameterizedCommand::new(Some(12), Some(10), Some(7), Command::Note(0x80));
+        let call_loop =
+            ParameterizedCommand::new(Some(0), Some(0), Some(0), Command::CallLoop(1, 1));
+        let mut out = Cursor::new(Vec::new());
+        let mut state = WriteState::new();
+        let mut call_loops = Vec::new();
+        for cmd in &[&note, &note, &call_loop, &note] {
+            cmd.write(&mut out, &mut state, &mut call_loops).unwrap();
+        }
+        let data = out.into_inner();
+        assert_eq!(vec![0x0c, 0x7a, 0x80, 0x80], data[..4].to_vec());
+        assert_eq!(vec![0x0c, 0x7a, 0x80], data[data.len() - 3..].to_vec());
+    }
+
+    #[test]
+    fn test_velocity_only_after_call_loop() {
+        let note = ParameterizedCommand::new(Some(12), Some(10), Some(7), Command::Note(0x80));
+        let call_loop =
+            ParameterizedCommand::new(Some(0), Some(0), Some(0), Command::CallLoop(1, 1));
+        let velocity_only = ParameterizedCommand::new(None, Some(5), None, Command::Note(0x80));
+        let mut out = Cursor::new(Vec::new());
+        let mut state = WriteState::new();
+        let mut call_loops = Vec::new();
+        for cmd in &[&note, &call_loop] {
+            cmd.write(&mut out, &mut state, &mut call_loops).unwrap();
+        }
+        let len = out.get_ref().len();
+        let err = velocity_only
+            .write(&mut out, &mut state, &mut call_loops)
+            .err()
+            .unwrap();
+        assert_eq!("A velocity or sustain right after a CallLoop needs a length", err.to_string());
+        assert_eq!(len, out.into_inner().len());
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -109,7 +158,7 @@ pub enum Command {
     Tremolo(u8, u8, u8),
     TremoloOff,
     ChannelVolume(u8),
-    ChannelVolumeFade(u8),
+    ChannelVolumeFade(u8, u8),
     CallLoop(usize, u8),
     VibratoFade(u8),
     PitchEnvelopeTo(u8, u8, u8),
@@ -201,9 +250,10 @@ impl Command {
                 out.write_u8(0xed)?;
                 out.write_u8(p1)?;
             }
-            Command::ChannelVolumeFade(p1) => {
+            Command::ChannelVolumeFade(p1, p2) => {
                 out.write_u8(0xee)?;
                 out.write_u8(p1)?;
+                out.write_u8(p2)?;
             }
             Command::CallLoop(p1, p2) => {
                 out.write_u8(0xef)?;
@@ -272,6 +322,106 @@ impl Command {
         };
         Ok(())
     }
+
+    /// Reads the command at pos in a track's data, returning it with the
+    /// number of bytes it takes.  A CallLoop's target is the track whose
+    /// reference is at the CallLoop's address.
+    pub fn read(
+        data: &[u8],
+        pos: usize,
+        call_loops: &[CallLoopRef],
+    ) -> Result<(Command, usize), Box<Error>> {
+        let param = |i: usize| match data.get(pos + i) {
+            Some(&byte) => Ok(byte),
+            None => Err(Box::<Error>::from(SimpleError::new(format!(
+                "command 0x{:X} at offset 0x{:X} runs past the end of the track",
+                data[pos], pos
+            )))),
+        };
+        let command = match data[pos] {
+            note @ MIN_NOTE..=MAX_NOTE => match note {
+                0xc8 => Command::Tie,
+                0xc9 => Command::Rest,
+                _ => Command::Note(note),
+            },
+            0xe0 => Command::SetInstrument(param(1)?),
+            0xe1 => Command::Pan(param(1)?),
+            0xe2 => Command::PanFade(param(1)?, param(2)?),
+            0xe3 => Command::Vibrato(param(1)?, param(2)?, param(3)?),
+            0xe4 => Command::VibratoOff,
+            0xe5 => Command::MasterVolume(param(1)?),
+            0xe6 => Command::MasterVolumeFade(param(1)?, param(2)?),
+            0xe7 => Command::Tempo(param(1)?),
+            0xe8 => Command::TempoFade(param(1)?, param(2)?),
+            0xe9 => Command::GlobalTranspose(param(1)?),
+            0xea => Command::ChannelTranspose(param(1)?),
+            0xeb => Command::Tremolo(param(1)?, param(2)?, param(3)?),
+            0xec => Command::TremoloOff,
+            0xed => Command::ChannelVolume(param(1)?),
+            0xee => Command::ChannelVolumeFade(param(1)?, param(2)?),
+            0xef => match call_loops
+                .iter()
+                .find(|call_loop| call_loop.ref_pos == (pos + 1) as u64)
+            {
+                Some(call_loop) => Command::CallLoop(call_loop.target_track, param(3)?),
+                None => {
+                    return Err(Box::from(SimpleError::new(format!(
+                        "CallLoop at offset 0x{:X} doesn't call a known track",
+                        pos
+                    ))));
+                }
+            },
+            0xf0 => Command::VibratoFade(param(1)?),
+            0xf1 => Command::PitchEnvelopeTo(param(1)?, param(2)?, param(3)?),
+            0xf2 => Command::PitchEnvelopeFrom(param(1)?, param(2)?, param(3)?),
+            0xf3 => Command::PitchEnvelopeOff,
+            0xf4 => Command::Tuning(param(1)?),
+            0xf5 => Command::EchoVolume(param(1)?, param(2)?, param(3)?),
+            0xf6 => Command::EchoOff,
+            0xf7 => Command::EchoParams(param(1)?, param(2)?, param(3)?),
+            0xf8 => Command::EchoVolumeFade(param(1)?, param(2)?, param(3)?),
+            0xf9 => Command::PitchSlide(param(1)?, param(2)?, param(3)?),
+            0xfa => Command::PercussionPatchBase(param(1)?),
+            byte => {
+                return Err(Box::from(SimpleError::new(format!(
+                    "unknown command 0x{:X} at offset 0x{:X}",
+                    byte, pos
+                ))));
+            }
+        };
+        let mut out = Cursor::new(Vec::new());
+        command.write(&mut out, &mut vec![])?;
+        Ok((command, out.into_inner().len()))
+    }
+}
+
+/// What the driver has in effect on a channel while a track is written, so
+/// that lengths and velocities which haven't changed can be left out.
+#[derive(Copy, Clone, Debug)]
+pub struct WriteState {
+    duration: u8,
+    velocity_sustain: Option<u8>,
+    velocity_sustain_in_effect: bool,
+}
+
+impl WriteState {
+    pub fn new() -> WriteState {
+        WriteState {
+            duration: 0xff,
+            velocity_sustain: None,
+            velocity_sustain_in_effect: false,
+        }
+    }
+
+    /// The driver keeps whatever length and velocity a subroutine left when a
+    /// CallLoop returns, so the next note has to give its own again.
+    pub fn after_call_loop(self) -> WriteState {
+        WriteState {
+            duration: 0,
+            velocity_sustain_in_effect: false,
+            ..self
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -300,16 +450,14 @@ impl ParameterizedCommand {
     pub fn write(
         &self,
         out: &mut Cursor<Vec<u8>>,
-        prev_duration: u8,
-        prev_velocity_sustain: Option<u8>,
+        state: &mut WriteState,
         call_loops: &mut Vec<CallLoopRef>,
-    ) -> Result<(u8, Option<u8>), Box<Error>> {
-        let mut duration_out = prev_duration;
-        let mut velocity_sustain_out = prev_velocity_sustain;
+    ) -> Result<(), Box<Error>> {
+        let prev_duration = state.duration;
         let mut duration_to_write = None;
         if let Some(duration) = self.duration {
             if duration != prev_duration {
-                duration_out = duration;
+                state.duration = duration;
                 if duration > 0 {
                     duration_to_write = Some(duration);
                 }
@@ -317,7 +465,7 @@ impl ParameterizedCommand {
         }
         let mut velocity_sustain = None;
         if self.velocity.is_some() || self.sustain.is_some() {
-            let mut velocity_sustain_value = prev_velocity_sustain.unwrap_or(0x7d);
+            let mut velocity_sustain_value = state.velocity_sustain.unwrap_or(0x7d);
             let mut nonzero = false;
             if let Some(velocity) = self.velocity {
                 if velocity > 0 {
@@ -337,27 +485,76 @@ impl ParameterizedCommand {
         }
         let mut velocity_sustain_to_write = None;
         if let Some(velocity_sustain) = velocity_sustain {
-            if prev_velocity_sustain.is_none() || prev_velocity_sustain.unwrap() != velocity_sustain
+            if !state.velocity_sustain_in_effect
+                || state.velocity_sustain != Some(velocity_sustain)
             {
-                velocity_sustain_out = Some(velocity_sustain);
                 velocity_sustain_to_write = Some(velocity_sustain);
             }
-        } else if prev_velocity_sustain.is_none() {
+        } else if state.velocity_sustain.is_none() {
             if let Command::Note(..) = self.command {
-                velocity_sustain_out = Some(0x7d);
                 velocity_sustain_to_write = Some(0x7d);
             }
         }
         if let Some(duration) = duration_to_write {
             out.write_u8(duration)?;
         } else if velocity_sustain_to_write.is_some() {
+            // after a CallLoop the length in effect is the subroutine's, which
+            // isn't known here, and a length of 0 would end the track
+            if prev_duration == 0 {
+                return Err(Box::from(SimpleError::new(
+                    "A velocity or sustain right after a CallLoop needs a length",
+                )));
+            }
             out.write_u8(prev_duration)?;
         }
         if let Some(velocity_sustain) = velocity_sustain_to_write {
             out.write_u8(velocity_sustain)?;
+            state.velocity_sustain = Some(velocity_sustain);
+            state.velocity_sustain_in_effect = true;
         }
         self.command.write(out, call_loops)?;
-        Ok((duration_out, velocity_sustain_out))
+        if let Command::CallLoop(..) = self.command {
+            *state = state.after_call_loop();
+        }
+        Ok(())
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    pub fn duration(&self) -> Option<u8> {
+        self.duration
+    }
+
+    pub fn velocity(&self) -> Option<u8> {
+        self.velocity
+    }
+
+    pub fn sustain(&self) -> Option<u8> {
+        self.sustain
+    }
+
+    /// Describes the first parameter that the driver can't play, if any.
+    pub fn range_error(&self) -> Option<String> {
+        let over = |name: &str, value: Option<u8>, max: u8| {
+            value
+                .filter(|&value| value > max)
+                .map(|value| format!("{} {} is over {}", name, value, max))
+        };
+        over("duration", self.duration, MAX_DURATION)
+            .or_else(|| over("velocity", self.velocity, MAX_VELOCITY))
+            .or_else(|| over("sustain", self.sustain, MAX_SUSTAIN))
+            .or_else(|| match self.command {
+                Command::Note(note) if note < MIN_NOTE || note > MAX_NOTE => Some(format!(
+                    "note {:#x} isn't between {:#x} and {:#x}",
+                    note, MIN_NOTE, MAX_NOTE
+                )),
+                Command::Pan(pan) => over("pan", Some(pan & 0x3f), MAX_PAN),
+                Command::PanFade(_, pan) => over("pan", Some(pan), MAX_PAN),
+                Command::EchoParams(delay, _, _) => over("echo delay", Some(delay), MAX_ECHO_DELAY),
+                _ => None,
+            })
     }
 
     pub fn call_loop_eligible(&self) -> bool {
@@ -368,6 +565,32 @@ impl ParameterizedCommand {
         }
     }
 
+    /// Splits a note, tie or rest into its first `length` ticks and a tie or
+    /// rest for the remainder, unless the split would change where a note
+    /// cut short by its sustain stops.
+    pub fn split(&self, length: u8) -> Option<(ParameterizedCommand, ParameterizedCommand)> {
+        let duration = self.duration?;
+        if length == 0 || length >= duration {
+            return None;
+        }
+        let remainder = match self.command {
+            Command::Note(..) | Command::Tie
+                if self.sustain.map_or(true, |sustain| sustain == MAX_SUSTAIN) =>
+            {
+                Command::Tie
+            }
+            Command::Rest => Command::Rest,
+            _ => return None,
+        };
+        Some((
+            ParameterizedCommand {
+                duration: Some(length),
+                ..self.clone()
+            },
+            ParameterizedCommand::new(Some(duration - length), None, None, remainder),
+        ))
+    }
+
     pub fn set_sustain(&mut self, next_command: &ParameterizedCommand) -> bool {
         if let Command::Note(..) = self.command {
             if let Command::Rest = next_command.command {
@@ -401,9 +624,9 @@ impl ParameterizedCommand {
         }
     }
 
-    pub fn is_slide(&self) -> bool {
+    pub fn is_volume(&self) -> bool {
         match self.command {
-            Command::PitchSlide(..) => true,
+            Command::ChannelVolume(..) => true,
             _ => false,
         }
     }
@@ -421,4 +644,11 @@ impl ParameterizedCommand {
             _ => true,
         }
     }
+
+    pub fn echo_delay(&self) -> Option<u8> {
+        match self.command {
+            Command::EchoParams(delay, _, _) => Some(delay),
+            _ => None,
+        }
+    }
 }
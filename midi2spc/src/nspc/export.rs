@@ -0,0 +1,245 @@
+use super::{set_word, word, CallLoopRef, Song, PART_TABLE_SIZE};
+use serde_derive::Serialize;
+use simple_error::SimpleError;
+use std::error::Error;
+use std::io::Cursor;
+
+const ASM_LINE_LEN: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "part track0
+
+track track0
+    c4 len=12
+    call_loop sub1 2
+
+track sub1
+    d4 len=6
+";
+
+    #[test]
+    fn test_relocatable() {
+        let song = RelocatableSong::new(&Song::from_text(SONG).unwrap(), true).unwrap();
+        let labels = song
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["header", "part0", "track0", "subroutine1"], labels);
+        let subroutine = song.labels[3].offset;
+        // part pointer, loop target, track pointer and CallLoop operand
+        assert_eq!(4, song.relocations.len());
+        let data = song.at(0x2000).unwrap();
+        assert_eq!(0x2008, word(&data, 0));
+        assert_eq!((0x00ff, 0x2000, 0), (word(&data, 2), word(&data, 4), word(&data, 6)));
+        assert_eq!(0x2018, word(&data, 8));
+        assert_eq!(0x2000 + subroutine, word(&data, song.relocations[3]));
+        assert_eq!(0xef, data[song.relocations[3] - 1]);
+        assert!(song.at(0x10000 - subroutine).is_err());
+    }
+
+    #[test]
+    fn test_empty_subroutine() {
+        let song = Song::from_text(
+            "part track0
+
+track track0
+    call_loop sub1 2
+
+track sub1
+",
+        )
+        .unwrap();
+        let err = RelocatableSong::new(&song, false).err().unwrap();
+        assert_eq!("Track 0 calls subroutine 1, which is empty or missing", err.to_string());
+    }
+
+    #[test]
+    fn test_asm() {
+        let song = RelocatableSong::new(&Song::from_text(SONG).unwrap(), false).unwrap();
+        let asm = song.asm("theme");
+        assert!(asm.starts_with(
+            "
+        .module theme
+
+theme_header:
+        .dw theme_part0
+        .db $00, $00
+theme_part0:
+        .dw theme_track0
+        .db $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00, $00
+theme_track0:
+"
+        ));
+        assert!(asm.contains("$A4, $EF\n        .dw theme_subroutine1\n        .db $02, $00\n"));
+        assert!(asm.ends_with("theme_subroutine1:\n        .db $06, $7D, $A6, $00\n"));
+    }
+}
+
+/// A named place in a relocatable song, as an offset from its start.
+#[derive(Debug, Serialize)]
+pub struct Label {
+    pub name: String,
+    pub offset: usize,
+}
+
+/// A song laid out as one block starting at offset 0, with every pointer in
+/// it listed so the block can be placed at any ARAM address.  Each
+/// relocation is the offset of a little-endian pointer that holds an offset
+/// into the block, to which the block's address must be added.
+#[derive(Debug, Serialize)]
+pub struct RelocatableSong {
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    pub size: usize,
+    pub labels: Vec<Label>,
+    pub relocations: Vec<usize>,
+}
+
+impl RelocatableSong {
+    pub fn new(song: &Song, loops: bool) -> Result<RelocatableSong, Box<Error>> {
+        let header_size = song.header_size(loops);
+        let num_part_tables = song.get_num_part_tables();
+        let mut labels = vec![Label {
+            name: "header".to_string(),
+            offset: 0,
+        }];
+        for part_table in 0..num_part_tables {
+            labels.push(Label {
+                name: format!("part{}", part_table),
+                offset: header_size + part_table * PART_TABLE_SIZE,
+            });
+        }
+
+        // tracks follow the part tables, with empty ones left at offset 0
+        let mut tracks = Vec::<u8>::new();
+        let mut track_offsets = Vec::<usize>::new();
+        let mut call_loops = Vec::<(usize, usize, usize)>::new();
+        for i in 0..song.get_num_tracks() {
+            let mut track_call_loops = Vec::<CallLoopRef>::new();
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            song.write_track(&mut cursor, i, &mut track_call_loops)?;
+            let track_data = cursor.into_inner();
+            if track_data.is_empty() {
+                track_offsets.push(0);
+                continue;
+            }
+            let offset = header_size + num_part_tables * PART_TABLE_SIZE + tracks.len();
+            labels.push(Label {
+                name: if song.is_subroutine(i) {
+                    format!("subroutine{}", i)
+                } else {
+                    format!("track{}", i)
+                },
+                offset,
+            });
+            for call_loop in &track_call_loops {
+                call_loops.push((i, offset + call_loop.ref_pos as usize, call_loop.target_track));
+            }
+            track_offsets.push(offset);
+            tracks.extend_from_slice(&track_data);
+        }
+
+        // the header and part tables are laid out as for a song at offset 0,
+        // so every nonzero pointer in them needs relocating
+        let mut data = song.write_header(0, loops);
+        let mut relocations = (0..song.get_num_parts())
+            .map(|i| i * 2)
+            .collect::<Vec<usize>>();
+        if loops {
+            relocations.push(song.get_num_parts() * 2 + 2);
+        }
+        let part_tables = song.write_part_tables(&track_offsets);
+        for pos in (0..part_tables.len()).step_by(2) {
+            if word(&part_tables, pos) != 0 {
+                relocations.push(header_size + pos);
+            }
+        }
+        data.extend(part_tables);
+        data.extend(tracks);
+        for &(track_idx, pos, target_track) in &call_loops {
+            let target = match track_offsets.get(target_track) {
+                Some(&offset) if offset != 0 => offset,
+                _ => {
+                    return Err(Box::from(SimpleError::new(format!(
+                        "Track {} calls subroutine {}, which is empty or missing",
+                        track_idx, target_track
+                    ))));
+                }
+            };
+            set_word(&mut data, pos, target);
+            relocations.push(pos);
+        }
+        relocations.sort();
+
+        Ok(RelocatableSong {
+            size: data.len(),
+            data,
+            labels,
+            relocations,
+        })
+    }
+
+    /// The song's data with its pointers set for it to be loaded at
+    /// `aram_addr`.
+    pub fn at(&self, aram_addr: usize) -> Result<Vec<u8>, Box<Error>> {
+        if aram_addr + self.data.len() > 0x10000 {
+            return Err(Box::from(SimpleError::new(format!(
+                "Song of 0x{:X} bytes at 0x{:X} extends past the end of ARAM",
+                self.data.len(),
+                aram_addr
+            ))));
+        }
+        let mut data = self.data.clone();
+        for &pos in &self.relocations {
+            let offset = word(&data, pos);
+            set_word(&mut data, pos, aram_addr + offset);
+        }
+        Ok(data)
+    }
+
+    /// The song as an ASM module, with a label prefixed by `prefix` for the
+    /// header, each part table, track and subroutine, and its pointers
+    /// written as those labels so the assembler can place it anywhere.
+    pub fn asm(&self, prefix: &str) -> String {
+        let name = |offset: usize| match self.labels.iter().find(|label| label.offset == offset) {
+            Some(label) => format!("{}_{}", prefix, label.name),
+            None => format!("{}_header+${:04X}", prefix, offset),
+        };
+        let mut out = format!("\n        .module {}\n\n", prefix);
+        let mut bytes = Vec::<u8>::new();
+        let flush = |out: &mut String, bytes: &mut Vec<u8>| {
+            if !bytes.is_empty() {
+                let line = bytes
+                    .iter()
+                    .map(|byte| format!("${:02X}", byte))
+                    .collect::<Vec<String>>();
+                out.push_str(&format!("        .db {}\n", line.join(", ")));
+                bytes.clear();
+            }
+        };
+        let mut pos = 0;
+        while pos < self.data.len() {
+            if let Some(label) = self.labels.iter().find(|label| label.offset == pos) {
+                flush(&mut out, &mut bytes);
+                out.push_str(&format!("{}_{}:\n", prefix, label.name));
+            }
+            if self.relocations.contains(&pos) {
+                flush(&mut out, &mut bytes);
+                out.push_str(&format!("        .dw {}\n", name(word(&self.data, pos))));
+                pos += 2;
+                continue;
+            }
+            bytes.push(self.data[pos]);
+            if bytes.len() == ASM_LINE_LEN {
+                flush(&mut out, &mut bytes);
+            }
+            pos += 1;
+        }
+        flush(&mut out, &mut bytes);
+        out
+    }
+}
@@ -0,0 +1,40 @@
+use super::command::{Command, ParameterizedCommand};
+use super::track::Track;
+use super::{Part, Song, DEFAULT_MASTER_VOLUME};
+
+// notes get a length, as a note right after a CallLoop has to give its own
+pub fn track(commands: &[Command]) -> Track {
+    Track {
+        commands: commands
+            .iter()
+            .map(|cmd| {
+                let duration = match cmd {
+                    Command::Note(_) => Some(1),
+                    _ => None,
+                };
+                ParameterizedCommand::new(duration, None, None, cmd.clone())
+            })
+            .collect(),
+    }
+}
+
+// a song playing one track
+pub fn song(commands: &[Command]) -> Song {
+    Song {
+        parts: vec![Part { tracks: vec![0] }],
+        tracks: vec![track(commands)],
+        master_volume: DEFAULT_MASTER_VOLUME,
+    }
+}
+
+pub fn motif() -> Vec<Command> {
+    vec![
+        Command::SetInstrument(1),
+        Command::Note(1),
+        Command::ChannelVolume(2),
+        Command::Note(2),
+        Command::Pan(3),
+        Command::Note(3),
+        Command::Vibrato(1, 2, 3),
+    ]
+}
@@ -0,0 +1,671 @@
+use super::command::*;
+use super::track::Track;
+use simple_error::SimpleError;
+use std::collections::HashMap;
+use std::error::Error;
+
+// durations are in N-SPC ticks, 24 to a beat
+const TICKS_PER_WHOLE_NOTE: u32 = 96;
+const MAX_DURATION: u32 = 0x7f;
+const MAX_CHANNELS: usize = 8;
+const MAX_LOOP_COUNT: u32 = 0xff;
+const DEFAULT_OCTAVE: i32 = 4;
+const DEFAULT_SUSTAIN: u8 = 7;
+// MIDI note numbers of the lowest and highest N-SPC notes, 0x80 and 0xc7
+const MIN_NOTE: i32 = 24;
+const MAX_NOTE: i32 = 95;
+const NOTE_OFFSET: i32 = 0x68;
+const PITCHES: [i32; 7] = [9, 11, 0, 2, 4, 5, 7];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn note(duration: u8, sustain: u8, note: u8) -> ParameterizedCommand {
+        ParameterizedCommand::new(Some(duration), None, Some(sustain), Command::Note(note))
+    }
+
+    fn other(duration: u8, command: Command) -> ParameterizedCommand {
+        ParameterizedCommand::new(Some(duration), None, None, command)
+    }
+
+    fn call(track: usize, count: u8) -> ParameterizedCommand {
+        ParameterizedCommand::new(Some(0), Some(0), Some(0), Command::CallLoop(track, count))
+    }
+
+    #[test]
+    fn test_notes_and_lengths() {
+        let (tracks, top_level) = parse("#0 o4 l8 c d+4. e-=12 r ^ > c ; comment c", 0.2).unwrap();
+        assert_eq!(1, top_level);
+        assert_eq!(
+            vec![
+                note(12, 7, 0xa4),
+                note(36, 7, 0xa7),
+                note(12, 7, 0xa7),
+                other(12, Command::Rest),
+                other(12, Command::Tie),
+                note(12, 7, 0xb0),
+            ],
+            tracks[0].commands
+        );
+    }
+
+    #[test]
+    fn test_long_notes_are_tied() {
+        let (tracks, _) = parse("#0 c1. r1.", 0.2).unwrap();
+        assert_eq!(
+            vec![
+                note(0x7f, 7, 0xa4),
+                other(17, Command::Tie),
+                other(0x7f, Command::Rest),
+                other(17, Command::Rest),
+            ],
+            tracks[0].commands
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let (tracks, _) = parse(
+            "#0 t120 @24 v200 y10 p8,20,40 p0 E15,40,40 D2,60,0 E q3 c",
+            0.2,
+        )
+        .unwrap();
+        let commands = tracks[0]
+            .commands
+            .iter()
+            .map(|cmd| cmd.command().clone())
+            .collect::<Vec<Command>>();
+        assert_eq!(
+            vec![
+                Command::Tempo(24),
+                Command::SetInstrument(24),
+                Command::ChannelVolume(200),
+                Command::Pan(10),
+                Command::Vibrato(8, 20, 40),
+                Command::VibratoOff,
+                Command::EchoVolume(15, 40, 40),
+                Command::EchoParams(2, 60, 0),
+                Command::EchoOff,
+                Command::Note(0xa4),
+            ],
+            commands
+        );
+        assert_eq!(Some(3), tracks[0].commands[9].sustain());
+    }
+
+    #[test]
+    fn test_loops_share_subroutines() {
+        let (tracks, top_level) = parse("#1 [c d]2 #0 [c d]3 [c d]", 0.2).unwrap();
+        assert_eq!(2, top_level);
+        assert_eq!(vec![call(2, 3), call(2, 2)], tracks[0].commands);
+        assert_eq!(vec![call(2, 2)], tracks[1].commands);
+        assert_eq!(
+            vec![note(24, 7, 0xa4), note(24, 7, 0xa6)],
+            tracks[2].commands
+        );
+    }
+
+    #[test]
+    fn test_definitions() {
+        let (tracks, _) = parse("#0 o5 !riff [!riff]2\n!riff = { c [d8]2 }", 0.2).unwrap();
+        assert_eq!(vec![call(1, 1), call(1, 2)], tracks[0].commands);
+        assert_eq!(
+            vec![note(24, 7, 0xb0), note(12, 7, 0xb2), note(12, 7, 0xb2)],
+            tracks[1].commands
+        );
+    }
+
+    #[test]
+    fn test_sustain_after_call() {
+        let (tracks, _) = parse("#0 q7 c [q3 d]2 q7 e", 0.2).unwrap();
+        assert_eq!(vec![note(24, 7, 0xa4), call(1, 2), note(24, 7, 0xa8)], tracks[0].commands);
+        // the subroutine leaves its own sustain in effect, so e gives its again
+        let mut out = Cursor::new(Vec::new());
+        let mut state = WriteState::new();
+        for cmd in &tracks[0].commands {
+            cmd.write(&mut out, &mut state, &mut Vec::new()).unwrap();
+        }
+        let data = out.into_inner();
+        assert_eq!(vec![0x18, 0x7d, 0xa8], data[data.len() - 3..].to_vec());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text, 0.2).unwrap_err().to_string();
+        assert_eq!("line 2, column 3: unexpected 'h'", error("#0 c\n  h"));
+        assert_eq!(
+            "line 1, column 1: expected a channel (#0 to #7) before commands",
+            error("c")
+        );
+        assert_eq!("line 1, column 2: channel 8 is out of range", error("#8 c"));
+        assert_eq!("line 1, column 4: unknown subroutine !x", error("#0 !x"));
+        assert_eq!(
+            "line 1, column 8: subroutine !x calls itself",
+            error("!x = { !x } #0 !x")
+        );
+        assert_eq!("line 1, column 7: note is out of range", error("#0 o0 c"));
+        assert_eq!(
+            "line 1, column 5: length 5 doesn't divide a whole note evenly",
+            error("#0 c5")
+        );
+        assert_eq!("line 1, column 4: unterminated loop", error("#0 [c"));
+        assert_eq!("no channel has any commands", error("#0 #1"));
+    }
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    octave: i32,
+    length: u32,
+    sustain: u8,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            octave: DEFAULT_OCTAVE,
+            length: TICKS_PER_WHOLE_NOTE / 4,
+            sustain: DEFAULT_SUSTAIN,
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    tempo_factor: f32,
+    state: State,
+    // positions of the opening brace of each definition
+    definitions: HashMap<String, usize>,
+    calling: Vec<String>,
+    subroutines: Vec<Track>,
+}
+
+/// Compiles MML into one track per channel, in channel order, followed by
+/// the subroutine tracks they call.  Returns the tracks and the number of
+/// channel tracks.
+///
+/// `#0` to `#7` start a channel.  Within a channel:
+///
+/// * `c` to `b` play a note, with `+` or `-` for sharp or flat, `r` rests and
+///   `^` extends the previous note.  Each can be followed by a length (`4`
+///   for a quarter note, `=18` for 18 ticks, with any number of dots) or uses
+///   the default length set by `l`.
+/// * `o` sets the octave, `<` and `>` move down or up one octave.
+/// * `q` sets the sustain (1 to 7) of the following notes.
+/// * `t` sets the tempo in BPM, `@` the instrument, `v` the volume and `y`
+///   the pan.
+/// * `p` sets vibrato delay, rate and depth, or turns it off with `p0`.
+/// * `E` sets echo channels, left and right volume, or turns echo off alone,
+///   and `D` sets echo delay, feedback and filter.
+/// * `[...]3` plays the enclosed commands three times (twice by default).
+/// * `!name` plays a subroutine defined by `!name = { ... }` outside of a
+///   channel.
+///
+/// `;` starts a comment running to the end of the line.
+pub fn parse(text: &str, tempo_factor: f32) -> Result<(Vec<Track>, usize), Box<Error>> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        tempo_factor,
+        state: State::default(),
+        definitions: HashMap::new(),
+        calling: Vec::new(),
+        subroutines: Vec::new(),
+    };
+    parser.read_definitions()?;
+    let channels = parser.read_channels()?;
+    if channels.iter().all(|(_, commands)| commands.is_empty()) {
+        return Err(Box::from(SimpleError::new("no channel has any commands")));
+    }
+    Ok(link(channels, parser.subroutines))
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, pos: usize, message: &str) -> Box<Error> {
+        let before = &self.text[..pos];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let column = pos
+            - before
+                .iter()
+                .rposition(|&c| c == b'\n')
+                .map_or(0, |i| i + 1)
+            + 1;
+        Box::from(SimpleError::new(format!(
+            "line {}, column {}: {}",
+            line, column, message
+        )))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    // skips whitespace and comments, returning false at the end of the text
+    fn skip_space(&mut self) -> bool {
+        while let Some(c) = self.peek() {
+            if c == b';' {
+                while self.peek().map_or(false, |c| c != b'\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn read_definitions(&mut self) -> Result<(), Box<Error>> {
+        while self.skip_space() {
+            let start = self.pos;
+            match self.definition() {
+                Some(name) => {
+                    if self.definitions.contains_key(&name) {
+                        return Err(
+                            self.error(start, &format!("subroutine !{} is defined twice", name))
+                        );
+                    }
+                    self.definitions.insert(name, self.pos);
+                    self.skip_definition(start)?;
+                }
+                None => self.pos += 1,
+            }
+        }
+        self.pos = 0;
+        Ok(())
+    }
+
+    // reads "!name = {" if it's next, leaving the position at the brace
+    fn definition(&mut self) -> Option<String> {
+        let start = self.pos;
+        if self.peek() == Some(b'!') {
+            self.pos += 1;
+            let name = self.name();
+            self.skip_space();
+            if !name.is_empty() && self.peek() == Some(b'=') {
+                self.pos += 1;
+                self.skip_space();
+                if self.peek() == Some(b'{') {
+                    return Some(name);
+                }
+            }
+        }
+        self.pos = start;
+        None
+    }
+
+    fn skip_definition(&mut self, start: usize) -> Result<(), Box<Error>> {
+        while self.skip_space() {
+            self.pos += 1;
+            if self.text[self.pos - 1] == b'}' {
+                return Ok(());
+            }
+        }
+        Err(self.error(start, "unterminated subroutine definition"))
+    }
+
+    fn read_channels(&mut self) -> Result<Vec<(usize, Vec<ParameterizedCommand>)>, Box<Error>> {
+        let mut channels: Vec<(usize, Vec<ParameterizedCommand>)> = Vec::new();
+        while self.skip_space() {
+            let start = self.pos;
+            if self.peek() == Some(b'#') {
+                self.pos += 1;
+                let channel = self.number()? as usize;
+                if channel >= MAX_CHANNELS {
+                    return Err(
+                        self.error(start + 1, &format!("channel {} is out of range", channel))
+                    );
+                }
+                if channels.iter().any(|&(ch, _)| ch == channel) {
+                    return Err(self.error(start, &format!("channel {} is defined twice", channel)));
+                }
+                channels.push((channel, Vec::new()));
+                self.state = State::default();
+            } else if self.definition().is_some() {
+                self.skip_definition(start)?;
+            } else {
+                match channels.last_mut() {
+                    Some((_, commands)) => self.command(commands, false)?,
+                    None => {
+                        return Err(
+                            self.error(start, "expected a channel (#0 to #7) before commands")
+                        )
+                    }
+                }
+            }
+        }
+        channels.sort_by_key(|&(channel, _)| channel);
+        Ok(channels)
+    }
+
+    // Loops and subroutine calls become CallLoops, except within a loop body
+    // or subroutine, where they're expanded since N-SPC calls can't nest.
+    fn command(
+        &mut self,
+        out: &mut Vec<ParameterizedCommand>,
+        in_subroutine: bool,
+    ) -> Result<(), Box<Error>> {
+        let start = self.pos;
+        let c = self.text[self.pos];
+        self.pos += 1;
+        match c {
+            b'a'..=b'g' => {
+                let mut note = PITCHES[(c - b'a') as usize] + 12 * (self.state.octave + 1);
+                loop {
+                    match self.peek() {
+                        Some(b'+') => note += 1,
+                        Some(b'-') => note -= 1,
+                        _ => break,
+                    }
+                    self.pos += 1;
+                }
+                if note < MIN_NOTE || note > MAX_NOTE {
+                    return Err(self.error(start, "note is out of range"));
+                }
+                let ticks = self.length()?;
+                let sustain = self.state.sustain;
+                push_duration(
+                    out,
+                    ticks,
+                    Some(sustain),
+                    Command::Note((note + NOTE_OFFSET) as u8),
+                );
+            }
+            b'r' => {
+                let ticks = self.length()?;
+                push_duration(out, ticks, None, Command::Rest);
+            }
+            b'^' => {
+                let ticks = self.length()?;
+                push_duration(out, ticks, None, Command::Tie);
+            }
+            b'o' => self.state.octave = self.number()? as i32,
+            b'<' => self.state.octave -= 1,
+            b'>' => self.state.octave += 1,
+            b'l' => {
+                if !self
+                    .peek()
+                    .map_or(false, |c| c.is_ascii_digit() || c == b'=')
+                {
+                    return Err(self.error(self.pos, "expected a length"));
+                }
+                self.state.length = self.length()?;
+            }
+            b'q' => {
+                let sustain = self.number()?;
+                if sustain < 1 || sustain > 7 {
+                    return Err(self.error(start + 1, "sustain should be from 1 to 7"));
+                }
+                self.state.sustain = sustain as u8;
+            }
+            b't' => {
+                let tempo = self.number()? as f32 * self.tempo_factor;
+                if tempo > 255.0 {
+                    return Err(self.error(start + 1, "tempo is out of range"));
+                }
+                push(out, Command::Tempo(tempo as u8));
+            }
+            b'@' => {
+                let instrument = self.byte()?;
+                push(out, Command::SetInstrument(instrument));
+            }
+            b'v' => {
+                let volume = self.byte()?;
+                push(out, Command::ChannelVolume(volume));
+            }
+            b'y' => {
+                let pan = self.byte()?;
+                push(out, Command::Pan(pan));
+            }
+            b'p' => {
+                let delay = self.byte()?;
+                if self.peek() == Some(b',') {
+                    let (rate, depth) = (self.next_byte()?, self.next_byte()?);
+                    push(out, Command::Vibrato(delay, rate, depth));
+                } else if delay == 0 {
+                    push(out, Command::VibratoOff);
+                } else {
+                    return Err(self.error(self.pos, "expected vibrato rate and depth"));
+                }
+            }
+            b'E' => {
+                if self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                    let channels = self.byte()?;
+                    let (left, right) = (self.next_byte()?, self.next_byte()?);
+                    push(out, Command::EchoVolume(channels, left, right));
+                } else {
+                    push(out, Command::EchoOff);
+                }
+            }
+            b'D' => {
+                let delay = self.byte()?;
+                let (feedback, filter) = (self.next_byte()?, self.next_byte()?);
+                push(out, Command::EchoParams(delay, feedback, filter));
+            }
+            b'[' => {
+                let mut body = Vec::new();
+                loop {
+                    if !self.skip_space() {
+                        return Err(self.error(start, "unterminated loop"));
+                    }
+                    if self.peek() == Some(b']') {
+                        self.pos += 1;
+                        break;
+                    }
+                    self.command(&mut body, true)?;
+                }
+                let count = if self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                    self.number()?
+                } else {
+                    2
+                };
+                if count < 1 || count > MAX_LOOP_COUNT {
+                    return Err(self.error(start, "loop count should be from 1 to 255"));
+                }
+                self.call(out, body, count as u8, in_subroutine);
+            }
+            b'!' => {
+                let name = self.name();
+                let body_start = match self.definitions.get(&name) {
+                    Some(&body_start) => body_start,
+                    None => return Err(self.error(start, &format!("unknown subroutine !{}", name))),
+                };
+                if self.calling.contains(&name) {
+                    return Err(self.error(start, &format!("subroutine !{} calls itself", name)));
+                }
+                let end = self.pos;
+                self.pos = body_start + 1;
+                self.calling.push(name);
+                let mut body = Vec::new();
+                while self.skip_space() && self.peek() != Some(b'}') {
+                    self.command(&mut body, true)?;
+                }
+                self.calling.pop();
+                self.pos = end;
+                self.call(out, body, 1, in_subroutine);
+            }
+            _ => return Err(self.error(start, &format!("unexpected '{}'", c as char))),
+        }
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        out: &mut Vec<ParameterizedCommand>,
+        body: Vec<ParameterizedCommand>,
+        count: u8,
+        in_subroutine: bool,
+    ) {
+        if body.is_empty() {
+            return;
+        }
+        if in_subroutine {
+            for _ in 0..count {
+                out.extend_from_slice(&body);
+            }
+            return;
+        }
+        let track = Track { commands: body };
+        let idx = match self.subroutines.iter().position(|sub| *sub == track) {
+            Some(idx) => idx,
+            None => {
+                self.subroutines.push(track);
+                self.subroutines.len() - 1
+            }
+        };
+        out.push(ParameterizedCommand::new(
+            Some(0),
+            Some(0),
+            Some(0),
+            Command::CallLoop(idx, count),
+        ));
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .map_or(false, |c| c.is_ascii_alphanumeric() || c == b'_')
+        {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.text[start..self.pos]).into_owned()
+    }
+
+    fn number(&mut self) -> Result<u32, Box<Error>> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.text[start..self.pos])
+            .parse::<u32>()
+            .map_err(|_| self.error(start, "expected a number"))
+    }
+
+    fn byte(&mut self) -> Result<u8, Box<Error>> {
+        let start = self.pos;
+        let value = self.number()?;
+        if value > 0xff {
+            return Err(self.error(start, "expected a number from 0 to 255"));
+        }
+        Ok(value as u8)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, Box<Error>> {
+        if self.peek() != Some(b',') {
+            return Err(self.error(self.pos, "expected ','"));
+        }
+        self.pos += 1;
+        self.byte()
+    }
+
+    fn length(&mut self) -> Result<u32, Box<Error>> {
+        let start = self.pos;
+        let mut ticks = match self.peek() {
+            Some(b'=') => {
+                self.pos += 1;
+                self.number()?
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let length = self.number()?;
+                if length == 0 || TICKS_PER_WHOLE_NOTE % length != 0 {
+                    return Err(self.error(
+                        start,
+                        &format!("length {} doesn't divide a whole note evenly", length),
+                    ));
+                }
+                TICKS_PER_WHOLE_NOTE / length
+            }
+            _ => self.state.length,
+        };
+        let mut dotted = ticks;
+        while self.peek() == Some(b'.') {
+            if dotted % 2 != 0 {
+                return Err(self.error(self.pos, "too many dots for this length"));
+            }
+            dotted /= 2;
+            ticks += dotted;
+            self.pos += 1;
+        }
+        if ticks == 0 {
+            return Err(self.error(start, "length should be at least one tick"));
+        }
+        Ok(ticks)
+    }
+}
+
+fn push(out: &mut Vec<ParameterizedCommand>, command: Command) {
+    out.push(ParameterizedCommand::new(None, None, None, command));
+}
+
+// durations too long for one command continue with ties or rests
+fn push_duration(
+    out: &mut Vec<ParameterizedCommand>,
+    ticks: u32,
+    sustain: Option<u8>,
+    command: Command,
+) {
+    let mut remaining = ticks;
+    let mut command = command;
+    let mut sustain = sustain;
+    while remaining > 0 {
+        let duration = remaining.min(MAX_DURATION);
+        out.push(ParameterizedCommand::new(
+            Some(duration as u8),
+            None,
+            sustain,
+            command.clone(),
+        ));
+        remaining -= duration;
+        if command != Command::Rest {
+            command = Command::Tie;
+        }
+        sustain = None;
+    }
+}
+
+// Orders the channel tracks before the subroutines they call, and points the
+// CallLoops at them.
+fn link(
+    channels: Vec<(usize, Vec<ParameterizedCommand>)>,
+    subroutines: Vec<Track>,
+) -> (Vec<Track>, usize) {
+    let channels = channels
+        .into_iter()
+        .filter(|(_, commands)| !commands.is_empty())
+        .map(|(_, commands)| commands)
+        .collect::<Vec<Vec<ParameterizedCommand>>>();
+    let top_level = channels.len();
+    let mut used: Vec<usize> = Vec::new();
+    let mut tracks = Vec::new();
+    for commands in channels {
+        let commands = commands
+            .into_iter()
+            .map(|cmd| match *cmd.command() {
+                Command::CallLoop(idx, count) => {
+                    let pos = used
+                        .iter()
+                        .position(|&used| used == idx)
+                        .unwrap_or_else(|| {
+                            used.push(idx);
+                            used.len() - 1
+                        });
+                    ParameterizedCommand::new(
+                        Some(0),
+                        Some(0),
+                        Some(0),
+                        Command::CallLoop(top_level + pos, count),
+                    )
+                }
+                _ => cmd,
+            })
+            .collect();
+        tracks.push(Track { commands });
+    }
+    tracks.extend(used.iter().map(|&idx| subroutines[idx].clone()));
+    (tracks, top_level)
+}
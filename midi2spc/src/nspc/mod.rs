@@ -1,56 +1,606 @@
 use byteorder::*;
-use crate::midi::MidiHandler;
+use crate::error::ConvertError;
+use crate::midi::{MidiHandler, Transform};
 use std::error::Error;
 use std::fs::*;
 use std::io::{Cursor, Write};
+use std::mem;
 use std::path::*;
+use std::time::{Duration, Instant};
 use serde_derive::{Serialize, Deserialize};
 use serde_json;
+use serde_json::Value;
+use simple_error::SimpleError;
 
 mod command;
+pub mod export;
+#[cfg(test)]
+mod fixtures;
 pub mod instruments;
-mod seqtree;
+mod mml;
+pub mod play;
+mod preview;
+mod sfx;
+mod suffixarray;
+mod text;
 mod track;
 
 use self::command::*;
-use self::seqtree::*;
+use self::suffixarray::*;
 use self::track::*;
 
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_share_call_loops_between_songs() {
+        let mut songs = (0..3)
+            .map(|i| {
+                let mut commands = vec![Command::Note(10 + i)];
+                commands.extend(motif());
+                song(&commands)
+            })
+            .collect::<Vec<Song>>();
+        let sizes_before = songs
+            .iter()
+            .map(|song| song.data_size().unwrap())
+            .collect::<Vec<usize>>();
+        let bytes_saved =
+            Song::share_call_loops(&mut songs, &OptimizeBudget::default(), false).unwrap();
+        assert!(bytes_saved > 0);
+        for song in &songs {
+            assert_eq!(2, song.get_num_tracks());
+            assert!(!song.is_subroutine(0));
+            assert!(song.is_subroutine(1));
+            assert_eq!(songs[0].tracks[1], song.tracks[1]);
+        }
+        // each song is only smaller once the shared subroutine is written once
+        let subroutine_size = songs[0].data_size().unwrap() - song_size_without(&songs[0], 1);
+        let total_before: usize = sizes_before.iter().sum();
+        let total_after: usize = songs
+            .iter()
+            .map(|song| song_size_without(song, 1))
+            .sum::<usize>()
+            + subroutine_size;
+        assert_eq!(total_before - bytes_saved, total_after);
+    }
+
+    #[test]
+    fn test_share_call_loops_budget() {
+        let mut songs = (0..3).map(|_| song(&motif())).collect::<Vec<Song>>();
+        let budget = OptimizeBudget {
+            max_iterations: Some(0),
+            max_time: None,
+        };
+        assert_eq!(0, Song::share_call_loops(&mut songs, &budget, false).unwrap());
+        assert!(songs.iter().all(|song| song.get_num_tracks() == 1));
+    }
+
+    #[test]
+    fn test_fades() {
+        let note = |duration| ParameterizedCommand::new(Some(duration), None, None, Command::Tie);
+        let mut track = Track {
+            commands: vec![note(96), note(255), note(250)],
+        };
+        let transform = Transform {
+            start: Some(4.0),
+            fade_in: Some(1.0),
+            fade_out: Some((18.0, 20.0)),
+            ..Transform::default()
+        };
+        Song::add_fades(&mut track, &transform, 180);
+        assert_eq!(
+            vec![
+                (Command::MasterVolume(0), None),
+                (Command::MasterVolumeFade(24, 180), None),
+                (Command::Tie, Some(96)),
+                (Command::Tie, Some(240)),
+                // 20 beats is too long for one fade, so it takes two
+                (Command::MasterVolumeFade(240, 90), None),
+                (Command::Tie, Some(15)),
+                (Command::Tie, Some(225)),
+                (Command::MasterVolumeFade(240, 0), None),
+                (Command::Tie, Some(25)),
+            ],
+            track
+                .commands
+                .iter()
+                .map(|cmd| (cmd.command().clone(), cmd.duration()))
+                .collect::<Vec<_>>()
+        );
+
+        // a fade in shorter than a tick still ends at full volume
+        let mut track = Track {
+            commands: vec![note(96)],
+        };
+        let transform = Transform {
+            fade_in: Some(0.01),
+            ..Transform::default()
+        };
+        Song::add_fades(&mut track, &transform, 180);
+        assert_eq!(
+            vec![Command::MasterVolume(0), Command::MasterVolumeFade(1, 180), Command::Tie],
+            track
+                .commands
+                .iter()
+                .map(|cmd| cmd.command().clone())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_tie_held_notes() {
+        let section_parts = vec![Part { tracks: vec![0] }, Part { tracks: vec![1] }];
+        let tied = |sequence: &[usize]| {
+            let mut tracks = vec![
+                track(&[Command::Rest, Command::Note(0xa4)]),
+                track(&[Command::ChannelVolume(0x80), Command::Note(0xa4), Command::Note(0xa6)]),
+            ];
+            Song::tie_held_notes(&mut tracks, &section_parts, &[vec![], vec![60]], sequence);
+            tracks[1]
+                .commands
+                .iter()
+                .map(|cmd| cmd.command().clone())
+                .collect::<Vec<Command>>()
+        };
+        assert_eq!(
+            vec![Command::ChannelVolume(0x80), Command::Tie, Command::Note(0xa6)],
+            tied(&[0, 1])
+        );
+        // the section also follows itself, which doesn't end on the held note
+        assert_eq!(
+            vec![Command::ChannelVolume(0x80), Command::Note(0xa4), Command::Note(0xa6)],
+            tied(&[0, 1, 1])
+        );
+    }
+
+    #[test]
+    fn test_json_version() {
+        let expected = song(&[Command::Note(0xa4), Command::Rest]);
+        let json = serde_json::to_value(SongFile {
+            version: SONG_FORMAT_VERSION,
+            song: &expected,
+        })
+        .unwrap();
+        assert_eq!(SONG_FORMAT_VERSION, json["version"].as_u64().unwrap());
+        assert_eq!(expected, Song::from_json_value(json.clone()).unwrap());
+        let mut unversioned = json.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        assert_eq!(expected, Song::from_json_value(unversioned).unwrap());
+        let mut newer = json;
+        newer["version"] = Value::from(SONG_FORMAT_VERSION + 1);
+        assert_eq!(
+            vec!["format version 3 isn't supported; the latest is 2"],
+            Song::from_json_value(newer).unwrap_err()
+        );
+
+        let fade = song(&[Command::ChannelVolumeFade(12, 100), Command::Note(0xa4)]);
+        let mut json = serde_json::to_value(&fade).unwrap();
+        json["version"] = Value::from(1);
+        assert_eq!(fade, Song::from_json_value(json.clone()).unwrap());
+        json["tracks"][0]["commands"][0]["command"]["ChannelVolumeFade"] = Value::from(12);
+        assert_eq!(
+            vec![
+                "tracks[0].commands[0]: ChannelVolumeFade from format version 1 has no target \
+                 volume; give it as [length, volume]"
+            ],
+            Song::from_json_value(json).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let mut bad = song(&[Command::Note(0x20), Command::CallLoop(3, 2), Command::Pan(0xd4)]);
+        bad.parts.push(Part { tracks: vec![0, 5] });
+        let json = serde_json::to_value(&bad).unwrap();
+        assert_eq!(
+            vec![
+                "parts[1]: no track 5",
+                "tracks[0].commands[0]: note 0x20 isn't between 0x80 and 0xdf",
+                "tracks[0].commands[1]: no track 3 to call",
+            ],
+            Song::from_json_value(json).unwrap_err()
+        );
+        assert!(Song::from_json_value(Value::from("song")).is_err());
+
+        // a part without tracks can't be written, so it's rejected on loading
+        let mut empty_part = song(&[Command::Note(0xa4)]);
+        empty_part.parts.push(Part { tracks: vec![] });
+        let json = serde_json::to_value(&empty_part).unwrap();
+        assert_eq!(vec!["parts[1]: no tracks"], Song::from_json_value(json).unwrap_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut valid = song(&[Command::Note(0xa4), Command::CallLoop(1, 2)]);
+        valid.tracks.push(Track {
+            commands: vec![ParameterizedCommand::new(Some(1), None, None, Command::Rest)],
+        });
+        assert!(valid.validate().is_empty());
+
+        let mut invalid = song(&[Command::CallLoop(0, 0), Command::CallLoop(1, 1)]);
+        invalid.tracks.push(Track {
+            commands: vec![ParameterizedCommand::new(
+                Some(0),
+                Some(0),
+                Some(0),
+                Command::CallLoop(1, 1),
+            )],
+        });
+        invalid.parts.push(Part { tracks: vec![] });
+        invalid.parts.push(Part {
+            tracks: vec![0; 9],
+        });
+        assert_eq!(
+            vec![
+                "parts[1]: no tracks",
+                "parts[2]: 9 tracks, but a part can play at most 8",
+                "tracks[0].commands[0]: calls track 0, which is played by a part",
+                "tracks[0].commands[0]: repeat count is 0",
+                "tracks[1].commands[0]: subroutines can't call other subroutines",
+            ],
+            invalid.validate()
+        );
+    }
+
+    fn song_size_without(song: &Song, track_idx: usize) -> usize {
+        let mut cursor = Cursor::new(Vec::new());
+        song.write_track(&mut cursor, track_idx, &mut vec![]).unwrap();
+        song.data_size().unwrap() - cursor.into_inner().len()
+    }
+}
+
 const PREAMBLE_TRACK_0: [u8; 6] = [
     0xfa, 0x19, // percussion offset
     0xe5, 0xc8, // global volume
     0xed, 0xc8, // channel volume
 ];
+const PREAMBLE_MASTER_VOLUME: usize = 3;
+// the tracks in a part's table, one for each channel
+pub const MAX_PART_TRACKS: usize = 8;
+pub const PART_TABLE_SIZE: usize = MAX_PART_TRACKS * 2;
+const DEFAULT_MASTER_VOLUME: u8 = 0xc8;
+// MIDI conversion writes a beat as 24 ticks
+const TICKS_PER_BEAT: u32 = 24;
+
+// written to Song JSON files; files without a version predate it and are read
+// as version 0, which has the same layout as version 1.  Version 2 gives
+// ChannelVolumeFade its target volume.
+pub const SONG_FORMAT_VERSION: u64 = 2;
+
+// ChannelVolumeFade only had a length before format version 2, and was
+// written without the target volume the driver reads after it
+fn check_one_byte_fades(fields: &serde_json::Map<String, Value>) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    let tracks = fields.get("tracks").and_then(Value::as_array);
+    for (i, track) in tracks.into_iter().flatten().enumerate() {
+        let commands = track.get("commands").and_then(Value::as_array);
+        for (j, command) in commands.into_iter().flatten().enumerate() {
+            if command["command"]["ChannelVolumeFade"].is_number() {
+                errors.push(format!(
+                    "tracks[{}].commands[{}]: ChannelVolumeFade from format version 1 has no \
+                     target volume; give it as [length, volume]",
+                    i, j
+                ));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
 
 const PREAMBLE_OTHER_TRACK: [u8; 2] = [
     0xed, 0xc8, // channel volume
 ];
 
+/// Limits on the work done by the CallLoop optimizer for a single song.  The
+/// optimizer extracts the best remaining subroutine on each iteration, so
+/// stopping early still leaves a valid, if larger, song.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OptimizeBudget {
+    pub max_iterations: Option<usize>,
+    pub max_time: Option<Duration>,
+}
+
+/// Combines errors about a song file, one per line.
+pub fn errors_in(path: &Path, errors: &[String]) -> Box<Error> {
+    Box::from(ConvertError::Invalid {
+        path: Some(path.to_path_buf()),
+        message: errors.join("\n"),
+    })
+}
+
+impl OptimizeBudget {
+    fn exhausted(&self, iterations: usize, start: Instant) -> bool {
+        self.max_iterations.map_or(false, |max| iterations >= max)
+            || self.max_time.map_or(false, |max| start.elapsed() >= max)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct CallLoopRef {
     pub target_track: usize,
     pub ref_pos: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 struct Part {
     tracks: Vec<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Song {
     parts: Vec<Part>,
     tracks: Vec<Track>,
+    #[serde(default = "default_master_volume")]
+    master_volume: u8,
+}
+
+#[derive(Serialize)]
+struct SongFile<'a> {
+    version: u64,
+    #[serde(flatten)]
+    song: &'a Song,
+}
+
+fn default_master_volume() -> u8 {
+    DEFAULT_MASTER_VOLUME
+}
+
+/// Appends `word` as a little-endian pointer.
+pub fn push_word(out: &mut Vec<u8>, word: usize) {
+    out.push((word & 0xff) as u8);
+    out.push(((word >> 8) & 0xff) as u8);
+}
+
+/// Overwrites the little-endian pointer at `pos` with `word`.
+pub fn set_word(data: &mut [u8], pos: usize, word: usize) {
+    data[pos] = (word & 0xff) as u8;
+    data[pos + 1] = ((word >> 8) & 0xff) as u8;
+}
+
+/// The little-endian pointer at `pos`.
+pub fn word(data: &[u8], pos: usize) -> usize {
+    data[pos] as usize + ((data[pos + 1] as usize) << 8)
+}
+
+// the index of the note a track starts with, if nothing plays before it
+fn first_note(track: &Track) -> Option<usize> {
+    for (idx, cmd) in track.commands.iter().enumerate() {
+        match *cmd.command() {
+            Command::Note(..) => return Some(idx),
+            Command::Tie | Command::Rest => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+// the note a track is still holding when it ends
+fn held_note(track: &Track) -> Option<&Command> {
+    for cmd in track.commands.iter().rev() {
+        match *cmd.command() {
+            Command::Note(..) => return Some(cmd.command()),
+            Command::Rest => return None,
+            _ => {}
+        }
+    }
+    None
 }
 
 impl Song {
     pub fn from_midi(
-        midi: &MidiHandler,
+        midi: &mut MidiHandler,
         tempo_factor: f32,
-        optimize_loops: bool,
+        optimize_loops: Option<OptimizeBudget>,
         verbose: bool,
     ) -> Result<Song, Box<Error>> {
-        let tracks: Result<Vec<Track>, Box<Error>> = (0..8)
+        match Song::voice_tracks(midi, tempo_factor) {
+            Ok(mut tracks) => {
+                let transform = &midi.transform;
+                if transform.transpose != 0 && !tracks.is_empty() {
+                    tracks[0].commands.insert(
+                        0,
+                        ParameterizedCommand::new(
+                            None,
+                            None,
+                            None,
+                            Command::GlobalTranspose(transform.transpose as u8),
+                        ),
+                    );
+                }
+                let master_volume = transform.master_volume.unwrap_or(DEFAULT_MASTER_VOLUME);
+                if !tracks.is_empty() {
+                    Song::add_fades(&mut tracks[0], transform, master_volume);
+                }
+                let mut parts = Vec::new();
+                let part = Part {
+                    tracks: tracks.iter().enumerate().map(|(i, _)| i).collect(),
+                };
+                parts.push(part);
+                if let Some(budget) = optimize_loops {
+                    let top_level_tracks = tracks.len();
+                    Ok(Song {
+                        parts,
+                        tracks: Song::optimize_call_loops(
+                            tracks,
+                            top_level_tracks,
+                            &budget,
+                            verbose,
+                        )?,
+                        master_volume,
+                    })
+                } else {
+                    Ok(Song {
+                        parts,
+                        tracks,
+                        master_volume,
+                    })
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn from_mml(
+        text: &str,
+        tempo_factor: f32,
+        optimize_loops: Option<OptimizeBudget>,
+        verbose: bool,
+    ) -> Result<Song, Box<Error>> {
+        let (tracks, top_level_tracks) = mml::parse(text, tempo_factor)?;
+        let parts = vec![Part {
+            tracks: (0..top_level_tracks).collect(),
+        }];
+        let tracks = match optimize_loops {
+            Some(budget) => {
+                Song::optimize_call_loops(tracks, top_level_tracks, &budget, verbose)?
+            }
+            None => tracks,
+        };
+        Ok(Song {
+            parts,
+            tracks,
+            master_volume: DEFAULT_MASTER_VOLUME,
+        })
+    }
+
+    /// Builds a song from sections played in the given order, each becoming
+    /// a part.  Sections played more than once share their part.
+    pub fn from_sections(
+        sections: &mut [MidiHandler],
+        ties: &[Vec<u8>],
+        sequence: &[usize],
+        tempo_factor: f32,
+        optimize_loops: Option<OptimizeBudget>,
+        verbose: bool,
+    ) -> Result<Song, Box<Error>> {
+        let mut tracks = Vec::new();
+        let mut section_parts = Vec::new();
+        for section in sections.iter_mut() {
+            let section_tracks = Song::voice_tracks(section, tempo_factor)?;
+            if section_tracks.is_empty() {
+                return Err(Box::from(SimpleError::new("Song section has no tracks")));
+            }
+            section_parts.push(Part {
+                tracks: (tracks.len()..tracks.len() + section_tracks.len()).collect(),
+            });
+            tracks.extend(section_tracks);
+        }
+        Song::tie_held_notes(&mut tracks, &section_parts, ties, sequence);
+        let parts = sequence
+            .iter()
+            .map(|&section| Part {
+                tracks: section_parts[section].tracks.clone(),
+            })
+            .collect();
+        let tracks = match optimize_loops {
+            Some(budget) => {
+                let top_level_tracks = tracks.len();
+                Song::optimize_call_loops(tracks, top_level_tracks, &budget, verbose)?
+            }
+            None => tracks,
+        };
+        Ok(Song {
+            parts,
+            tracks,
+            master_volume: DEFAULT_MASTER_VOLUME,
+        })
+    }
+
+    // a note held into a section is started again at the section's start, so
+    // it's tied on instead where every section played before it is still
+    // holding that note on the same voice
+    fn tie_held_notes(
+        tracks: &mut [Track],
+        section_parts: &[Part],
+        ties: &[Vec<u8>],
+        sequence: &[usize],
+    ) {
+        for (section, pitches) in ties.iter().enumerate() {
+            for (voice, &track_idx) in section_parts[section].tracks.iter().enumerate() {
+                let first = match first_note(&tracks[track_idx]) {
+                    Some(first) => first,
+                    None => continue,
+                };
+                let note = tracks[track_idx].commands[first].clone();
+                if !pitches
+                    .iter()
+                    .any(|&pitch| *note.command() == Command::Note(pitch + 0x68))
+                {
+                    continue;
+                }
+                let held = sequence
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &played)| played == section)
+                    .all(|(pos, _)| {
+                        pos > 0
+                            && section_parts[sequence[pos - 1]]
+                                .tracks
+                                .get(voice)
+                                .map_or(false, |&before| {
+                                    held_note(&tracks[before]) == Some(note.command())
+                                })
+                    });
+                if held {
+                    tracks[track_idx].commands[first] = ParameterizedCommand::new(
+                        note.duration(),
+                        note.velocity(),
+                        note.sustain(),
+                        Command::Tie,
+                    );
+                }
+            }
+        }
+    }
+
+    // fades the master volume in from silence or out at the end, with times
+    // taken from the start of the part of the MIDI file that was converted
+    fn add_fades(track: &mut Track, transform: &Transform, master_volume: u8) {
+        let to_ticks = |beats: f32| (beats.max(0.0) * TICKS_PER_BEAT as f32).round() as u32;
+        if let Some(length) = transform.fade_in {
+            let silence = ParameterizedCommand::new(None, None, None, Command::MasterVolume(0));
+            track.insert_at(0, silence);
+            Song::fade(track, 0, to_ticks(length), 0, master_volume);
+        }
+        if let Some((start, length)) = transform.fade_out {
+            let start = to_ticks(start - transform.start.unwrap_or(0.0));
+            Song::fade(track, start, to_ticks(length), master_volume, 0);
+        }
+    }
+
+    // a MasterVolumeFade lasts at most 255 ticks, so longer fades take several,
+    // and at least one, so a fade too short to round to a tick still happens
+    fn fade(track: &mut Track, start: u32, length: u32, from: u8, to: u8) {
+        let length = length.max(1);
+        let steps = (length + 0xfe) / 0xff;
+        for step in 0..steps {
+            let step_start = length * step / steps;
+            let step_end = length * (step + 1) / steps;
+            let volume = from as i32 + (to as i32 - from as i32) * (step + 1) as i32 / steps as i32;
+            track.insert_at(
+                start + step_start,
+                ParameterizedCommand::new(
+                    None,
+                    None,
+                    None,
+                    Command::MasterVolumeFade((step_end - step_start) as u8, volume as u8),
+                ),
+            );
+        }
+    }
+
+    // the tracks the MIDI's voices play, adding anything that couldn't be
+    // written to its warnings
+    fn voice_tracks(midi: &mut MidiHandler, tempo_factor: f32) -> Result<Vec<Track>, Box<Error>> {
+        let mut warnings = Vec::new();
+        let tracks = (0..8)
             .filter_map(|voice| {
                 match Track::new(
                     midi.events_for_voice(voice),
@@ -58,6 +608,8 @@ impl Song {
                     midi.max_time,
                     tempo_factor,
                     voice,
+                    midi.transform.aftertouch,
+                    &mut warnings,
                 ) {
                     Ok(track) => {
                         if track.commands.is_empty() {
@@ -70,48 +622,111 @@ impl Song {
                 }
             })
             .collect();
-        match tracks {
-            Ok(tracks) => {
-                let mut parts = Vec::new();
-                let part = Part {
-                    tracks: tracks.iter().enumerate().map(|(i, _)| i).collect(),
-                };
-                parts.push(part);
-                if optimize_loops {
-                    let top_level_tracks = tracks.len();
-                    Ok(Song {
-                        parts,
-                        tracks: Song::optimize_call_loops(tracks, top_level_tracks, verbose),
-                    })
-                } else {
-                    Ok(Song { parts, tracks })
-                }
-            }
-            Err(err) => Err(err),
-        }
+        midi.warnings.extend(warnings);
+        tracks
     }
 
     fn optimize_call_loops(
-        tracks: Vec<Track>,
+        mut tracks: Vec<Track>,
         top_level_tracks: usize,
+        budget: &OptimizeBudget,
         verbose: bool,
-    ) -> Vec<Track> {
-        let mut seqtree = SeqTree::new();
-        for (i, track) in tracks.iter().take(top_level_tracks).enumerate() {
-            seqtree.add_track(track, i);
+    ) -> Result<Vec<Track>, Box<Error>> {
+        let start = Instant::now();
+        let mut iterations = 0;
+        while !budget.exhausted(iterations, start) {
+            let best_sequence = RepeatFinder::new(&tracks[..top_level_tracks])?.best_sequence();
+            if verbose {
+                println!("optimal call loop sequence {:?}", best_sequence);
+            };
+            match best_sequence {
+                None => return Ok(tracks),
+                Some(seq) => {
+                    tracks = Song::extract_sequence(tracks, seq, top_level_tracks);
+                    iterations += 1;
+                }
+            }
         }
-        let best_sequence = seqtree.best_sequence();
         if verbose {
-            println!("optimal call loop sequence {:?}", best_sequence);
-        };
-        match best_sequence {
-            None => tracks,
-            Some(seq) => Song::optimize_call_loops(
-                Song::extract_sequence(tracks, seq, top_level_tracks),
-                top_level_tracks,
-                verbose,
-            ),
+            println!(
+                "call loop optimization stopped after {} iterations in {:?}",
+                iterations,
+                start.elapsed()
+            );
         }
+        Ok(tracks)
+    }
+
+    // Extracts sequences shared between songs into subroutines.  Each song gets
+    // its own copy of a shared subroutine, which is only written once per bank
+    // since identical subroutine tracks share an address.
+    pub fn share_call_loops(
+        songs: &mut Vec<Song>,
+        budget: &OptimizeBudget,
+        verbose: bool,
+    ) -> Result<usize, Box<Error>> {
+        let start = Instant::now();
+        let mut iterations = 0;
+        let mut bytes_saved = 0;
+        while !budget.exhausted(iterations, start) {
+            let mut tracks = Vec::new();
+            let mut track_songs = Vec::new();
+            for (song_idx, song) in songs.iter().enumerate() {
+                for track_idx in 0..song.top_level_tracks() {
+                    tracks.push(song.tracks[track_idx].clone());
+                    track_songs.push((song_idx, track_idx));
+                }
+            }
+            let best_sequence = RepeatFinder::new(&tracks)?.best_sequence();
+            if verbose {
+                println!("optimal shared call loop sequence {:?}", best_sequence);
+            };
+            let seq = match best_sequence {
+                None => break,
+                Some(seq) => seq,
+            };
+            for (song_idx, song) in songs.iter_mut().enumerate() {
+                let locations = seq
+                    .locations
+                    .iter()
+                    .filter(|loc| track_songs[loc.track_idx].0 == song_idx)
+                    .map(|loc| Location {
+                        track_idx: track_songs[loc.track_idx].1,
+                        ..*loc
+                    })
+                    .collect::<Vec<Location>>();
+                if !locations.is_empty() {
+                    let top_level_tracks = song.top_level_tracks();
+                    let tracks = mem::replace(&mut song.tracks, vec![]);
+                    song.tracks = Song::extract_sequence(
+                        tracks,
+                        Sequence {
+                            commands: seq.commands.clone(),
+                            locations,
+                            bytes_saved: 0,
+                        },
+                        top_level_tracks,
+                    );
+                }
+            }
+            bytes_saved += seq.bytes_saved;
+            iterations += 1;
+        }
+        Ok(bytes_saved)
+    }
+
+    // the leading tracks played directly by parts; the rest are subroutines
+    fn top_level_tracks(&self) -> usize {
+        (0..self.tracks.len())
+            .take_while(|&i| !self.is_subroutine(i))
+            .count()
+    }
+
+    pub fn is_subroutine(&self, track_idx: usize) -> bool {
+        !self
+            .parts
+            .iter()
+            .any(|part| part.tracks.contains(&track_idx))
     }
 
     fn extract_sequence(
@@ -156,20 +771,219 @@ impl Song {
         new_tracks
     }
 
-    pub fn from_json(path: &Path) -> Song {
-        let file = File::open(path).unwrap();
-        serde_json::from_reader(file).unwrap()
+    /// Reads a song back from the data written for it: the track each part
+    /// plays on each channel, and each track's data with the CallLoops in it.
+    pub fn from_data(
+        parts: &[[Option<usize>; MAX_PART_TRACKS]],
+        tracks: &[(Vec<u8>, Vec<CallLoopRef>)],
+    ) -> Result<Song, Box<Error>> {
+        let mut master_volume = DEFAULT_MASTER_VOLUME;
+        let mut song_tracks = Vec::new();
+        for (i, (data, call_loops)) in tracks.iter().enumerate() {
+            // the preambles aren't part of the tracks they're written with
+            let preamble = if parts.iter().any(|part| part[0] == Some(i))
+                && data.len() >= PREAMBLE_TRACK_0.len()
+                && data[..PREAMBLE_MASTER_VOLUME] == PREAMBLE_TRACK_0[..PREAMBLE_MASTER_VOLUME]
+                && data[PREAMBLE_MASTER_VOLUME + 1..PREAMBLE_TRACK_0.len()]
+                    == PREAMBLE_TRACK_0[PREAMBLE_MASTER_VOLUME + 1..]
+            {
+                master_volume = data[PREAMBLE_MASTER_VOLUME];
+                PREAMBLE_TRACK_0.len()
+            } else if parts.iter().any(|part| part.contains(&Some(i)))
+                && data.starts_with(&PREAMBLE_OTHER_TRACK)
+            {
+                PREAMBLE_OTHER_TRACK.len()
+            } else {
+                0
+            };
+            let call_loops = call_loops
+                .iter()
+                .filter(|call_loop| call_loop.ref_pos as usize >= preamble)
+                .map(|call_loop| CallLoopRef {
+                    target_track: call_loop.target_track,
+                    ref_pos: call_loop.ref_pos - preamble as u64,
+                })
+                .collect::<Vec<CallLoopRef>>();
+            song_tracks.push(Track::read(&data[preamble..], &call_loops)?);
+        }
+        // silent channels play an empty track
+        let empty_track = song_tracks.len();
+        let parts = parts
+            .iter()
+            .map(|part| {
+                let used = part.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+                Part {
+                    tracks: part[..used]
+                        .iter()
+                        .map(|track| track.unwrap_or(empty_track))
+                        .collect(),
+                }
+            })
+            .collect::<Vec<Part>>();
+        if parts
+            .iter()
+            .any(|part| part.tracks.contains(&empty_track))
+        {
+            song_tracks.push(Track { commands: vec![] });
+        }
+        Ok(Song {
+            parts,
+            tracks: song_tracks,
+            master_volume,
+        })
+    }
+
+    pub fn from_json(path: &Path) -> Result<Song, Box<Error>> {
+        let file = File::open(path).map_err(ConvertError::io(path))?;
+        let json: Value = serde_json::from_reader(file)
+            .map_err(|err| errors_in(path, &[err.to_string()]))?;
+        Song::from_json_value(json).map_err(|errors| errors_in(path, &errors))
+    }
+
+    fn from_json_value(mut json: Value) -> Result<Song, Vec<String>> {
+        if let Some(fields) = json.as_object_mut() {
+            match fields.remove("version") {
+                None => check_one_byte_fades(fields)?,
+                Some(Value::Number(ref version))
+                    if version.as_u64().map_or(false, |v| v <= SONG_FORMAT_VERSION) =>
+                {
+                    if version.as_u64() < Some(2) {
+                        check_one_byte_fades(fields)?;
+                    }
+                }
+                Some(version) => {
+                    return Err(vec![format!(
+                        "format version {} isn't supported; the latest is {}",
+                        version, SONG_FORMAT_VERSION
+                    )])
+                }
+            }
+        }
+        let song: Song = serde_json::from_value(json).map_err(|err| vec![err.to_string()])?;
+        let errors = song.check();
+        if errors.is_empty() {
+            Ok(song)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn write_to_json(&self, path: &Path) -> Result<(), Box<Error>> {
+        let out = File::create(path)?;
+        serde_json::to_writer_pretty(
+            out,
+            &SongFile {
+                version: SONG_FORMAT_VERSION,
+                song: self,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn from_text(text: &str) -> Result<Song, Box<Error>> {
+        let song = text::parse(text)?;
+        let errors = song.check();
+        if errors.is_empty() {
+            Ok(song)
+        } else {
+            Err(Box::from(SimpleError::new(errors.join("\n"))))
+        }
+    }
+
+    // tracks and parameters that can't be written out
+    fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (part_idx, part) in self.parts.iter().enumerate() {
+            if part.tracks.is_empty() {
+                errors.push(format!("parts[{}]: no tracks", part_idx));
+            } else if part.tracks.len() > MAX_PART_TRACKS {
+                errors.push(format!(
+                    "parts[{}]: {} tracks, but a part can play at most {}",
+                    part_idx,
+                    part.tracks.len(),
+                    MAX_PART_TRACKS
+                ));
+            }
+            for &track_idx in &part.tracks {
+                if track_idx >= self.tracks.len() {
+                    errors.push(format!("parts[{}]: no track {}", part_idx, track_idx));
+                }
+            }
+        }
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            for (cmd_idx, cmd) in track.commands.iter().enumerate() {
+                let location = format!("tracks[{}].commands[{}]", track_idx, cmd_idx);
+                if let Command::CallLoop(target, _) = *cmd.command() {
+                    if target >= self.tracks.len() {
+                        errors.push(format!("{}: no track {} to call", location, target));
+                    }
+                }
+                if let Some(err) = cmd.range_error() {
+                    errors.push(format!("{}: {}", location, err));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Describes everything that would stop the driver from playing the song,
+    /// or nothing if it's playable.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = self.check();
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            let subroutine = self.is_subroutine(track_idx);
+            for (cmd_idx, cmd) in track.commands.iter().enumerate() {
+                let location = format!("tracks[{}].commands[{}]", track_idx, cmd_idx);
+                if let Command::CallLoop(target, count) = *cmd.command() {
+                    if subroutine {
+                        errors.push(format!(
+                            "{}: subroutines can't call other subroutines",
+                            location
+                        ));
+                    } else if target < self.tracks.len() && !self.is_subroutine(target) {
+                        errors.push(format!(
+                            "{}: calls track {}, which is played by a part",
+                            location, target
+                        ));
+                    }
+                    if count == 0 {
+                        errors.push(format!("{}: repeat count is 0", location));
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    pub fn to_text(&self) -> String {
+        text::write(self)
     }
 
-    pub fn write_to_json(&self, path: &Path) {
-        let out = File::create(path).unwrap();
-        serde_json::to_writer_pretty(out, self).unwrap()
+    /// What each of the driver's channels plays, with CallLoops expanded.
+    pub fn play(&self) -> Result<Vec<play::Voice>, Box<Error>> {
+        play::play(self)
+    }
+
+    /// The song as a sound effect, if it only plays on one voice.
+    pub fn sfx_data(&self) -> Result<Vec<u8>, Box<Error>> {
+        sfx::encode(self)
+    }
+
+    /// A piano roll of what each voice plays, as text for a terminal.
+    pub fn preview(&self, ticks_per_column: u32) -> Result<String, Box<Error>> {
+        preview::text(self, ticks_per_column)
+    }
+
+    /// A piano roll of what each voice plays, as an SVG image.
+    pub fn preview_svg(&self) -> Result<String, Box<Error>> {
+        preview::svg(self)
     }
 
     pub fn empty() -> Result<Song, Box<Error>> {
         Ok(Song {
             parts: vec![Part { tracks: vec![0] }],
-            tracks: vec![Track::new(&vec![], 24, 0, 0.3, 0)?],
+            tracks: vec![Track::new(&vec![], 24, 0, 0.3, 0, false, &mut vec![])?],
+            master_volume: DEFAULT_MASTER_VOLUME,
         })
     }
 
@@ -177,10 +991,92 @@ impl Song {
         self.parts[part_idx].tracks.as_slice()
     }
 
+    pub fn get_num_parts(&self) -> usize {
+        self.parts.len()
+    }
+
+    // the part table played by each part, with identical parts sharing a table
+    pub fn get_part_tables(&self) -> Vec<usize> {
+        let mut tables: Vec<&Part> = Vec::new();
+        self.parts
+            .iter()
+            .map(|part| match tables.iter().position(|&table| table == part) {
+                Some(table) => table,
+                None => {
+                    tables.push(part);
+                    tables.len() - 1
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_num_part_tables(&self) -> usize {
+        self.get_part_tables().iter().max().map_or(0, |&max| max + 1)
+    }
+
+    pub fn header_size(&self, loops: bool) -> usize {
+        if loops {
+            // part addresses + loop command + loop target + terminator
+            self.parts.len() * 2 + 6
+        } else {
+            // part addresses + terminator
+            self.parts.len() * 2 + 2
+        }
+    }
+
+    /// The header of the song at `song_addr`, with its part tables straight
+    /// after it.
+    pub fn write_header(&self, song_addr: usize, loops: bool) -> Vec<u8> {
+        let part_tables_addr = song_addr + self.header_size(loops);
+        let mut header = Vec::<u8>::new();
+        for &part_table in &self.get_part_tables() {
+            push_word(&mut header, part_tables_addr + part_table * PART_TABLE_SIZE);
+        }
+        if loops {
+            push_word(&mut header, 0x00ff);
+            push_word(&mut header, song_addr);
+        }
+        push_word(&mut header, 0);
+        header
+    }
+
+    /// The song's part tables, with empty tracks left at address 0.
+    pub fn write_part_tables(&self, track_addrs: &[usize]) -> Vec<u8> {
+        let part_tables = self.get_part_tables();
+        let mut data = vec![0; self.get_num_part_tables() * PART_TABLE_SIZE];
+        for part_table in 0..self.get_num_part_tables() {
+            let part_idx = part_tables.iter().position(|&table| table == part_table).unwrap();
+            for (i, &track_idx) in self.get_part_tracks(part_idx).iter().enumerate() {
+                set_word(&mut data, part_table * PART_TABLE_SIZE + i * 2, track_addrs[track_idx]);
+            }
+        }
+        data
+    }
+
     pub fn get_num_tracks(&self) -> usize {
         self.tracks.len()
     }
 
+    // the largest echo delay set by any track
+    pub fn echo_delay(&self) -> u8 {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.commands.iter())
+            .filter_map(|cmd| cmd.echo_delay())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn data_size(&self) -> Result<usize, Box<Error>> {
+        let mut size = 0;
+        for i in 0..self.tracks.len() {
+            let mut cursor = Cursor::new(Vec::new());
+            self.write_track(&mut cursor, i, &mut vec![])?;
+            size += cursor.into_inner().len();
+        }
+        Ok(size)
+    }
+
     pub fn write_track(
         &self,
         out: &mut Cursor<Vec<u8>>,
@@ -190,7 +1086,9 @@ impl Song {
         let track = &self.tracks[track_idx];
         if !track.commands.is_empty() {
             if self.parts.iter().any(|part| part.tracks[0] == track_idx) {
-                out.write(&PREAMBLE_TRACK_0)?;
+                let mut preamble = PREAMBLE_TRACK_0;
+                preamble[PREAMBLE_MASTER_VOLUME] = self.master_volume;
+                out.write(&preamble)?;
             } else if self
                 .parts
                 .iter()
@@ -0,0 +1,188 @@
+use super::command::Command;
+use super::{CallLoopRef, Song, MAX_PART_TRACKS};
+use std::error::Error;
+use std::io::Cursor;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "part track0 track1
+
+track track0
+    c4 len=12 vel=10 sus=3
+    call_loop sub2 2 len=0 vel=0 sus=0
+    d4
+
+track track1
+    e4 len=48
+
+track sub2
+    e4 len=6 vel=4
+";
+
+    #[test]
+    fn test_play() {
+        let voices = play(&Song::from_text(SONG).unwrap()).unwrap();
+        assert_eq!(MAX_PART_TRACKS, voices.len());
+        let notes = voices[0]
+            .commands
+            .iter()
+            .filter(|played| played.length() > 0)
+            .map(|played| (played.time, played.duration, played.velocity_sustain))
+            .collect::<Vec<(u32, u8, u8)>>();
+        // the last note keeps what the subroutine left in effect, since the
+        // track doesn't give its length or velocity again
+        assert_eq!(
+            vec![(0, 12, 0x3a), (12, 6, 0x74), (18, 6, 0x74), (24, 6, 0x74)],
+            notes
+        );
+        assert_eq!(vec![(12, 24, 2)], voices[0].call_loops);
+        // track1 is cut off where track0 ends the part
+        assert_eq!(30, voices[1].end());
+    }
+}
+
+/// A command as the driver plays it, with the note length and the velocity
+/// and sustain in effect on its channel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Played {
+    pub time: u32,
+    pub duration: u8,
+    pub velocity_sustain: u8,
+    pub command: Command,
+}
+
+impl Played {
+    /// How many ticks the command takes, which is only nonzero for notes,
+    /// ties and rests.
+    pub fn length(&self) -> u32 {
+        match self.command {
+            Command::Note(..) | Command::Tie | Command::Rest => self.duration as u32,
+            _ => 0,
+        }
+    }
+}
+
+/// What one of the driver's channels plays over the whole song, with the
+/// ticks each CallLoop starts and ends at and the subroutine it calls.
+#[derive(Debug, Default)]
+pub struct Voice {
+    pub commands: Vec<Played>,
+    pub call_loops: Vec<(u32, u32, usize)>,
+}
+
+// what the driver keeps between commands on a channel
+#[derive(Copy, Clone, Default)]
+struct ChannelState {
+    duration: u8,
+    velocity_sustain: u8,
+}
+
+impl Voice {
+    pub fn end(&self) -> u32 {
+        self.commands
+            .iter()
+            .map(|played| played.time + played.length())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn play(
+        &mut self,
+        tracks: &[(Vec<u8>, Vec<CallLoopRef>)],
+        track_idx: usize,
+        subroutine: bool,
+        time: &mut u32,
+        state: &mut ChannelState,
+    ) -> Result<(), Box<Error>> {
+        let (data, call_loops) = &tracks[track_idx];
+        let mut pos = 0;
+        while pos < data.len() {
+            match data[pos] {
+                0x00 => break,
+                duration @ 0x01..=0x7f => {
+                    state.duration = duration;
+                    pos += 1;
+                    if pos < data.len() && data[pos] < 0x80 {
+                        state.velocity_sustain = data[pos];
+                        pos += 1;
+                    }
+                }
+                _ => {
+                    let (command, length) = Command::read(data, pos, call_loops)?;
+                    match command {
+                        // subroutines can't call other subroutines
+                        Command::CallLoop(target, count) if !subroutine => {
+                            let start = *time;
+                            for _ in 0..count {
+                                self.play(tracks, target, true, time, state)?;
+                            }
+                            self.call_loops.push((start, *time, target));
+                        }
+                        Command::CallLoop(..) => {}
+                        command => {
+                            let played = Played {
+                                time: *time,
+                                duration: state.duration,
+                                velocity_sustain: state.velocity_sustain,
+                                command,
+                            };
+                            *time += played.length();
+                            self.commands.push(played);
+                        }
+                    }
+                    pos += length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // drops whatever plays after a part has ended
+    fn cut(&mut self, end: u32) {
+        self.commands.retain(|played| played.time < end);
+        for played in &mut self.commands {
+            if played.time + played.length() > end {
+                played.duration = (end - played.time) as u8;
+            }
+        }
+        self.call_loops.retain(|&(start, _, _)| start < end);
+        for call_loop in &mut self.call_loops {
+            call_loop.1 = call_loop.1.min(end);
+        }
+    }
+}
+
+/// Plays the song the way the driver would from the data written for it,
+/// one part after another until each part's first track ends.
+pub fn play(song: &Song) -> Result<Vec<Voice>, Box<Error>> {
+    let mut tracks = Vec::new();
+    for i in 0..song.tracks.len() {
+        let mut out = Cursor::new(Vec::new());
+        let mut call_loops = Vec::new();
+        song.write_track(&mut out, i, &mut call_loops)?;
+        tracks.push((out.into_inner(), call_loops));
+    }
+    let mut voices = (0..MAX_PART_TRACKS)
+        .map(|_| Voice::default())
+        .collect::<Vec<Voice>>();
+    let mut states = [ChannelState::default(); MAX_PART_TRACKS];
+    let mut part_start = 0;
+    for part in &song.parts {
+        let mut part_end = None;
+        for (voice, &track_idx) in part.tracks.iter().enumerate().take(MAX_PART_TRACKS) {
+            let mut time = part_start;
+            if track_idx < tracks.len() {
+                voices[voice].play(&tracks, track_idx, false, &mut time, &mut states[voice])?;
+            }
+            part_end = part_end.or(Some(time));
+        }
+        let part_end = part_end.unwrap_or(part_start);
+        for voice in &mut voices {
+            voice.cut(part_end);
+        }
+        part_start = part_end;
+    }
+    Ok(voices)
+}
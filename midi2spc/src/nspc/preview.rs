@@ -0,0 +1,328 @@
+use super::command::Command;
+use super::play;
+use super::text::note_name;
+use super::{Song, TICKS_PER_BEAT};
+use std::error::Error;
+
+const LABEL_WIDTH: usize = 6;
+const SVG_LABEL_WIDTH: u32 = 60;
+const SVG_TICK_WIDTH: u32 = 2;
+const SVG_PITCH_HEIGHT: u32 = 4;
+const SVG_LANE_GAP: u32 = 16;
+const SVG_COLORS: [&str; 8] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "part track0 track1
+
+track track0
+    set_instrument 2
+    c4 len=12
+    tie len=6
+    call_loop sub2 2
+    rest len=6
+
+track track1
+    e4 len=24
+    set_instrument 5
+    d4 len=24
+
+track sub2
+    d4 len=6
+";
+
+    #[test]
+    fn test_roll() {
+        let song = Song::from_text(SONG).unwrap();
+        let roll = Roll::new(&song).unwrap();
+        assert_eq!(36, roll.length);
+        assert_eq!(8, roll.voices.len());
+        assert_eq!(
+            vec![
+                RollNote {
+                    start: 0,
+                    length: 18,
+                    note: 0xa4,
+                },
+                RollNote {
+                    start: 18,
+                    length: 6,
+                    note: 0xa6,
+                },
+                RollNote {
+                    start: 24,
+                    length: 6,
+                    note: 0xa6,
+                },
+            ],
+            roll.voices[0].notes
+        );
+        assert_eq!(vec![(18, 30, 2)], roll.voices[0].call_loops);
+        // track1 runs past the end of track0, which ends the part
+        assert_eq!(
+            vec![
+                RollNote {
+                    start: 0,
+                    length: 24,
+                    note: 0xa8,
+                },
+                RollNote {
+                    start: 24,
+                    length: 12,
+                    note: 0xa6,
+                },
+            ],
+            roll.voices[1].notes
+        );
+        assert_eq!(vec![(24, 5)], roll.voices[1].instruments);
+    }
+
+    #[test]
+    fn test_text() {
+        let song = Song::from_text(SONG).unwrap();
+        assert_eq!(
+            " beat |+   +
+voice 0
+ inst |2
+ loop |   ==
+   d4 |   ##
+  c#4 |
+   c4 |#--
+voice 1
+ inst |    5
+   e4 |#---
+  d#4 |
+   d4 |    #-
+voice 2: silent
+voice 3: silent
+voice 4: silent
+voice 5: silent
+voice 6: silent
+voice 7: silent
+",
+            Roll::new(&song).unwrap().text(6)
+        );
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct RollNote {
+    start: u32,
+    length: u32,
+    note: u8,
+}
+
+#[derive(Default)]
+struct Voice {
+    notes: Vec<RollNote>,
+    // (tick, instrument)
+    instruments: Vec<(u32, u8)>,
+    // (start tick, end tick, subroutine track)
+    call_loops: Vec<(u32, u32, usize)>,
+}
+
+impl Voice {
+    fn new(played: &play::Voice) -> Voice {
+        let mut voice = Voice {
+            call_loops: played.call_loops.clone(),
+            ..Voice::default()
+        };
+        for command in &played.commands {
+            match command.command {
+                Command::Note(note) => voice.notes.push(RollNote {
+                    start: command.time,
+                    length: command.length(),
+                    note,
+                }),
+                Command::Tie => {
+                    if let Some(last) = voice.notes.last_mut() {
+                        if last.start + last.length == command.time {
+                            last.length += command.length();
+                        }
+                    }
+                }
+                Command::SetInstrument(instrument) => {
+                    voice.instruments.push((command.time, instrument))
+                }
+                _ => {}
+            }
+        }
+        voice
+    }
+
+    fn pitches(&self) -> Option<(u8, u8)> {
+        let lowest = self.notes.iter().map(|note| note.note).min()?;
+        let highest = self.notes.iter().map(|note| note.note).max()?;
+        Some((lowest, highest))
+    }
+
+    fn is_silent(&self) -> bool {
+        self.notes.is_empty() && self.instruments.is_empty()
+    }
+}
+
+/// The notes each of the driver's voices plays, in ticks from the start of
+/// the song.
+struct Roll {
+    voices: Vec<Voice>,
+    length: u32,
+}
+
+impl Roll {
+    fn new(song: &Song) -> Result<Roll, Box<Error>> {
+        let voices = song.play()?;
+        Ok(Roll {
+            length: voices.iter().map(play::Voice::end).max().unwrap_or(0),
+            voices: voices.iter().map(Voice::new).collect(),
+        })
+    }
+
+    fn text(&self, ticks_per_column: u32) -> String {
+        let ticks_per_column = ticks_per_column.max(1);
+        let columns = ((self.length + ticks_per_column - 1) / ticks_per_column) as usize;
+        let column = |tick: u32| (tick / ticks_per_column) as usize;
+        let last_column = |end: u32| column(end.max(1) - 1);
+        let mut ruler = vec![' '; columns];
+        for beat in (0..self.length).step_by(TICKS_PER_BEAT as usize) {
+            ruler[column(beat)] = '+';
+        }
+        let mut out = String::new();
+        out.push_str(&row("beat", &ruler));
+        for (i, voice) in self.voices.iter().enumerate() {
+            if voice.is_silent() {
+                out.push_str(&format!("voice {}: silent\n", i));
+                continue;
+            }
+            out.push_str(&format!("voice {}\n", i));
+            if !voice.instruments.is_empty() {
+                let mut cells = vec![' '; columns];
+                for &(time, instrument) in &voice.instruments {
+                    for (j, c) in instrument.to_string().chars().enumerate() {
+                        if column(time) + j < columns {
+                            cells[column(time) + j] = c;
+                        }
+                    }
+                }
+                out.push_str(&row("inst", &cells));
+            }
+            if !voice.call_loops.is_empty() {
+                let mut cells = vec![' '; columns];
+                for &(start, end, _) in &voice.call_loops {
+                    for cell in &mut cells[column(start)..=last_column(end)] {
+                        *cell = '=';
+                    }
+                }
+                out.push_str(&row("loop", &cells));
+            }
+            if let Some((lowest, highest)) = voice.pitches() {
+                for pitch in (lowest..=highest).rev() {
+                    let mut cells = vec![' '; columns];
+                    for note in voice.notes.iter().filter(|note| note.note == pitch) {
+                        let end = note.start + note.length;
+                        for cell in &mut cells[column(note.start)..=last_column(end)] {
+                            if *cell != '#' {
+                                *cell = '-';
+                            }
+                        }
+                        cells[column(note.start)] = '#';
+                    }
+                    out.push_str(&row(&pitch_name(pitch), &cells));
+                }
+            }
+        }
+        out
+    }
+
+    fn svg(&self) -> String {
+        let width = SVG_LABEL_WIDTH + self.length * SVG_TICK_WIDTH;
+        let x = |tick: u32| SVG_LABEL_WIDTH + tick * SVG_TICK_WIDTH;
+        let mut body = String::new();
+        let mut y = SVG_LANE_GAP;
+        for (i, voice) in self.voices.iter().enumerate() {
+            let (lowest, highest) = voice.pitches().unwrap_or((0, 0));
+            let height = (highest - lowest + 1) as u32 * SVG_PITCH_HEIGHT;
+            body.push_str(&format!(
+                "<text x=\"0\" y=\"{}\" font-size=\"10\">voice {}</text>\n",
+                y + 10,
+                i
+            ));
+            body.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#f4f4f4\"/>\n",
+                x(0),
+                y,
+                self.length * SVG_TICK_WIDTH,
+                height
+            ));
+            for &(start, end, track) in &voice.call_loops {
+                body.push_str(&format!(
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#ffe680\">\
+                     <title>call loop sub{}</title></rect>\n",
+                    x(start),
+                    y,
+                    (end - start) * SVG_TICK_WIDTH,
+                    height,
+                    track
+                ));
+            }
+            for note in &voice.notes {
+                body.push_str(&format!(
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\">\
+                     <title>{}</title></rect>\n",
+                    x(note.start),
+                    y + (highest - note.note) as u32 * SVG_PITCH_HEIGHT,
+                    note.length * SVG_TICK_WIDTH,
+                    SVG_PITCH_HEIGHT,
+                    SVG_COLORS[i % SVG_COLORS.len()],
+                    pitch_name(note.note)
+                ));
+            }
+            for &(time, instrument) in &voice.instruments {
+                body.push_str(&format!(
+                    "<line x1=\"{0}\" y1=\"{1}\" x2=\"{0}\" y2=\"{2}\" stroke=\"black\"/>\n\
+                     <text x=\"{3}\" y=\"{4}\" font-size=\"8\">{5}</text>\n",
+                    x(time),
+                    y,
+                    y + height,
+                    x(time) + 2,
+                    y - 2,
+                    instrument
+                ));
+            }
+            y += height + SVG_LANE_GAP;
+        }
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n{}</svg>\n",
+            width, y, body
+        )
+    }
+}
+
+fn row(label: &str, cells: &[char]) -> String {
+    let cells = cells.iter().collect::<String>();
+    format!(
+        "{:>width$}|{}\n",
+        format!("{} ", label),
+        cells.trim_end(),
+        width = LABEL_WIDTH
+    )
+}
+
+fn pitch_name(note: u8) -> String {
+    // percussion notes have no pitch
+    note_name(note).unwrap_or_else(|| format!("{:#x}", note))
+}
+
+/// Renders each voice as rows of text, one for each pitch it plays, with a
+/// column for every `ticks_per_column` ticks.
+pub fn text(song: &Song, ticks_per_column: u32) -> Result<String, Box<Error>> {
+    Ok(Roll::new(song)?.text(ticks_per_column))
+}
+
+/// Renders each voice as a lane of an SVG image.
+pub fn svg(song: &Song) -> Result<String, Box<Error>> {
+    Ok(Roll::new(song)?.svg())
+}
@@ -0,0 +1,158 @@
+use super::command::Command;
+use super::Song;
+use simple_error::SimpleError;
+use std::error::Error;
+
+// notes the driver can play in a sound effect, which has no percussion
+const NOTE_RANGE: (u8, u8) = (0x80, 0xc7);
+// the volume in effect before a track sets one, as the song preamble does
+const DEFAULT_VOLUME: u8 = 0xc8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let song = Song::from_text(
+            "part track0
+
+track track0
+    tempo 40
+    set_instrument 3
+    global_transpose 2
+    c4 len=12
+    d4
+    channel_volume 100
+    call_loop sub1 2
+    rest len=6
+
+track sub1
+    e4 len=3
+",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                0xe0, 0x03, 0x0c, 0x64, 0xa6, 0xa8, 0x03, 0x32, 0xaa, 0xaa, 0x06, 0xc9, 0x00
+            ],
+            encode(&song).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let two_voices = Song::from_text("part track0 track0\n\ntrack track0\n    c4 len=12\n");
+        assert_eq!(
+            "A sound effect plays on one voice, but voices 0 and 1 play notes",
+            encode(&two_voices.unwrap()).unwrap_err().to_string()
+        );
+        let pan = Song::from_text("part track0\n\ntrack track0\n    pan 10\n    c4 len=12\n");
+        assert_eq!(
+            "Pan(10) can't be used in a sound effect",
+            encode(&pan.unwrap()).unwrap_err().to_string()
+        );
+    }
+}
+
+/// Encodes a song that plays on one voice in the driver's sound effect
+/// format.  A duration byte, then a volume byte, comes before a note when
+/// either changes, `0xe0` sets the instrument from the sound effect
+/// instrument table and `0x00` ends the sound.  Sound effects play at the
+/// driver's own rate, so tempo and other song-wide commands are left out,
+/// and transposes are applied to the notes.
+pub fn encode(song: &Song) -> Result<Vec<u8>, Box<Error>> {
+    let voices = song.play()?;
+    let playing = (0..voices.len())
+        .filter(|&i| {
+            voices[i]
+                .commands
+                .iter()
+                .any(|played| played.command != Command::Rest && played.length() > 0)
+        })
+        .collect::<Vec<usize>>();
+    if playing.len() > 1 {
+        return Err(Box::from(SimpleError::new(format!(
+            "A sound effect plays on one voice, but voices {} and {} play notes",
+            playing[..playing.len() - 1]
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            playing[playing.len() - 1]
+        ))));
+    }
+    let mut out = Vec::new();
+    let voice = match playing.first() {
+        Some(&i) => &voices[i],
+        None => {
+            out.push(0x00);
+            return Ok(out);
+        }
+    };
+    let mut duration = None;
+    let mut volume = DEFAULT_VOLUME;
+    let mut written_volume = None;
+    let mut transpose = (0i16, 0i16);
+    for played in &voice.commands {
+        let byte = match played.command {
+            Command::Note(note) => {
+                let transposed = note as i16 + transpose.0 + transpose.1;
+                if transposed < NOTE_RANGE.0 as i16 || transposed > NOTE_RANGE.1 as i16 {
+                    return Err(Box::from(SimpleError::new(format!(
+                        "Note 0x{:X} is outside the range a sound effect can play",
+                        transposed
+                    ))));
+                }
+                transposed as u8
+            }
+            Command::Tie => 0xc8,
+            Command::Rest => 0xc9,
+            Command::SetInstrument(instrument) => {
+                out.push(0xe0);
+                out.push(instrument);
+                continue;
+            }
+            Command::ChannelVolume(channel_volume) => {
+                volume = channel_volume;
+                continue;
+            }
+            Command::GlobalTranspose(semitones) => {
+                transpose.0 = semitones as i8 as i16;
+                continue;
+            }
+            Command::ChannelTranspose(semitones) => {
+                transpose.1 = semitones as i8 as i16;
+                continue;
+            }
+            Command::Tempo(..)
+            | Command::TempoFade(..)
+            | Command::MasterVolume(..)
+            | Command::MasterVolumeFade(..)
+            | Command::PercussionPatchBase(..)
+            | Command::EchoVolume(..)
+            | Command::EchoOff
+            | Command::EchoParams(..)
+            | Command::EchoVolumeFade(..) => continue,
+            ref command => {
+                return Err(Box::from(SimpleError::new(format!(
+                    "{:?} can't be used in a sound effect",
+                    command
+                ))));
+            }
+        };
+        // the volume byte is only read after a duration byte
+        let sfx_volume = volume >> 1;
+        if duration != Some(played.duration) || written_volume != Some(sfx_volume) {
+            out.push(played.duration);
+            duration = Some(played.duration);
+            if written_volume != Some(sfx_volume) {
+                out.push(sfx_volume);
+                written_volume = Some(sfx_volume);
+            }
+        }
+        out.push(byte);
+    }
+    out.push(0x00);
+    Ok(out)
+}
@@ -0,0 +1,546 @@
+use super::command::{ParameterizedCommand, WriteState};
+use super::track::Track;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Cursor;
+
+// bytes taken by a CallLoop command: opcode, target address, repeat count
+const CALL_LOOP_SIZE: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Command;
+    use super::super::fixtures::*;
+    use super::*;
+
+    fn naive_suffix_array(text: &[usize]) -> Vec<usize> {
+        let mut sa = (0..text.len()).collect::<Vec<usize>>();
+        sa.sort_by(|&a, &b| text[a..].cmp(&text[b..]));
+        sa
+    }
+
+    fn best_sequence(tracks: &[Track]) -> Option<Sequence> {
+        RepeatFinder::new(tracks).unwrap().best_sequence()
+    }
+
+    fn assert_seq(seq: &Sequence, commands: &[Command]) {
+        assert_eq!(seq.commands, track(commands).commands);
+    }
+
+    #[test]
+    fn test_suffix_array_matches_naive() {
+        let mut seed = 12345u32;
+        for len in 0..200 {
+            let text = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    ((seed >> 16) % 4) as usize
+                })
+                .collect::<Vec<usize>>();
+            assert_eq!(naive_suffix_array(&text), suffix_array(&text, 3), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_suffix_array_repetitive() {
+        let text = vec![1; 100];
+        assert_eq!(naive_suffix_array(&text), suffix_array(&text, 1));
+        let text = (0..100).map(|i| i % 3).collect::<Vec<usize>>();
+        assert_eq!(naive_suffix_array(&text), suffix_array(&text, 2));
+    }
+
+    #[test]
+    fn test_lcp() {
+        let text = vec![1, 2, 1, 2, 1];
+        let sa = suffix_array(&text, 2);
+        assert_eq!(vec![4, 2, 0, 3, 1], sa);
+        assert_eq!(vec![1, 3, 0, 2], lcp_array(&text, &sa));
+    }
+
+    #[test]
+    fn test_best_seq_none() {
+        assert!(best_sequence(&[track(&motif())]).is_none());
+    }
+
+    #[test]
+    fn test_best_seq_too_short() {
+        assert!(best_sequence(&[track(&[
+            Command::Note(1),
+            Command::Note(2),
+            Command::Note(3),
+            Command::Note(4),
+            Command::Note(5),
+            Command::Note(1),
+            Command::Note(2),
+            Command::Note(3),
+            Command::Note(4),
+        ])])
+        .is_none());
+    }
+
+    #[test]
+    fn test_best_seq_repeats() {
+        let mut commands = motif();
+        commands.push(Command::Note(9));
+        commands.extend(motif());
+        commands.push(Command::Note(10));
+        commands.extend(motif());
+        commands.extend(motif());
+        commands.extend(motif());
+        let seq = best_sequence(&[track(&commands)]).unwrap();
+        assert_seq(&seq, &motif());
+        assert_eq!(
+            seq.locations,
+            vec![
+                Location {
+                    track_idx: 0,
+                    cmd_idx: 0,
+                    repeat_count: 1,
+                },
+                Location {
+                    track_idx: 0,
+                    cmd_idx: 8,
+                    repeat_count: 1,
+                },
+                Location {
+                    track_idx: 0,
+                    cmd_idx: 16,
+                    repeat_count: 3,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_best_seq_shared_between_tracks() {
+        let mut first = motif();
+        first.push(Command::Note(9));
+        let mut second = vec![Command::Note(10)];
+        second.extend(motif());
+        let seq = best_sequence(&[track(&first), track(&motif()), track(&second)]).unwrap();
+        assert_seq(&seq, &motif());
+        assert_eq!(
+            seq.locations
+                .iter()
+                .map(|loc| (loc.track_idx, loc.cmd_idx))
+                .collect::<Vec<(usize, usize)>>(),
+            vec![(0, 0), (1, 0), (2, 1)]
+        );
+    }
+
+    #[test]
+    fn test_best_seq_counts_bytes() {
+        // eight one-byte notes replace more commands, but five four-byte vibratos
+        // replace more bytes
+        let notes = (1..9).map(Command::Note).collect::<Vec<Command>>();
+        let vibratos = (1..6)
+            .map(|i| Command::Vibrato(i, i, i))
+            .collect::<Vec<Command>>();
+        let mut commands = notes.clone();
+        commands.extend(vibratos.clone());
+        commands.push(Command::Rest);
+        commands.extend(notes);
+        commands.push(Command::Tie);
+        commands.extend(vibratos.clone());
+        assert_seq(&best_sequence(&[track(&commands)]).unwrap(), &vibratos);
+    }
+
+    #[test]
+    fn test_ineligible_command() {
+        let mut commands = motif();
+        commands.push(Command::CallLoop(0, 1));
+        commands.extend(motif());
+        commands.push(Command::CallLoop(0, 1));
+        commands.extend(motif());
+        let seq = best_sequence(&[track(&commands)]).unwrap();
+        assert_seq(&seq, &motif());
+        assert_eq!(3, seq.locations.len());
+    }
+
+    #[test]
+    fn test_seq_doesnt_start_with_tie() {
+        let mut commands = vec![];
+        for i in 0..3 {
+            commands.push(Command::Note(20 + i));
+            commands.push(Command::Tie);
+            commands.extend(motif());
+        }
+        assert_seq(&best_sequence(&[track(&commands)]).unwrap(), &motif());
+    }
+
+    #[test]
+    fn test_seq_doesnt_break_slides() {
+        let mut commands = motif();
+        commands.push(Command::PitchSlide(1, 2, 3));
+        commands.push(Command::Tie);
+        commands.extend(motif());
+        commands.push(Command::PitchSlide(1, 2, 3));
+        commands.push(Command::Tie);
+        assert_seq(&best_sequence(&[track(&commands)]).unwrap(), &motif());
+    }
+
+    #[test]
+    fn test_seq_doesnt_break_slides_at_slide_boundary() {
+        let mut commands = vec![Command::Note(1)];
+        for _ in 0..8 {
+            commands.push(Command::PitchSlide(1, 2, 3));
+            commands.push(Command::Tie);
+        }
+        assert!(best_sequence(&[track(&commands)]).is_none());
+    }
+}
+
+/// Builds the suffix array of `text`, whose symbols must all be at most
+/// `max_symbol`, using induced sorting (SA-IS) in linear time.
+pub fn suffix_array(text: &[usize], max_symbol: usize) -> Vec<usize> {
+    let n = text.len();
+    if n < 8 {
+        let mut sa = (0..n).collect::<Vec<usize>>();
+        sa.sort_by(|&a, &b| text[a..].cmp(&text[b..]));
+        return sa;
+    }
+
+    // is_s[i] is true if the suffix at i is smaller than the suffix at i + 1
+    let mut is_s = vec![false; n];
+    for i in (0..n - 1).rev() {
+        is_s[i] = if text[i] == text[i + 1] {
+            is_s[i + 1]
+        } else {
+            text[i] < text[i + 1]
+        };
+    }
+    let mut sum_l = vec![0usize; max_symbol + 2];
+    let mut sum_s = vec![0usize; max_symbol + 2];
+    for i in 0..n {
+        if is_s[i] {
+            sum_l[text[i] + 1] += 1;
+        } else {
+            sum_s[text[i]] += 1;
+        }
+    }
+    for i in 0..=max_symbol {
+        sum_s[i] += sum_l[i];
+        sum_l[i + 1] += sum_s[i];
+    }
+
+    let is_lms = |i: usize| i > 0 && !is_s[i - 1] && is_s[i];
+    let induce = |sa: &mut Vec<usize>, lms: &[usize]| {
+        const EMPTY: usize = usize::max_value();
+        for entry in sa.iter_mut() {
+            *entry = EMPTY;
+        }
+        let mut buf = sum_s.clone();
+        for &pos in lms {
+            sa[buf[text[pos]]] = pos;
+            buf[text[pos]] += 1;
+        }
+        let mut buf = sum_l.clone();
+        sa[buf[text[n - 1]]] = n - 1;
+        buf[text[n - 1]] += 1;
+        for i in 0..n {
+            let pos = sa[i];
+            if pos != EMPTY && pos >= 1 && !is_s[pos - 1] {
+                sa[buf[text[pos - 1]]] = pos - 1;
+                buf[text[pos - 1]] += 1;
+            }
+        }
+        let mut buf = sum_l.clone();
+        for i in (0..n).rev() {
+            let pos = sa[i];
+            if pos != EMPTY && pos >= 1 && is_s[pos - 1] {
+                buf[text[pos - 1] + 1] -= 1;
+                sa[buf[text[pos - 1] + 1]] = pos - 1;
+            }
+        }
+    };
+
+    let lms = (1..n).filter(|&i| is_lms(i)).collect::<Vec<usize>>();
+    let mut lms_idx = vec![None; n];
+    for (idx, &pos) in lms.iter().enumerate() {
+        lms_idx[pos] = Some(idx);
+    }
+    let mut sa = vec![0; n];
+    induce(&mut sa, &lms);
+
+    if !lms.is_empty() {
+        // name the sorted LMS substrings and recursively sort the reduced string
+        let sorted_lms = sa
+            .iter()
+            .cloned()
+            .filter(|&pos| lms_idx[pos].is_some())
+            .collect::<Vec<usize>>();
+        let lms_end = |pos: usize| {
+            let idx = lms_idx[pos].unwrap();
+            if idx + 1 < lms.len() {
+                lms[idx + 1]
+            } else {
+                n
+            }
+        };
+        let mut reduced = vec![0; lms.len()];
+        let mut max_name = 0;
+        for i in 1..sorted_lms.len() {
+            let (left, right) = (sorted_lms[i - 1], sorted_lms[i]);
+            let (left_end, right_end) = (lms_end(left), lms_end(right));
+            let same = left_end - left == right_end - right
+                && left_end < n
+                && right_end < n
+                && text[left..=left_end] == text[right..=right_end];
+            if !same {
+                max_name += 1;
+            }
+            reduced[lms_idx[right].unwrap()] = max_name;
+        }
+        let reduced_sa = suffix_array(&reduced, max_name);
+        let sorted_lms = reduced_sa
+            .iter()
+            .map(|&idx| lms[idx])
+            .collect::<Vec<usize>>();
+        induce(&mut sa, &sorted_lms);
+    }
+    sa
+}
+
+/// Computes the longest common prefix of each pair of adjacent suffixes in
+/// `sa` using Kasai's algorithm.
+pub fn lcp_array(text: &[usize], sa: &[usize]) -> Vec<usize> {
+    let n = text.len();
+    if n == 0 {
+        return vec![];
+    }
+    let mut rank = vec![0; n];
+    for (i, &pos) in sa.iter().enumerate() {
+        rank[pos] = i;
+    }
+    let mut lcp = vec![0; n - 1];
+    let mut h = 0usize;
+    for i in 0..n {
+        if h > 0 {
+            h -= 1;
+        }
+        if rank[i] == 0 {
+            continue;
+        }
+        let j = sa[rank[i] - 1];
+        while i + h < n && j + h < n && text[i + h] == text[j + h] {
+            h += 1;
+        }
+        lcp[rank[i] - 1] = h;
+    }
+    lcp
+}
+
+fn written_size(
+    cmd: &ParameterizedCommand,
+    mut state: WriteState,
+) -> Result<(usize, WriteState), Box<Error>> {
+    let mut out = Cursor::new(Vec::new());
+    cmd.write(&mut out, &mut state, &mut vec![])?;
+    Ok((out.position() as usize, state))
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+    pub track_idx: usize,
+    pub cmd_idx: usize,
+    pub repeat_count: u8,
+}
+
+#[derive(Debug)]
+pub struct Sequence {
+    pub commands: Vec<ParameterizedCommand>,
+    pub locations: Vec<Location>,
+    pub bytes_saved: usize,
+}
+
+impl Sequence {
+    // prefer the most bytes saved, then the longest sequence, then the earliest
+    fn rank(&self) -> (usize, usize, Reverse<(usize, usize)>) {
+        let first = &self.locations[0];
+        (
+            self.bytes_saved,
+            self.commands.len(),
+            Reverse((first.track_idx, first.cmd_idx)),
+        )
+    }
+}
+
+/// Finds repeated command sequences across a set of tracks which can be moved
+/// into CallLoop subroutines.
+///
+/// The tracks are concatenated into a single string of symbols, with track
+/// boundaries and commands that can't appear in a subroutine replaced by unique
+/// separators, so that every repeat is a path in the implicit suffix tree given
+/// by the suffix array and its LCP intervals.
+pub struct RepeatFinder<'a> {
+    tracks: &'a [Track],
+    positions: Vec<Option<(usize, usize)>>,
+    // byte size of the text up to each position, as written in its track
+    cumulative_size: Vec<usize>,
+    // writer state before each position
+    states: Vec<WriteState>,
+    sa: Vec<usize>,
+    lcp: Vec<usize>,
+}
+
+impl<'a> RepeatFinder<'a> {
+    pub fn new(tracks: &'a [Track]) -> Result<RepeatFinder<'a>, Box<Error>> {
+        let mut symbols = HashMap::<&ParameterizedCommand, usize>::new();
+        let mut text = Vec::new();
+        let mut positions = Vec::new();
+        let mut cumulative_size = vec![0];
+        let mut states = Vec::new();
+        let mut next_symbol = 0;
+        for (track_idx, track) in tracks.iter().enumerate() {
+            let mut state = WriteState::new();
+            for (cmd_idx, cmd) in track.commands.iter().enumerate() {
+                if cmd.call_loop_eligible() {
+                    let symbol = *symbols.entry(cmd).or_insert_with(|| {
+                        next_symbol += 1;
+                        next_symbol - 1
+                    });
+                    text.push(symbol);
+                } else {
+                    text.push(next_symbol);
+                    next_symbol += 1;
+                }
+                positions.push(Some((track_idx, cmd_idx)));
+                states.push(state);
+                let (size, next_state) = written_size(cmd, state)?;
+                cumulative_size.push(cumulative_size.last().unwrap() + size);
+                state = next_state;
+            }
+            text.push(next_symbol);
+            next_symbol += 1;
+            positions.push(None);
+            states.push(state);
+            cumulative_size.push(*cumulative_size.last().unwrap());
+        }
+        let sa = suffix_array(&text, next_symbol);
+        let lcp = lcp_array(&text, &sa);
+        Ok(RepeatFinder {
+            tracks,
+            positions,
+            cumulative_size,
+            states,
+            sa,
+            lcp,
+        })
+    }
+
+    fn command(&self, pos: usize) -> &ParameterizedCommand {
+        let (track_idx, cmd_idx) = self.positions[pos].unwrap();
+        &self.tracks[track_idx].commands[cmd_idx]
+    }
+
+    /// Returns the sequence whose extraction into a subroutine would save the
+    /// most bytes, if any would save bytes at all.
+    pub fn best_sequence(&self) -> Option<Sequence> {
+        let mut best: Option<Sequence> = None;
+        self.for_each_interval(|start, end, parent_lcp, lcp| {
+            let first = self.sa[start];
+            if self.positions[first].is_none() || !self.command(first).can_be_first_in_call_loop() {
+                return;
+            }
+            let length = match (parent_lcp + 1..=lcp)
+                .rev()
+                .find(|&len| self.command(first + len - 1).can_be_last_in_call_loop())
+            {
+                Some(length) => length,
+                None => return,
+            };
+            let mut starts = self.sa[start..=end].to_vec();
+            starts.sort();
+            if let Some(seq) = self.evaluate(&starts, length) {
+                if best.as_ref().map_or(true, |best| seq.rank() > best.rank()) {
+                    best = Some(seq);
+                }
+            }
+        });
+        best
+    }
+
+    /// Calls `f(start, end, parent_lcp, lcp)` for each LCP interval of the
+    /// suffix array, i.e. each internal node of the suffix tree: suffixes
+    /// `sa[start..=end]` share a prefix of length `lcp`, and those of the
+    /// enclosing interval share `parent_lcp`.
+    fn for_each_interval<F: FnMut(usize, usize, usize, usize)>(&self, mut f: F) {
+        let mut stack: Vec<(usize, usize)> = vec![(0, 0)];
+        for i in 0..=self.lcp.len() {
+            let lcp = if i < self.lcp.len() { self.lcp[i] } else { 0 };
+            let mut left = i;
+            while lcp < stack.last().unwrap().0 {
+                let (interval_lcp, interval_left) = stack.pop().unwrap();
+                let parent_lcp = lcp.max(stack.last().unwrap().0);
+                f(interval_left, i, parent_lcp, interval_lcp);
+                left = interval_left;
+            }
+            if lcp > stack.last().unwrap().0 {
+                stack.push((lcp, left));
+            }
+        }
+    }
+
+    fn evaluate(&self, starts: &[usize], length: usize) -> Option<Sequence> {
+        let mut locations: Vec<(usize, Location)> = Vec::new();
+        for &pos in starts {
+            let (track_idx, cmd_idx) = self.positions[pos].unwrap();
+            if let Some((_, last)) = locations.last_mut() {
+                let last_end = last.cmd_idx + length * last.repeat_count as usize;
+                if last.track_idx == track_idx && cmd_idx < last_end {
+                    // overlaps previous location, skip
+                    continue;
+                }
+                if last.track_idx == track_idx && cmd_idx == last_end && last.repeat_count < 0xff
+                {
+                    last.repeat_count += 1;
+                    continue;
+                }
+            }
+            locations.push((
+                pos,
+                Location {
+                    track_idx,
+                    cmd_idx,
+                    repeat_count: 1,
+                },
+            ));
+        }
+        if locations
+            .iter()
+            .fold(0, |acc, &(_, loc)| acc + loc.repeat_count as usize)
+            < 2
+        {
+            return None;
+        }
+
+        let commands = self.tracks[locations[0].1.track_idx].commands
+            [locations[0].1.cmd_idx..locations[0].1.cmd_idx + length]
+            .to_vec();
+        let subroutine = Track { commands };
+        let subroutine_size = subroutine.command_sizes().ok()?.iter().sum::<usize>() + 1;
+        let mut replaced_size = 0;
+        let mut used_size = subroutine_size;
+        for &(pos, loc) in &locations {
+            let end = pos + length * loc.repeat_count as usize;
+            replaced_size += self.cumulative_size[end] - self.cumulative_size[pos];
+            used_size += CALL_LOOP_SIZE;
+            // the next command may have to write its length and velocity again
+            if self.positions[end].is_some() {
+                let state = self.states[pos].after_call_loop();
+                let (return_size, _) = written_size(self.command(end), state).ok()?;
+                replaced_size += self.cumulative_size[end + 1] - self.cumulative_size[end];
+                used_size += return_size;
+            }
+        }
+        if replaced_size <= used_size {
+            return None;
+        }
+        Some(Sequence {
+            commands: subroutine.commands,
+            locations: locations.into_iter().map(|(_, loc)| loc).collect(),
+            bytes_saved: replaced_size - used_size,
+        })
+    }
+}
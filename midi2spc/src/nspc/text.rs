@@ -0,0 +1,448 @@
+use super::command::*;
+use super::track::Track;
+use super::{Part, Song, DEFAULT_MASTER_VOLUME};
+use simple_error::SimpleError;
+use std::collections::HashMap;
+use std::error::Error;
+
+// N-SPC note bytes run from 0x80 (MIDI note 24, c1) to 0xc7 (b6)
+const MIN_NOTE: u8 = 0x80;
+const MAX_NOTE: u8 = 0xc7;
+const NOTE_OFFSET: i32 = 0x68;
+const NOTE_NAMES: [&str; 12] = [
+    "c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b",
+];
+const PITCHES: [i32; 7] = [9, 11, 0, 2, 4, 5, 7];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "master_volume 180
+part track0 track1
+part track0 track1
+
+track track0
+    set_instrument 9
+    c4 len=24 sus=7 vel=13
+    c#4 len=12
+    call_loop sub2 3 len=0 vel=0 sus=0
+    rest len=12
+
+; a subroutine
+track track1
+    echo_params 2 60 0
+    note 12 len=4
+    bb3
+    master_volume 120
+
+track sub2
+    tie
+";
+
+    #[test]
+    fn test_parse() {
+        let song = parse(TEXT).unwrap();
+        assert_eq!(180, song.master_volume);
+        assert_eq!(
+            vec![vec![0, 1], vec![0, 1]],
+            song.parts
+                .iter()
+                .map(|part| part.tracks.clone())
+                .collect::<Vec<Vec<usize>>>()
+        );
+        assert_eq!(3, song.tracks.len());
+        assert_eq!(
+            vec![
+                ParameterizedCommand::new(None, None, None, Command::SetInstrument(9)),
+                ParameterizedCommand::new(Some(24), Some(13), Some(7), Command::Note(0xa4)),
+                ParameterizedCommand::new(Some(12), None, None, Command::Note(0xa5)),
+                ParameterizedCommand::new(Some(0), Some(0), Some(0), Command::CallLoop(2, 3)),
+                ParameterizedCommand::new(Some(12), None, None, Command::Rest),
+            ],
+            song.tracks[0].commands
+        );
+        assert_eq!(Command::Note(12), *song.tracks[1].commands[1].command());
+        assert_eq!(Command::Note(0xa2), *song.tracks[1].commands[2].command());
+        assert_eq!(
+            Command::MasterVolume(120),
+            *song.tracks[1].commands[3].command()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let song = parse(TEXT).unwrap();
+        let text = write(&song);
+        assert_eq!(song, parse(&text).unwrap());
+        assert_eq!(text, write(&parse(&text).unwrap()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+        assert_eq!("line 2: unknown track foo", error("track bar\npart foo"));
+        assert_eq!(
+            "line 3: track bar is defined twice",
+            error("track bar\n\ntrack bar")
+        );
+        assert_eq!("line 1: command outside of a track", error("c4"));
+        assert_eq!("line 2: unknown command jump", error("track a\njump"));
+        assert_eq!(
+            "line 2: set_instrument takes 1 argument",
+            error("track a\nset_instrument")
+        );
+        assert_eq!("line 2: invalid number 300", error("track a\npan 300"));
+        assert_eq!("line 2: note c9 is out of range", error("track a\nc9"));
+        assert_eq!("line 2: unknown option foo=1", error("track a\nrest foo=1"));
+    }
+}
+
+/// Reads a song from its text form.
+///
+/// The song starts with an optional `master_volume` and a `part` line for
+/// each part, listing the names of the tracks it plays.  Each `track NAME`
+/// line then starts a track, with one command per line until the next track.
+/// Tracks are numbered in the order they're defined.
+///
+/// A command is a note name like `c4`, `f#5` or `bb3` (MIDI note 60 is
+/// `c4`), `note N` for a raw note byte, `call_loop NAME COUNT` to call the
+/// named track as a subroutine, or the snake_case name of any other command
+/// followed by its parameters.  It can be followed by `len=`, `vel=` and
+/// `sus=` to set its duration, velocity and sustain.
+///
+/// `;` starts a comment running to the end of the line.
+pub fn parse(text: &str) -> Result<Song, Box<Error>> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let line = line.split(';').next().unwrap_or("");
+            (idx + 1, line.split_whitespace().collect::<Vec<&str>>())
+        })
+        .filter(|(_, tokens)| !tokens.is_empty())
+        .collect::<Vec<(usize, Vec<&str>)>>();
+    let mut names = HashMap::new();
+    for (line, tokens) in &lines {
+        if tokens[0] == "track" {
+            if tokens.len() != 2 {
+                return Err(error(*line, "track takes a name"));
+            }
+            if names.insert(tokens[1], names.len()).is_some() {
+                return Err(error(
+                    *line,
+                    &format!("track {} is defined twice", tokens[1]),
+                ));
+            }
+        }
+    }
+    let track_idx = |line: usize, name: &str| -> Result<usize, Box<Error>> {
+        names
+            .get(name)
+            .cloned()
+            .ok_or_else(|| error(line, &format!("unknown track {}", name)))
+    };
+    let mut parts = Vec::new();
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut master_volume = DEFAULT_MASTER_VOLUME;
+    for (line, tokens) in lines {
+        match tokens[0] {
+            "track" => tracks.push(Track {
+                commands: Vec::new(),
+            }),
+            "part" => parts.push(Part {
+                tracks: tokens[1..]
+                    .iter()
+                    .map(|name| track_idx(line, name))
+                    .collect::<Result<Vec<usize>, Box<Error>>>()?,
+            }),
+            "master_volume" if tracks.is_empty() => {
+                if tokens.len() != 2 {
+                    return Err(error(line, "master_volume takes 1 argument"));
+                }
+                master_volume = number(line, tokens[1])?;
+            }
+            _ => {
+                let command = read_command(line, &tokens, &track_idx)?;
+                match tracks.last_mut() {
+                    Some(track) => track.commands.push(command),
+                    None => return Err(error(line, "command outside of a track")),
+                }
+            }
+        }
+    }
+    Ok(Song {
+        parts,
+        tracks,
+        master_volume,
+    })
+}
+
+/// Writes a song in the form read by `parse`.  Tracks played by parts are
+/// named `track` and subroutines `sub`, followed by the track number.
+pub fn write(song: &Song) -> String {
+    let names = (0..song.tracks.len())
+        .map(|idx| {
+            if song.is_subroutine(idx) {
+                format!("sub{}", idx)
+            } else {
+                format!("track{}", idx)
+            }
+        })
+        .collect::<Vec<String>>();
+    let mut out = format!("master_volume {}\n", song.master_volume);
+    for part in &song.parts {
+        out.push_str("part");
+        for &track in &part.tracks {
+            out.push(' ');
+            out.push_str(&names[track]);
+        }
+        out.push('\n');
+    }
+    for (track, name) in song.tracks.iter().zip(&names) {
+        out.push_str(&format!("\ntrack {}\n", name));
+        for command in &track.commands {
+            out.push_str("    ");
+            out.push_str(&write_command(command, &names));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn error(line: usize, message: &str) -> Box<Error> {
+    Box::from(SimpleError::new(format!("line {}: {}", line, message)))
+}
+
+fn number(line: usize, token: &str) -> Result<u8, Box<Error>> {
+    let value = if token.starts_with("0x") {
+        u8::from_str_radix(&token[2..], 16)
+    } else {
+        token.parse::<u8>()
+    };
+    value.map_err(|_| error(line, &format!("invalid number {}", token)))
+}
+
+pub fn note_name(note: u8) -> Option<String> {
+    if note < MIN_NOTE || note > MAX_NOTE {
+        return None;
+    }
+    let midi = note as i32 - NOTE_OFFSET;
+    Some(format!(
+        "{}{}",
+        NOTE_NAMES[(midi % 12) as usize],
+        midi / 12 - 1
+    ))
+}
+
+fn read_note(line: usize, token: &str) -> Result<Option<u8>, Box<Error>> {
+    let mut chars = token.chars();
+    let pitch = match chars.next() {
+        Some(c) if c >= 'a' && c <= 'g' => PITCHES[(c as u8 - b'a') as usize],
+        _ => return Ok(None),
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = if rest.starts_with('#') {
+        (1, &rest[1..])
+    } else if rest.starts_with('b') {
+        (-1, &rest[1..])
+    } else {
+        (0, rest)
+    };
+    let octave = match octave.parse::<i32>() {
+        Ok(octave) => octave,
+        Err(_) => return Ok(None),
+    };
+    let note = 12 * (octave + 1) + pitch + accidental + NOTE_OFFSET;
+    if note < MIN_NOTE as i32 || note > MAX_NOTE as i32 {
+        return Err(error(line, &format!("note {} is out of range", token)));
+    }
+    Ok(Some(note as u8))
+}
+
+fn read_command(
+    line: usize,
+    tokens: &[&str],
+    track_idx: &Fn(usize, &str) -> Result<usize, Box<Error>>,
+) -> Result<ParameterizedCommand, Box<Error>> {
+    let name = tokens[0];
+    let args_len = tokens[1..]
+        .iter()
+        .take_while(|token| !token.contains('='))
+        .count();
+    let (args, options) = tokens[1..].split_at(args_len);
+    let (mut duration, mut velocity, mut sustain) = (None, None, None);
+    for option in options {
+        let mut split = option.splitn(2, '=');
+        let key = split.next().unwrap_or("");
+        let value = Some(number(line, split.next().unwrap_or(""))?);
+        match key {
+            "len" => duration = value,
+            "vel" => velocity = value,
+            "sus" => sustain = value,
+            _ => return Err(error(line, &format!("unknown option {}", option))),
+        }
+    }
+    let command = if name == "call_loop" {
+        if args.len() != 2 {
+            return Err(error(line, "call_loop takes a track name and a count"));
+        }
+        Command::CallLoop(track_idx(line, args[0])?, number(line, args[1])?)
+    } else if let Some(note) = read_note(line, name)? {
+        if !args.is_empty() {
+            return Err(error(line, &format!("{} takes no arguments", name)));
+        }
+        Command::Note(note)
+    } else {
+        let params = args
+            .iter()
+            .map(|arg| number(line, arg))
+            .collect::<Result<Vec<u8>, Box<Error>>>()?;
+        let expected = match params_count(name) {
+            Some(expected) => expected,
+            None => return Err(error(line, &format!("unknown command {}", name))),
+        };
+        if params.len() != expected {
+            return Err(error(
+                line,
+                &format!(
+                    "{} takes {} argument{}",
+                    name,
+                    expected,
+                    if expected == 1 { "" } else { "s" }
+                ),
+            ));
+        }
+        make_command(name, &params)
+    };
+    Ok(ParameterizedCommand::new(
+        duration, velocity, sustain, command,
+    ))
+}
+
+fn params_count(name: &str) -> Option<usize> {
+    match name {
+        "rest" | "tie" | "vibrato_off" | "tremolo_off" | "pitch_envelope_off" | "echo_off" => {
+            Some(0)
+        }
+        "note"
+        | "set_instrument"
+        | "pan"
+        | "master_volume"
+        | "tempo"
+        | "global_transpose"
+        | "channel_transpose"
+        | "channel_volume"
+        | "vibrato_fade"
+        | "tuning"
+        | "percussion_patch_base" => Some(1),
+        "pan_fade" | "master_volume_fade" | "tempo_fade" | "channel_volume_fade" => Some(2),
+        "vibrato"
+        | "tremolo"
+        | "pitch_envelope_to"
+        | "pitch_envelope_from"
+        | "echo_volume"
+        | "echo_params"
+        | "echo_volume_fade"
+        | "pitch_slide" => Some(3),
+        _ => None,
+    }
+}
+
+fn make_command(name: &str, p: &[u8]) -> Command {
+    match name {
+        "note" => Command::Note(p[0]),
+        "rest" => Command::Rest,
+        "tie" => Command::Tie,
+        "set_instrument" => Command::SetInstrument(p[0]),
+        "pan" => Command::Pan(p[0]),
+        "pan_fade" => Command::PanFade(p[0], p[1]),
+        "vibrato" => Command::Vibrato(p[0], p[1], p[2]),
+        "vibrato_off" => Command::VibratoOff,
+        "master_volume" => Command::MasterVolume(p[0]),
+        "master_volume_fade" => Command::MasterVolumeFade(p[0], p[1]),
+        "tempo" => Command::Tempo(p[0]),
+        "tempo_fade" => Command::TempoFade(p[0], p[1]),
+        "global_transpose" => Command::GlobalTranspose(p[0]),
+        "channel_transpose" => Command::ChannelTranspose(p[0]),
+        "tremolo" => Command::Tremolo(p[0], p[1], p[2]),
+        "tremolo_off" => Command::TremoloOff,
+        "channel_volume" => Command::ChannelVolume(p[0]),
+        "channel_volume_fade" => Command::ChannelVolumeFade(p[0], p[1]),
+        "vibrato_fade" => Command::VibratoFade(p[0]),
+        "pitch_envelope_to" => Command::PitchEnvelopeTo(p[0], p[1], p[2]),
+        "pitch_envelope_from" => Command::PitchEnvelopeFrom(p[0], p[1], p[2]),
+        "pitch_envelope_off" => Command::PitchEnvelopeOff,
+        "tuning" => Command::Tuning(p[0]),
+        "echo_volume" => Command::EchoVolume(p[0], p[1], p[2]),
+        "echo_off" => Command::EchoOff,
+        "echo_params" => Command::EchoParams(p[0], p[1], p[2]),
+        "echo_volume_fade" => Command::EchoVolumeFade(p[0], p[1], p[2]),
+        "pitch_slide" => Command::PitchSlide(p[0], p[1], p[2]),
+        _ => Command::PercussionPatchBase(p[0]),
+    }
+}
+
+fn write_command(command: &ParameterizedCommand, names: &[String]) -> String {
+    let mut out = match *command.command() {
+        Command::Note(note) => note_name(note).unwrap_or_else(|| format!("note {}", note)),
+        Command::CallLoop(track, count) => match names.get(track) {
+            Some(name) => format!("call_loop {} {}", name, count),
+            None => format!("call_loop track{} {}", track, count),
+        },
+        ref other => {
+            let (name, params) = describe(other);
+            let mut out = name.to_string();
+            for param in params {
+                out.push_str(&format!(" {}", param));
+            }
+            out
+        }
+    };
+    let options = [
+        ("len", command.duration()),
+        ("vel", command.velocity()),
+        ("sus", command.sustain()),
+    ];
+    for (key, value) in options.iter() {
+        if let Some(value) = value {
+            out.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    out
+}
+
+fn describe(command: &Command) -> (&'static str, Vec<u8>) {
+    match *command {
+        Command::Note(note) => ("note", vec![note]),
+        Command::Rest => ("rest", vec![]),
+        Command::Tie => ("tie", vec![]),
+        Command::SetInstrument(p1) => ("set_instrument", vec![p1]),
+        Command::Pan(p1) => ("pan", vec![p1]),
+        Command::PanFade(p1, p2) => ("pan_fade", vec![p1, p2]),
+        Command::Vibrato(p1, p2, p3) => ("vibrato", vec![p1, p2, p3]),
+        Command::VibratoOff => ("vibrato_off", vec![]),
+        Command::MasterVolume(p1) => ("master_volume", vec![p1]),
+        Command::MasterVolumeFade(p1, p2) => ("master_volume_fade", vec![p1, p2]),
+        Command::Tempo(p1) => ("tempo", vec![p1]),
+        Command::TempoFade(p1, p2) => ("tempo_fade", vec![p1, p2]),
+        Command::GlobalTranspose(p1) => ("global_transpose", vec![p1]),
+        Command::ChannelTranspose(p1) => ("channel_transpose", vec![p1]),
+        Command::Tremolo(p1, p2, p3) => ("tremolo", vec![p1, p2, p3]),
+        Command::TremoloOff => ("tremolo_off", vec![]),
+        Command::ChannelVolume(p1) => ("channel_volume", vec![p1]),
+        Command::ChannelVolumeFade(p1, p2) => ("channel_volume_fade", vec![p1, p2]),
+        Command::CallLoop(_, p2) => ("call_loop", vec![p2]),
+        Command::VibratoFade(p1) => ("vibrato_fade", vec![p1]),
+        Command::PitchEnvelopeTo(p1, p2, p3) => ("pitch_envelope_to", vec![p1, p2, p3]),
+        Command::PitchEnvelopeFrom(p1, p2, p3) => ("pitch_envelope_from", vec![p1, p2, p3]),
+        Command::PitchEnvelopeOff => ("pitch_envelope_off", vec![]),
+        Command::Tuning(p1) => ("tuning", vec![p1]),
+        Command::EchoVolume(p1, p2, p3) => ("echo_volume", vec![p1, p2, p3]),
+        Command::EchoOff => ("echo_off", vec![]),
+        Command::EchoParams(p1, p2, p3) => ("echo_params", vec![p1, p2, p3]),
+        Command::EchoVolumeFade(p1, p2, p3) => ("echo_volume_fade", vec![p1, p2, p3]),
+        Command::PitchSlide(p1, p2, p3) => ("pitch_slide", vec![p1, p2, p3]),
+        Command::PercussionPatchBase(p1) => ("percussion_patch_base", vec![p1]),
+    }
+}
@@ -1,3 +1,4 @@
+use crate::midi::master_volume;
 use ghakuf::messages::*;
 use simple_error::SimpleError;
 use std::error::Error;
@@ -7,7 +8,132 @@ use super::command::*;
 use super::instruments::*;
 use super::CallLoopRef;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// the channel volume each track starts with
+const DEFAULT_VOLUME: u8 = 0xc8;
+// the shortest part of a held note, in ticks, that aftertouch swells over
+const MIN_SWELL_TICKS: u32 = 3;
+// the longest gap, in ticks, between master volume changes that are written as one fade
+const MAX_FADE_STEP_TICKS: u32 = 24;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Vec<(Message, u32)> {
+        let event = |event, time| (Message::MidiEvent { delta_time: 0, event }, time);
+        let pressure = |pressure, time| event(MidiEvent::ChannelPressure { ch: 0, pressure }, time);
+        vec![
+            event(MidiEvent::NoteOn { ch: 0, note: 60, velocity: 100 }, 0),
+            pressure(64, 0),
+            pressure(127, 12),
+            pressure(100, 13),
+            event(MidiEvent::NoteOff { ch: 0, note: 60, velocity: 0 }, 24),
+            event(MidiEvent::NoteOn { ch: 0, note: 62, velocity: 100 }, 24),
+            event(MidiEvent::NoteOff { ch: 0, note: 62, velocity: 0 }, 48),
+        ]
+    }
+
+    fn commands(track: &Track) -> Vec<(Command, Option<u8>)> {
+        track
+            .commands
+            .iter()
+            .map(|cmd| (cmd.command().clone(), cmd.duration()))
+            .collect()
+    }
+
+    #[test]
+    fn test_aftertouch_swells() {
+        let track = Track::new(&events(), 24, 48, 1.0, 0, true, &mut vec![]).unwrap();
+        assert_eq!(
+            vec![
+                (Command::ChannelVolume(100), None),
+                (Command::ChannelVolumeFade(12, 200), None),
+                (Command::Note(0xa4), Some(12)),
+                // the last swell is too close to get its own part of the note
+                (Command::ChannelVolumeFade(12, 157), None),
+                (Command::Tie, Some(12)),
+                (Command::ChannelVolume(200), None),
+                (Command::Note(0xa6), Some(24)),
+            ],
+            commands(&track)
+        );
+    }
+
+    #[test]
+    fn test_master_volume_fade() {
+        let volume = |value: u8, time| {
+            let data = vec![0x7f, 0x7f, 0x04, 0x01, 0x00, value, 0xf7];
+            (Message::SysExEvent { delta_time: 0, event: SysExEvent::F0, data }, time)
+        };
+        let events = vec![volume(100, 0), volume(80, 24), volume(60, 48), volume(127, 96)];
+        let track = Track::new(&events, 24, 96, 1.0, 0, false, &mut vec![]).unwrap();
+        assert_eq!(
+            vec![
+                (Command::MasterVolume(200), None),
+                (Command::MasterVolumeFade(48, 120), None),
+                (Command::Rest, Some(96)),
+                (Command::MasterVolume(254), None),
+            ],
+            commands(&track)
+        );
+    }
+
+    #[test]
+    fn test_master_volume_in_note() {
+        let volume = |value: u8, time| {
+            let data = vec![0x7f, 0x7f, 0x04, 0x01, 0x00, value, 0xf7];
+            (Message::SysExEvent { delta_time: 0, event: SysExEvent::F0, data }, time)
+        };
+        let event = |event, time| (Message::MidiEvent { delta_time: 0, event }, time);
+        let events = vec![
+            event(MidiEvent::NoteOn { ch: 0, note: 60, velocity: 100 }, 0),
+            volume(64, 12),
+            event(MidiEvent::NoteOff { ch: 0, note: 60, velocity: 0 }, 24),
+        ];
+        let track = Track::new(&events, 24, 24, 1.0, 0, false, &mut vec![]).unwrap();
+        assert_eq!(
+            vec![
+                (Command::Note(0xa4), Some(12)),
+                (Command::MasterVolume(128), None),
+                (Command::Tie, Some(12)),
+            ],
+            commands(&track)
+        );
+    }
+
+    #[test]
+    fn test_aftertouch_ignored() {
+        let track = Track::new(&events(), 24, 48, 1.0, 0, false, &mut vec![]).unwrap();
+        assert_eq!(
+            vec![(Command::Note(0xa4), Some(24)), (Command::Note(0xa6), Some(24))],
+            commands(&track)
+        );
+    }
+
+    #[test]
+    fn test_dropped_notes() {
+        let event = |event, time| (Message::MidiEvent { delta_time: 0, event }, time);
+        let events = vec![
+            event(MidiEvent::NoteOn { ch: 0, note: 62, velocity: 100 }, 0),
+            event(MidiEvent::NoteOff { ch: 0, note: 62, velocity: 0 }, 96),
+            event(MidiEvent::NoteOn { ch: 0, note: 60, velocity: 100 }, 96),
+            event(MidiEvent::NoteOff { ch: 0, note: 60, velocity: 0 }, 96),
+        ];
+        let mut warnings = Vec::new();
+        let track = Track::new(&events, 96, 192, 1.0, 2, false, &mut warnings).unwrap();
+        let notes = commands(&track)
+            .into_iter()
+            .filter(|&(ref command, _)| if let Command::Note(..) = *command { true } else { false })
+            .count();
+        assert_eq!(1, notes);
+        assert_eq!(
+            vec!["voice 3: a note at beat 1 is too short to get a length and was dropped"],
+            warnings
+        );
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct Track {
     pub commands: Vec<ParameterizedCommand>,
 }
@@ -15,6 +141,7 @@ pub struct Track {
 #[derive(Debug)]
 struct Duration {
     length: u8,
+    quantized_length: u32,
     quantized_ticks: u32,
     overflow_count: u8,
 }
@@ -35,12 +162,14 @@ impl Track {
             if overflow_amount == 0 {
                 Duration {
                     length: 0x7f,
+                    quantized_length,
                     quantized_ticks,
                     overflow_count: (overflow_count - 1) as u8,
                 }
             } else {
                 Duration {
                     length: overflow_amount as u8,
+                    quantized_length,
                     quantized_ticks,
                     overflow_count: overflow_count as u8,
                 }
@@ -48,6 +177,7 @@ impl Track {
         } else {
             Duration {
                 length: quantized_length as u8,
+                quantized_length,
                 quantized_ticks,
                 overflow_count: 0,
             }
@@ -84,20 +214,87 @@ impl Track {
         }
     }
 
+    // writes a held note, or the part of it since the last swell, tying it to
+    // what came before if it doesn't start the note
+    fn push_held(
+        commands: &mut Vec<ParameterizedCommand>,
+        duration: &Duration,
+        velocity: u8,
+        note: u8,
+        as_tie: bool,
+    ) {
+        commands.push(ParameterizedCommand::new(
+            Some(if duration.overflow_count > 0 {
+                0x7f
+            } else {
+                duration.length
+            }),
+            Some(velocity / 8),
+            Some(7),
+            if as_tie {
+                Command::Tie
+            } else {
+                Command::Note(note + 0x68)
+            },
+        ));
+        for i in 0..duration.overflow_count {
+            commands.push(ParameterizedCommand::new(
+                Some(if i < duration.overflow_count - 1 {
+                    0x7f
+                } else {
+                    duration.length
+                }),
+                None,
+                None,
+                Command::Tie,
+            ));
+        }
+    }
+
+    fn swell_volume(volume: u8, pressure: u8) -> u8 {
+        (volume as u32 * pressure.min(0x7f) as u32 / 0x7f) as u8
+    }
+
+    // sets the volume, replacing a change made at the same time
+    fn set_volume(commands: &mut Vec<ParameterizedCommand>, volume: u8) {
+        let command = ParameterizedCommand::new(None, None, None, Command::ChannelVolume(volume));
+        match commands.last_mut() {
+            Some(last) if last.is_volume() => *last = command,
+            _ => commands.push(command),
+        }
+    }
+
+    // fades over the length of a held note
+    fn fade_volume(duration: &Duration, volume: u8) -> ParameterizedCommand {
+        let length = duration.quantized_length.min(0xff) as u8;
+        ParameterizedCommand::new(None, None, None, Command::ChannelVolumeFade(length, volume))
+    }
+
     pub fn new(
         events: &Vec<(Message, u32)>,
         ticks_per_beat: u16,
         max_time: u32,
         tempo_factor: f32,
         voice: usize,
+        aftertouch: bool,
+        warnings: &mut Vec<String>,
     ) -> Result<Track, Box<Error>> {
         let mut commands = Vec::new();
         let mut note_start: Option<u32> = None;
         let mut note_velocity = 0;
+        let mut note_pitch = 0;
+        // whether part of the held note has been written for a swell
+        let mut note_split = false;
+        let mut slide_into_note = false;
         let mut last_note_end = 0u32;
         let mut portamento = false;
         let mut port_time = 0u16;
-        for &(ref message, abs_time) in events {
+        let mut volume = DEFAULT_VOLUME;
+        let mut pressure: Option<u8> = None;
+        // a swell too close to the last one to get its own part of the note
+        let mut pending_swell: Option<u8> = None;
+        let mut master_fade_end: Option<u32> = None;
+        for (i, &(ref message, abs_time)) in events.iter().enumerate() {
             match *message {
                 Message::MetaEvent {
                     ref event,
@@ -123,39 +320,38 @@ impl Track {
                             if let Some(start) = note_start {
                                 let duration =
                                     Track::get_duration(abs_time - start, ticks_per_beat, true);
-                                let push_as_tie =
-                                    commands.last().map_or(false, |cmd| cmd.is_slide());
-                                commands.push(ParameterizedCommand::new(
-                                    Some(if duration.overflow_count > 0 {
-                                        0x7f
-                                    } else {
-                                        duration.length
-                                    }),
-                                    Some(note_velocity / 8),
-                                    Some(7),
-                                    if push_as_tie {
-                                        Command::Tie
-                                    } else {
-                                        Command::Note(note + 0x68)
-                                    },
-                                ));
-                                for i in 0..duration.overflow_count {
-                                    commands.push(ParameterizedCommand::new(
-                                        Some(if i < duration.overflow_count - 1 {
-                                            0x7f
-                                        } else {
-                                            duration.length
-                                        }),
-                                        None,
-                                        None,
-                                        Command::Tie,
+                                let as_tie = note_split || slide_into_note;
+                                note_start = None;
+                                note_split = false;
+                                // a note too short to get a length can't be written
+                                if duration.length == 0 && duration.overflow_count == 0 {
+                                    warnings.push(format!(
+                                        "voice {}: a note at beat {} is too short to get a \
+                                         length and was dropped",
+                                        voice + 1,
+                                        start as f32 / ticks_per_beat as f32
                                     ));
+                                    pending_swell = None;
+                                    continue;
                                 }
+                                if let Some(target) = pending_swell.take() {
+                                    commands.push(Track::fade_volume(&duration, target));
+                                }
+                                Track::push_held(
+                                    &mut commands,
+                                    &duration,
+                                    note_velocity,
+                                    note,
+                                    as_tie,
+                                );
                                 last_note_end = start + duration.quantized_ticks;
-                                note_start = None;
                             }
                         }
                         MidiEvent::NoteOn { velocity, note, .. } => {
+                            // swells only last as long as the note they're in
+                            if pressure.take().is_some() {
+                                Track::set_volume(&mut commands, volume);
+                            }
                             last_note_end = Track::insert_rest(
                                 &mut commands,
                                 last_note_end,
@@ -178,16 +374,49 @@ impl Track {
                                         )
                                     });
                                 }
+                                slide_into_note = pitch_slide.is_some();
                                 pitch_slide.map(|slide| commands.push(slide));
+                            } else {
+                                slide_into_note = false;
                             }
                             if note_start.is_some() {
                                 return Err(Box::from(SimpleError::new(format!("More than one voice needed on voice {}: notes start at {} and {}", voice, note_start.unwrap(), abs_time))));
                             }
                             note_start = Some(last_note_end);
                             note_velocity = velocity;
+                            note_pitch = note;
                         }
-                        MidiEvent::PolyphonicKeyPressure { .. } => {
-                            // TODO
+                        MidiEvent::PolyphonicKeyPressure { velocity: value, .. }
+                        | MidiEvent::ChannelPressure { pressure: value, .. }
+                            if aftertouch =>
+                        {
+                            if let Some(start) = note_start {
+                                let target = Track::swell_volume(volume, value);
+                                let duration =
+                                    Track::get_duration(abs_time - start, ticks_per_beat, false);
+                                if duration.quantized_length == 0 {
+                                    Track::set_volume(&mut commands, target);
+                                    pending_swell = None;
+                                } else if duration.quantized_length < MIN_SWELL_TICKS {
+                                    pending_swell = Some(target);
+                                } else {
+                                    // fade over the part of the note since the last swell,
+                                    // then tie the rest of the note on
+                                    let as_tie = note_split || slide_into_note;
+                                    commands.push(Track::fade_volume(&duration, target));
+                                    Track::push_held(
+                                        &mut commands,
+                                        &duration,
+                                        note_velocity,
+                                        note_pitch,
+                                        as_tie,
+                                    );
+                                    note_start = Some(start + duration.quantized_ticks);
+                                    note_split = true;
+                                    pending_swell = None;
+                                }
+                                pressure = Some(value);
+                            }
                         }
                         MidiEvent::ControlChange { control, data, .. } => {
                             match control {
@@ -197,11 +426,16 @@ impl Track {
                                 }
                                 7 => {
                                     // channel volume
+                                    volume = data * 2;
                                     commands.push(ParameterizedCommand::new(
                                         None,
                                         None,
                                         None,
-                                        Command::ChannelVolume(data * 2),
+                                        Command::ChannelVolume(
+                                            pressure.map_or(volume, |value| {
+                                                Track::swell_volume(volume, value)
+                                            }),
+                                        ),
                                     ));
                                 }
                                 37 => {
@@ -235,9 +469,6 @@ impl Track {
                                 Command::SetInstrument(instrument),
                             ));
                         }
-                        MidiEvent::ChannelPressure { .. } => {
-                            // TODO
-                        }
                         MidiEvent::PitchBendChange { .. } => {
                             // TODO
                         }
@@ -245,7 +476,73 @@ impl Track {
                     }
                 }
                 Message::SysExEvent { .. } => {
-                    // TODO (global volume?)
+                    if let Some(volume) = master_volume(message) {
+                        if master_fade_end.map_or(false, |end| abs_time <= end) {
+                            continue;
+                        }
+                        // changes close together are taken as steps of one fade
+                        let mut end = (abs_time, volume);
+                        for &(ref next, next_time) in &events[i + 1..] {
+                            if let Some(next_volume) = master_volume(next) {
+                                let step = next_time - end.0;
+                                if Track::get_duration(step, ticks_per_beat, false).quantized_length
+                                    > MAX_FADE_STEP_TICKS
+                                {
+                                    break;
+                                }
+                                end = (next_time, next_volume);
+                            }
+                        }
+                        match note_start {
+                            // a held note is split so the change comes at its time
+                            Some(start) => {
+                                let duration =
+                                    Track::get_duration(abs_time - start, ticks_per_beat, false);
+                                if duration.quantized_length > 0 {
+                                    if let Some(target) = pending_swell.take() {
+                                        commands.push(Track::fade_volume(&duration, target));
+                                    }
+                                    let as_tie = note_split || slide_into_note;
+                                    Track::push_held(
+                                        &mut commands,
+                                        &duration,
+                                        note_velocity,
+                                        note_pitch,
+                                        as_tie,
+                                    );
+                                    note_start = Some(start + duration.quantized_ticks);
+                                    note_split = true;
+                                }
+                            }
+                            None => {
+                                last_note_end = Track::insert_rest(
+                                    &mut commands,
+                                    last_note_end,
+                                    abs_time,
+                                    ticks_per_beat,
+                                );
+                            }
+                        }
+                        commands.push(ParameterizedCommand::new(
+                            None,
+                            None,
+                            None,
+                            Command::MasterVolume(volume),
+                        ));
+                        if end.0 > abs_time {
+                            let fade = Track::get_duration(end.0 - abs_time, ticks_per_beat, false);
+                            commands.push(ParameterizedCommand::new(
+                                None,
+                                None,
+                                None,
+                                Command::MasterVolumeFade(
+                                    fade.quantized_length.min(0xff) as u8,
+                                    end.1,
+                                ),
+                            ));
+                            master_fade_end = Some(end.0);
+                        }
+                    }
                 }
                 _ => {}
             }
@@ -280,13 +577,92 @@ impl Track {
         out: &mut Cursor<Vec<u8>>,
         call_loops: &mut Vec<CallLoopRef>,
     ) -> Result<(), Box<Error>> {
-        let mut duration = 0xff;
-        let mut velocity = None;
+        let mut state = WriteState::new();
         for cmd in &self.commands {
-            let (duration_out, velocity_out) = cmd.write(out, duration, velocity, call_loops)?;
-            duration = duration_out;
-            velocity = velocity_out;
+            cmd.write(out, &mut state, call_loops)?;
         }
         Ok(())
     }
+
+    /// Reads a track back from its data, which ends at the first end command.
+    pub fn read(data: &[u8], call_loops: &[CallLoopRef]) -> Result<Track, Box<Error>> {
+        let mut commands = Vec::new();
+        let mut duration = None;
+        let mut velocity_sustain = None;
+        let mut pos = 0;
+        while pos < data.len() {
+            match data[pos] {
+                0x00 => break,
+                length @ 0x01..=0x7f => {
+                    duration = Some(length);
+                    pos += 1;
+                    if pos < data.len() && data[pos] < 0x80 {
+                        velocity_sustain = Some(data[pos]);
+                        pos += 1;
+                    }
+                }
+                _ => {
+                    let (command, length) = Command::read(data, pos, call_loops)?;
+                    commands.push(match command {
+                        // the writer resets the duration after a CallLoop this way
+                        Command::CallLoop(..) => {
+                            ParameterizedCommand::new(Some(0), Some(0), Some(0), command)
+                        }
+                        _ => ParameterizedCommand::new(
+                            duration.take(),
+                            velocity_sustain.map(|value| value & 0x0f),
+                            velocity_sustain.take().map(|value| value >> 4),
+                            command,
+                        ),
+                    });
+                    pos += length;
+                }
+            }
+        }
+        Ok(Track { commands })
+    }
+
+    /// Inserts a command at a tick from the start of the track, after anything
+    /// else that runs then, splitting whatever plays over that tick if it can.
+    pub fn insert_at(&mut self, time: u32, command: ParameterizedCommand) {
+        let mut now = 0;
+        let mut duration = 0;
+        for i in 0..self.commands.len() {
+            duration = self.commands[i].duration().unwrap_or(duration);
+            let length = match *self.commands[i].command() {
+                Command::Note(..) | Command::Tie | Command::Rest => duration as u32,
+                _ => 0,
+            };
+            if length > 0 && now >= time {
+                self.commands.insert(i, command);
+                return;
+            }
+            if now + length > time {
+                match self.commands[i].split((time - now) as u8) {
+                    Some((first, rest)) => {
+                        self.commands[i] = first;
+                        self.commands.insert(i + 1, command);
+                        self.commands.insert(i + 2, rest);
+                    }
+                    None => self.commands.insert(i, command),
+                }
+                return;
+            }
+            now += length;
+        }
+        self.commands.push(command);
+    }
+
+    pub fn command_sizes(&self) -> Result<Vec<usize>, Box<Error>> {
+        let mut out = Cursor::new(Vec::new());
+        let mut call_loops = Vec::new();
+        let mut state = WriteState::new();
+        let mut sizes = Vec::with_capacity(self.commands.len());
+        for cmd in &self.commands {
+            let start = out.position();
+            cmd.write(&mut out, &mut state, &mut call_loops)?;
+            sizes.push((out.position() - start) as usize);
+        }
+        Ok(sizes)
+    }
 }
@@ -0,0 +1,341 @@
+use crate::error::ConvertError;
+use simple_error::SimpleError;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+// a record can't start here since the offset would read as the footer
+const IPS_EOF_OFFSET: usize = 0x454F46;
+const IPS_MAX_OFFSET: usize = 0xFFFFFF;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+// unchanged bytes worth including in a record rather than starting a new one
+const IPS_MERGE_GAP: usize = 5;
+// identical bytes worth writing as an RLE record
+const IPS_MIN_RUN: usize = 16;
+
+const BPS_HEADER: &[u8] = b"BPS1";
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_ips(source: &[u8], patch: &[u8]) -> Vec<u8> {
+        assert_eq!(IPS_HEADER, &patch[..5]);
+        let mut target = source.to_vec();
+        let mut pos = 5;
+        while &patch[pos..pos + 3] != IPS_FOOTER {
+            let offset = ((patch[pos] as usize) << 16)
+                + ((patch[pos + 1] as usize) << 8)
+                + (patch[pos + 2] as usize);
+            let size = ((patch[pos + 3] as usize) << 8) + (patch[pos + 4] as usize);
+            pos += 5;
+            let data = if size == 0 {
+                let run = ((patch[pos] as usize) << 8) + (patch[pos + 1] as usize);
+                pos += 3;
+                vec![patch[pos - 1]; run]
+            } else {
+                pos += size;
+                patch[pos - size..pos].to_vec()
+            };
+            if target.len() < offset + data.len() {
+                target.resize(offset + data.len(), 0);
+            }
+            target[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        target
+    }
+
+    fn decode(patch: &[u8], pos: &mut usize) -> u64 {
+        let mut data = 0;
+        let mut shift = 1;
+        loop {
+            let x = patch[*pos] as u64;
+            *pos += 1;
+            data += (x & 0x7f) * shift;
+            if x & 0x80 != 0 {
+                return data;
+            }
+            shift <<= 7;
+            data += shift;
+        }
+    }
+
+    fn apply_bps(source: &[u8], patch: &[u8]) -> Vec<u8> {
+        assert_eq!(BPS_HEADER, &patch[..4]);
+        let footer = patch.len() - 12;
+        assert_eq!(crc32(&patch[..footer + 8]), read_u32(&patch[footer + 8..]));
+        assert_eq!(crc32(source), read_u32(&patch[footer..]));
+        let mut pos = 4;
+        assert_eq!(source.len() as u64, decode(patch, &mut pos));
+        let target_size = decode(patch, &mut pos) as usize;
+        assert_eq!(0, decode(patch, &mut pos));
+        let mut target = Vec::new();
+        while pos < footer {
+            let action = decode(patch, &mut pos);
+            let length = (action >> 2) as usize + 1;
+            match action & 3 {
+                BPS_SOURCE_READ => {
+                    let start = target.len();
+                    target.extend_from_slice(&source[start..start + length]);
+                }
+                BPS_TARGET_READ => {
+                    target.extend_from_slice(&patch[pos..pos + length]);
+                    pos += length;
+                }
+                _ => panic!("unexpected BPS action"),
+            }
+        }
+        assert_eq!(target_size, target.len());
+        assert_eq!(crc32(&target), read_u32(&patch[footer + 4..]));
+        target
+    }
+
+    fn read_u32(bytes: &[u8]) -> u32 {
+        (bytes[0] as u32)
+            | ((bytes[1] as u32) << 8)
+            | ((bytes[2] as u32) << 16)
+            | ((bytes[3] as u32) << 24)
+    }
+
+    fn modified() -> (Vec<u8>, Vec<u8>) {
+        let source = (0..0x20000).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+        let mut target = source.clone();
+        target[0x10] = 0xff;
+        target[0x12] = 0xfe;
+        for i in 0x1000..0x1400 {
+            target[i] = 0x55;
+        }
+        for i in 0x8000..0x18000 {
+            target[i] = (i % 13) as u8;
+        }
+        target.resize(0x28000, 0);
+        target[0x20010] = 1;
+        (source, target)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xCBF43926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn test_ips_round_trip() {
+        let (source, target) = modified();
+        let patch = create_ips(&source, &target).unwrap();
+        assert_eq!(target, apply_ips(&source, &patch));
+        assert_eq!(source, apply_ips(&source, &create_ips(&source, &source).unwrap()));
+    }
+
+    #[test]
+    fn test_ips_avoids_eof_offset() {
+        let source = vec![0; IPS_EOF_OFFSET + 4];
+        let mut target = source.clone();
+        target[IPS_EOF_OFFSET] = 1;
+        let patch = create_ips(&source, &target).unwrap();
+        assert_eq!(&[0x45, 0x4F, 0x45], &patch[5..8]);
+        assert_eq!(target, apply_ips(&source, &patch));
+    }
+
+    #[test]
+    fn test_ips_shrinking_rom() {
+        assert!(create_ips(&[0; 4], &[0; 2]).is_err());
+    }
+
+    #[test]
+    fn test_bps_round_trip() {
+        let (source, target) = modified();
+        let patch = create_bps(&source, &target);
+        assert_eq!(target, apply_bps(&source, &patch));
+        assert_eq!(source, apply_bps(&source, &create_bps(&source, &source)));
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    pub fn from_path(path: &Path) -> Result<PatchFormat, Box<Error>> {
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+        {
+            Some(ref ext) if ext == "ips" => Ok(PatchFormat::Ips),
+            Some(ref ext) if ext == "bps" => Ok(PatchFormat::Bps),
+            _ => Err(Box::from(SimpleError::new(format!(
+                "Patch file {} should have an .ips or .bps extension",
+                path.display()
+            )))),
+        }
+    }
+}
+
+/// Writes a patch converting source into target, in the format given by the
+/// extension of path.
+pub fn write(source: &[u8], target: &[u8], path: &Path) -> Result<(), Box<Error>> {
+    let patch = match PatchFormat::from_path(path)? {
+        PatchFormat::Ips => create_ips(source, target)?,
+        PatchFormat::Bps => create_bps(source, target),
+    };
+    fs::write(path, patch).map_err(ConvertError::io(path))?;
+    Ok(())
+}
+
+pub fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    if target.len() < source.len() {
+        return Err(Box::from(SimpleError::new(
+            "IPS patches can't shrink a ROM",
+        )));
+    }
+    if target.len() > IPS_MAX_OFFSET + 1 {
+        return Err(Box::from(SimpleError::new(format!(
+            "ROM of 0x{:X} bytes is too large for an IPS patch",
+            target.len()
+        ))));
+    }
+    // bytes past the end of the source are always written so the patched
+    // file is expanded to the full length
+    let changed = |i: usize| i >= source.len() || source[i] != target[i];
+    let mut patch = IPS_HEADER.to_vec();
+    let mut i = 0;
+    while i < target.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+        let start = if i == IPS_EOF_OFFSET { i - 1 } else { i };
+        let mut end = i + 1;
+        while end < target.len() && end - start < IPS_MAX_RECORD {
+            if changed(end) {
+                end += 1;
+            } else {
+                match (end..(end + IPS_MERGE_GAP).min(target.len())).find(|&j| changed(j)) {
+                    Some(next) if next - start < IPS_MAX_RECORD => end = next,
+                    _ => break,
+                }
+            }
+        }
+        write_ips_records(&mut patch, target, start, end);
+        i = end;
+    }
+    patch.extend_from_slice(IPS_FOOTER);
+    Ok(patch)
+}
+
+// writes target[start..end], splitting out long runs of one byte as RLE records
+fn write_ips_records(patch: &mut Vec<u8>, target: &[u8], start: usize, end: usize) {
+    let mut literal_start = start;
+    let mut i = start;
+    while i < end {
+        let run_end = (i..end).find(|&j| target[j] != target[i]).unwrap_or(end);
+        // neither record may start at the footer offset
+        let rle_end = if run_end == IPS_EOF_OFFSET {
+            run_end - 1
+        } else {
+            run_end
+        };
+        if rle_end >= i + IPS_MIN_RUN && i != IPS_EOF_OFFSET {
+            if literal_start < i {
+                write_ips_header(patch, literal_start, i - literal_start);
+                patch.extend_from_slice(&target[literal_start..i]);
+            }
+            write_ips_header(patch, i, 0);
+            patch.push(((rle_end - i) >> 8) as u8);
+            patch.push((rle_end - i) as u8);
+            patch.push(target[i]);
+            literal_start = rle_end;
+        }
+        i = run_end;
+    }
+    if literal_start < end {
+        write_ips_header(patch, literal_start, end - literal_start);
+        patch.extend_from_slice(&target[literal_start..end]);
+    }
+}
+
+fn write_ips_header(patch: &mut Vec<u8>, offset: usize, size: usize) {
+    patch.push((offset >> 16) as u8);
+    patch.push((offset >> 8) as u8);
+    patch.push(offset as u8);
+    patch.push((size >> 8) as u8);
+    patch.push(size as u8);
+}
+
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_HEADER.to_vec();
+    write_bps_number(&mut patch, source.len() as u64);
+    write_bps_number(&mut patch, target.len() as u64);
+    // no metadata
+    write_bps_number(&mut patch, 0);
+    let same = |i: usize| i < source.len() && source[i] == target[i];
+    let mut i = 0;
+    while i < target.len() {
+        let is_same = same(i);
+        let end = (i..target.len())
+            .find(|&j| same(j) != is_same)
+            .unwrap_or_else(|| target.len());
+        let action = if is_same {
+            BPS_SOURCE_READ
+        } else {
+            BPS_TARGET_READ
+        };
+        write_bps_number(&mut patch, (((end - i - 1) as u64) << 2) | action);
+        if !is_same {
+            patch.extend_from_slice(&target[i..end]);
+        }
+        i = end;
+    }
+    write_u32(&mut patch, crc32(source));
+    write_u32(&mut patch, crc32(target));
+    let patch_crc = crc32(&patch);
+    write_u32(&mut patch, patch_crc);
+    patch
+}
+
+fn write_bps_number(patch: &mut Vec<u8>, mut data: u64) {
+    loop {
+        let x = (data & 0x7f) as u8;
+        data >>= 7;
+        if data == 0 {
+            patch.push(0x80 | x);
+            return;
+        }
+        patch.push(x);
+        data -= 1;
+    }
+}
+
+fn write_u32(patch: &mut Vec<u8>, value: u32) {
+    patch.extend_from_slice(&[
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]);
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for i in 0..256 {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xEDB88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+        table[i] = crc;
+    }
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
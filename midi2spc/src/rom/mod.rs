@@ -1,26 +1,92 @@
-use pbr::*;
 use simple_error::SimpleError;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
-use std::io::{Cursor, SeekFrom};
+use std::io::Cursor;
 use std::path::Path;
-use std::thread;
 
+use crate::error::ConvertError;
+use crate::log::Event;
 use crate::manifest::*;
-use crate::nspc::{CallLoopRef, Song};
+use crate::midi::Transform;
+use crate::patch;
+use crate::nspc::{errors_in, CallLoopRef, OptimizeBudget, Song, PART_TABLE_SIZE};
+
+mod original;
+pub mod report;
+mod sfx;
+
+use self::original::*;
+use self::report::*;
 
 pub const DEFAULT_BANK_BASE_ADDRS: [u32; 3] = [0x914, 0x926, 0x932];
+const DEFAULT_BANK_NAMES: [&str; 3] = ["overworld", "indoor", "ending"];
 const BANK_FIRST_SONG_ADDRS: [usize; 3] = [0xD036, 0xD046, 0xD046];
+const BANK_FIRST_SONGS: [usize; 3] = [0, 15, 31];
 const ARAM_BASE: usize = 0xd000;
+// sample directory, instrument table and sample data
+const SAMPLE_ARAM: (usize, usize) = (0x3c00, 0xd000);
+// the driver places the echo buffer at the top of ARAM, 0x800 bytes per unit of delay
+const ECHO_BUFFER_UNIT: usize = 0x800;
+
+const LOROM_BANK_SIZE: usize = 0x8000;
+const MAX_ROM_SIZE: usize = 0x400000;
+const ROM_SIZE_HEADER_ADDR: usize = 0x7FD7;
 
 const DEFAULT_ASM_LABEL_PREFIX: &str = "music";
 const DEFAULT_ASM_MODULE_PREFIX: &str = "music";
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reserved() {
+        let bank = Bank {
+            name: "overworld".to_string(),
+            base_addr: None,
+            first_song: None,
+            first_song_addr: None,
+            songs: vec![],
+        };
+        let reserved = vec![ReservedRange {
+            name: "echo buffer".to_string(),
+            start: 0xf000,
+            end: 0x10000,
+        }];
+        assert!(check_reserved(&reserved, 0xefe0, 0x20, &bank, "a.mid", "track 1").is_ok());
+        let err = check_reserved(&reserved, 0xeff0, 0x20, &bank, "a.mid", "track 2")
+            .unwrap_err()
+            .downcast::<ConvertError>()
+            .unwrap();
+        match *err {
+            ConvertError::LayoutOverflow {
+                ref bank,
+                ref song,
+                ref message,
+            } => {
+                assert_eq!(Some("overworld"), bank.as_ref().map(String::as_str));
+                assert_eq!(Some("a.mid"), song.as_ref().map(String::as_str));
+                assert_eq!(
+                    "track 2 at 0xEFF0-0xF010 collides with echo buffer at 0xF000-0x10000",
+                    message
+                );
+            }
+            ref err => panic!("unexpected error {:?}", err),
+        }
+    }
+}
+
 fn snes_to_pc_addr(snes_addr: u32) -> usize {
     ((snes_addr & 0x7FFF) + ((snes_addr / 2) & 0xFF8000)) as usize
 }
 
+fn pc_to_snes_addr(pc_addr: usize) -> u32 {
+    (((pc_addr << 1) & 0xFF0000) | (pc_addr & 0x7FFF) | 0x8000) as u32
+}
+
 fn snes_bytes_to_pc_addr(bank: u8, high: u8, low: u8) -> usize {
     snes_to_pc_addr(((bank as u32) << 16) + ((high as u32) << 8) + (low as u32))
 }
@@ -29,10 +95,30 @@ fn addr_to_bytes(addr: usize) -> (u8, u8) {
     ((addr >> 8 & 0xFF) as u8, (addr & 0xFF) as u8)
 }
 
+// verbose output goes to the log along with the events
+fn detail(log: &Fn(&Event), message: &str) {
+    log(&Event::Detail { message });
+}
+
+// the name an input is logged by, which is its file name when it has one
+fn input_name(path: &Path) -> Cow<'_, str> {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+}
+
+/// Writes music banks to free ROM space instead of over their original song
+/// chunks.  The pointer at each bank base address is updated to the new
+/// location, and any extra ARAM chunks are added after the original ones.
+#[derive(Clone, Debug, Default)]
+pub struct Relocation {
+    pub rom_addr: usize,
+    pub aram_chunks: Vec<(usize, usize)>,
+}
+
 struct RomCallLoopRef {
     pub target_track: usize,
-    pub chunk_base: usize,
-    pub ref_pos: u64,
+    pub chunk_idx: usize,
+    pub ref_pos: usize,
 }
 
 struct Chunk {
@@ -62,435 +148,1094 @@ impl Chunk {
     }
 }
 
+// loads the APU transfer blocks starting at start_addr, including the
+// zero-length terminator
+fn load_chunks(romdata: &Vec<u8>, start_addr: usize) -> Result<Vec<Chunk>, Box<Error>> {
+    let mut chunks = Vec::new();
+    let mut addr = start_addr;
+    loop {
+        if addr + 4 > romdata.len() {
+            return Err(Box::from(SimpleError::new(format!(
+                "Chunk header at 0x{:X} is past the end of the ROM",
+                addr
+            ))));
+        }
+        let chunk = Chunk::load(romdata, addr);
+        if chunk.offset_addr + chunk.length > romdata.len() {
+            return Err(Box::from(SimpleError::new(format!(
+                "Chunk at 0x{:X} extends past the end of the ROM",
+                addr
+            ))));
+        }
+        addr = chunk.offset_addr + chunk.length;
+        let last = chunk.length == 0;
+        chunks.push(chunk);
+        if last {
+            return Ok(chunks);
+        }
+    }
+}
+
+// song data staged for one ARAM chunk before being written to the ROM
+struct SongChunk {
+    rom_addr: Option<usize>,
+    aram_addr: usize,
+    data: Vec<u8>,
+    used: usize,
+}
+
+struct BankSummary {
+    report: BankReport,
+    rom_end: Option<usize>,
+}
+
+fn chunk_name(chunk_idx: usize) -> String {
+    match chunk_idx {
+        0 => "base".to_string(),
+        1 => "overflow".to_string(),
+        _ => format!("overflow{}", chunk_idx),
+    }
+}
+
+fn check_reserved(
+    reserved: &[ReservedRange],
+    aram_addr: usize,
+    size: usize,
+    bank: &Bank,
+    song_name: &str,
+    what: &str,
+) -> Result<(), Box<Error>> {
+    match reserved
+        .iter()
+        .find(|range| range.overlaps(aram_addr, size))
+    {
+        Some(range) => Err(Box::from(ConvertError::layout(
+            &bank.name,
+            Some(song_name),
+            format!(
+                "{} at 0x{:X}-0x{:X} collides with {} at 0x{:X}-0x{:X}",
+                what,
+                aram_addr,
+                aram_addr + size,
+                range.name,
+                range.start,
+                range.end
+            ),
+        ))),
+        None => Ok(()),
+    }
+}
+
+// where a bank's songs go: its base address, the ARAM address of its first
+// song and that song's number
+struct BankAddrs {
+    base_addr: u32,
+    first_song_addr: usize,
+    first_song: usize,
+}
+
+// the bank's addresses, with banks not given them falling back to the default
+// bank of the same name, or for the first song, following the songs of the
+// bank before
+fn bank_addrs(
+    bank: &Bank,
+    bank_base_addrs: [u32; 3],
+    next_song: usize,
+) -> Result<BankAddrs, Box<Error>> {
+    let default_idx = DEFAULT_BANK_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(&bank.name));
+    let base_addr = match bank
+        .base_addr
+        .or_else(|| default_idx.map(|idx| bank_base_addrs[idx]))
+    {
+        Some(base_addr) => base_addr,
+        None => {
+            return Err(Box::from(SimpleError::new(format!(
+                "No base address given for {} bank",
+                bank.name
+            ))));
+        }
+    };
+    let first_song_addr = match bank
+        .first_song_addr
+        .or_else(|| default_idx.map(|idx| BANK_FIRST_SONG_ADDRS[idx]))
+    {
+        Some(first_song_addr) => first_song_addr,
+        None => {
+            return Err(Box::from(SimpleError::new(format!(
+                "No first song address given for {} bank",
+                bank.name
+            ))));
+        }
+    };
+    let first_song = bank
+        .first_song
+        .or_else(|| default_idx.map(|idx| BANK_FIRST_SONGS[idx]))
+        .unwrap_or(next_song);
+    Ok(BankAddrs {
+        base_addr,
+        first_song_addr,
+        first_song,
+    })
+}
+
+// the APU transfer blocks of the bank at base_addr
+fn bank_chunks(romdata: &Vec<u8>, base_addr: u32) -> Result<Vec<Chunk>, Box<Error>> {
+    let bank_addr = romdata[snes_to_pc_addr(base_addr + 8)];
+    let high_addr = romdata[snes_to_pc_addr(base_addr + 4)];
+    let low_addr = romdata[snes_to_pc_addr(base_addr)];
+    load_chunks(romdata, snes_bytes_to_pc_addr(bank_addr, high_addr, low_addr))
+}
+
+/// How `write` builds a ROM from a manifest: where the result goes, how
+/// songs are converted and laid out, and what's reported along the way.
+pub struct BuildOptions<'a> {
+    pub output_path: Option<&'a Path>,
+    pub patch_path: Option<&'a Path>,
+    pub bank_base_addrs: [u32; 3],
+    pub converter: &'a Fn(&Path, f32, &Transform) -> Result<Song, Box<Error>>,
+    pub optimize: Option<OptimizeBudget>,
+    pub relocation: Option<&'a Relocation>,
+    pub log: &'a Fn(&Event),
+    pub verbose: bool,
+    pub asm_file: Option<&'a str>,
+    pub asm_module: Option<&'a str>,
+    pub asm_label: Option<&'a str>,
+}
+
 pub fn write(
     manifest: &Manifest,
     path: &Path,
-    bank_base_addrs: [u32; 3],
-    converter: &Fn(&Path, f32) -> Result<Song, Box<Error>>,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
-) -> Result<(), Box<Error>> {
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(asm_file.is_none())
-        .open(path)?;
-    let mut romdata = Vec::new();
-    file.read_to_end(&mut romdata)?;
+    build: &BuildOptions,
+) -> Result<Report, Box<Error>> {
+    let BuildOptions {
+        output_path,
+        patch_path,
+        bank_base_addrs,
+        converter,
+        log,
+        verbose,
+        ..
+    } = *build;
+    let mut romdata = fs::read(path).map_err(ConvertError::io(path))?;
+    let original = if patch_path.is_some() {
+        romdata.clone()
+    } else {
+        vec![]
+    };
+    let original_len = romdata.len();
 
-    let num_songs = manifest
-        .banks
-        .iter()
-        .fold(0, |acc, bank| acc + bank.songs.len());
-    let mut mb = MultiBar::new();
-    let mut songs_pb = mb.create_bar(num_songs as u64);
-    let mut bank_pbs = manifest
-        .banks
-        .iter()
-        .map(|bank| {
-            let mut pb = mb.create_bar(1);
-            pb.message(&format!("{} ", bank.name));
-            pb.format("[==-]");
-            pb.show_speed = false;
-            pb.show_time_left = false;
-            pb.show_counter = false;
-            pb.set(0);
-            pb
-        })
-        .collect::<Vec<ProgressBar<Pipe>>>();
+    let mut relocation = build.relocation.cloned();
+    let mut report = Report::default();
+    // the driver's data is updated first, in case a relocated bank copies it
+    if let Some(sfx) = &manifest.sfx {
+        report.sfx = Some(sfx::write(sfx, &mut romdata, converter, log, verbose)?);
+    }
+    let mut next_song = 0;
+    for (i, bank) in manifest.banks.iter().enumerate() {
+        let addrs = bank_addrs(bank, bank_base_addrs, next_song)?;
+        log(&Event::BankStarted {
+            bank: &bank.name,
+            songs: bank.songs.len(),
+        });
+        let summary = write_bank(bank, &mut romdata, &addrs, relocation.as_ref(), build, i == 0)?;
+        log(&Event::BankFinished {
+            report: &summary.report,
+        });
+        if let (Some(relocation), Some(rom_end)) = (relocation.as_mut(), summary.rom_end) {
+            relocation.rom_addr = rom_end;
+        }
+        report.banks.push(summary.report);
+        next_song = addrs.first_song + bank.songs.len();
+    }
 
-    let mb_thread = thread::spawn(move || {
-        mb.listen();
-    });
+    if romdata.len() > original_len {
+        expand_rom(&mut romdata, log, verbose)?;
+    }
+    if let Some(output_path) = output_path {
+        fs::write(output_path, &romdata).map_err(ConvertError::io(output_path))?;
+    }
+    if let Some(patch_path) = patch_path {
+        patch::write(&original, &romdata, patch_path)?;
+    }
+    Ok(report)
+}
 
-    manifest.banks.iter().enumerate().fold(
-        Ok(0),
-        |first_song_result: Result<usize, Box<Error>>, (i, bank)| {
-            first_song_result.and_then(|first_song| {
-                songs_pb.message(&format!("Writing {} songs ", bank.name));
-                write_bank(
-                    bank,
-                    &mut romdata,
-                    bank_base_addrs[i],
-                    BANK_FIRST_SONG_ADDRS[i],
-                    first_song,
-                    converter,
-                    &mut songs_pb,
-                    &mut bank_pbs[i],
-                    verbose,
-                    asm_file,
-                    asm_module,
-                    asm_label,
-                    i == 0,
-                )?;
-                bank_pbs[i].finish_print(&format!("{} bank complete.", bank.name));
-                Ok(first_song + bank.songs.len())
-            })
-        },
-    )?;
+/// Reads back the songs in the manifest's banks as they were written to the
+/// ROM, by bank and slot.  Slots that don't play a song are None.
+pub fn read(
+    manifest: &Manifest,
+    path: &Path,
+    bank_base_addrs: [u32; 3],
+) -> Result<Vec<Vec<Option<Song>>>, Box<Error>> {
+    let romdata = fs::read(path).map_err(ConvertError::io(path))?;
+    let mut banks = Vec::new();
+    let mut next_song = 0;
+    for bank in &manifest.banks {
+        let BankAddrs {
+            base_addr,
+            first_song,
+            ..
+        } = bank_addrs(bank, bank_base_addrs, next_song)?;
+        let mut aram = AramImage::new();
+        for chunk in bank_chunks(&romdata, base_addr)? {
+            aram.load(
+                chunk.aram_addr,
+                &romdata[chunk.offset_addr..chunk.offset_addr + chunk.length],
+            );
+        }
+        let mut song_addrs = Vec::<Option<usize>>::new();
+        for slot in 0..bank.songs.len() {
+            song_addrs.push(Some(aram.word(ARAM_BASE + (first_song + slot) * 2)?));
+        }
+        let songs = read_songs(&aram, &song_addrs, first_song)?;
+        let mut bank_songs = Vec::new();
+        for song_addr in &song_addrs {
+            bank_songs.push(match song_addr.and_then(|addr| songs.get(&addr)) {
+                Some(song) => Some(song.to_song()?),
+                None => None,
+            });
+        }
+        banks.push(bank_songs);
+        next_song = first_song + bank.songs.len();
+    }
+    Ok(banks)
+}
 
-    if asm_file.is_none() {
-        file.seek(SeekFrom::Start(0))?;
-        file.write(&romdata)?;
+// pads the ROM to a whole number of banks and updates the size in the header
+fn expand_rom(romdata: &mut Vec<u8>, log: &Fn(&Event), verbose: bool) -> Result<(), Box<Error>> {
+    let len = (romdata.len() + LOROM_BANK_SIZE - 1) / LOROM_BANK_SIZE * LOROM_BANK_SIZE;
+    if len > MAX_ROM_SIZE {
+        return Err(Box::from(ConvertError::LayoutOverflow {
+            bank: None,
+            song: None,
+            message: format!(
+                "Relocated banks need 0x{:X} bytes of ROM, more than the maximum of 0x{:X}",
+                romdata.len(),
+                MAX_ROM_SIZE
+            ),
+        }));
+    }
+    romdata.resize(len, 0);
+    let mut size_kb = 1;
+    let mut size_byte = 0;
+    while size_kb * 1024 < len {
+        size_kb *= 2;
+        size_byte += 1;
+    }
+    romdata[ROM_SIZE_HEADER_ADDR] = size_byte;
+    if verbose {
+        detail(log, &format!("Expanded ROM to 0x{:X} bytes", len));
     }
-    songs_pb.finish_print("All songs written.");
-    mb_thread.join().unwrap();
     Ok(())
 }
 
+fn next_chunk(
+    song_chunks: &mut Vec<SongChunk>,
+    chunk_idx: usize,
+    used: usize,
+    bank: &Bank,
+    song_name: &str,
+    log: &Fn(&Event),
+    verbose: bool,
+) -> Result<usize, Box<Error>> {
+    song_chunks[chunk_idx].used = song_chunks[chunk_idx].used.max(used);
+    if chunk_idx + 1 == song_chunks.len() {
+        return Err(Box::from(ConvertError::layout(
+            &bank.name,
+            Some(song_name),
+            "does not fit in available chunks".to_string(),
+        )));
+    }
+    log(&Event::ChunkSwitched {
+        bank: &bank.name,
+        song: song_name,
+        aram_addr: song_chunks[chunk_idx + 1].aram_addr,
+        space_left: song_chunks[chunk_idx].data.len() - used,
+    });
+    if verbose {
+        detail(log, &format!(
+            "Switching to chunk at ARAM 0x{:X} - space left before switch 0x{:X}",
+            song_chunks[chunk_idx + 1].aram_addr,
+            song_chunks[chunk_idx].data.len() - used
+        ));
+    }
+    Ok(chunk_idx + 1)
+}
+
 fn write_bank(
     bank: &Bank,
     romdata: &mut Vec<u8>,
-    base_addr: u32,
-    first_song_addr: usize,
-    first_song: usize,
-    converter: &Fn(&Path, f32) -> Result<Song, Box<Error>>,
-    songs_pb: &mut ProgressBar<Pipe>,
-    bank_pb: &mut ProgressBar<Pipe>,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
+    addrs: &BankAddrs,
+    relocation: Option<&Relocation>,
+    build: &BuildOptions,
     first_bank: bool,
-) -> Result<(), Box<Error>> {
-    // find chunk going to ARAM D000
-    let bank_addr = romdata[snes_to_pc_addr(base_addr + 8)];
-    let high_addr = romdata[snes_to_pc_addr(base_addr + 4)];
-    let low_addr = romdata[snes_to_pc_addr(base_addr)];
-    let mut addr = snes_bytes_to_pc_addr(bank_addr, high_addr, low_addr);
-    let mut last_chunk_length = 0xffffusize;
-    let mut base_chunk_addr = 0;
-    let mut base_chunk_len = 0;
-    let mut overflow_chunk_addr = 0;
-    let mut overflow_chunk_len = 0;
-    let mut aram_overflow_base = 0;
-    while last_chunk_length != 0 {
-        let chunk = Chunk::load(&romdata, addr);
-        last_chunk_length = chunk.length;
-        if chunk.aram_addr == ARAM_BASE {
-            base_chunk_addr = chunk.offset_addr;
-            base_chunk_len = chunk.length;
-            addr = chunk.offset_addr + chunk.length;
-            let chunk = Chunk::load(&romdata, addr);
-            last_chunk_length = chunk.length;
-            overflow_chunk_addr = chunk.offset_addr;
-            overflow_chunk_len = chunk.length;
-            aram_overflow_base = chunk.aram_addr;
+) -> Result<BankSummary, Box<Error>> {
+    let BankAddrs {
+        base_addr,
+        first_song_addr,
+        first_song,
+    } = *addrs;
+    let BuildOptions {
+        converter,
+        optimize,
+        log,
+        verbose,
+        asm_file,
+        asm_module,
+        asm_label,
+        ..
+    } = *build;
+    // find chunk going to ARAM D000 and the overflow chunk following it
+    let chunks = bank_chunks(romdata, base_addr)?;
+    let base_chunk_idx = match chunks
+        .iter()
+        .position(|chunk| chunk.aram_addr == ARAM_BASE && chunk.length != 0)
+    {
+        Some(idx) => idx,
+        None => {
+            return Err(Box::from(SimpleError::new(format!(
+                "Couldn't find base chunk for {} bank",
+                bank.name
+            ))));
         }
-        addr = chunk.offset_addr + chunk.length;
+    };
+    let mut song_chunk_idxs = vec![base_chunk_idx];
+    if chunks[base_chunk_idx + 1].length != 0 {
+        song_chunk_idxs.push(base_chunk_idx + 1);
     }
-    if base_chunk_addr == 0 {
-        return Err(Box::from(SimpleError::new(format!(
-            "Couldn't find base chunk for {} bank",
-            bank.name
-        ))));
+    let mut song_chunks = song_chunk_idxs
+        .iter()
+        .map(|&idx| {
+            let chunk = &chunks[idx];
+            SongChunk {
+                rom_addr: Some(chunk.offset_addr),
+                aram_addr: chunk.aram_addr,
+                data: romdata[chunk.offset_addr..chunk.offset_addr + chunk.length].to_vec(),
+                used: 0,
+            }
+        })
+        .collect::<Vec<SongChunk>>();
+    if let Some(relocation) = relocation {
+        for &(aram_addr, length) in &relocation.aram_chunks {
+            song_chunks.push(SongChunk {
+                rom_addr: None,
+                aram_addr,
+                data: vec![0; length],
+                used: 0,
+            });
+        }
+    }
+    for (i, chunk) in song_chunks.iter().enumerate() {
+        if chunk.aram_addr + chunk.data.len() > 0x10000 {
+            return Err(Box::from(SimpleError::new(format!(
+                "ARAM chunk at 0x{:X} extends past the end of ARAM",
+                chunk.aram_addr
+            ))));
+        }
+        if let Some(other) = song_chunks[..i].iter().find(|other| {
+            chunk.aram_addr < other.aram_addr + other.data.len()
+                && other.aram_addr < chunk.aram_addr + chunk.data.len()
+        }) {
+            return Err(Box::from(SimpleError::new(format!(
+                "ARAM chunks at 0x{:X} and 0x{:X} overlap in {} bank",
+                other.aram_addr, chunk.aram_addr, bank.name
+            ))));
+        }
+    }
+    if first_song_addr < ARAM_BASE || song_chunks[0].data.len() < first_song_addr - ARAM_BASE {
+        return Err(Box::from(ConvertError::layout(
+            &bank.name,
+            None,
+            "base chunk is too short for the song table".to_string(),
+        )));
     }
-    if overflow_chunk_addr == 0 {
-        return Err(Box::from(SimpleError::new(format!(
-            "Couldn't find overflow chunk for {} bank",
-            bank.name
-        ))));
+    if (first_song + bank.songs.len()) * 2 > first_song_addr - ARAM_BASE {
+        return Err(Box::from(ConvertError::layout(
+            &bank.name,
+            None,
+            format!("song table runs past the first song address 0x{:X}", first_song_addr),
+        )));
     }
-    let mut rom_addr = base_chunk_addr;
-    let mut chunk_length = base_chunk_len;
-    let mut aram_base_addr = ARAM_BASE;
 
-    if verbose {
-        println!(
-            "Writing {} bank to 0x{:X} starting at song {}.  Available chunk length is 0x{:X}",
-            bank.name, rom_addr, first_song, chunk_length
+    // read the songs being kept before anything is overwritten
+    let mut aram = AramImage::new();
+    for chunk in &chunks {
+        aram.load(
+            chunk.aram_addr,
+            &romdata[chunk.offset_addr..chunk.offset_addr + chunk.length],
         );
     }
+    let mut original_addrs = Vec::<Option<usize>>::new();
+    for (slot, song_def) in bank.songs.iter().enumerate() {
+        original_addrs.push(if song_def.original {
+            Some(aram.word(ARAM_BASE + (first_song + slot) * 2)?)
+        } else {
+            None
+        });
+    }
+    let originals = read_songs(&aram, &original_addrs, first_song)?;
 
-    bank_pb.total = (base_chunk_len + overflow_chunk_len) as u64;
-    bank_pb.show_counter = true;
+    let mut chunk_idx = 0;
+    let mut song_offset = first_song_addr - ARAM_BASE;
+    song_chunks[0].used = song_offset;
 
-    let mut song_table_addr = rom_addr + first_song * 2;
-    let mut song_offset = first_song_addr - aram_base_addr;
+    if verbose {
+        detail(log, &format!(
+            "Writing {} bank starting at song {}.  Available chunk lengths are {:X?}",
+            bank.name,
+            first_song,
+            song_chunks
+                .iter()
+                .map(|chunk| chunk.data.len())
+                .collect::<Vec<usize>>()
+        ));
+    }
 
+    // convert all songs up front so identical songs and common subroutines
+    // can be shared across the bank
+    let mut songs = Vec::<Song>::new();
+    let mut song_refs = Vec::<Option<usize>>::new();
+    let mut bytes_shared = 0;
     for song_def in &bank.songs {
-        bank_pb.message(&match &song_def.input {
-            Some(path) => format!("{} ", path.file_name().unwrap().to_str().unwrap()),
-            None => "[empty song] ".to_string(),
-        });
-        bank_pb.set(
-            (song_offset
-                + if rom_addr == base_chunk_addr {
-                    0
-                } else {
-                    base_chunk_len
-                }) as u64,
-        );
-
+        if song_def.original {
+            song_refs.push(None);
+            continue;
+        }
         let song_data = match &song_def.input {
-            Some(path) => converter(&path, song_def.tempo_factor)?,
+            Some(path) => {
+                let song = converter(&path, song_def.tempo_factor, &song_def.transform)?;
+                let errors = song.validate();
+                if !errors.is_empty() {
+                    return Err(errors_in(path, &errors));
+                }
+                song
+            }
             None => Song::empty()?,
         };
+        let existing = (0..song_refs.len()).find(|&slot| {
+            bank.songs[slot].loops == song_def.loops
+                && song_refs[slot].map_or(false, |song_ref| songs[song_ref] == song_data)
+        });
+        match existing {
+            Some(slot) => {
+                if verbose {
+                    detail(log, &format!(
+                        "Song {} is identical to song {}",
+                        first_song + song_refs.len(),
+                        first_song + slot
+                    ));
+                }
+                song_refs.push(song_refs[slot]);
+            }
+            None => {
+                song_refs.push(Some(songs.len()));
+                songs.push(song_data);
+            }
+        }
+    }
+    if let Some(budget) = optimize {
+        let bytes_saved = Song::share_call_loops(&mut songs, &budget, verbose)?;
+        if verbose {
+            detail(log, &format!(
+                "Extracted subroutines shared between {} songs - estimated 0x{:X} bytes saved",
+                bank.name, bytes_saved
+            ));
+        }
+    }
 
-        // check if non-track data fits in chunk
-        if song_offset + (if song_def.loops { 8 } else { 4 }) + 16 > chunk_length {
-            if aram_base_addr == aram_overflow_base {
-                return Err(Box::from(SimpleError::new(format!(
-                    "{} bank does not fit in available chunks",
-                    bank.name
-                ))));
+    let mut reserved = vec![ReservedRange {
+        name: "sample memory".to_string(),
+        start: SAMPLE_ARAM.0,
+        end: SAMPLE_ARAM.1,
+    }];
+    let echo_delay = songs
+        .iter()
+        .map(|song| song.echo_delay())
+        .chain(originals.values().map(|song| song.echo_delay))
+        .max()
+        .unwrap_or(0) as usize;
+    if echo_delay > 0 {
+        reserved.push(ReservedRange {
+            name: "echo buffer".to_string(),
+            start: 0x10000 - echo_delay * ECHO_BUFFER_UNIT,
+            end: 0x10000,
+        });
+    }
+    let mut song_reports = Vec::<SongReport>::new();
+
+    let mut song_table_offset = first_song * 2;
+    let mut song_addrs = vec![None; songs.len()];
+    let mut written_originals = HashMap::<usize, (usize, usize)>::new();
+    let mut subroutine_addrs = HashMap::<Vec<u8>, usize>::new();
+
+    for (slot, song_def) in bank.songs.iter().enumerate() {
+        let song_name: &str = &match &song_def.input {
+            Some(path) => input_name(path),
+            None if song_def.original => Cow::from("[original song]"),
+            None => Cow::from("[empty song]"),
+        };
+        log(&Event::SongStarted {
+            bank: &bank.name,
+            number: first_song + slot,
+            name: song_name,
+        });
+        let mut song_report = SongReport {
+            number: first_song + slot,
+            input: song_def
+                .input
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            original: song_def.original,
+            shared_with: None,
+            header: None,
+            part_table: None,
+            tracks: Vec::new(),
+        };
+        if let Some(original_addr) = original_addrs[slot] {
+            let song_aram_addr = match (originals.get(&original_addr), written_originals.get(&original_addr)) {
+                (Some(original), Some(&(song_aram_addr, first_slot))) => {
+                    bytes_shared += original.data_size();
+                    song_report.shared_with = Some(first_song + first_slot);
+                    song_aram_addr
+                }
+                (Some(original), None) => {
+                    let (song_aram_addr, layout) = write_original_song(
+                        original,
+                        &mut song_chunks,
+                        &mut chunk_idx,
+                        &mut song_offset,
+                        &reserved,
+                        bank,
+                        build,
+                    )?;
+                    song_report.header = layout.header;
+                    song_report.part_table = layout.part_table;
+                    song_report.tracks = layout.tracks;
+                    written_originals.insert(original_addr, (song_aram_addr, slot));
+                    song_aram_addr
+                }
+                // the original song table has no song in this slot
+                (None, _) => 0,
+            };
+            if verbose {
+                detail(log, &format!(
+                    "Writing original song address 0x{:X} to song table at 0x{:X}",
+                    song_aram_addr,
+                    ARAM_BASE + song_table_offset
+                ));
             }
+            let song_addr_bytes = addr_to_bytes(song_aram_addr);
+            song_chunks[0].data[song_table_offset + 1] = song_addr_bytes.0;
+            song_chunks[0].data[song_table_offset] = song_addr_bytes.1;
+            song_table_offset += 2;
+            log(&Event::SongWritten {
+                bank: &bank.name,
+                number: first_song + slot,
+                name: song_name,
+                bytes: song_report.size(),
+            });
+            song_reports.push(song_report);
+            continue;
+        }
+        let song_ref = song_refs[slot].unwrap();
+        let song_data = &songs[song_ref];
+        if let Some(song_addr) = song_addrs[song_ref] {
             if verbose {
-                println!(
-                    "Switching to overflow chunk - data size before switch 0x{:X}",
-                    chunk_length - song_offset
-                );
+                detail(log, &format!(
+                    "Writing shared song address 0x{:X} to song table at 0x{:X}",
+                    song_addr,
+                    ARAM_BASE + song_table_offset
+                ));
             }
+            let song_addr_bytes = addr_to_bytes(song_addr);
+            song_chunks[0].data[song_table_offset + 1] = song_addr_bytes.0;
+            song_chunks[0].data[song_table_offset] = song_addr_bytes.1;
+            song_table_offset += 2;
+            bytes_shared += song_data.header_size(song_def.loops)
+                + song_data.get_num_part_tables() * PART_TABLE_SIZE
+                + song_data.data_size()?;
+            song_report.shared_with = song_refs[..slot]
+                .iter()
+                .position(|&other_ref| other_ref == Some(song_ref))
+                .map(|first_slot| first_song + first_slot);
+            log(&Event::SongWritten {
+                bank: &bank.name,
+                number: first_song + slot,
+                name: song_name,
+                bytes: song_report.size(),
+            });
+            song_reports.push(song_report);
+            continue;
+        }
+
+        // check if non-track data fits in chunk
+        let header_size = song_data.header_size(song_def.loops);
+        let part_tables_size = song_data.get_num_part_tables() * PART_TABLE_SIZE;
+        while song_offset + header_size + part_tables_size > song_chunks[chunk_idx].data.len()
+        {
+            chunk_idx = next_chunk(
+                &mut song_chunks,
+                chunk_idx,
+                song_offset,
+                bank,
+                song_name,
+                log,
+                verbose,
+            )?;
             song_offset = 0;
-            rom_addr = overflow_chunk_addr;
-            chunk_length = overflow_chunk_len;
-            aram_base_addr = aram_overflow_base;
-        };
+        }
+        let song_aram_addr = song_chunks[chunk_idx].aram_addr + song_offset;
+        check_reserved(
+            &reserved,
+            song_aram_addr,
+            header_size + part_tables_size,
+            bank,
+            song_name,
+            "header",
+        )?;
+        song_report.header = Some(DataReport {
+            chunk: chunk_name(chunk_idx),
+            aram_addr: song_aram_addr,
+            size: header_size,
+        });
+        song_report.part_table = Some(DataReport {
+            chunk: chunk_name(chunk_idx),
+            aram_addr: song_aram_addr + header_size,
+            size: part_tables_size,
+        });
 
         // write song address to song table
         if verbose {
-            println!(
-                "Writing song address 0x{:X} (0x{:X}) to song table at 0x{:X}",
-                rom_addr + song_offset,
-                aram_base_addr + song_offset,
-                song_table_addr
-            );
+            detail(log, &format!(
+                "Writing song address 0x{:X} to song table at 0x{:X}",
+                song_aram_addr,
+                ARAM_BASE + song_table_offset
+            ));
         }
-        let song_addr_bytes = addr_to_bytes(aram_base_addr + song_offset);
-        romdata[song_table_addr + 1] = song_addr_bytes.0;
-        romdata[song_table_addr] = song_addr_bytes.1;
-        song_table_addr += 2;
+        song_addrs[song_ref] = Some(song_aram_addr);
+        let song_addr_bytes = addr_to_bytes(song_aram_addr);
+        song_chunks[0].data[song_table_offset + 1] = song_addr_bytes.0;
+        song_chunks[0].data[song_table_offset] = song_addr_bytes.1;
+        song_table_offset += 2;
 
         // song data
-        let part_data_offset;
-        if song_def.loops {
-            // part address + loop command + loop target + terminator
-            part_data_offset = song_offset + 8;
-        } else {
-            // part address + terminator
-            part_data_offset = song_offset + 4;
-        };
-        let part_data_aram_addr = aram_base_addr + part_data_offset;
-        let part_data_rom_addr = rom_addr + part_data_offset;
-        let part_data_bytes = addr_to_bytes(part_data_aram_addr);
-        romdata[rom_addr + song_offset + 1] = part_data_bytes.0;
-        romdata[rom_addr + song_offset] = part_data_bytes.1;
-        if song_def.loops {
-            romdata[rom_addr + song_offset + 3] = 0x00;
-            romdata[rom_addr + song_offset + 2] = 0xff;
-            romdata[rom_addr + song_offset + 5] = song_addr_bytes.0;
-            romdata[rom_addr + song_offset + 4] = song_addr_bytes.1;
-            romdata[rom_addr + song_offset + 7] = 0x00;
-            romdata[rom_addr + song_offset + 6] = 0x00;
-        } else {
-            romdata[rom_addr + song_offset + 3] = 0x00;
-            romdata[rom_addr + song_offset + 2] = 0x00;
-        };
+        let part_chunk_idx = chunk_idx;
+        let part_data_offset = song_offset + header_size;
+        let part_data_aram_addr = song_chunks[chunk_idx].aram_addr + part_data_offset;
+        song_chunks[chunk_idx].data.splice(
+            song_offset..part_data_offset,
+            song_data.write_header(song_aram_addr, song_def.loops),
+        );
 
         // track data
-        let mut track_data_offset = part_data_offset + 16;
+        let mut track_data_offset = part_data_offset + part_tables_size;
         let mut track_addrs = Vec::<usize>::new();
 
         let mut call_loops = Vec::<RomCallLoopRef>::new();
 
         for i in 0..song_data.get_num_tracks() {
-            let track_data = Vec::<u8>::new();
             let mut track_call_loops = Vec::<CallLoopRef>::new();
-            let mut pre_chunk_switch_track_call_loops = Vec::<CallLoopRef>::new();
-            let track_start_offset = track_data_offset;
-            let mut cursor = Cursor::new(track_data);
+            let mut cursor = Cursor::new(Vec::<u8>::new());
             song_data.write_track(&mut cursor, i, &mut track_call_loops)?;
             let track_data = cursor.into_inner();
 
-            // check if track data fits in chunk
-            if track_data_offset + track_data.len() > chunk_length {
-                if aram_base_addr == aram_overflow_base {
-                    return Err(Box::from(SimpleError::new(format!(
-                        "Couldn't find base chunk for {} bank",
-                        bank.name
-                    ))));
-                }
-                if verbose {
-                    println!(
-                        "Switching to overflow chunk - data size before switch 0x{:X}",
-                        chunk_length - song_offset
-                    );
+            // subroutines can't contain CallLoops, so identical data can share an address
+            if song_data.is_subroutine(i) {
+                if let Some(&track_addr) = subroutine_addrs.get(&track_data) {
+                    if verbose {
+                        detail(log, &format!("Using shared subroutine at 0x{:X}", track_addr));
+                    }
+                    bytes_shared += track_data.len();
+                    track_addrs.push(track_addr);
+                    song_report.tracks.push(TrackReport {
+                        index: i,
+                        subroutine: true,
+                        shared: true,
+                        data: DataReport {
+                            chunk: chunk_name(
+                                song_chunks
+                                    .iter()
+                                    .position(|chunk| {
+                                        track_addr >= chunk.aram_addr
+                                            && track_addr < chunk.aram_addr + chunk.data.len()
+                                    })
+                                    .unwrap(),
+                            ),
+                            aram_addr: track_addr,
+                            size: track_data.len(),
+                        },
+                    });
+                    continue;
                 }
+            }
+            if track_data.is_empty() {
+                track_addrs.push(0);
+                continue;
+            }
+
+            // check if track data fits in chunk
+            while track_data_offset + track_data.len() > song_chunks[chunk_idx].data.len() {
+                chunk_idx = next_chunk(
+                    &mut song_chunks,
+                    chunk_idx,
+                    track_data_offset,
+                    bank,
+                    song_name,
+                    log,
+                    verbose,
+                )?;
                 track_data_offset = 0;
-                rom_addr = overflow_chunk_addr;
-                chunk_length = overflow_chunk_len;
-                aram_base_addr = aram_overflow_base;
-                pre_chunk_switch_track_call_loops.extend_from_slice(track_call_loops.as_slice());
-                track_call_loops.clear();
-            };
+            }
 
+            let chunk = &mut song_chunks[chunk_idx];
+            let track_addr = chunk.aram_addr + track_data_offset;
             if verbose {
-                println!(
-                    "Writing track to 0x{:X} (0x{:X})",
-                    rom_addr + track_data_offset,
-                    aram_base_addr + track_data_offset
-                );
+                detail(log, &format!("Writing track to 0x{:X}", track_addr));
+            }
+            check_reserved(
+                &reserved,
+                track_addr,
+                track_data.len(),
+                bank,
+                song_name,
+                &format!("track {}", i),
+            )?;
+            song_report.tracks.push(TrackReport {
+                index: i,
+                subroutine: song_data.is_subroutine(i),
+                shared: false,
+                data: DataReport {
+                    chunk: chunk_name(chunk_idx),
+                    aram_addr: track_addr,
+                    size: track_data.len(),
+                },
+            });
+            chunk.data.splice(
+                track_data_offset..(track_data_offset + track_data.len()),
+                track_data.iter().cloned(),
+            );
+            track_addrs.push(track_addr);
+            track_call_loops.iter().for_each(|call_loop| {
+                call_loops.push(RomCallLoopRef {
+                    target_track: call_loop.target_track,
+                    chunk_idx,
+                    ref_pos: track_data_offset + call_loop.ref_pos as usize,
+                })
+            });
+            track_data_offset += track_data.len();
+            if song_data.is_subroutine(i) {
+                subroutine_addrs.insert(track_data, track_addr);
             }
-            if track_data.len() > 0 {
-                romdata.splice(
-                    (rom_addr + track_data_offset)
-                        ..(rom_addr + track_data_offset + track_data.len()),
-                    track_data.iter().cloned(),
-                );
-                track_addrs.push(aram_base_addr + track_data_offset);
-                pre_chunk_switch_track_call_loops
-                    .iter()
-                    .for_each(|call_loop| {
-                        call_loops.push(RomCallLoopRef {
-                            target_track: call_loop.target_track,
-                            chunk_base: base_chunk_addr + track_start_offset,
-                            ref_pos: call_loop.ref_pos,
-                        })
-                    });
-                track_call_loops.iter().for_each(|call_loop| {
-                    call_loops.push(RomCallLoopRef {
-                        target_track: call_loop.target_track,
-                        chunk_base: rom_addr + track_data_offset,
-                        ref_pos: call_loop.ref_pos,
-                    })
-                });
-                track_data_offset += track_data.len();
-            } else {
-                track_addrs.push(0);
-            };
         }
 
         // part data
         if verbose {
-            println!(
-                "Writing part data to 0x{:X} (0x{:X})",
-                part_data_rom_addr, part_data_aram_addr
-            );
+            detail(log, &format!("Writing part data to 0x{:X}", part_data_aram_addr));
         }
-        let mut part_data_addr = part_data_rom_addr;
-        for i in 0..16 {
-            romdata[part_data_addr + i] = 0;
+        song_chunks[part_chunk_idx].data.splice(
+            part_data_offset..part_data_offset + part_tables_size,
+            song_data.write_part_tables(&track_addrs),
+        );
+
+        write_call_loops(&mut song_chunks, &call_loops, &track_addrs, log, verbose);
+
+        if verbose {
+            detail(log, &format!(
+                "{} - song data ends at 0x{:X}",
+                song_name,
+                song_chunks[chunk_idx].aram_addr + track_data_offset,
+            ));
         }
-        song_data.get_part_tracks(0).iter().for_each(|&track_idx| {
-            if verbose {
-                println!(
-                    "Writing track address 0x{:X} to part data at 0x{:X}",
-                    track_addrs[track_idx], part_data_addr
+        song_offset = track_data_offset;
+        log(&Event::SongWritten {
+            bank: &bank.name,
+            number: first_song + slot,
+            name: song_name,
+            bytes: song_report.size(),
+        });
+        song_reports.push(song_report);
+    }
+    song_chunks[chunk_idx].used = song_chunks[chunk_idx].used.max(song_offset);
+    if verbose {
+        detail(log, &format!("{} bank - 0x{:X} bytes saved by sharing", bank.name, bytes_shared));
+    }
+    for i in song_table_offset..(first_song_addr - ARAM_BASE) {
+        song_chunks[0].data[i] = 0x00;
+    }
+    let report = BankReport {
+        name: bank.name.to_string(),
+        chunks: song_chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| ChunkReport {
+                name: chunk_name(i),
+                aram_addr: chunk.aram_addr,
+                length: chunk.data.len(),
+                used: chunk.used,
+                free: chunk.data.len() - chunk.used,
+            })
+            .collect(),
+        reserved,
+        songs: song_reports,
+        bytes_shared,
+    };
+
+    match relocation {
+        None => {
+            for chunk in &song_chunks {
+                let rom_addr = chunk.rom_addr.unwrap();
+                romdata.splice(
+                    rom_addr..(rom_addr + chunk.data.len()),
+                    chunk.data.iter().cloned(),
                 );
             }
-            let track_bytes = addr_to_bytes(track_addrs[track_idx]);
-            romdata[part_data_addr + 1] = track_bytes.0;
-            romdata[part_data_addr] = track_bytes.1;
-            part_data_addr += 2;
-        });
+            if asm_file.is_some() {
+                for (i, chunk) in song_chunks.iter().enumerate() {
+                    if i > 0 && chunk.used == 0 {
+                        continue;
+                    }
+                    let name = chunk_name(i);
+                    nsasm::write_asm(
+                        &vec![(
+                            &format!("{}_{}_{}", asm_label.unwrap_or(DEFAULT_ASM_LABEL_PREFIX), bank.name, name),
+                            chunk.data.clone(),
+                        )],
+                        asm_file.unwrap(),
+                        &format!("{}_{}_{}", asm_module.unwrap_or(DEFAULT_ASM_MODULE_PREFIX), bank.name, name),
+                        &format!("{:06X}", chunk.rom_addr.unwrap()),
+                        16,
+                        first_bank && i == 0,
+                    )?;
+                }
+            }
+            Ok(BankSummary {
+                report,
+                rom_end: None,
+            })
+        }
+        Some(relocation) => {
+            // rebuild the bank's transfer blocks with the song chunks trimmed
+            // to the data actually used, keeping any other chunks as they were
+            let mut stream = Vec::<u8>::new();
+            for (i, chunk) in chunks.iter().enumerate() {
+                if i == base_chunk_idx {
+                    for (j, song_chunk) in song_chunks.iter().enumerate() {
+                        if j > 0 && song_chunk.used == 0 {
+                            continue;
+                        }
+                        let header_addr = stream.len();
+                        stream.extend_from_slice(&[0; 4]);
+                        Chunk {
+                            offset_addr: 0,
+                            length: song_chunk.used,
+                            aram_addr: song_chunk.aram_addr,
+                        }
+                        .write_header(&mut stream, header_addr);
+                        stream.extend_from_slice(&song_chunk.data[..song_chunk.used]);
+                    }
+                } else if !song_chunk_idxs.contains(&i) {
+                    let header_addr = stream.len();
+                    stream.extend_from_slice(&[0; 4]);
+                    chunk.write_header(&mut stream, header_addr);
+                    stream.extend_from_slice(
+                        &romdata[chunk.offset_addr..chunk.offset_addr + chunk.length],
+                    );
+                }
+            }
 
-        call_loops.iter().for_each(|call_loop| {
-            let call_loop_addr = call_loop.chunk_base + (call_loop.ref_pos as usize);
+            // the APU upload routine moves on to the next ROM bank when its
+            // index wraps, so the blocks don't need to avoid bank boundaries
+            let rom_addr = relocation.rom_addr;
+            let rom_end = rom_addr + stream.len();
             if verbose {
-                println!(
-                    "Writing loop address 0x{:X} to CallLoop instruction at 0x{:X}",
-                    track_addrs[call_loop.target_track], call_loop_addr
-                );
+                detail(log, &format!(
+                    "Relocating {} bank to 0x{:X}-0x{:X}",
+                    bank.name, rom_addr, rom_end
+                ));
             }
-            let track_bytes = addr_to_bytes(track_addrs[call_loop.target_track]);
-            romdata[call_loop_addr + 1] = track_bytes.0;
-            romdata[call_loop_addr] = track_bytes.1;
-        });
+            if romdata.len() < rom_end {
+                romdata.resize(rom_end, 0);
+            }
+            romdata.splice(rom_addr..rom_end, stream.iter().cloned());
+            let snes_addr = pc_to_snes_addr(rom_addr);
+            let pointer = [
+                (base_addr, (snes_addr & 0xFF) as u8),
+                (base_addr + 4, ((snes_addr >> 8) & 0xFF) as u8),
+                (base_addr + 8, ((snes_addr >> 16) & 0xFF) as u8),
+            ];
+            for &(addr, byte) in &pointer {
+                romdata[snes_to_pc_addr(addr)] = byte;
+            }
+            if asm_file.is_some() {
+                nsasm::write_asm(
+                    &vec![(
+                        &format!("{}_{}_relocated", asm_label.unwrap_or(DEFAULT_ASM_LABEL_PREFIX), bank.name),
+                        stream,
+                    )],
+                    asm_file.unwrap(),
+                    &format!("{}_{}_relocated", asm_module.unwrap_or(DEFAULT_ASM_MODULE_PREFIX), bank.name),
+                    &format!("{:06X}", rom_addr),
+                    16,
+                    first_bank,
+                )?;
+                for (i, &(addr, byte)) in pointer.iter().enumerate() {
+                    nsasm::write_asm(
+                        &vec![(
+                            &format!("{}_{}_pointer{}", asm_label.unwrap_or(DEFAULT_ASM_LABEL_PREFIX), bank.name, i),
+                            vec![byte],
+                        )],
+                        asm_file.unwrap(),
+                        &format!("{}_{}_pointer{}", asm_module.unwrap_or(DEFAULT_ASM_MODULE_PREFIX), bank.name, i),
+                        &format!("{:06X}", snes_to_pc_addr(addr)),
+                        16,
+                        false,
+                    )?;
+                }
+            }
+            Ok(BankSummary {
+                report,
+                rom_end: Some(rom_end),
+            })
+        }
+    }
+}
 
+fn write_call_loops(
+    song_chunks: &mut Vec<SongChunk>,
+    call_loops: &[RomCallLoopRef],
+    track_addrs: &[usize],
+    log: &Fn(&Event),
+    verbose: bool,
+) {
+    call_loops.iter().for_each(|call_loop| {
+        let chunk = &mut song_chunks[call_loop.chunk_idx];
         if verbose {
-            println!(
-                "{} - total track size in current bank 0x{:X}",
-                match &song_def.input {
-                    Some(path) => path.file_name().unwrap().to_str().unwrap(),
-                    None => "[empty song]",
-                },
-                if track_data_offset > song_offset {
-                    track_data_offset - song_offset
-                } else {
-                    track_data_offset
-                },
-            );
+            detail(log, &format!(
+                "Writing loop address 0x{:X} to CallLoop instruction at 0x{:X}",
+                track_addrs[call_loop.target_track],
+                chunk.aram_addr + call_loop.ref_pos
+            ));
         }
-        song_offset = track_data_offset;
-        songs_pb.inc();
-    }
-    for i in song_table_addr..(base_chunk_addr + first_song_addr - ARAM_BASE) {
-        romdata[i] = 0x00;
-    }
-    if asm_file.is_some() {
-        nsasm::write_asm(
-            &vec![(
-                &format!("{}_{}_base", asm_label.unwrap_or(DEFAULT_ASM_LABEL_PREFIX), bank.name),
-                romdata[base_chunk_addr..base_chunk_addr + base_chunk_len].to_vec(),
-            )],
-            asm_file.unwrap(),
-            &format!("{}_{}_base", asm_module.unwrap_or(DEFAULT_ASM_MODULE_PREFIX), bank.name),
-            &format!("{:06X}", base_chunk_addr),
-            16,
-            first_bank,
+        let track_bytes = addr_to_bytes(track_addrs[call_loop.target_track]);
+        chunk.data[call_loop.ref_pos + 1] = track_bytes.0;
+        chunk.data[call_loop.ref_pos] = track_bytes.1;
+    });
+}
+
+// writes a song read from the original bank, returning its new address and
+// a report of where its data went
+fn write_original_song(
+    song: &OriginalSong,
+    song_chunks: &mut Vec<SongChunk>,
+    chunk_idx: &mut usize,
+    song_offset: &mut usize,
+    reserved: &[ReservedRange],
+    bank: &Bank,
+    build: &BuildOptions,
+) -> Result<(usize, SongReport), Box<Error>> {
+    let BuildOptions { log, verbose, .. } = *build;
+    let mut song_report = SongReport::default();
+    let song_name = "[original song]";
+    // the header and part tables are kept together
+    let table_size = song.header_size() + song.parts.len() * PART_TABLE_SIZE;
+    while *song_offset + table_size > song_chunks[*chunk_idx].data.len() {
+        *chunk_idx =
+            next_chunk(song_chunks, *chunk_idx, *song_offset, bank, song_name, log, verbose)?;
+        *song_offset = 0;
+    }
+    let table_chunk_idx = *chunk_idx;
+    let table_offset = *song_offset;
+    let song_aram_addr = song_chunks[table_chunk_idx].aram_addr + table_offset;
+    check_reserved(reserved, song_aram_addr, table_size, bank, song_name, "header")?;
+    if verbose {
+        detail(log, &format!("Writing original song to 0x{:X}", song_aram_addr));
+    }
+    song_report.header = Some(DataReport {
+        chunk: chunk_name(table_chunk_idx),
+        aram_addr: song_aram_addr,
+        size: song.header_size(),
+    });
+    song_report.part_table = Some(DataReport {
+        chunk: chunk_name(table_chunk_idx),
+        aram_addr: song_aram_addr + song.header_size(),
+        size: song.parts.len() * PART_TABLE_SIZE,
+    });
+    *song_offset += table_size;
+
+    let mut track_addrs = Vec::<usize>::new();
+    let mut call_loops = Vec::<RomCallLoopRef>::new();
+    for (i, track) in song.tracks.iter().enumerate() {
+        while *song_offset + track.data.len() > song_chunks[*chunk_idx].data.len() {
+            *chunk_idx =
+                next_chunk(song_chunks, *chunk_idx, *song_offset, bank, song_name, log, verbose)?;
+            *song_offset = 0;
+        }
+        let chunk = &mut song_chunks[*chunk_idx];
+        let track_addr = chunk.aram_addr + *song_offset;
+        check_reserved(
+            reserved,
+            track_addr,
+            track.data.len(),
+            bank,
+            song_name,
+            &format!("track {}", i),
         )?;
-        if rom_addr == overflow_chunk_addr {
-            nsasm::write_asm(
-                &vec![(
-                    &format!("{}_{}_overflow", asm_label.unwrap_or(DEFAULT_ASM_LABEL_PREFIX), bank.name),
-                    romdata[overflow_chunk_addr..overflow_chunk_addr + overflow_chunk_len].to_vec()
-                )],
-                asm_file.unwrap(),
-                &format!("{}_{}_overflow", asm_module.unwrap_or(DEFAULT_ASM_MODULE_PREFIX), bank.name),
-                &format!("{:06X}", overflow_chunk_addr),
-                16,
-                false,
-            )?;
+        song_report.tracks.push(TrackReport {
+            index: i,
+            subroutine: track.subroutine,
+            shared: false,
+            data: DataReport {
+                chunk: chunk_name(*chunk_idx),
+                aram_addr: track_addr,
+                size: track.data.len(),
+            },
+        });
+        chunk.data.splice(
+            *song_offset..(*song_offset + track.data.len()),
+            track.data.iter().cloned(),
+        );
+        for &(pos, target_track) in &track.call_loops {
+            call_loops.push(RomCallLoopRef {
+                target_track,
+                chunk_idx: *chunk_idx,
+                ref_pos: *song_offset + pos,
+            });
         }
+        track_addrs.push(track_addr);
+        *song_offset += track.data.len();
     }
-    Ok(())
+
+    let part_addrs = (0..song.parts.len())
+        .map(|i| song_aram_addr + song.header_size() + i * PART_TABLE_SIZE)
+        .collect::<Vec<usize>>();
+    let mut table = song.write_header(song_aram_addr, &part_addrs);
+    for i in 0..song.parts.len() {
+        table.extend(song.write_part(i, &track_addrs));
+    }
+    song_chunks[table_chunk_idx].data.splice(
+        table_offset..(table_offset + table_size),
+        table.iter().cloned(),
+    );
+    write_call_loops(song_chunks, &call_loops, &track_addrs, log, verbose);
+    Ok((song_aram_addr, song_report))
 }
 
 pub fn write_all_overworld(
     song_path: &Path,
     rom_path: &Path,
-    bank_base_addrs: [u32; 3],
-    converter: &Fn(&Path, f32) -> Result<Song, Box<Error>>,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
-) -> Result<(), Box<Error>> {
-    write(
-        &Manifest::single_song(song_path),
-        rom_path,
-        bank_base_addrs,
-        converter,
-        verbose,
-        asm_file,
-        asm_module,
-        asm_label,
-    )?;
-    Ok(())
+    build: &BuildOptions,
+) -> Result<Report, Box<Error>> {
+    write(&Manifest::single_song(song_path), rom_path, build)
 }
 
 pub fn write_file_select(
     song_path: &Path,
     rom_path: &Path,
-    bank_base_addrs: [u32; 3],
-    converter: &Fn(&Path, f32) -> Result<Song, Box<Error>>,
-    verbose: bool,
-    asm_file: Option<&str>,
-    asm_module: Option<&str>,
-    asm_label: Option<&str>,
-) -> Result<(), Box<Error>> {
-    write(
-        &Manifest::file_select(song_path),
-        rom_path,
-        bank_base_addrs,
-        converter,
-        verbose,
-        asm_file,
-        asm_module,
-        asm_label,
-    )?;
-    Ok(())
+    build: &BuildOptions,
+) -> Result<Report, Box<Error>> {
+    write(&Manifest::file_select(song_path), rom_path, build)
 }
 
 pub fn gen_fake_rom(
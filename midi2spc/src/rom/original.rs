@@ -0,0 +1,493 @@
+use crate::nspc::{push_word, CallLoopRef, Song, MAX_PART_TRACKS, PART_TABLE_SIZE};
+use simple_error::SimpleError;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+// parameter bytes taken by commands 0xE0-0xFA
+const COMMAND_PARAMS: [usize; 27] = [
+    1, 1, 2, 3, 0, 1, 2, 1, 2, 1, 1, 3, 0, 1, 2, 3, 1, 3, 3, 0, 1, 3, 0, 3, 3, 3, 1,
+];
+const CALL_LOOP: u8 = 0xef;
+const ECHO_PARAMS: u8 = 0xf7;
+// longest song header read before giving up on finding its end
+const MAX_SEQUENCE_LEN: usize = 0x100;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aram(data: &[(usize, &[u8])]) -> AramImage {
+        let mut aram = AramImage::new();
+        for &(addr, bytes) in data {
+            aram.load(addr, bytes);
+        }
+        aram
+    }
+
+    #[test]
+    fn test_read_song() {
+        let aram = aram(&[
+            // part, part, loop back to the second part forever
+            (
+                0xd100,
+                &[0x10, 0xd1, 0x20, 0xd1, 0xff, 0x00, 0x02, 0xd1, 0x00, 0x00],
+            ),
+            (
+                0xd110,
+                &[0x30, 0xd1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0xd1],
+            ),
+            (
+                0xd120,
+                &[0x30, 0xd1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (0xd130, &[0x18, 0x7f, 0xa4, 0xef, 0x50, 0xd1, 0x02, 0x00]),
+            (0xd140, &[0xf7, 0x03, 0x40, 0x00, 0x0c, 0xc9, 0xa8, 0x00]),
+            (0xd150, &[0xe0, 0x01, 0xa0, 0x00, 0xa2, 0x00]),
+        ]);
+        let song = OriginalSong::read(&aram, 0xd100, &HashSet::new()).unwrap();
+        assert_eq!(
+            vec![
+                SequenceEntry::Part(0),
+                SequenceEntry::Part(1),
+                SequenceEntry::Loop(0xff, 1)
+            ],
+            song.sequence
+        );
+        assert_eq!(10, song.header_size());
+        assert_eq!(2, song.parts.len());
+        assert_eq!(song.parts[0][0], song.parts[1][0]);
+        assert_eq!(3, song.tracks.len());
+        let main = &song.tracks[song.parts[0][0].unwrap()];
+        assert_eq!(
+            vec![0x18, 0x7f, 0xa4, 0xef, 0x50, 0xd1, 0x02, 0x00],
+            main.data
+        );
+        assert_eq!(1, main.call_loops.len());
+        assert_eq!(4, main.call_loops[0].0);
+        let subroutine = &song.tracks[main.call_loops[0].1];
+        assert!(subroutine.subroutine);
+        // the subroutine ends at its first end command
+        assert_eq!(vec![0xe0, 0x01, 0xa0, 0x00], subroutine.data);
+        assert_eq!(3, song.echo_delay);
+    }
+
+    #[test]
+    fn test_write_header() {
+        let aram = aram(&[
+            (0xd100, &[0x08, 0xd1, 0x03, 0x00, 0x00, 0xd1, 0x00, 0x00]),
+            (
+                0xd108,
+                &[0x18, 0xd1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (0xd118, &[0x18, 0xa4, 0x00]),
+        ]);
+        let song = OriginalSong::read(&aram, 0xd100, &HashSet::new()).unwrap();
+        assert_eq!(
+            vec![0x00, 0x30, 0x03, 0x00, 0x00, 0x30, 0x00, 0x00],
+            song.write_header(0x3000, &[0x3000])
+        );
+        assert_eq!(
+            vec![0x00, 0x31, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            song.write_part(0, &[0x3100])
+        );
+    }
+
+    #[test]
+    fn test_track_stops_at_next_track() {
+        let aram = aram(&[
+            (0xd100, &[0x04, 0xd1, 0x00, 0x00]),
+            (
+                0xd104,
+                &[0x14, 0xd1, 0x17, 0xd1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (0xd114, &[0x18, 0xa4, 0xa5, 0x18, 0xa6, 0x00]),
+        ]);
+        let song = OriginalSong::read(&aram, 0xd100, &HashSet::new()).unwrap();
+        assert_eq!(vec![0x18, 0xa4, 0xa5, 0x00], song.tracks[0].data);
+        assert_eq!(vec![0x18, 0xa6, 0x00], song.tracks[1].data);
+    }
+
+    #[test]
+    fn test_unloaded_aram() {
+        let aram = aram(&[(0xd100, &[0x04, 0xd1, 0x00, 0x00])]);
+        assert!(OriginalSong::read(&aram, 0xd100, &HashSet::new()).is_err());
+    }
+}
+
+/// ARAM contents after a bank's transfer blocks are uploaded.
+pub struct AramImage {
+    data: Vec<u8>,
+    loaded: Vec<bool>,
+}
+
+impl AramImage {
+    pub fn new() -> AramImage {
+        AramImage {
+            data: vec![0; 0x10000],
+            loaded: vec![false; 0x10000],
+        }
+    }
+
+    pub fn load(&mut self, aram_addr: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if aram_addr + i < self.data.len() {
+                self.data[aram_addr + i] = byte;
+                self.loaded[aram_addr + i] = true;
+            }
+        }
+    }
+
+    pub fn byte(&self, addr: usize) -> Result<u8, Box<Error>> {
+        if addr >= self.data.len() || !self.loaded[addr] {
+            return Err(Box::from(SimpleError::new(format!(
+                "Original song data at ARAM 0x{:X} isn't loaded by the bank",
+                addr
+            ))));
+        }
+        Ok(self.data[addr])
+    }
+
+    pub fn word(&self, addr: usize) -> Result<usize, Box<Error>> {
+        Ok(self.byte(addr)? as usize + ((self.byte(addr + 1)? as usize) << 8))
+    }
+}
+
+/// One entry of a song header.
+#[derive(Debug, Eq, PartialEq)]
+pub enum SequenceEntry {
+    Part(usize),
+    // repeat count, or 0x80 and up to always jump, and the entry to go to
+    Loop(u8, usize),
+}
+
+pub struct OriginalTrack {
+    pub data: Vec<u8>,
+    // positions of CallLoop addresses in the data and the tracks they call
+    pub call_loops: Vec<(usize, usize)>,
+    pub subroutine: bool,
+}
+
+/// A song read back from ARAM so it can be written again at a new address.
+pub struct OriginalSong {
+    pub sequence: Vec<SequenceEntry>,
+    pub parts: Vec<[Option<usize>; MAX_PART_TRACKS]>,
+    pub tracks: Vec<OriginalTrack>,
+    pub echo_delay: u8,
+}
+
+impl OriginalSong {
+    /// Reads the song at song_addr.  Tracks without an end command stop at
+    /// the start of any other data in the song or at any of boundaries.
+    pub fn read(
+        aram: &AramImage,
+        song_addr: usize,
+        boundaries: &HashSet<usize>,
+    ) -> Result<OriginalSong, Box<Error>> {
+        // header words, with the offsets they start at
+        let mut entries = Vec::<(usize, usize, Option<usize>)>::new();
+        let mut offset = 0;
+        loop {
+            if entries.len() == MAX_SEQUENCE_LEN {
+                return Err(Box::from(SimpleError::new(format!(
+                    "Couldn't find the end of the song header at ARAM 0x{:X}",
+                    song_addr
+                ))));
+            }
+            let word = aram.word(song_addr + offset)?;
+            if word == 0 {
+                break;
+            }
+            if word < 0x100 {
+                let target = aram.word(song_addr + offset + 2)?;
+                if target < song_addr || (target - song_addr) % 2 != 0 {
+                    return Err(Box::from(SimpleError::new(format!(
+                        "Song header at ARAM 0x{:X} loops to 0x{:X}, outside the header",
+                        song_addr, target
+                    ))));
+                }
+                entries.push((offset, word, Some(target - song_addr)));
+                offset += 4;
+                // nothing after an unconditional jump is played unless
+                // another entry loops past it
+                if word >= 0x80
+                    && entries
+                        .iter()
+                        .all(|&(_, _, target)| target.map_or(true, |target| target < offset))
+                {
+                    break;
+                }
+            } else {
+                entries.push((offset, word, None));
+                offset += 2;
+            }
+        }
+
+        let mut part_addrs = Vec::<usize>::new();
+        let mut sequence = Vec::<SequenceEntry>::new();
+        for &(_, word, target) in &entries {
+            match target {
+                Some(target) => {
+                    let target_idx = if target == offset {
+                        entries.len()
+                    } else {
+                        match entries.iter().position(|&(offset, _, _)| offset == target) {
+                            Some(idx) => idx,
+                            None => {
+                                return Err(Box::from(SimpleError::new(format!(
+                                    "Song header at ARAM 0x{:X} loops to 0x{:X}, outside the header",
+                                    song_addr,
+                                    song_addr + target
+                                ))));
+                            }
+                        }
+                    };
+                    sequence.push(SequenceEntry::Loop(word as u8, target_idx));
+                }
+                None => {
+                    let part_idx = match part_addrs.iter().position(|&addr| addr == word) {
+                        Some(idx) => idx,
+                        None => {
+                            part_addrs.push(word);
+                            part_addrs.len() - 1
+                        }
+                    };
+                    sequence.push(SequenceEntry::Part(part_idx));
+                }
+            }
+        }
+
+        let mut track_addrs = Vec::<usize>::new();
+        let mut parts = Vec::<[Option<usize>; MAX_PART_TRACKS]>::new();
+        for &part_addr in &part_addrs {
+            let mut part = [None; MAX_PART_TRACKS];
+            for i in 0..MAX_PART_TRACKS {
+                let track_addr = aram.word(part_addr + i * 2)?;
+                if track_addr != 0 {
+                    part[i] = Some(
+                        match track_addrs.iter().position(|&addr| addr == track_addr) {
+                            Some(idx) => idx,
+                            None => {
+                                track_addrs.push(track_addr);
+                                track_addrs.len() - 1
+                            }
+                        },
+                    );
+                }
+            }
+            parts.push(part);
+        }
+
+        let mut stops = boundaries.clone();
+        stops.insert(song_addr);
+        stops.extend(part_addrs.iter().cloned());
+        stops.extend(track_addrs.iter().cloned());
+        let num_part_tracks = track_addrs.len();
+        // subroutines found while reading tracks are further stops, so read
+        // again until no new ones turn up
+        loop {
+            let mut tracks = Vec::<OriginalTrack>::new();
+            let mut echo_delay = 0;
+            let mut i = 0;
+            while i < track_addrs.len() {
+                let (track, track_echo_delay) =
+                    read_track(aram, track_addrs[i], &stops, &mut track_addrs)?;
+                echo_delay = echo_delay.max(track_echo_delay);
+                tracks.push(OriginalTrack {
+                    subroutine: i >= num_part_tracks,
+                    ..track
+                });
+                i += 1;
+            }
+            if track_addrs.iter().all(|addr| stops.contains(addr)) {
+                return Ok(OriginalSong {
+                    sequence,
+                    parts,
+                    tracks,
+                    echo_delay,
+                });
+            }
+            stops.extend(track_addrs.iter().cloned());
+        }
+    }
+
+    /// The song as the converter would have written it, with its parts in
+    /// the order the header first plays them.
+    pub fn to_song(&self) -> Result<Song, Box<Error>> {
+        let parts = self
+            .sequence
+            .iter()
+            .filter_map(|entry| match *entry {
+                SequenceEntry::Part(idx) => Some(self.parts[idx]),
+                SequenceEntry::Loop(..) => None,
+            })
+            .collect::<Vec<[Option<usize>; MAX_PART_TRACKS]>>();
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| {
+                let call_loops = track
+                    .call_loops
+                    .iter()
+                    .map(|&(pos, target)| CallLoopRef {
+                        target_track: target,
+                        ref_pos: pos as u64,
+                    })
+                    .collect();
+                (track.data.clone(), call_loops)
+            })
+            .collect::<Vec<(Vec<u8>, Vec<CallLoopRef>)>>();
+        Song::from_data(&parts, &tracks)
+    }
+
+    pub fn data_size(&self) -> usize {
+        self.tracks
+            .iter()
+            .fold(self.header_size() + self.parts.len() * PART_TABLE_SIZE, |acc, track| {
+                acc + track.data.len()
+            })
+    }
+
+    pub fn header_size(&self) -> usize {
+        self.sequence.iter().fold(2, |acc, entry| {
+            acc + match entry {
+                SequenceEntry::Part(_) => 2,
+                SequenceEntry::Loop(_, _) => 4,
+            }
+        })
+    }
+
+    pub fn write_header(&self, song_addr: usize, part_addrs: &[usize]) -> Vec<u8> {
+        let mut entry_addrs = Vec::<usize>::new();
+        let mut addr = song_addr;
+        for entry in &self.sequence {
+            entry_addrs.push(addr);
+            addr += match entry {
+                SequenceEntry::Part(_) => 2,
+                SequenceEntry::Loop(_, _) => 4,
+            };
+        }
+        entry_addrs.push(addr);
+        let mut header = Vec::<u8>::new();
+        for entry in &self.sequence {
+            match *entry {
+                SequenceEntry::Part(idx) => push_word(&mut header, part_addrs[idx]),
+                SequenceEntry::Loop(count, target) => {
+                    push_word(&mut header, count as usize);
+                    push_word(&mut header, entry_addrs[target]);
+                }
+            }
+        }
+        push_word(&mut header, 0);
+        header
+    }
+
+    pub fn write_part(&self, part_idx: usize, track_addrs: &[usize]) -> Vec<u8> {
+        let mut part = Vec::<u8>::new();
+        for track in self.parts[part_idx].iter() {
+            push_word(&mut part, track.map_or(0, |idx| track_addrs[idx]));
+        }
+        part
+    }
+}
+
+// reads one track, adding the subroutines it calls to track_addrs
+fn read_track(
+    aram: &AramImage,
+    start_addr: usize,
+    stops: &HashSet<usize>,
+    track_addrs: &mut Vec<usize>,
+) -> Result<(OriginalTrack, u8), Box<Error>> {
+    let mut data = Vec::<u8>::new();
+    let mut call_loops = Vec::<(usize, usize)>::new();
+    let mut echo_delay = 0;
+    let mut addr = start_addr;
+    loop {
+        if addr != start_addr && stops.contains(&addr) {
+            data.push(0);
+            break;
+        }
+        let command = aram.byte(addr)?;
+        let length = match command {
+            0x00 => {
+                data.push(0);
+                break;
+            }
+            // duration, with optional quantization and velocity
+            0x01..=0x7f => {
+                if aram.byte(addr + 1)? < 0x80 {
+                    2
+                } else {
+                    1
+                }
+            }
+            0x80..=0xdf => 1,
+            0xe0..=0xfa => 1 + COMMAND_PARAMS[(command - 0xe0) as usize],
+            _ => {
+                return Err(Box::from(SimpleError::new(format!(
+                    "Unknown command 0x{:X} in original track at ARAM 0x{:X}",
+                    command, addr
+                ))));
+            }
+        };
+        if command == CALL_LOOP {
+            let target_addr = aram.word(addr + 1)?;
+            let target = match track_addrs.iter().position(|&addr| addr == target_addr) {
+                Some(idx) => idx,
+                None => {
+                    track_addrs.push(target_addr);
+                    track_addrs.len() - 1
+                }
+            };
+            call_loops.push((data.len() + 1, target));
+        }
+        if command == ECHO_PARAMS {
+            echo_delay = aram.byte(addr + 1)?;
+        }
+        for i in 0..length {
+            data.push(aram.byte(addr + i)?);
+        }
+        addr += length;
+    }
+    Ok((
+        OriginalTrack {
+            data,
+            call_loops,
+            subroutine: false,
+        },
+        echo_delay,
+    ))
+}
+
+/// Reads the songs at song_addrs, keyed by address.  Each slot's address is
+/// None unless it keeps its song, and the slots are numbered from first_song.
+pub fn read_songs(
+    aram: &AramImage,
+    song_addrs: &[Option<usize>],
+    first_song: usize,
+) -> Result<HashMap<usize, OriginalSong>, Box<Error>> {
+    // songs packed back to back end where the next one starts
+    let boundaries = song_addrs
+        .iter()
+        .filter_map(|&addr| addr)
+        .collect::<HashSet<usize>>();
+    let mut songs = HashMap::<usize, OriginalSong>::new();
+    for (slot, &song_addr) in song_addrs.iter().enumerate() {
+        let song_addr = match song_addr {
+            Some(song_addr) if song_addr != 0 && !songs.contains_key(&song_addr) => song_addr,
+            _ => continue,
+        };
+        match OriginalSong::read(aram, song_addr, &boundaries) {
+            Ok(song) => {
+                songs.insert(song_addr, song);
+            }
+            Err(err) => {
+                return Err(Box::from(SimpleError::new(format!(
+                    "Couldn't read original song {}: {}",
+                    first_song + slot,
+                    err
+                ))));
+            }
+        }
+    }
+    Ok(songs)
+}
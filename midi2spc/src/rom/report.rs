@@ -0,0 +1,206 @@
+use serde_derive::Serialize;
+use std::error::Error;
+use std::io::Write;
+
+#[derive(Copy, Clone, Debug)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub banks: Vec<BankReport>,
+    pub sfx: Option<SfxReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BankReport {
+    pub name: String,
+    pub chunks: Vec<ChunkReport>,
+    pub reserved: Vec<ReservedRange>,
+    pub songs: Vec<SongReport>,
+    pub bytes_shared: usize,
+}
+
+/// The ARAM given to sound effect data and the sound effects written there.
+#[derive(Debug, Serialize)]
+pub struct SfxReport {
+    pub aram_addr: usize,
+    pub length: usize,
+    pub used: usize,
+    pub sounds: Vec<SoundReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SoundReport {
+    pub table: String,
+    pub slot: usize,
+    pub input: String,
+    pub shared: bool,
+    pub aram_addr: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkReport {
+    pub name: String,
+    pub aram_addr: usize,
+    pub length: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// ARAM used by the sound driver for something other than song data.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReservedRange {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SongReport {
+    pub number: usize,
+    pub input: Option<String>,
+    pub original: bool,
+    pub shared_with: Option<usize>,
+    pub header: Option<DataReport>,
+    pub part_table: Option<DataReport>,
+    pub tracks: Vec<TrackReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataReport {
+    pub chunk: String,
+    pub aram_addr: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackReport {
+    pub index: usize,
+    pub subroutine: bool,
+    pub shared: bool,
+    pub data: DataReport,
+}
+
+impl ReservedRange {
+    pub fn overlaps(&self, aram_addr: usize, size: usize) -> bool {
+        aram_addr < self.end && self.start < aram_addr + size
+    }
+}
+
+impl SongReport {
+    pub fn size(&self) -> usize {
+        self.header.as_ref().map_or(0, |header| header.size)
+            + self.part_table.as_ref().map_or(0, |part_table| part_table.size)
+            + self
+                .tracks
+                .iter()
+                .filter(|track| !track.shared)
+                .fold(0, |acc, track| acc + track.data.size)
+    }
+}
+
+impl Report {
+    pub fn write(&self, out: &mut Write, format: ReportFormat) -> Result<(), Box<Error>> {
+        match format {
+            ReportFormat::Text => self.write_text(out),
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_text(&self, out: &mut Write) -> Result<(), Box<Error>> {
+        for bank in &self.banks {
+            writeln!(out, "{} bank", bank.name)?;
+            for chunk in &bank.chunks {
+                writeln!(
+                    out,
+                    "  {:<10} ARAM 0x{:04X}-0x{:04X}  used 0x{:04X} of 0x{:04X}, 0x{:04X} free",
+                    chunk.name,
+                    chunk.aram_addr,
+                    chunk.aram_addr + chunk.length,
+                    chunk.used,
+                    chunk.length,
+                    chunk.free
+                )?;
+            }
+            for reserved in &bank.reserved {
+                writeln!(
+                    out,
+                    "  {:<10} ARAM 0x{:04X}-0x{:04X}  reserved",
+                    reserved.name, reserved.start, reserved.end
+                )?;
+            }
+            for song in &bank.songs {
+                let input = match &song.input {
+                    Some(input) => input,
+                    None if song.original => "[original song]",
+                    None => "[empty song]",
+                };
+                if let Some(shared_with) = song.shared_with {
+                    writeln!(
+                        out,
+                        "  song {:>2}  {}  shared with song {}",
+                        song.number, input, shared_with
+                    )?;
+                    continue;
+                }
+                writeln!(out, "  song {:>2}  {}  0x{:04X} bytes", song.number, input, song.size())?;
+                if let Some(header) = &song.header {
+                    write_data(out, "header", header)?;
+                }
+                if let Some(part_table) = &song.part_table {
+                    write_data(out, "part table", part_table)?;
+                }
+                for track in &song.tracks {
+                    let name = format!(
+                        "{} {}{}",
+                        if track.subroutine { "subroutine" } else { "track" },
+                        track.index,
+                        if track.shared { " (shared)" } else { "" }
+                    );
+                    write_data(out, &name, &track.data)?;
+                }
+            }
+            writeln!(out, "  0x{:04X} bytes saved by sharing", bank.bytes_shared)?;
+        }
+        if let Some(sfx) = &self.sfx {
+            writeln!(
+                out,
+                "sound effects  ARAM 0x{:04X}-0x{:04X}  used 0x{:04X} of 0x{:04X}",
+                sfx.aram_addr,
+                sfx.aram_addr + sfx.length,
+                sfx.used,
+                sfx.length
+            )?;
+            for sound in &sfx.sounds {
+                writeln!(
+                    out,
+                    "  {} {:>2}  {}  ARAM 0x{:04X}  0x{:04X} bytes{}",
+                    sound.table,
+                    sound.slot,
+                    sound.input,
+                    sound.aram_addr,
+                    sound.size,
+                    if sound.shared { " (shared)" } else { "" }
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_data(out: &mut Write, name: &str, data: &DataReport) -> Result<(), Box<Error>> {
+    writeln!(
+        out,
+        "    {:<24} {:<10} ARAM 0x{:04X}  0x{:04X} bytes",
+        name, data.chunk, data.aram_addr, data.size
+    )?;
+    Ok(())
+}
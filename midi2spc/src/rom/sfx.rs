@@ -0,0 +1,140 @@
+use simple_error::SimpleError;
+use std::error::Error;
+use std::path::Path;
+
+use super::report::{SfxReport, SoundReport};
+use super::{addr_to_bytes, bank_chunks, detail, input_name, Chunk, ARAM_BASE};
+use crate::error::ConvertError;
+use crate::log::Event;
+use crate::manifest::Sfx;
+use crate::midi::Transform;
+use crate::nspc::{errors_in, Song};
+
+const SFX_BANK_NAME: &str = "sfx";
+
+// the ROM address of ARAM loaded by one of the transfer blocks
+fn rom_addr(
+    chunks: &[Chunk],
+    aram_addr: usize,
+    size: usize,
+    what: &str,
+) -> Result<usize, Box<Error>> {
+    match chunks.iter().find(|chunk| {
+        chunk.aram_addr <= aram_addr && aram_addr + size <= chunk.aram_addr + chunk.length
+    }) {
+        Some(chunk) => Ok(chunk.offset_addr + aram_addr - chunk.aram_addr),
+        None => Err(Box::from(SimpleError::new(format!(
+            "{} at ARAM 0x{:X}-0x{:X} isn't loaded by the driver's transfer blocks",
+            what,
+            aram_addr,
+            aram_addr + size
+        )))),
+    }
+}
+
+/// Converts the manifest's sound effects and writes them over the driver's
+/// data, pointing their slots in the sound effect tables at them.
+pub fn write(
+    sfx: &Sfx,
+    romdata: &mut Vec<u8>,
+    converter: &Fn(&Path, f32, &Transform) -> Result<Song, Box<Error>>,
+    log: &Fn(&Event),
+    verbose: bool,
+) -> Result<SfxReport, Box<Error>> {
+    let chunks = bank_chunks(romdata, sfx.base_addr)?;
+    if sfx.data_end > ARAM_BASE {
+        return Err(Box::from(ConvertError::layout(
+            SFX_BANK_NAME,
+            None,
+            format!("sound effect data can't go past the song data at 0x{:X}", ARAM_BASE),
+        )));
+    }
+    let data_rom_addr = rom_addr(
+        &chunks,
+        sfx.data_addr,
+        sfx.data_end - sfx.data_addr,
+        "Sound effect data",
+    )?;
+    let mut data = Vec::<u8>::new();
+    let mut sounds = Vec::<SoundReport>::new();
+    for table in &sfx.tables {
+        let table_rom_addr = rom_addr(
+            &chunks,
+            table.addr,
+            table.slots * 2,
+            &format!("Sound effect table {}", table.name),
+        )?;
+        for &(slot, ref sound) in &table.sounds {
+            let path = match &sound.input {
+                Some(path) => path,
+                None => continue,
+            };
+            let name = input_name(path);
+            let song = converter(path, sound.tempo_factor, &sound.transform)?;
+            let sound_data = song.sfx_data().map_err(|err| errors_in(path, &[err.to_string()]))?;
+            // identical sound effects share their data
+            let existing = sounds
+                .iter()
+                .position(|other| !other.shared && data_of(&data, sfx, other) == &sound_data[..]);
+            let (aram_addr, shared) = match existing {
+                Some(i) => (sounds[i].aram_addr, true),
+                None => {
+                    let aram_addr = sfx.data_addr + data.len();
+                    data.extend_from_slice(&sound_data);
+                    (aram_addr, false)
+                }
+            };
+            if data.len() > sfx.data_end - sfx.data_addr {
+                return Err(Box::from(ConvertError::layout(
+                    SFX_BANK_NAME,
+                    Some(&name),
+                    format!(
+                        "sound effects need 0x{:X} bytes, 0x{:X} more than the 0x{:X} at 0x{:X}",
+                        data.len(),
+                        data.len() - (sfx.data_end - sfx.data_addr),
+                        sfx.data_end - sfx.data_addr,
+                        sfx.data_addr
+                    ),
+                )));
+            }
+            if verbose {
+                detail(log, &format!(
+                    "Writing sound effect {} to 0x{:X} for {} slot {}",
+                    name, aram_addr, table.name, slot
+                ));
+            }
+            let addr_bytes = addr_to_bytes(aram_addr);
+            romdata[table_rom_addr + slot * 2 + 1] = addr_bytes.0;
+            romdata[table_rom_addr + slot * 2] = addr_bytes.1;
+            log(&Event::SoundWritten {
+                table: &table.name,
+                slot,
+                name: &name,
+                bytes: if shared { 0 } else { sound_data.len() },
+            });
+            sounds.push(SoundReport {
+                table: table.name.clone(),
+                slot,
+                input: path.to_string_lossy().into_owned(),
+                shared,
+                aram_addr,
+                size: sound_data.len(),
+            });
+        }
+    }
+    romdata.splice(
+        data_rom_addr..data_rom_addr + data.len(),
+        data.iter().cloned(),
+    );
+    Ok(SfxReport {
+        aram_addr: sfx.data_addr,
+        length: sfx.data_end - sfx.data_addr,
+        used: data.len(),
+        sounds,
+    })
+}
+
+fn data_of<'a>(data: &'a [u8], sfx: &Sfx, sound: &SoundReport) -> &'a [u8] {
+    let start = sound.aram_addr - sfx.data_addr;
+    &data[start..start + sound.size]
+}
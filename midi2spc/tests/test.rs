@@ -1,9 +1,14 @@
 extern crate midi2spc;
 
-use midi2spc::rom::DEFAULT_BANK_BASE_ADDRS;
+use midi2spc::error::ConvertError;
+use midi2spc::log::Event;
+use midi2spc::nspc::play::Played;
 use midi2spc::*;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 fn sample_path(filename: &str) -> PathBuf {
     let mut path_buf = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -15,54 +20,567 @@ fn sample_path(filename: &str) -> PathBuf {
     path_buf
 }
 
+// files written by the tests go in a temporary directory, not the sample one
+fn temp_path(filename: &str) -> PathBuf {
+    let path_buf = env::temp_dir().join("midi2spc-test");
+    fs::create_dir_all(&path_buf).unwrap();
+    path_buf.join(filename)
+}
+
 fn copy_dummy_rom(suffix: &str) -> PathBuf {
-    let dummy_path = sample_path("dummy.smc");
-    let copy_path = dummy_path.parent().unwrap().join("dummy".to_owned() + suffix + ".smc");
-    fs::copy(dummy_path, copy_path.clone()).unwrap();
+    let copy_path = temp_path(&("dummy".to_owned() + suffix + ".smc"));
+    fs::copy(sample_path("dummy.smc"), copy_path.clone()).unwrap();
     copy_path
 }
 
+// a manifest song playing a file in the sample directory, with any other fields
+fn sample_song(filename: &str, fields: &str) -> String {
+    format!(
+        "{{\"input\": {:?}{}}}",
+        sample_path(filename).to_str().unwrap(),
+        fields
+    )
+}
+
 #[test]
 fn test_file_select() {
-    write_file_select(
-        sample_path("adagio-for-strings.mid").to_str().unwrap(),
-        copy_dummy_rom("1").to_str().unwrap(),
-        DEFAULT_BANK_BASE_ADDRS,
-        true,
-        false,
-        None,
-        None,
-        None,
+    let rom_path = copy_dummy_rom("1");
+    Options::new()
+        .output(&rom_path)
+        .write_file_select(&sample_path("adagio-for-strings.mid"), &rom_path)
+        .unwrap();
+}
+
+#[test]
+fn test_all_overworld() {
+    let rom_path = copy_dummy_rom("2");
+    // verbose output goes to the log rather than straight to stdout
+    let details = Arc::new(AtomicUsize::new(0));
+    let counter = details.clone();
+    Options::new()
+        .output(&rom_path)
+        .verbose(true)
+        .log(move |event| {
+            if let Event::Detail { .. } = event {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .write_all_overworld(&sample_path("adagio-for-strings.mid"), &rom_path)
+        .unwrap();
+    assert!(details.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn test_build_rom() {
+    let rom_path = copy_dummy_rom("3");
+    let songs_written = Arc::new(AtomicUsize::new(0));
+    let counter = songs_written.clone();
+    let report = Options::new()
+        .output(&rom_path)
+        .log(move |event| {
+            if let Event::SongWritten { .. } = event {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .build_rom(&sample_path("manifest.json"), &rom_path)
+        .unwrap();
+    assert_eq!(3, report.banks.len());
+    assert_eq!(
+        report
+            .banks
+            .iter()
+            .fold(0, |acc, bank| acc + bank.songs.len()),
+        songs_written.load(Ordering::SeqCst)
+    );
+    for bank in &report.banks {
+        for chunk in &bank.chunks {
+            assert_eq!(chunk.length, chunk.used + chunk.free);
+        }
+        let songs_size = bank.songs.iter().fold(0, |acc, song| acc + song.size());
+        let chunks_used = bank.chunks.iter().fold(0, |acc, chunk| acc + chunk.used);
+        assert!(songs_size <= chunks_used);
+    }
+    assert_eq!(15, report.banks[0].songs.len());
+}
+
+#[test]
+fn test_relocate() {
+    let rom_path = copy_dummy_rom("4");
+    let relocation = rom::Relocation {
+        rom_addr: 0x100000,
+        aram_chunks: vec![(0x3A00, 0x200)],
+    };
+    Options::new()
+        .output(&rom_path)
+        .relocation(relocation)
+        .build_rom(&sample_path("manifest.json"), &rom_path)
+        .unwrap();
+    let romdata = fs::read(rom_path).unwrap();
+    assert_eq!(0x108000, romdata.len());
+    assert_eq!(0x0B, romdata[0x7FD7]);
+    // overworld bank pointer operands now point at $20:8000
+    assert_eq!(0x00, romdata[0x914]);
+    assert_eq!(0x80, romdata[0x918]);
+    assert_eq!(0x20, romdata[0x91C]);
+    // the relocated blocks start with the base chunk going to ARAM $D000
+    assert_eq!([0x00, 0xD0], romdata[0x100002..0x100004]);
+}
+
+#[test]
+fn test_dry_run() {
+    let rom_path = copy_dummy_rom("5");
+    let report = Options::new()
+        .build_rom(&sample_path("manifest.json"), &rom_path)
+        .unwrap();
+    assert_eq!(3, report.banks.len());
+    assert_eq!(
+        fs::read(sample_path("dummy.smc")).unwrap(),
+        fs::read(rom_path).unwrap()
+    );
+}
+
+#[test]
+fn test_output() {
+    let rom_path = copy_dummy_rom("6");
+    let output_path = temp_path("dummy6-out.smc");
+    Options::new()
+        .output(&output_path)
+        .write_file_select(&sample_path("adagio-for-strings.mid"), &rom_path)
+        .unwrap();
+    let original = fs::read(sample_path("dummy.smc")).unwrap();
+    assert_eq!(original, fs::read(rom_path).unwrap());
+    assert_ne!(original, fs::read(output_path).unwrap());
+}
+
+#[test]
+fn test_patch() {
+    let rom_path = copy_dummy_rom("7");
+    for ext in &["ips", "bps"] {
+        let patch_path = temp_path(&("dummy7.".to_owned() + ext));
+        Options::new()
+            .patch(&patch_path)
+            .write_all_overworld(&sample_path("adagio-for-strings.mid"), &rom_path)
+            .unwrap();
+        assert!(fs::metadata(patch_path).unwrap().len() > 0);
+    }
+    assert_eq!(
+        fs::read(sample_path("dummy.smc")).unwrap(),
+        fs::read(rom_path).unwrap()
+    );
+}
+
+fn build_overworld(rom_path: &PathBuf, suffix: &str, songs: &[String]) -> rom::report::Report {
+    overworld_options(rom_path, suffix, songs, Options::new().output(rom_path)).unwrap()
+}
+
+fn overworld_options(
+    rom_path: &PathBuf,
+    suffix: &str,
+    songs: &[String],
+    options: Options,
+) -> Result<rom::report::Report, ConvertError> {
+    let slots = songs
+        .iter()
+        .enumerate()
+        .map(|(i, song)| format!("\"{}\": {}", i, song))
+        .collect::<Vec<String>>();
+    let manifest_path = temp_path(&format!("manifest{}.json", suffix));
+    fs::write(
+        &manifest_path,
+        format!(
+            "{{\"banks\": [{{\"name\": \"overworld\"}}], \"overworld\": {{{}}}}}",
+            slots.join(", ")
+        ),
     )
     .unwrap();
+    options.build_rom(&manifest_path, rom_path)
 }
 
 #[test]
-fn test_all_overworld() {
-    write_all_overworld(
-        sample_path("adagio-for-strings.mid").to_str().unwrap(),
-        copy_dummy_rom("2").to_str().unwrap(),
-        DEFAULT_BANK_BASE_ADDRS,
-        true,
-        false,
-        None,
-        None,
-        None,
+fn test_keep_original_songs() {
+    let rom_path = copy_dummy_rom("8");
+    let inputs = [
+        sample_song("at-peace.mid", ""),
+        sample_song("polar-penguin.mid", ", \"loop\": false"),
+        sample_song("short.mid", ""),
+    ];
+    let songs = (0..15)
+        .map(|i| inputs[i % 3].clone())
+        .collect::<Vec<String>>();
+    build_overworld(&rom_path, "8", &songs);
+
+    let mut songs = vec!["\"original\"".to_string(); 15];
+    songs[0] = "\"empty\"".to_string();
+    let report = build_overworld(&rom_path, "8", &songs);
+    let kept = fs::read(&rom_path).unwrap();
+    assert!(!report.banks[0].songs[0].original);
+    assert!(report.banks[0].songs[1..].iter().all(|song| song.original));
+    // slots that shared a song still share it
+    assert_eq!(Some(1), report.banks[0].songs[4].shared_with);
+
+    // reading the kept songs back gives the same layout
+    let report_again = build_overworld(&rom_path, "8", &songs);
+    assert_eq!(kept, fs::read(&rom_path).unwrap());
+    for (song, song_again) in report.banks[0]
+        .songs
+        .iter()
+        .zip(&report_again.banks[0].songs)
+    {
+        assert_eq!(song.size(), song_again.size());
+    }
+}
+
+#[test]
+fn test_default_bank_first_song() {
+    let rom_path = copy_dummy_rom("19");
+    let manifest_path = temp_path("manifest19.json");
+    fs::write(
+        &manifest_path,
+        format!(
+            "{{\"banks\": [{{\"name\": \"ending\"}}], \"ending\": {{\"0\": {}}}}}",
+            sample_song("short.mid", "")
+        ),
     )
     .unwrap();
+    let report = Options::new()
+        .output(&rom_path)
+        .build_rom(&manifest_path, &rom_path)
+        .unwrap();
+    assert_eq!(31, report.banks[0].songs[0].number);
+    let romdata = fs::read(&rom_path).unwrap();
+    let table = aram_rom_addr(&romdata, 0x932, 0xd000 + 31 * 2);
+    let header = report.banks[0].songs[0].header.as_ref().unwrap();
+    assert_eq!(
+        header.aram_addr,
+        romdata[table] as usize + ((romdata[table + 1] as usize) << 8)
+    );
 }
 
 #[test]
-fn test_build_rom() {
-    build_rom(
-        sample_path("manifest.json").to_str().unwrap(),
-        copy_dummy_rom("3").to_str().unwrap(),
-        DEFAULT_BANK_BASE_ADDRS,
-        true,
-        false,
-        None,
-        None,
-        None,
+fn test_file_select_keeps_songs() {
+    let rom_path = copy_dummy_rom("9");
+    build_overworld(&rom_path, "9", &vec![sample_song("at-peace.mid", ""); 15]);
+    let file_select = || {
+        Options::new()
+            .output(&rom_path)
+            .write_file_select(&sample_path("short.mid"), &rom_path)
+            .unwrap()
+    };
+    let report = file_select();
+    let romdata = fs::read(&rom_path).unwrap();
+    assert_eq!(1, report.banks.len());
+    for (i, song) in report.banks[0].songs.iter().enumerate() {
+        assert_eq!(i != 10, song.original);
+    }
+    assert!(report.banks[0].songs[0].size() > 0);
+    file_select();
+    assert_eq!(romdata, fs::read(&rom_path).unwrap());
+}
+
+#[test]
+fn test_mml_song() {
+    let rom_path = copy_dummy_rom("10");
+    let report = build_overworld(&rom_path, "10", &[sample_song("sample.mml", "")]);
+    let song = &report.banks[0].songs[0];
+    assert!(song.input.as_ref().unwrap().ends_with("sample.mml"));
+    assert_eq!(
+        3,
+        song.tracks.iter().filter(|track| !track.subroutine).count()
+    );
+    assert!(song.tracks.iter().any(|track| track.subroutine));
+}
+
+#[test]
+fn test_musicxml_song() {
+    let rom_path = copy_dummy_rom("11");
+    let report = build_overworld(&rom_path, "11", &[sample_song("sample.musicxml", "")]);
+    let song = &report.banks[0].songs[0];
+    assert!(song.input.as_ref().unwrap().ends_with("sample.musicxml"));
+    // five parts, with the two passes through the repeat sharing a part table
+    assert_eq!(16, song.header.as_ref().unwrap().size);
+    assert_eq!(4 * 16, song.part_table.as_ref().unwrap().size);
+}
+
+#[test]
+fn test_text_song_round_trip() {
+    let json_path = temp_path("short-converted.json");
+    let text_path = temp_path("short-converted.nspc");
+    let round_trip_path = temp_path("short-round-trip.json");
+    let options = Options::new().optimize(None);
+    let song = Options::new()
+        .convert_song(&sample_path("short.mid"))
+        .unwrap();
+    song.write_to_json(&json_path).unwrap();
+    fs::write(
+        &text_path,
+        options.convert_song(&json_path).unwrap().to_text(),
+    )
+    .unwrap();
+    options
+        .convert_song(&text_path)
+        .unwrap()
+        .write_to_json(&round_trip_path)
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(&json_path).unwrap(),
+        fs::read_to_string(&round_trip_path).unwrap()
+    );
+
+    let rom_path = copy_dummy_rom("12");
+    let report = build_overworld(
+        &rom_path,
+        "12",
+        &["{\"input\": \"short-converted.nspc\"}".to_string()],
+    );
+    assert!(report.banks[0].songs[0]
+        .input
+        .as_ref()
+        .unwrap()
+        .ends_with("short-converted.nspc"));
+}
+
+#[test]
+fn test_validate() {
+    let options = Options::new().optimize(None);
+    assert!(options
+        .validate(&sample_path("sample.mml"))
+        .unwrap()
+        .is_empty());
+
+    let invalid = temp_path("nested-call.nspc");
+    fs::write(
+        &invalid,
+        "part track0\ntrack track0\n    call_loop sub1 2\ntrack sub1\n    call_loop sub1 1\n",
+    )
+    .unwrap();
+    assert_eq!(
+        vec!["tracks[1].commands[0]: subroutines can't call other subroutines".to_string()],
+        options.validate(&invalid).unwrap()
+    );
+
+    let rom_path = copy_dummy_rom("13");
+    let manifest_path = temp_path("manifest13.json");
+    fs::write(&manifest_path, "{\"banks\": [{\"name\": \"overworld\"}], \"overworld\": {\"0\": {\"input\": \"nested-call.nspc\"}}}").unwrap();
+    let err = options.build_rom(&manifest_path, &rom_path).unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("tracks[1].commands[0]: subroutines can't call other subroutines"));
+}
+
+#[test]
+fn test_errors() {
+    let options = Options::new().optimize(None);
+    match options
+        .convert_song(&sample_path("missing.nspc"))
+        .unwrap_err()
+    {
+        ConvertError::Io { path, .. } => assert!(path.ends_with("missing.nspc")),
+        err => panic!("unexpected error {:?}", err),
+    }
+    let not_midi = temp_path("not-midi.mid");
+    fs::write(&not_midi, "part track0\n").unwrap();
+    match options.convert_song(&not_midi).unwrap_err() {
+        ConvertError::MidiParse { path, .. } => assert_eq!(not_midi, path),
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    // every song is different, so nothing can be shared to make them fit
+    let rom_path = copy_dummy_rom("14");
+    let inputs = [
+        "adagio-for-strings.mid",
+        "at-peace.mid",
+        "polar-penguin.mid",
+        "Caper-Arranged.mid",
+    ];
+    let songs = (0..15)
+        .map(|i| {
+            sample_song(
+                inputs[i % 4],
+                &format!(", \"tempoAdjust\": {}", 0.2 + i as f32 * 0.01),
+            )
+        })
+        .collect::<Vec<String>>();
+    match overworld_options(&rom_path, "14", &songs, Options::new().optimize(None)).unwrap_err() {
+        ConvertError::LayoutOverflow { bank, song, .. } => {
+            assert_eq!(Some("overworld".to_string()), bank);
+            assert!(song.is_some());
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+// the ROM address of ARAM loaded by the transfer blocks whose pointer is at base_addr
+fn aram_rom_addr(romdata: &[u8], base_addr: usize, aram_addr: usize) -> usize {
+    let pc = |addr: usize| (addr & 0x7fff) + ((addr / 2) & 0xff8000);
+    let mut offset = pc(((romdata[pc(base_addr + 8)] as usize) << 16)
+        + ((romdata[pc(base_addr + 4)] as usize) << 8)
+        + romdata[pc(base_addr)] as usize);
+    loop {
+        let length = romdata[offset] as usize + ((romdata[offset + 1] as usize) << 8);
+        let start = romdata[offset + 2] as usize + ((romdata[offset + 3] as usize) << 8);
+        assert!(length > 0, "ARAM 0x{:X} isn't loaded", aram_addr);
+        if aram_addr >= start && aram_addr < start + length {
+            return offset + 4 + aram_addr - start;
+        }
+        offset += 4 + length;
+    }
+}
+
+#[test]
+fn test_sfx() {
+    fs::write(
+        temp_path("chime.nspc"),
+        "part track0\n\ntrack track0\n    set_instrument 2\n    c5 len=6\n    e5\n",
     )
     .unwrap();
+    // the ending bank's transfer blocks stand in for the driver's
+    let manifest = |suffix: &str, data_end: &str| {
+        let manifest_path = temp_path(&format!("manifest{}.json", suffix));
+        fs::write(
+            &manifest_path,
+            format!(
+                r#"{{
+                    "banks": [{{"name": "overworld"}}],
+                    "sfx": {{
+                        "baseAddr": "932",
+                        "dataAddr": "2A00",
+                        "dataEnd": "{}",
+                        "tables": [{{"name": "sfx2", "addr": "2900", "slots": 8, "sounds": {{
+                            "1": {{"input": "chime.nspc"}},
+                            "3": {{"input": "chime.nspc"}}
+                        }}}}]
+                    }}
+                }}"#,
+                data_end
+            ),
+        )
+        .unwrap();
+        manifest_path
+    };
+
+    let rom_path = copy_dummy_rom("17");
+    let report = Options::new()
+        .output(&rom_path)
+        .build_rom(&manifest("17", "2A20"), &rom_path)
+        .unwrap();
+    let sfx = report.sfx.unwrap();
+    assert_eq!((0x20, 7), (sfx.length, sfx.used));
+    assert_eq!(
+        vec![false, true],
+        sfx.sounds
+            .iter()
+            .map(|sound| sound.shared)
+            .collect::<Vec<_>>()
+    );
+    let romdata = fs::read(&rom_path).unwrap();
+    let table = aram_rom_addr(&romdata, 0x932, 0x2900);
+    assert_eq!([0x00, 0x2a], romdata[table + 2..table + 4]);
+    assert_eq!([0x00, 0x2a], romdata[table + 6..table + 8]);
+    let data = aram_rom_addr(&romdata, 0x932, 0x2a00);
+    assert_eq!(
+        [0xe0, 0x02, 0x06, 0x64, 0xb0, 0xb4, 0x00],
+        romdata[data..data + 7]
+    );
+
+    let rom_path = copy_dummy_rom("18");
+    match Options::new()
+        .output(&rom_path)
+        .build_rom(&manifest("18", "2A04"), &rom_path)
+        .unwrap_err()
+    {
+        ConvertError::LayoutOverflow { bank, song, .. } => {
+            assert_eq!(Some("sfx".to_string()), bank);
+            assert_eq!(Some("chime.nspc".to_string()), song);
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+fn sample_midis() -> Vec<PathBuf> {
+    let mut paths = fs::read_dir(sample_path(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "mid"))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    paths
+}
+
+fn played(song: &nspc::Song) -> Vec<Vec<Played>> {
+    song.play()
+        .unwrap()
+        .into_iter()
+        .map(|voice| voice.commands)
+        .collect()
+}
+
+// run with MIDI2SPC_BLESS set to write the golden files again after a change
+// to the converter's output
+fn check_golden(midi_path: &Path, song: &nspc::Song) {
+    let golden_path = sample_path("golden")
+        .join(midi_path.file_stem().unwrap())
+        .with_extension("nspc");
+    if env::var_os("MIDI2SPC_BLESS").is_some() {
+        fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+        fs::write(&golden_path, song.to_text()).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&golden_path)
+        .unwrap_or_else(|_| panic!("no golden output for {}", midi_path.display()));
+    assert!(
+        golden == song.to_text(),
+        "{} doesn't match {}",
+        midi_path.display(),
+        golden_path.display()
+    );
+}
+
+#[test]
+fn test_sample_songs() {
+    let options = Options::new();
+    let unoptimized = Options::new().optimize(None);
+    for path in sample_midis() {
+        let song = options.convert_song(&path).unwrap();
+        assert!(
+            song.to_text() == options.convert_song(&path).unwrap().to_text(),
+            "{} converts differently each time",
+            path.display()
+        );
+        assert!(
+            played(&song) == played(&unoptimized.convert_song(&path).unwrap()),
+            "CallLoops change what {} plays",
+            path.display()
+        );
+        check_golden(&path, &song);
+    }
+}
+
+#[test]
+fn test_rom_round_trip() {
+    let manifest_path = sample_path("manifest.json");
+    let rom_path = copy_dummy_rom("15");
+    let again_path = copy_dummy_rom("16");
+    for path in &[&rom_path, &again_path] {
+        Options::new()
+            .output(path)
+            .build_rom(&manifest_path, path)
+            .unwrap();
+    }
+    assert!(fs::read(&rom_path).unwrap() == fs::read(&again_path).unwrap());
+
+    // songs shared across the bank still play what each converts to alone
+    let manifest = manifest::Manifest::new(&manifest_path).unwrap();
+    let banks = rom::read(&manifest, &rom_path, rom::DEFAULT_BANK_BASE_ADDRS).unwrap();
+    let options = Options::new();
+    for (bank, songs) in manifest.banks.iter().zip(&banks) {
+        for (song_def, song) in bank.songs.iter().zip(songs) {
+            let input = song_def.input.as_ref().unwrap();
+            assert!(
+                played(&options.convert_song(input).unwrap()) == played(song.as_ref().unwrap()),
+                "{} bank: {} plays differently from the ROM",
+                bank.name,
+                input.display()
+            );
+        }
+    }
 }